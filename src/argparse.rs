@@ -17,10 +17,42 @@
  *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use clap::{crate_version, App, AppSettings, Arg, ArgGroup};
+use crate::parsing::{strip_comment, Exclusion};
+use crate::tags::TagPattern;
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches};
+use clap_generate::generate;
+use clap_generate::generators::{Bash, Fish, Zsh};
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
 use std::str::FromStr;
+use std::time::Duration;
+use url::Url;
 
-#[derive(Copy, Clone, PartialEq, Debug)]
+/// Values accepted by --log-level and --log-file-level
+const LOG_LEVELS: &[&str] = &["error", "warn", "info", "debug", "trace"];
+
+/// Config file that is read from the current directory when --config is
+/// not given
+const DEFAULT_CONFIG: &str = "scrying.toml";
+
+/// Version shown by --version, along with the commit it was built from and
+/// the optional features that were compiled in. Web pages are always
+/// captured with headless Chrome.
+pub const VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (commit ",
+    env!("SCRYING_GIT_HASH"),
+    ", web capture: headless Chrome, features: ",
+    env!("SCRYING_FEATURES"),
+    ")"
+);
+
+#[derive(Copy, Clone, PartialEq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Mode {
     Auto,
     Web,
@@ -37,6 +69,17 @@ impl Mode {
         use Mode::*;
         self == &Auto || self == &filter || filter == Auto
     }
+
+    /// Lowercase name of the mode, as given to --mode
+    pub fn as_str(&self) -> &'static str {
+        use Mode::*;
+        match self {
+            Auto => "auto",
+            Web => "web",
+            Rdp => "rdp",
+            Vnc => "vnc",
+        }
+    }
 }
 
 impl Default for Mode {
@@ -54,8 +97,194 @@ impl FromStr for Mode {
             "web" => Ok(Web),
             "rdp" => Ok(Rdp),
             "vnc" => Ok(Vnc),
-            "auto" => Ok(Auto),
-            _ => Err("Mode must be \"auto\", \"web\" or \"rdp\""),
+            "auto" | "all" => Ok(Auto),
+            _ => Err("Mode must be \"auto\", \"web\", \"rdp\" or \"vnc\""),
+        }
+    }
+}
+
+/// File format used to save captured images
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ImageFormat {
+    Png,
+    Jpg,
+}
+
+impl ImageFormat {
+    /// File extension for images saved in this format
+    pub fn extension(&self) -> &'static str {
+        use ImageFormat::*;
+        match self {
+            Png => "png",
+            Jpg => "jpg",
+        }
+    }
+}
+
+impl Default for ImageFormat {
+    fn default() -> Self {
+        ImageFormat::Png
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use ImageFormat::*;
+        match s {
+            "png" => Ok(Png),
+            "jpg" | "jpeg" => Ok(Jpg),
+            _ => Err("Image format must be \"png\" or \"jpg\""),
+        }
+    }
+}
+
+/// What is saved of each web page, with --output-format
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum OutputFormat {
+    /// Only the captured image
+    Image,
+    /// A PDF of the page as well as the image
+    Pdf,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::Image
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use OutputFormat::*;
+        match s {
+            "image" => Ok(Image),
+            "pdf" => Ok(Pdf),
+            _ => Err("Output format must be \"image\" or \"pdf\""),
+        }
+    }
+}
+
+/// Paper size of the PDFs saved with --output-format pdf
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PaperSize {
+    A3,
+    A4,
+    Letter,
+    Legal,
+}
+
+impl PaperSize {
+    /// Width and height in inches, in portrait
+    pub fn inches(&self) -> (f64, f64) {
+        use PaperSize::*;
+        match self {
+            A3 => (11.69, 16.54),
+            A4 => (8.27, 11.69),
+            Letter => (8.5, 11.0),
+            Legal => (8.5, 14.0),
+        }
+    }
+}
+
+impl Default for PaperSize {
+    fn default() -> Self {
+        PaperSize::A4
+    }
+}
+
+impl FromStr for PaperSize {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use PaperSize::*;
+        match s {
+            "a3" => Ok(A3),
+            "a4" => Ok(A4),
+            "letter" => Ok(Letter),
+            "legal" => Ok(Legal),
+            _ => Err(
+                "Paper size must be \"a3\", \"a4\", \"letter\" or \"legal\"",
+            ),
+        }
+    }
+}
+
+/// Device emulated by Chrome for web captures with --device
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Device {
+    Iphone,
+    Ipad,
+    Pixel,
+    Galaxy,
+}
+
+impl Device {
+    /// Viewport width and height in CSS pixels, in portrait
+    pub fn window_size(&self) -> (u32, u32) {
+        use Device::*;
+        match self {
+            Iphone => (390, 844),
+            Ipad => (820, 1180),
+            Pixel => (393, 851),
+            Galaxy => (360, 800),
+        }
+    }
+
+    /// Number of screen pixels per CSS pixel
+    pub fn scale_factor(&self) -> f64 {
+        use Device::*;
+        match self {
+            Iphone | Galaxy => 3.0,
+            Ipad => 2.0,
+            Pixel => 2.75,
+        }
+    }
+
+    pub fn user_agent(&self) -> &'static str {
+        use Device::*;
+        match self {
+            Iphone => concat!(
+                "Mozilla/5.0 (iPhone; CPU iPhone OS 14_0 like Mac OS X) ",
+                "AppleWebKit/605.1.15 (KHTML, like Gecko) Version/14.0 ",
+                "Mobile/15E148 Safari/604.1"
+            ),
+            Ipad => concat!(
+                "Mozilla/5.0 (iPad; CPU OS 14_0 like Mac OS X) ",
+                "AppleWebKit/605.1.15 (KHTML, like Gecko) Version/14.0 ",
+                "Mobile/15E148 Safari/604.1"
+            ),
+            Pixel => concat!(
+                "Mozilla/5.0 (Linux; Android 11; Pixel 5) ",
+                "AppleWebKit/537.36 (KHTML, like Gecko) ",
+                "Chrome/90.0.4430.91 Mobile Safari/537.36"
+            ),
+            Galaxy => concat!(
+                "Mozilla/5.0 (Linux; Android 10; SM-G981B) ",
+                "AppleWebKit/537.36 (KHTML, like Gecko) ",
+                "Chrome/90.0.4430.91 Mobile Safari/537.36"
+            ),
+        }
+    }
+}
+
+impl FromStr for Device {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use Device::*;
+        match s {
+            "iphone" => Ok(Iphone),
+            "ipad" => Ok(Ipad),
+            "pixel" => Ok(Pixel),
+            "galaxy" => Ok(Galaxy),
+            _ => Err(concat!(
+                "Device must be \"iphone\", \"ipad\", \"pixel\" or ",
+                "\"galaxy\""
+            )),
         }
     }
 }
@@ -63,38 +292,148 @@ impl FromStr for Mode {
 #[derive(Debug, Default)]
 pub struct Opts {
     pub files: Vec<String>,
+    pub url_lists: Vec<String>,
     pub targets: Vec<String>,
     pub mode: Mode,
     pub rdp_timeout: usize,
+    pub rdp_connect_timeout: u64,
+    pub no_rdp_security: bool,
+    pub rdp_resolution: (u16, u16),
+    pub web_timeout: u64,
+    pub port_check_timeout: u64,
+    pub retries: u32,
+    pub retry_delay: u64,
+    pub no_port_check: bool,
+    pub probe_only: bool,
+    pub probe_tls: bool,
     pub threads: usize,
+    pub rate_limit: Option<f64>,
+    pub jitter: f64,
+    /// How long the scan may run before it stops starting captures
+    pub max_duration: Option<Duration>,
+    pub per_host_rate: Option<f64>,
+    pub shuffle: bool,
+    pub stream: bool,
+    pub seed: Option<u64>,
+    pub limit: Option<usize>,
     pub log_file: Option<String>,
+    pub log_file_level: Option<LevelFilter>,
+    pub log_max_size: Option<u64>,
+    pub log_keep: usize,
+    pub syslog: bool,
+    pub syslog_server: Option<String>,
     pub nmaps: Vec<String>,
     pub nessus: Vec<String>,
+    pub masscans: Vec<String>,
+    pub gnmaps: Vec<String>,
+    pub exclude: Vec<Exclusion>,
     pub output_dir: String,
+    pub image_format: ImageFormat,
+    pub jpeg_quality: u8,
+    pub thumbnails: bool,
+    pub thumbnail_size: u32,
+    pub max_dimension: Option<u32>,
+    pub keep_original: bool,
+    pub dedup: bool,
+    pub baseline: Option<String>,
+    pub change_threshold: f64,
+    pub diff_images: bool,
+    pub skip_blank: bool,
+    pub skip_existing: bool,
+    pub blank_threshold: f64,
+    pub skip_network_broadcast: bool,
+    /// Host bits allowed in a CIDR range by --max-range-bits, or None for
+    /// the default
+    pub max_range_bits: Option<u32>,
     pub web_proxy: Option<String>,
+    pub all_schemes: bool,
+    /// Capture every address that RDP and VNC host names resolve to
+    pub all_addresses: bool,
+    /// Scheme to use for web targets on each port, from --scheme-port
+    pub scheme_ports: Vec<(u16, String)>,
+    pub save_html: bool,
+    pub output_format: OutputFormat,
+    pub pdf_paper: PaperSize,
+    pub pdf_landscape: bool,
+    pub matches: Vec<TagPattern>,
+    pub ignore_tls_errors: bool,
+    pub chrome_path: Option<String>,
+    /// JavaScript to run on each web page before it is captured, from
+    /// --eval followed by the contents of each --eval-file
+    pub scripts: Vec<String>,
+    pub wait: u64,
+    pub wait_for_selector: Option<String>,
+    pub max_redirects: usize,
+    /// DevTools resource types that web pages aren't allowed to load,
+    /// e.g. Image
+    pub block_resources: Vec<String>,
+    pub user_agent: Option<String>,
+    pub mobile: bool,
+    pub device: Option<Device>,
+    pub lang: Option<String>,
+    pub timezone: Option<String>,
+    pub window_size: Option<(u16, u16)>,
+    pub full_page: bool,
+    pub max_height: u32,
+    pub headers: Vec<(String, String)>,
+    pub web_auth: Option<String>,
+    pub web_auth_hosts: Vec<String>,
     pub rdp_proxy: Option<String>,
+    pub source_addr: Option<IpAddr>,
+    pub rdp_user: Option<String>,
+    pub rdp_pass: Option<String>,
+    pub rdp_domain: Option<String>,
     pub silent: bool,
     pub verbose: u64,
+    pub log_level: Option<LevelFilter>,
+    pub no_color: bool,
     pub test_import: bool,
+    pub dry_run: bool,
+    pub no_report: bool,
+    pub report_embed: bool,
+    pub no_sidecar: bool,
+    pub ocr: bool,
+    pub json_summary: bool,
+    pub ndjson: bool,
+    pub webhook: Option<String>,
+    pub webhook_on_error: bool,
+    pub csv: Option<String>,
+    pub from_manifest: Vec<String>,
+    pub fail_on_error: bool,
 }
 
-pub fn parse() -> Result<Opts, Box<dyn std::error::Error>> {
-    let args = App::new("Scrying")
-        .version(crate_version!())
+/// Build the command-line definition, which is also used to generate the
+/// shell completion scripts
+pub fn app() -> App<'static> {
+    let app = App::new("Scrying")
+        .version(VERSION)
         .author("David Young https://github.com/nccgroup/scrying")
         .about("Automatic RDP, Web, and VNC screenshotting tool")
-        .setting(AppSettings::ArgRequiredElseHelp)
         .arg(
             Arg::new("FILE")
-                .about("Targets file, one per line")
+                .about("Targets file, one per line, or - to read from stdin")
                 .long("file")
                 .multiple(true)
                 .short('f')
                 .takes_value(true),
         )
+        .arg(
+            Arg::new("URL LIST")
+                .about(concat!(
+                    "Targets file to download, one per line, which may be",
+                    " gzipped"
+                ))
+                .long("url-list")
+                .multiple(true)
+                .takes_value(true)
+                .validator(is_http_url),
+        )
         .arg(
             Arg::new("TARGET")
-                .about("Target, e.g. http://example.com, rdp://[2001:db8::4]")
+                .about(concat!(
+                    "Target, e.g. http://example.com, rdp://[2001:db8::4],",
+                    " 192.0.2.0/24"
+                ))
                 .long("target")
                 .multiple(true)
                 .short('t')
@@ -102,13 +441,151 @@ pub fn parse() -> Result<Opts, Box<dyn std::error::Error>> {
         )
         .arg(
             Arg::new("MODE")
-                .about("Force targets to be parsed as `web`, `rdp`, `vnc`")
+                .about(concat!(
+                    "Only capture `web`, `rdp` or `vnc` targets. `auto` and",
+                    " `all` capture everything"
+                ))
                 .default_value("auto")
                 .long("mode")
-                .possible_values(&["web", "rdp", "vnc", "auto"])
+                .possible_values(&["web", "rdp", "vnc", "auto", "all"])
                 .short('m')
                 .takes_value(true),
         )
+        .arg(
+            Arg::new("IMAGE FORMAT")
+                .about("File format to save captured images in")
+                .default_value("png")
+                .long("image-format")
+                .possible_values(&["png", "jpg"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("JPEG QUALITY")
+                .about("Quality of JPEG images, from 1 to 100")
+                .default_value("85")
+                .long("jpeg-quality")
+                .takes_value(true)
+                .validator(is_quality),
+        )
+        .arg(
+            Arg::new("THUMBNAILS")
+                .about(concat!(
+                    "Save a small JPEG thumbnail next to each capture and",
+                    " use it in the report"
+                ))
+                .long("thumbnails"),
+        )
+        .arg(
+            Arg::new("THUMBNAIL SIZE")
+                .about("Maximum width and height of thumbnails in pixels")
+                .default_value("300")
+                .long("thumbnail-size")
+                .takes_value(true)
+                .validator(is_nonzero_u32),
+        )
+        .arg(
+            Arg::new("MAX DIMENSION")
+                .about(concat!(
+                    "Shrink captures whose width or height is more than",
+                    " this many pixels to fit within it"
+                ))
+                .long("max-dimension")
+                .takes_value(true)
+                .validator(is_nonzero_u32),
+        )
+        .arg(
+            Arg::new("KEEP ORIGINAL")
+                .about(concat!(
+                    "Keep the full size image of captures shrunk by",
+                    " --max-dimension as <name>.orig.<ext>"
+                ))
+                .long("keep-original")
+                .requires("MAX DIMENSION"),
+        )
+        .arg(
+            Arg::new("DEDUP")
+                .about(concat!(
+                    "Delete captures that look the same as an earlier one",
+                    " and group them together in the report"
+                ))
+                .long("dedup"),
+        )
+        .arg(
+            Arg::new("BASELINE")
+                .about(concat!(
+                    "Output directory of an earlier scan to compare the",
+                    " captures against"
+                ))
+                .long("baseline")
+                .takes_value(true)
+                .validator(is_dir),
+        )
+        .arg(
+            Arg::new("CHANGE THRESHOLD")
+                .about(concat!(
+                    "Fraction of pixels that must differ from the baseline",
+                    " for a capture to count as changed, 0.01 by default"
+                ))
+                .long("change-threshold")
+                .requires("BASELINE")
+                .takes_value(true)
+                .validator(is_fraction),
+        )
+        .arg(
+            Arg::new("DIFF IMAGES")
+                .about(concat!(
+                    "Save an image highlighting what changed since the",
+                    " baseline"
+                ))
+                .long("diff-images")
+                .requires("BASELINE"),
+        )
+        .arg(
+            Arg::new("SKIP EXISTING")
+                .about(concat!(
+                    "Skip targets that already have a capture in the output",
+                    " directory, to resume an interrupted scan"
+                ))
+                .long("skip-existing"),
+        )
+        .arg(
+            Arg::new("SKIP BLANK")
+                .about("Do not save captures that are blank")
+                .long("skip-blank"),
+        )
+        .arg(
+            Arg::new("BLANK THRESHOLD")
+                .about(concat!(
+                    "Largest standard deviation of brightness (0-255) for",
+                    " an image to be considered blank"
+                ))
+                .default_value("8")
+                .long("blank-threshold")
+                .takes_value(true)
+                .validator(is_threshold),
+        )
+        .arg(
+            Arg::new("RETRIES")
+                .about(concat!(
+                    "Number of times to retry a capture that timed out or",
+                    " was refused"
+                ))
+                .default_value("0")
+                .long("retries")
+                .takes_value(true)
+                .validator(is_number),
+        )
+        .arg(
+            Arg::new("RETRY DELAY")
+                .about(concat!(
+                    "Milliseconds to wait before the first retry, doubling",
+                    " for each one after up to five minutes"
+                ))
+                .default_value("1000")
+                .long("retry-delay")
+                .takes_value(true)
+                .validator(is_number),
+        )
         .arg(
             Arg::new("RDP TIMEOUT")
                 .about(
@@ -117,123 +594,969 @@ pub fn parse() -> Result<Opts, Box<dyn std::error::Error>> {
                 .default_value("2")
                 .long("rdp-timeout")
                 .takes_value(true)
-                .takes_value(true),
+                .validator(is_number),
         )
         .arg(
-            Arg::new("THREADS")
-                .about("Number of worker threads for each target type")
+            Arg::new("RDP CONNECT TIMEOUT")
+                .about(concat!(
+                    "Seconds to wait for an RDP server to connect or",
+                    " respond before giving up"
+                ))
                 .default_value("10")
-                .long("threads")
-                .takes_value(true),
+                .long("rdp-connect-timeout")
+                .takes_value(true)
+                .validator(is_nonzero),
         )
         .arg(
-            Arg::new("LOG FILE")
-                .about("Save logs to the given file")
-                .long("log-file")
-                .short('l')
-                .takes_value(true),
+            Arg::new("NO RDP SECURITY")
+                .about(concat!(
+                    "Do not make the extra connections to each RDP server",
+                    " that record its security and certificate"
+                ))
+                .long("no-rdp-security"),
         )
         .arg(
-            Arg::new("NMAP XML FILE")
-                .about("Nmap XML file")
-                .long("nmap")
-                .multiple(true)
-                .takes_value(true),
+            Arg::new("RDP RESOLUTION")
+                .about(
+                    "Desktop size to request from RDP servers, e.g. 1920x1080",
+                )
+                .default_value("1280x1024")
+                .long("rdp-resolution")
+                .takes_value(true)
+                .validator(is_resolution),
         )
         .arg(
-            Arg::new("NESSUS XML FILE")
-                .about("Nessus XML file")
-                .long("nessus")
-                .multiple(true)
-                .takes_value(true),
+            Arg::new("WEB TIMEOUT")
+                .about(concat!(
+                    "Seconds to wait for a web page to load, render and be",
+                    " captured, all together"
+                ))
+                .default_value("30")
+                .long("web-timeout")
+                .takes_value(true)
+                .validator(is_nonzero),
         )
         .arg(
-            Arg::new("OUTPUT DIR")
-                .about("Directory to save the captured images in")
-                .default_value("output")
-                .long("output")
-                .short('o')
-                .takes_value(true),
+            Arg::new("PORT CHECK TIMEOUT")
+                .about(concat!(
+                    "Milliseconds to wait when checking whether a target's",
+                    " port is open before capturing"
+                ))
+                .default_value("1000")
+                .long("port-check-timeout")
+                .takes_value(true)
+                .validator(is_nonzero),
         )
         .arg(
-            Arg::new("WEB PROXY")
+            Arg::new("NO PORT CHECK")
                 .about(concat!(
-                    "HTTP/SOCKS Proxy to use for web requests",
-                    " e.g. http://[::1]:8080"
+                    "Attempt to capture targets without checking for an",
+                    " open port first"
                 ))
-                .long("web-proxy")
-                .takes_value(true),
+                .long("no-port-check"),
         )
         .arg(
-            Arg::new("RDP PROXY")
+            Arg::new("PROBE ONLY")
                 .about(concat!(
-                    "SOCKS5 proxy to use for RDP connections",
-                    " e.g. socks5://[::1]:1080"
+                    "Only check which targets are listening, and report",
+                    " them without capturing anything"
                 ))
-                .long("rdp-proxy")
-                .takes_value(true),
+                .conflicts_with_all(&["NO PORT CHECK", "DRY RUN"])
+                .long("probe-only"),
         )
         .arg(
-            Arg::new("PROXY")
+            Arg::new("PROBE TLS")
                 .about(concat!(
-                    "Default SOCKS5 proxy to use for connections",
-                    " e.g. socks5://[::1]:1080"
+                    "Complete a TLS handshake with https targets when",
+                    " probing, and record their certificates"
                 ))
-                .long("proxy")
+                .long("probe-tls")
+                .requires("PROBE ONLY"),
+        )
+        .arg(
+            Arg::new("RATE LIMIT")
+                .about(concat!(
+                    "Most captures to start per second across all threads,",
+                    " e.g. 0.5 for one every two seconds"
+                ))
+                .long("rate-limit")
                 .takes_value(true)
-                .validator(is_socks5),
+                .validator(is_rate),
         )
         .arg(
-            Arg::new("SILENT")
-                .about("Suppress most log messages")
-                .long("silent")
-                .short('s'),
+            Arg::new("JITTER")
+                .about(concat!(
+                    "Vary the time between captures by up to this fraction",
+                    " so that they don't come at a fixed rate, 0 by default"
+                ))
+                .long("jitter")
+                .requires("RATE LIMIT")
+                .takes_value(true)
+                .validator(is_fraction),
         )
         .arg(
-            Arg::new("VERBOSE")
-                .about("Increase log verbosity")
-                .long("verbose")
-                .multiple(true)
-                .short('v')
-                .takes_value(false),
+            Arg::new("MAX DURATION")
+                .about(concat!(
+                    "Stop starting captures after this long, e.g. 90m,",
+                    " letting the running ones finish. The rest are",
+                    " recorded as not attempted"
+                ))
+                .long("max-duration")
+                .takes_value(true)
+                .validator(is_duration),
         )
         .arg(
-            Arg::new("TEST IMPORT")
-                .about("Exit after importing targets")
-                .long("test-import"),
+            Arg::new("PER HOST RATE")
+                .about(concat!(
+                    "Most captures to start per second against any one host,",
+                    " other hosts are captured in the meantime"
+                ))
+                .long("per-host-rate")
+                .takes_value(true)
+                .validator(is_rate),
         )
-        .group(
-            ArgGroup::new("inputs")
-                .multiple(true)
-                .required(true)
-                .args(&["FILE", "NMAP XML FILE", "NESSUS XML FILE", "TARGET"]),
+        .arg(
+            Arg::new("SHUFFLE")
+                .about(concat!(
+                    "Capture the targets in a random order to spread the",
+                    " load across hosts"
+                ))
+                .long("shuffle"),
         )
-        .get_matches();
-
-    // Grab input files if present, otherwise an empty Vec
-    let mut files: Vec<String> = Vec::new();
-    if let Some(f) = args.values_of("FILE") {
-        for file in f {
-            files.push(file.to_string());
-        }
-    }
-
-    // Grab targets if present, otherwise an empty Vec
-    let mut targets: Vec<String> = Vec::new();
-    if let Some(t) = args.values_of("TARGET") {
-        for target in t {
-            targets.push(target.to_string());
-        }
-    }
-
-    // Grab Nmap files if present, otherwise an empty Vec
-    let mut nmaps: Vec<String> = Vec::new();
+        .arg(
+            Arg::new("SEED")
+                .about("Seed for --shuffle, to repeat the same order")
+                .long("seed")
+                .requires("SHUFFLE")
+                .takes_value(true)
+                .validator(is_seed),
+        )
+        .arg(
+            Arg::new("LIMIT")
+                .about(concat!(
+                    "Only capture the first N targets, or a random N with",
+                    " --shuffle"
+                ))
+                .long("limit")
+                .takes_value(true)
+                .validator(is_nonzero),
+        )
+        .arg(
+            Arg::new("STREAM")
+                .about(concat!(
+                    "Start capturing while the targets are still being",
+                    " read, for very large target files"
+                ))
+                .long("stream")
+                .conflicts_with_all(&[
+                    "SHUFFLE",
+                    "PER HOST RATE",
+                    "DRY RUN",
+                    "TEST IMPORT",
+                ]),
+        )
+        .arg(
+            Arg::new("THREADS")
+                .about(concat!(
+                    "Number of captures to run at once across all target",
+                    " types"
+                ))
+                .default_value("10")
+                .long("threads")
+                .takes_value(true)
+                .validator(is_nonzero),
+        )
+        .arg(
+            Arg::new("LOG FILE")
+                .about("Save logs to the given file")
+                .long("log-file")
+                .short('l')
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("LOG FILE LEVEL")
+                .about(concat!(
+                    "Level of messages to save to the log file, instead of",
+                    " the one set by --verbose"
+                ))
+                .long("log-file-level")
+                .possible_values(LOG_LEVELS)
+                .requires("LOG FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("LOG MAX SIZE")
+                .about(concat!(
+                    "Rotate the log file once it is this big, e.g. 100M.",
+                    " Suffixes K, M and G are accepted"
+                ))
+                .long("log-max-size")
+                .requires("LOG FILE")
+                .takes_value(true)
+                .validator(is_size),
+        )
+        .arg(
+            Arg::new("LOG KEEP")
+                .about("Number of rotated log files to keep, 5 by default")
+                .long("log-keep")
+                .requires("LOG MAX SIZE")
+                .takes_value(true)
+                .validator(is_number),
+        )
+        .arg(
+            Arg::new("SYSLOG")
+                .about("Also send logs to the local syslog daemon")
+                .long("syslog"),
+        )
+        .arg(
+            Arg::new("SYSLOG SERVER")
+                .about("Send logs to a remote syslog server at host:port")
+                .long("syslog-server")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("NMAP XML FILE")
+                .about("Nmap XML file")
+                .long("nmap")
+                .multiple(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("NESSUS XML FILE")
+                .about("Nessus XML file")
+                .long("nessus")
+                .multiple(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("MASSCAN FILE")
+                .about("Masscan list output file, from -oL")
+                .long("masscan")
+                .multiple(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("GNMAP FILE")
+                .about("Nmap greppable output file, from -oG")
+                .long("gnmap")
+                .multiple(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("EXCLUDE")
+                .about(concat!(
+                    "Never capture this address or CIDR range, even if it",
+                    " is in the targets"
+                ))
+                .long("exclude")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .validator(is_exclusion),
+        )
+        .arg(
+            Arg::new("EXCLUDE FILE")
+                .about("File of addresses and CIDR ranges to exclude")
+                .long("exclude-file")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .validator(is_file),
+        )
+        .arg(
+            Arg::new("OUTPUT DIR")
+                .about("Directory to save the captured images in")
+                .default_value("output")
+                .long("output")
+                .alias("output-dir")
+                .short('o')
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("ALL SCHEMES")
+                .about(concat!(
+                    "Capture both http:// and https:// for web targets",
+                    " given without a scheme, instead of guessing one"
+                ))
+                .long("all-schemes"),
+        )
+        .arg(
+            Arg::new("ALL ADDRESSES")
+                .about(concat!(
+                    "Capture every address that RDP and VNC host names",
+                    " resolve to, instead of just the first"
+                ))
+                .long("all-addresses"),
+        )
+        .arg(
+            Arg::new("SCHEME PORT")
+                .about(concat!(
+                    "Scheme to use for web targets on a port when they are",
+                    " given without one, e.g. 9000=https"
+                ))
+                .long("scheme-port")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .validator(is_scheme_port),
+        )
+        .arg(
+            Arg::new("SAVE HTML")
+                .about("Save the rendered HTML of web pages next to the images")
+                .long("save-html"),
+        )
+        .arg(
+            Arg::new("OUTPUT FORMAT")
+                .about(concat!(
+                    "What to save of web pages, either the image alone or a",
+                    " PDF as well, image by default"
+                ))
+                .long("output-format")
+                .possible_values(&["image", "pdf"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("PDF PAPER")
+                .about(concat!(
+                    "Paper size of the PDFs saved with --output-format pdf,",
+                    " a4 by default"
+                ))
+                .long("pdf-paper")
+                .possible_values(&["a3", "a4", "letter", "legal"])
+                .requires("OUTPUT FORMAT")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("PDF LANDSCAPE")
+                .about(concat!(
+                    "Print the PDFs saved with --output-format pdf in",
+                    " landscape"
+                ))
+                .long("pdf-landscape")
+                .requires("OUTPUT FORMAT"),
+        )
+        .arg(
+            Arg::new("MATCH")
+                .about(concat!(
+                    "Tag captures whose page source or OCR text matches a",
+                    " regex, given as name=regex",
+                ))
+                .long("match")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .validator(is_tag_pattern),
+        )
+        .arg(
+            Arg::new("IGNORE TLS ERRORS")
+                .about("Capture web pages with invalid TLS certificates")
+                .long("ignore-tls-errors"),
+        )
+        .arg(
+            Arg::new("CHROME PATH")
+                .about(concat!(
+                    "Path to the Chrome or Chromium binary to use for web ",
+                    "captures, instead of searching for one"
+                ))
+                .long("chrome-path")
+                .env("SCRYING_CHROME")
+                .takes_value(true)
+                .validator(is_file),
+        )
+        .arg(
+            Arg::new("EVAL")
+                .about(concat!(
+                    "JavaScript to run on web pages before capturing them, ",
+                    "e.g. to dismiss cookie banners"
+                ))
+                .long("eval")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("EVAL FILE")
+                .about(concat!(
+                    "File of JavaScript to run on web pages before ",
+                    "capturing them"
+                ))
+                .long("eval-file")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .validator(is_file),
+        )
+        .arg(
+            Arg::new("WAIT")
+                .about(concat!(
+                    "Milliseconds to wait after a web page loads before ",
+                    "capturing it"
+                ))
+                .default_value("0")
+                .long("wait")
+                .takes_value(true)
+                .validator(is_number),
+        )
+        .arg(
+            Arg::new("WAIT FOR SELECTOR")
+                .about(concat!(
+                    "Wait until an element matching this CSS selector ",
+                    "appears before capturing web pages, up to the web timeout"
+                ))
+                .long("wait-for-selector")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("MAX REDIRECTS")
+                .about("Give up on web pages that redirect more than this")
+                .default_value("10")
+                .long("max-redirects")
+                .takes_value(true)
+                .validator(is_number),
+        )
+        .arg(
+            Arg::new("BLOCK RESOURCES")
+                .about(concat!(
+                    "Stop web pages from loading these types of resource,",
+                    " which makes captures quicker but leaves the pages",
+                    " looking broken, e.g. image,font"
+                ))
+                .long("block-resources")
+                .multiple(true)
+                .possible_values(&["css", "image", "font", "media"])
+                .takes_value(true)
+                .use_delimiter(true),
+        )
+        .arg(
+            Arg::new("WEB PROXY")
+                .about(concat!(
+                    "HTTP/SOCKS Proxy to use for web requests",
+                    " e.g. http://[::1]:8080"
+                ))
+                .long("web-proxy")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("USER AGENT")
+                .about("User agent to send with web requests")
+                .long("user-agent")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("MOBILE")
+                .about(concat!(
+                    "Use a mobile user agent and window size for web",
+                    " captures"
+                ))
+                .long("mobile"),
+        )
+        .arg(
+            Arg::new("DEVICE")
+                .about(concat!(
+                    "Emulate a device's screen, touch and user agent for web",
+                    " captures"
+                ))
+                .long("device")
+                .possible_values(&["iphone", "ipad", "pixel", "galaxy"])
+                .conflicts_with("MOBILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("LANG")
+                .about(concat!(
+                    "Language for web pages to be shown in, e.g. fr-FR, which",
+                    " sets Accept-Language and the browser locale"
+                ))
+                .long("lang")
+                .takes_value(true)
+                .validator(is_language),
+        )
+        .arg(
+            Arg::new("TIMEZONE")
+                .about(concat!(
+                    "Timezone for web pages to see, e.g. Europe/Paris,",
+                    " instead of the local one"
+                ))
+                .long("timezone")
+                .takes_value(true)
+                .validator(is_timezone),
+        )
+        .arg(
+            Arg::new("WINDOW SIZE")
+                .about(concat!(
+                    "Browser window size for web captures, e.g. 1920x1080. ",
+                    "Overrides the --mobile and --device window size"
+                ))
+                .long("window-size")
+                .takes_value(true)
+                .validator(is_resolution),
+        )
+        .arg(
+            Arg::new("FULL PAGE")
+                .about(concat!(
+                    "Capture the whole of each web page rather than just ",
+                    "the part that fits in the window"
+                ))
+                .long("full-page"),
+        )
+        .arg(
+            Arg::new("MAX HEIGHT")
+                .about(concat!(
+                    "Cut off --full-page captures at this many pixels,",
+                    " 10000 by default"
+                ))
+                .long("max-height")
+                .requires("FULL PAGE")
+                .takes_value(true)
+                .validator(is_nonzero_u32),
+        )
+        .arg(
+            Arg::new("HEADER")
+                .about(concat!(
+                    "Extra HTTP header to send with web requests,",
+                    " e.g. \"X-Forwarded-For: 192.0.2.1\""
+                ))
+                .long("header")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .validator(is_header),
+        )
+        .arg(
+            Arg::new("WEB AUTH")
+                .about("HTTP basic auth credentials, e.g. admin:password")
+                .long("web-auth")
+                .takes_value(true)
+                .validator(is_credentials),
+        )
+        .arg(
+            Arg::new("WEB AUTH HOST")
+                .about(concat!(
+                    "Host to send --web-auth credentials to, instead of",
+                    " the host of each target. May be given multiple times"
+                ))
+                .long("web-auth-host")
+                .multiple(true)
+                .number_of_values(1)
+                .requires("WEB AUTH")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("RDP PROXY")
+                .about(concat!(
+                    "SOCKS5 proxy to use for RDP connections",
+                    " e.g. socks5://[::1]:1080"
+                ))
+                .long("rdp-proxy")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("RDP USER")
+                .about(concat!(
+                    "Username for RDP authentication. Without this the",
+                    " login screen is captured"
+                ))
+                .long("rdp-user")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("RDP PASS")
+                .about("Password for RDP authentication")
+                .long("rdp-pass")
+                .requires("RDP USER")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("RDP DOMAIN")
+                .about("Domain for RDP authentication")
+                .long("rdp-domain")
+                .requires("RDP USER")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("PROXY")
+                .about(concat!(
+                    "Default SOCKS5 proxy to use for connections",
+                    " e.g. socks5://[::1]:1080"
+                ))
+                .long("proxy")
+                .takes_value(true)
+                .validator(is_socks5),
+        )
+        .arg(
+            Arg::new("SOURCE ADDR")
+                .about(concat!(
+                    "Local address to make connections from, e.g. that of",
+                    " a management interface. Not supported by Chrome"
+                ))
+                .long("source-addr")
+                .takes_value(true)
+                .validator(is_source_addr),
+        )
+        .arg(
+            Arg::new("SKIP NETWORK BROADCAST")
+                .about(concat!(
+                    "Skip the network and broadcast addresses when",
+                    " expanding legacy IP CIDR ranges"
+                ))
+                .long("skip-network-broadcast"),
+        )
+        .arg(
+            Arg::new("MAX RANGE BITS")
+                .about(concat!(
+                    "Number of host bits in the largest CIDR range that will",
+                    " be expanded, 16 by default, i.e. a legacy IP /16 or an",
+                    " IPv6 /112. Every address is held in memory unless",
+                    " --stream is given"
+                ))
+                .long("max-range-bits")
+                .takes_value(true)
+                .validator(is_range_bits),
+        )
+        .arg(
+            Arg::new("SILENT")
+                .about("Suppress most log messages and the end of scan summary")
+                .long("silent")
+                .short('s'),
+        )
+        .arg(
+            Arg::new("NO COLOR")
+                .about(concat!(
+                    "Log to the terminal without colours, which is also the",
+                    " default when NO_COLOR is set"
+                ))
+                .long("no-color"),
+        )
+        .arg(
+            Arg::new("JSON SUMMARY")
+                .about("Print the end of scan summary to stdout as JSON")
+                .long("json-summary"),
+        )
+        .arg(
+            Arg::new("NDJSON")
+                .about(concat!(
+                    "Print each result to stdout as a line of JSON as soon",
+                    " as it completes, with the log on stderr"
+                ))
+                .long("ndjson")
+                .conflicts_with_all(&["JSON SUMMARY", "DRY RUN"]),
+        )
+        .arg(
+            Arg::new("WEBHOOK")
+                .about(concat!(
+                    "POST a JSON summary of the scan to this URL once it",
+                    " has finished"
+                ))
+                .long("webhook")
+                .takes_value(true)
+                .validator(is_http_url),
+        )
+        .arg(
+            Arg::new("WEBHOOK ON ERROR")
+                .about("Also POST to the webhook whenever a capture fails")
+                .long("webhook-on-error")
+                .requires("WEBHOOK"),
+        )
+        .arg(
+            Arg::new("FROM MANIFEST")
+                .about(concat!(
+                    "Regenerate the report from the results.json of earlier",
+                    " scans instead of capturing. Give more than one to",
+                    " merge them"
+                ))
+                .long("from-manifest")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .validator(is_file),
+        )
+        .arg(
+            Arg::new("CSV")
+                .about("Also save the results as a CSV file at this path")
+                .long("csv")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("FAIL ON ERROR")
+                .about(concat!(
+                    "Exit with a non-zero code if any capture fails, rather",
+                    " than only if they all do"
+                ))
+                .long("fail-on-error"),
+        )
+        .arg(
+            Arg::new("VERBOSE")
+                .about("Increase log verbosity")
+                .long("verbose")
+                .multiple(true)
+                .short('v')
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("LOG LEVEL")
+                .about(concat!(
+                    "Level of messages to log to the terminal, instead of",
+                    " the one set by --verbose or --silent"
+                ))
+                .long("log-level")
+                .possible_values(LOG_LEVELS)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("TEST IMPORT")
+                .about("Exit after importing targets")
+                .long("test-import"),
+        )
+        .arg(
+            Arg::new("DRY RUN")
+                .about(concat!(
+                    "List each target with its type and output file, then",
+                    " exit without capturing anything"
+                ))
+                .long("dry-run"),
+        )
+        .arg(
+            Arg::new("NO REPORT")
+                .about("Do not generate an HTML report of the results")
+                .long("no-report"),
+        )
+        .arg(
+            Arg::new("REPORT EMBED")
+                .about(concat!(
+                    "Embed thumbnails of the captures in report.html so",
+                    " that it can be shared on its own"
+                ))
+                .conflicts_with("NO REPORT")
+                .long("report-embed"),
+        )
+        .arg(
+            Arg::new("NO SIDECAR")
+                .about(concat!(
+                    "Do not save a .json file of the details of each",
+                    " capture next to its image"
+                ))
+                .long("no-sidecar"),
+        )
+        .arg(
+            Arg::new("CONFIG")
+                .about(concat!(
+                    "TOML file of default options, keyed by their long",
+                    " names. Defaults to scrying.toml if it exists"
+                ))
+                .long("config")
+                .takes_value(true)
+                .validator(is_file),
+        )
+        .group(ArgGroup::new("inputs").multiple(true).args(&[
+            "FILE",
+            "URL LIST",
+            "NMAP XML FILE",
+            "NESSUS XML FILE",
+            "MASSCAN FILE",
+            "GNMAP FILE",
+            "TARGET",
+            "FROM MANIFEST",
+        ]))
+        .subcommand(
+            App::new("completions")
+                .about("Print a shell completion script and exit")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .arg(
+                    Arg::new("SHELL")
+                        .about("Shell to generate the completions for")
+                        .possible_values(&["bash", "zsh", "fish"])
+                        .required(true),
+                ),
+        );
+
+    // Only offered when built with the ocr feature
+    if cfg!(feature = "ocr") {
+        app.arg(
+            Arg::new("OCR")
+                .about(concat!(
+                    "Recognise the text in each capture with tesseract,",
+                    " saving it alongside the image and in the report"
+                ))
+                .long("ocr"),
+        )
+    } else {
+        app
+    }
+}
+
+/// Write the completion script for `shell`, one of the SHELL values of the
+/// completions subcommand
+fn print_completions(shell: &str, buf: &mut dyn Write) {
+    let mut app = app();
+    match shell {
+        "bash" => generate::<Bash, _>(&mut app, "scrying", buf),
+        "zsh" => generate::<Zsh, _>(&mut app, "scrying", buf),
+        _ => generate::<Fish, _>(&mut app, "scrying", buf),
+    }
+}
+
+/// Options that can be set with an environment variable, along with the
+/// variable, kept in step with the `env` of each one. These take priority
+/// over the config file.
+const ENV_OPTIONS: &[(&str, &str)] = &[("CHROME PATH", "SCRYING_CHROME")];
+
+/// Read the command line leniently, as it may be missing options, such as
+/// ones that another option requires, that only the config file gives.
+/// Nothing is checked until it is parsed again with them added.
+fn lenient_matches(app: &App, cli_args: &[OsString]) -> ArgMatches {
+    app.clone()
+        .setting(AppSettings::IgnoreErrors)
+        .get_matches_from(cli_args)
+}
+
+/// Turn the contents of a config file into extra command-line arguments.
+/// Options that were given on the command line or by an environment
+/// variable are left out so that they take priority, and unknown keys are
+/// warned about.
+fn config_args(
+    app: &App,
+    cli: &ArgMatches,
+    config: &str,
+    source: &str,
+) -> Result<Vec<String>, String> {
+    use toml::Value;
+    let table: toml::value::Table = toml::from_str(config)
+        .map_err(|e| format!("Invalid config file {}: {}", source, e))?;
+
+    let mut args = Vec::new();
+    for (key, value) in table {
+        // The loggers are not set up yet so warnings go to stderr
+        let arg = match app
+            .get_arguments()
+            .find(|a| a.get_long() == Some(key.as_str()))
+        {
+            Some(arg) => arg,
+            None => {
+                eprintln!("Warning: unknown option {} in {}", key, source);
+                continue;
+            }
+        };
+        let from_env = ENV_OPTIONS.iter().any(|(name, var)| {
+            *name == arg.get_name() && std::env::var_os(var).is_some()
+        });
+        if cli.occurrences_of(arg.get_name()) > 0 || from_env {
+            continue;
+        }
+
+        let values = match value {
+            Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                Value::Boolean(true) => {
+                    args.push(format!("--{}", key));
+                    continue;
+                }
+                Value::Boolean(false) => continue,
+                Value::String(s) => s,
+                Value::Integer(i) => i.to_string(),
+                Value::Float(f) => f.to_string(),
+                _ => {
+                    return Err(format!(
+                        "Unsupported value for {} in {}",
+                        key, source
+                    ))
+                }
+            };
+            args.push(format!("--{}={}", key, value));
+        }
+    }
+    Ok(args)
+}
+
+pub fn parse() -> Result<Opts, Box<dyn std::error::Error>> {
+    let app = app();
+    let cli_args: Vec<OsString> = std::env::args_os().collect();
+    let cli = lenient_matches(&app, &cli_args);
+
+    // Options from the config file go before the ones on the command line,
+    // and parsing properly waits until they have been added
+    let mut merged = cli_args.clone();
+    let config = match cli.value_of("CONFIG") {
+        Some(c) => Some(c.to_string()),
+        None if Path::new(DEFAULT_CONFIG).is_file() => {
+            Some(DEFAULT_CONFIG.to_string())
+        }
+        None => None,
+    };
+    if let Some(config) = config {
+        let content = std::fs::read_to_string(&config).map_err(|e| {
+            format!("Unable to read config file {}: {}", config, e)
+        })?;
+        let extra = config_args(&app, &cli, &content, &config)?;
+        merged.splice(1..1, extra.into_iter().map(OsString::from));
+    }
+    let args = app.clone().get_matches_from(merged);
+
+    // Printing the completions doesn't need the rest of the options
+    if let Some(completions) = args.subcommand_matches("completions") {
+        let shell = completions.value_of("SHELL").unwrap();
+        print_completions(shell, &mut std::io::stdout());
+        std::process::exit(0);
+    }
+
+    // The PDF options are no use unless PDFs are being saved, which clap
+    // can only check the presence of --output-format for
+    let output_format: OutputFormat =
+        args.value_of_t("OUTPUT FORMAT").unwrap_or_default();
+    if output_format != OutputFormat::Pdf
+        && (args.is_present("PDF PAPER") || args.is_present("PDF LANDSCAPE"))
+    {
+        return Err(
+            "--pdf-paper and --pdf-landscape need --output-format pdf".into()
+        );
+    }
+
+    // Grab input files if present, otherwise an empty Vec
+    let mut files: Vec<String> = Vec::new();
+    if let Some(f) = args.values_of("FILE") {
+        for file in f {
+            files.push(file.to_string());
+        }
+    }
+
+    // Grab targets if present, otherwise an empty Vec
+    let mut targets: Vec<String> = Vec::new();
+    if let Some(t) = args.values_of("TARGET") {
+        for target in t {
+            targets.push(target.to_string());
+        }
+    }
+
+    // Grab Nmap files if present, otherwise an empty Vec
+    let mut nmaps: Vec<String> = Vec::new();
     if let Some(n) = args.values_of("NMAP XML FILE") {
         for nmap in n {
             nmaps.push(nmap.to_string());
         }
     }
 
+    // Grab masscan and greppable nmap files if present
+    let masscans: Vec<String> = args
+        .values_of("MASSCAN FILE")
+        .map_or_else(Vec::new, |m| m.map(String::from).collect());
+    let gnmaps: Vec<String> = args
+        .values_of("GNMAP FILE")
+        .map_or_else(Vec::new, |g| g.map(String::from).collect());
+
+    // If no inputs were given then read targets from stdin, provided that
+    // something is being piped in. Otherwise there is nothing to do, so
+    // show the help text.
+    if !args.is_present("inputs") {
+        if atty::is(atty::Stream::Stdin) {
+            app.clone().print_help()?;
+            println!();
+            std::process::exit(1);
+        }
+        files.push("-".to_string());
+    }
+
     // Grab Nessus files if present, otherwise an empty Vec
     let mut nessus: Vec<String> = Vec::new();
     if let Some(n) = args.values_of("NESSUS XML FILE") {
@@ -242,6 +1565,69 @@ pub fn parse() -> Result<Opts, Box<dyn std::error::Error>> {
         }
     }
 
+    // Grab extra headers if present, otherwise an empty Vec. They have
+    // already been validated so parsing will succeed.
+    let mut headers: Vec<(String, String)> = Vec::new();
+    if let Some(h) = args.values_of("HEADER") {
+        for header in h {
+            headers.push(parse_header(header).unwrap());
+        }
+    }
+
+    let scheme_ports: Vec<(u16, String)> =
+        args.values_of("SCHEME PORT").map_or_else(Vec::new, |s| {
+            s.map(|s| parse_scheme_port(s).unwrap()).collect()
+        });
+
+    // Grab scripts to run on web pages if present, otherwise an empty Vec
+    let mut scripts: Vec<String> = Vec::new();
+    if let Some(s) = args.values_of("EVAL") {
+        for script in s {
+            scripts.push(script.to_string());
+        }
+    }
+    if let Some(f) = args.values_of("EVAL FILE") {
+        for file in f {
+            scripts.push(std::fs::read_to_string(file)?);
+        }
+    }
+
+    // Grab exclusions from the arguments and files if present. Exclusion
+    // files have one per line and may have # comments.
+    let mut exclude: Vec<Exclusion> = Vec::new();
+    if let Some(e) = args.values_of("EXCLUDE") {
+        for exclusion in e {
+            exclude.push(exclusion.parse()?);
+        }
+    }
+    if let Some(f) = args.values_of("EXCLUDE FILE") {
+        for file in f {
+            for line in std::fs::read_to_string(file)?.lines() {
+                let line = strip_comment(line);
+                if !line.is_empty() {
+                    exclude.push(line.parse().map_err(|e| {
+                        format!("{} in exclude file {}", e, file)
+                    })?);
+                }
+            }
+        }
+    }
+
+    let mut matches: Vec<TagPattern> = Vec::new();
+    if let Some(m) = args.values_of("MATCH") {
+        for pattern in m {
+            matches.push(pattern.parse()?);
+        }
+    }
+
+    // Grab basic auth hosts if present, otherwise an empty Vec
+    let mut web_auth_hosts: Vec<String> = Vec::new();
+    if let Some(h) = args.values_of("WEB AUTH HOST") {
+        for host in h {
+            web_auth_hosts.push(host.to_string());
+        }
+    }
+
     // If global proxy setting is configured then set all indivitual
     // proxy values to it. Then override each one in turn if applicable
     let mut web_proxy = None;
@@ -261,21 +1647,129 @@ pub fn parse() -> Result<Opts, Box<dyn std::error::Error>> {
 
     Ok(Opts {
         files,
+        url_lists: args
+            .values_of("URL LIST")
+            .map_or_else(Vec::new, |u| u.map(String::from).collect()),
         targets,
         mode: args.value_of_t("MODE").unwrap(),
         rdp_timeout: args.value_of_t("RDP TIMEOUT").unwrap(),
+        rdp_connect_timeout: args.value_of_t("RDP CONNECT TIMEOUT").unwrap(),
+        no_rdp_security: args.is_present("NO RDP SECURITY"),
+        rdp_resolution: parse_resolution(
+            args.value_of("RDP RESOLUTION").unwrap(),
+        )
+        .unwrap(),
+        web_timeout: args.value_of_t("WEB TIMEOUT").unwrap(),
+        port_check_timeout: args.value_of_t("PORT CHECK TIMEOUT").unwrap(),
+        retries: args.value_of_t("RETRIES").unwrap(),
+        retry_delay: args.value_of_t("RETRY DELAY").unwrap(),
+        no_port_check: args.is_present("NO PORT CHECK"),
+        probe_only: args.is_present("PROBE ONLY"),
+        probe_tls: args.is_present("PROBE TLS"),
         threads: args.value_of_t("THREADS").unwrap(),
+        rate_limit: args.value_of("RATE LIMIT").map(|r| r.parse().unwrap()),
+        jitter: args.value_of_t("JITTER").unwrap_or(0.0),
+        max_duration: args
+            .value_of("MAX DURATION")
+            .map(|d| parse_duration(d).unwrap()),
+        per_host_rate: args
+            .value_of("PER HOST RATE")
+            .map(|r| r.parse().unwrap()),
+        shuffle: args.is_present("SHUFFLE"),
+        stream: args.is_present("STREAM"),
+        seed: args.value_of("SEED").map(|s| s.parse().unwrap()),
+        limit: args.value_of("LIMIT").map(|l| l.parse().unwrap()),
         log_file: args
             .value_of("LOG FILE")
             .map_or_else(|| None, |s| Some(s.to_string())),
+        log_file_level: args.value_of_t("LOG FILE LEVEL").ok(),
+        log_max_size: args
+            .value_of("LOG MAX SIZE")
+            .map(|s| parse_size(s).unwrap()),
+        log_keep: args.value_of_t("LOG KEEP").unwrap_or(5),
+        syslog: args.is_present("SYSLOG"),
+        syslog_server: args.value_of("SYSLOG SERVER").map(String::from),
         nmaps,
         nessus,
+        masscans,
+        gnmaps,
+        exclude,
         output_dir: args.value_of_t("OUTPUT DIR").unwrap(),
+        image_format: args.value_of_t("IMAGE FORMAT").unwrap(),
+        jpeg_quality: args.value_of_t("JPEG QUALITY").unwrap(),
+        thumbnails: args.is_present("THUMBNAILS"),
+        thumbnail_size: args.value_of_t("THUMBNAIL SIZE").unwrap(),
+        max_dimension: args
+            .value_of("MAX DIMENSION")
+            .map(|d| d.parse().unwrap()),
+        keep_original: args.is_present("KEEP ORIGINAL"),
+        dedup: args.is_present("DEDUP"),
+        baseline: args.value_of("BASELINE").map(String::from),
+        change_threshold: args.value_of_t("CHANGE THRESHOLD").unwrap_or(0.01),
+        diff_images: args.is_present("DIFF IMAGES"),
+        skip_blank: args.is_present("SKIP BLANK"),
+        skip_existing: args.is_present("SKIP EXISTING"),
+        blank_threshold: args.value_of_t("BLANK THRESHOLD").unwrap(),
+        skip_network_broadcast: args.is_present("SKIP NETWORK BROADCAST"),
+        max_range_bits: args
+            .value_of("MAX RANGE BITS")
+            .map(|b| b.parse().unwrap()),
         web_proxy,
+        all_schemes: args.is_present("ALL SCHEMES"),
+        all_addresses: args.is_present("ALL ADDRESSES"),
+        scheme_ports,
+        save_html: args.is_present("SAVE HTML"),
+        output_format,
+        pdf_paper: args.value_of_t("PDF PAPER").unwrap_or_default(),
+        pdf_landscape: args.is_present("PDF LANDSCAPE"),
+        matches,
+        ignore_tls_errors: args.is_present("IGNORE TLS ERRORS"),
+        chrome_path: args.value_of("CHROME PATH").map(String::from),
+        scripts,
+        wait: args.value_of_t("WAIT").unwrap(),
+        wait_for_selector: args.value_of("WAIT FOR SELECTOR").map(String::from),
+        max_redirects: args.value_of_t("MAX REDIRECTS").unwrap(),
+        block_resources: args
+            .values_of("BLOCK RESOURCES")
+            .map_or_else(Vec::new, |r| r.map(resource_type).collect()),
+        user_agent: args.value_of("USER AGENT").map(|s| s.to_string()),
+        mobile: args.is_present("MOBILE"),
+        device: args.value_of("DEVICE").map(|d| d.parse().unwrap()),
+        lang: args.value_of("LANG").map(String::from),
+        timezone: args.value_of("TIMEZONE").map(String::from),
+        window_size: args
+            .value_of("WINDOW SIZE")
+            .map(|s| parse_resolution(s).unwrap()),
+        full_page: args.is_present("FULL PAGE"),
+        max_height: args.value_of_t("MAX HEIGHT").unwrap_or(10000),
+        headers,
+        web_auth: args.value_of("WEB AUTH").map(|s| s.to_string()),
+        web_auth_hosts,
         rdp_proxy,
+        source_addr: args.value_of("SOURCE ADDR").map(|a| a.parse().unwrap()),
+        rdp_user: args.value_of("RDP USER").map(|s| s.to_string()),
+        rdp_pass: args.value_of("RDP PASS").map(|s| s.to_string()),
+        rdp_domain: args.value_of("RDP DOMAIN").map(|s| s.to_string()),
         silent: args.is_present("SILENT"),
         verbose: args.occurrences_of("VERBOSE"),
+        log_level: args.value_of_t("LOG LEVEL").ok(),
+        no_color: args.is_present("NO COLOR")
+            || no_color_requested(std::env::var_os("NO_COLOR")),
         test_import: args.is_present("TEST IMPORT"),
+        dry_run: args.is_present("DRY RUN"),
+        no_report: args.is_present("NO REPORT"),
+        report_embed: args.is_present("REPORT EMBED"),
+        no_sidecar: args.is_present("NO SIDECAR"),
+        ocr: cfg!(feature = "ocr") && args.is_present("OCR"),
+        json_summary: args.is_present("JSON SUMMARY"),
+        ndjson: args.is_present("NDJSON"),
+        webhook: args.value_of("WEBHOOK").map(String::from),
+        webhook_on_error: args.is_present("WEBHOOK ON ERROR"),
+        csv: args.value_of("CSV").map(String::from),
+        from_manifest: args
+            .values_of("FROM MANIFEST")
+            .map_or_else(Vec::new, |m| m.map(String::from).collect()),
+        fail_on_error: args.is_present("FAIL ON ERROR"),
     })
 }
 
@@ -287,6 +1781,294 @@ fn is_socks5(val: &str) -> Result<(), String> {
     }
 }
 
+/// Split a "Name: Value" header into its name and value
+fn parse_header(val: &str) -> Result<(String, String), String> {
+    let mut parts = val.splitn(2, ':');
+    let name = parts.next().unwrap_or_default().trim();
+    match parts.next() {
+        Some(value) if !name.is_empty() => {
+            Ok((name.to_string(), value.trim().to_string()))
+        }
+        _ => Err(format!(
+            "Invalid header \"{}\", expected the format \"Name: Value\"",
+            val
+        )),
+    }
+}
+
+fn is_header(val: &str) -> Result<(), String> {
+    parse_header(val).map(|_| ())
+}
+
+/// Parse a PORT=SCHEME pair for --scheme-port
+fn parse_scheme_port(val: &str) -> Result<(u16, String), String> {
+    let invalid = || {
+        format!(
+            "Invalid scheme for a port \"{}\", expected e.g. 9000=https",
+            val
+        )
+    };
+    let mut parts = val.splitn(2, '=');
+    let port = parts.next().unwrap_or_default().trim();
+    let scheme = parts.next().ok_or_else(invalid)?.trim().to_lowercase();
+    let port: u16 = port.parse().map_err(|_| invalid())?;
+    if port == 0 {
+        return Err(invalid());
+    }
+    match scheme.as_str() {
+        "http" | "https" => Ok((port, scheme)),
+        _ => Err(format!(
+            "Unsupported scheme {} for port {}, expected http or https",
+            scheme, port
+        )),
+    }
+}
+
+fn is_scheme_port(val: &str) -> Result<(), String> {
+    parse_scheme_port(val).map(|_| ())
+}
+
+/// Parse a resolution in the format WIDTHxHEIGHT, rejecting anything too
+/// small to be useful or larger than an RDP server will accept
+pub fn parse_resolution(val: &str) -> Result<(u16, u16), String> {
+    let invalid = || {
+        format!(
+            "Invalid resolution \"{}\", expected the format WIDTHxHEIGHT",
+            val
+        )
+    };
+    let mut parts = val.splitn(2, |c| c == 'x' || c == 'X');
+    let width: u16 = parts
+        .next()
+        .and_then(|w| w.trim().parse().ok())
+        .ok_or_else(invalid)?;
+    let height: u16 = parts
+        .next()
+        .and_then(|h| h.trim().parse().ok())
+        .ok_or_else(invalid)?;
+
+    if !(200..=8192).contains(&width) || !(200..=8192).contains(&height) {
+        return Err(format!(
+            "Resolution {}x{} out of range, each dimension must be between \
+            200 and 8192",
+            width, height
+        ));
+    }
+    Ok((width, height))
+}
+
+fn is_resolution(val: &str) -> Result<(), String> {
+    parse_resolution(val).map(|_| ())
+}
+
+fn is_credentials(val: &str) -> Result<(), String> {
+    if val.contains(':') {
+        Ok(())
+    } else {
+        Err("Credentials must be in the format username:password".to_string())
+    }
+}
+
+fn is_threshold(val: &str) -> Result<(), String> {
+    match val.parse::<f64>() {
+        Ok(t) if (0.0..=255.0).contains(&t) => Ok(()),
+        _ => Err("Threshold must be a number between 0 and 255".to_string()),
+    }
+}
+
+fn is_quality(val: &str) -> Result<(), String> {
+    match val.parse::<u8>() {
+        Ok(1..=100) => Ok(()),
+        _ => Err("JPEG quality must be between 1 and 100".to_string()),
+    }
+}
+
+/// Parse a size in bytes, which may have a K, M or G suffix
+fn parse_size(val: &str) -> Result<u64, String> {
+    let (number, multiplier) = match val.chars().last() {
+        Some('K') | Some('k') => (&val[..val.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&val[..val.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&val[..val.len() - 1], 1 << 30),
+        _ => (val, 1),
+    };
+    match number.parse::<u64>() {
+        Ok(n) if n > 0 => n
+            .checked_mul(multiplier)
+            .ok_or_else(|| format!("Size is too large: {}", val)),
+        _ => Err(format!("Invalid size \"{}\", expected e.g. 100M", val)),
+    }
+}
+
+fn is_size(val: &str) -> Result<(), String> {
+    parse_size(val).map(|_| ())
+}
+
+/// Parse a duration in seconds, which may have an s, m or h suffix
+fn parse_duration(val: &str) -> Result<Duration, String> {
+    let (number, multiplier) = match val.chars().last() {
+        Some('s') => (&val[..val.len() - 1], 1),
+        Some('m') => (&val[..val.len() - 1], 60),
+        Some('h') => (&val[..val.len() - 1], 60 * 60),
+        _ => (val, 1),
+    };
+    match number.parse::<u64>() {
+        Ok(n) if n > 0 => n
+            .checked_mul(multiplier)
+            .map(Duration::from_secs)
+            .ok_or_else(|| format!("Duration is too long: {}", val)),
+        _ => Err(format!("Invalid duration \"{}\", expected e.g. 2h", val)),
+    }
+}
+
+fn is_duration(val: &str) -> Result<(), String> {
+    parse_duration(val).map(|_| ())
+}
+
+fn is_number(val: &str) -> Result<(), String> {
+    val.parse::<u32>()
+        .map(|_| ())
+        .map_err(|e| format!("Invalid number: {}", e))
+}
+
+fn is_exclusion(val: &str) -> Result<(), String> {
+    val.parse::<Exclusion>().map(|_| ())
+}
+
+fn is_tag_pattern(val: &str) -> Result<(), String> {
+    val.parse::<TagPattern>().map(|_| ())
+}
+
+fn is_http_url(val: &str) -> Result<(), String> {
+    match Url::parse(val) {
+        Ok(u) if u.scheme() == "http" || u.scheme() == "https" => Ok(()),
+        Ok(_) => Err("Only http and https URLs are supported".into()),
+        Err(e) => Err(format!("Invalid URL: {}", e)),
+    }
+}
+
+fn is_source_addr(val: &str) -> Result<(), String> {
+    val.parse::<IpAddr>()
+        .map(|_| ())
+        .map_err(|e| format!("Invalid source address: {}", e))
+}
+
+/// DevTools name for the type of resource given to --block-resources
+fn resource_type(name: &str) -> String {
+    match name {
+        "css" => "Stylesheet",
+        "image" => "Image",
+        "font" => "Font",
+        _ => "Media",
+    }
+    .to_string()
+}
+
+/// Check that a language looks like a BCP 47 tag, e.g. en or pt-BR
+fn is_language(val: &str) -> Result<(), String> {
+    let mut subtags = val.split('-');
+    let primary = subtags.next().unwrap_or_default();
+    let valid = (2..=8).contains(&primary.len())
+        && primary.chars().all(|c| c.is_ascii_alphabetic())
+        && subtags.all(|s| {
+            (1..=8).contains(&s.len())
+                && s.chars().all(|c| c.is_ascii_alphanumeric())
+        });
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("Invalid language \"{}\", e.g. en or pt-BR", val))
+    }
+}
+
+/// Check that a timezone looks like an IANA name, e.g. UTC or
+/// America/New_York. Chrome has the list of which ones exist.
+fn is_timezone(val: &str) -> Result<(), String> {
+    let valid = val.split('/').all(|part| {
+        !part.is_empty()
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "_-+".contains(c))
+    });
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "Invalid timezone \"{}\", e.g. UTC or Europe/London",
+            val
+        ))
+    }
+}
+
+fn is_seed(val: &str) -> Result<(), String> {
+    val.parse::<u64>()
+        .map(|_| ())
+        .map_err(|e| format!("Invalid seed: {}", e))
+}
+
+fn is_file(val: &str) -> Result<(), String> {
+    if std::path::Path::new(val).is_file() {
+        Ok(())
+    } else {
+        Err(format!("{} does not exist or is not a file", val))
+    }
+}
+
+fn is_dir(val: &str) -> Result<(), String> {
+    if std::path::Path::new(val).is_dir() {
+        Ok(())
+    } else {
+        Err(format!("{} does not exist or is not a directory", val))
+    }
+}
+
+fn is_fraction(val: &str) -> Result<(), String> {
+    match val.parse::<f64>() {
+        Ok(f) if (0.0..=1.0).contains(&f) => Ok(()),
+        _ => Err("Value must be a number between 0 and 1".to_string()),
+    }
+}
+
+/// Slowest rate that is accepted, so that the time between captures
+/// still fits in a Duration
+const MIN_RATE: f64 = 1e-9;
+
+fn is_rate(val: &str) -> Result<(), String> {
+    match val.parse::<f64>() {
+        Ok(r) if r >= MIN_RATE && r.is_finite() => Ok(()),
+        _ => Err(format!("Rate must be a number of at least {}", MIN_RATE)),
+    }
+}
+
+/// Whether the value of the NO_COLOR environment variable asks for the
+/// output to be left uncoloured, which it does whenever it is set to
+/// anything but an empty string
+fn no_color_requested(val: Option<OsString>) -> bool {
+    val.map_or(false, |v| !v.is_empty())
+}
+
+fn is_range_bits(val: &str) -> Result<(), String> {
+    match val.parse::<u32>() {
+        Ok(bits) if bits <= 32 => Ok(()),
+        _ => Err("Range bits must be a number from 0 to 32".to_string()),
+    }
+}
+
+fn is_nonzero(val: &str) -> Result<(), String> {
+    match val.parse::<usize>() {
+        Ok(0) => Err("Value must be at least 1".to_string()),
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Invalid number: {}", e)),
+    }
+}
+
+fn is_nonzero_u32(val: &str) -> Result<(), String> {
+    match val.parse::<u32>() {
+        Ok(0) => Err("Value must be at least 1".to_string()),
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Invalid number: {}", e)),
+    }
+}
+
 #[cfg(test)]
 mod test {
     #[test]
@@ -309,4 +2091,379 @@ mod test {
         assert!(!web.selected(Rdp));
         assert!(web.selected(Web));
     }
+
+    #[test]
+    fn mode_from_str() {
+        use super::Mode::{self, *};
+
+        assert_eq!("auto".parse::<Mode>(), Ok(Auto));
+        assert_eq!("all".parse::<Mode>(), Ok(Auto));
+        assert_eq!("web".parse::<Mode>(), Ok(Web));
+        assert_eq!("rdp".parse::<Mode>(), Ok(Rdp));
+        assert_eq!("vnc".parse::<Mode>(), Ok(Vnc));
+        assert!("ssh".parse::<Mode>().is_err());
+    }
+
+    #[test]
+    fn paper_from_str() {
+        use super::PaperSize::{self, *};
+
+        assert_eq!("a4".parse::<PaperSize>(), Ok(A4));
+        assert_eq!("letter".parse::<PaperSize>(), Ok(Letter));
+        assert!("b5".parse::<PaperSize>().is_err());
+        assert_eq!(A4.inches(), (8.27, 11.69));
+    }
+
+    #[test]
+    fn device_from_str() {
+        use super::Device::{self, *};
+
+        assert_eq!("iphone".parse::<Device>(), Ok(Iphone));
+        assert_eq!("pixel".parse::<Device>(), Ok(Pixel));
+        assert!("nokia".parse::<Device>().is_err());
+    }
+
+    #[test]
+    fn header_parsing() {
+        use super::parse_header;
+
+        assert_eq!(
+            parse_header("X-Forwarded-For: 192.0.2.1"),
+            Ok(("X-Forwarded-For".to_string(), "192.0.2.1".to_string()))
+        );
+        assert_eq!(
+            parse_header("Authorization:Bearer a:b"),
+            Ok(("Authorization".to_string(), "Bearer a:b".to_string()))
+        );
+        assert_eq!(
+            parse_header("X-Empty:"),
+            Ok(("X-Empty".to_string(), "".to_string()))
+        );
+        assert!(parse_header("X-Forwarded-For 192.0.2.1").is_err());
+        assert!(parse_header(": value").is_err());
+    }
+
+    #[test]
+    fn scheme_port_parsing() {
+        use super::parse_scheme_port;
+
+        assert_eq!(parse_scheme_port("9000=https"), Ok((9000, "https".into())));
+        assert_eq!(
+            parse_scheme_port(" 8443 = HTTP "),
+            Ok((8443, "http".into()))
+        );
+        assert!(parse_scheme_port("9000").is_err());
+        assert!(parse_scheme_port("9000=rdp").is_err());
+        assert!(parse_scheme_port("0=https").is_err());
+        assert!(parse_scheme_port("65536=https").is_err());
+        assert!(parse_scheme_port("=https").is_err());
+    }
+
+    #[test]
+    fn resolution_parsing() {
+        use super::parse_resolution;
+
+        assert_eq!(parse_resolution("1280x1024"), Ok((1280, 1024)));
+        assert_eq!(parse_resolution("1920X1080"), Ok((1920, 1080)));
+        assert!(parse_resolution("1280").is_err());
+        assert!(parse_resolution("1280x").is_err());
+        assert!(parse_resolution("x1024").is_err());
+        assert!(parse_resolution("0x0").is_err());
+        assert!(parse_resolution("100000x100000").is_err());
+        assert!(parse_resolution("-1x1024").is_err());
+    }
+
+    #[test]
+    fn rate_validator() {
+        use super::is_rate;
+
+        assert!(is_rate("10").is_ok());
+        assert!(is_rate("0.5").is_ok());
+        assert!(is_rate("1e-9").is_ok());
+        assert!(is_rate("0").is_err());
+        assert!(is_rate("-1").is_err());
+        assert!(is_rate("1e-300").is_err());
+        assert!(is_rate("inf").is_err());
+        assert!(is_rate("NaN").is_err());
+        assert!(is_rate("fast").is_err());
+    }
+
+    #[test]
+    fn range_bits_validator() {
+        use super::is_range_bits;
+
+        assert!(is_range_bits("0").is_ok());
+        assert!(is_range_bits("24").is_ok());
+        assert!(is_range_bits("32").is_ok());
+        assert!(is_range_bits("33").is_err());
+        assert!(is_range_bits("-1").is_err());
+        assert!(is_range_bits("all").is_err());
+    }
+
+    #[test]
+    fn nonzero_validator() {
+        use super::{is_nonzero, is_nonzero_u32};
+
+        assert!(is_nonzero_u32("10000").is_ok());
+        assert!(is_nonzero_u32("0").is_err());
+        assert!(is_nonzero_u32("4294967296").is_err());
+        assert!(super::app()
+            .try_get_matches_from(vec![
+                "scrying",
+                "--thumbnail-size",
+                "4294967296",
+            ])
+            .is_err());
+
+        assert!(is_nonzero("1").is_ok());
+        assert!(is_nonzero("10").is_ok());
+        assert!(is_nonzero("0").is_err());
+        assert!(is_nonzero("-1").is_err());
+        assert!(is_nonzero("ten").is_err());
+    }
+
+    #[test]
+    fn locale_validators() {
+        use super::{is_language, is_timezone};
+
+        assert!(is_language("en").is_ok());
+        assert!(is_language("pt-BR").is_ok());
+        assert!(is_language("zh-Hant-TW").is_ok());
+        assert!(is_language("e").is_err());
+        assert!(is_language("en-").is_err());
+        assert!(is_language("en_GB").is_err());
+        assert!(is_timezone("UTC").is_ok());
+        assert!(is_timezone("America/Argentina/Buenos_Aires").is_ok());
+        assert!(is_timezone("Etc/GMT+5").is_ok());
+        assert!(is_timezone("Europe/").is_err());
+        assert!(is_timezone("Europe London").is_err());
+    }
+
+    #[test]
+    fn file_validator() {
+        use super::{is_dir, is_file};
+
+        assert!(is_file("Cargo.toml").is_ok());
+        assert!(is_file("src").is_err());
+        assert!(is_file("does/not/exist").is_err());
+        assert!(is_dir("src").is_ok());
+        assert!(is_dir("Cargo.toml").is_err());
+    }
+
+    #[test]
+    fn size_parsing() {
+        use super::parse_size;
+
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("10K"), Ok(10 * 1024));
+        assert_eq!(parse_size("100M"), Ok(100 * 1024 * 1024));
+        assert_eq!(parse_size("2g"), Ok(2 * 1024 * 1024 * 1024));
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("10MB").is_err());
+        assert!(parse_size("99999999999999999G").is_err());
+    }
+
+    #[test]
+    fn duration_parsing() {
+        use super::parse_duration;
+        use std::time::Duration;
+
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("45m"), Ok(Duration::from_secs(45 * 60)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert!(parse_duration("0").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("1d").is_err());
+        assert!(parse_duration("99999999999999999999h").is_err());
+    }
+
+    #[test]
+    fn no_color_env() {
+        use super::no_color_requested;
+
+        assert!(!no_color_requested(None));
+        assert!(!no_color_requested(Some("".into())));
+        assert!(no_color_requested(Some("1".into())));
+        assert!(no_color_requested(Some("0".into())));
+    }
+
+    #[test]
+    fn version_has_build_metadata() {
+        use super::VERSION;
+
+        assert!(VERSION.starts_with(env!("CARGO_PKG_VERSION")));
+        assert!(VERSION.contains(" (commit "));
+        let features = VERSION.rsplit("features: ").next().unwrap();
+        assert_eq!(features.contains("ocr"), cfg!(feature = "ocr"));
+    }
+
+    #[test]
+    fn shell_completions() {
+        use super::{app, print_completions};
+
+        let matches = app()
+            .try_get_matches_from(vec!["scrying", "completions", "zsh"])
+            .unwrap();
+        let completions = matches.subcommand_matches("completions").unwrap();
+        assert_eq!(completions.value_of("SHELL"), Some("zsh"));
+        assert!(app()
+            .try_get_matches_from(vec!["scrying", "completions", "tcsh"])
+            .is_err());
+
+        for shell in &["bash", "zsh", "fish"] {
+            let mut script = Vec::new();
+            print_completions(shell, &mut script);
+            let script = String::from_utf8(script).unwrap();
+            assert!(script.contains("max-duration"), "{}", shell);
+            assert!(script.contains("scrying"), "{}", shell);
+        }
+    }
+
+    #[test]
+    fn log_levels() {
+        use super::app;
+        use log::LevelFilter;
+
+        let matches = app()
+            .try_get_matches_from(vec![
+                "scrying",
+                "--log-level",
+                "warn",
+                "--log-file",
+                "scan.log",
+                "--log-file-level",
+                "trace",
+            ])
+            .unwrap();
+        let level: LevelFilter = matches.value_of_t("LOG LEVEL").unwrap();
+        assert_eq!(level, LevelFilter::Warn);
+        let level: LevelFilter = matches.value_of_t("LOG FILE LEVEL").unwrap();
+        assert_eq!(level, LevelFilter::Trace);
+
+        // Only the listed levels are accepted, and the log file level is
+        // no use without a log file
+        assert!(app()
+            .try_get_matches_from(vec!["scrying", "--log-level", "loud"])
+            .is_err());
+        assert!(app()
+            .try_get_matches_from(vec!["scrying", "--log-file-level", "info"])
+            .is_err());
+    }
+
+    #[test]
+    fn pdf_output_format() {
+        use super::{app, OutputFormat};
+
+        assert_eq!("pdf".parse(), Ok(OutputFormat::Pdf));
+        assert_eq!("image".parse(), Ok(OutputFormat::Image));
+        assert!("png".parse::<OutputFormat>().is_err());
+        assert!(app()
+            .try_get_matches_from(vec![
+                "scrying",
+                "--output-format",
+                "pdf",
+                "--pdf-paper",
+                "letter",
+                "--pdf-landscape",
+            ])
+            .is_ok());
+        assert!(app()
+            .try_get_matches_from(vec!["scrying", "--pdf-paper", "a3"])
+            .is_err());
+        assert!(app()
+            .try_get_matches_from(vec!["scrying", "--output-format", "docx"])
+            .is_err());
+    }
+
+    #[test]
+    fn defaults_leave_requirements_alone() {
+        use super::app;
+
+        // Options that require another one have no clap default, which
+        // would count as them being given
+        assert!(app()
+            .try_get_matches_from(vec!["scrying", "-t", "192.0.2.1"])
+            .is_ok());
+        assert!(app()
+            .try_get_matches_from(vec!["scrying", "--max-height", "5000"])
+            .is_err());
+        assert!(app()
+            .try_get_matches_from(vec![
+                "scrying",
+                "--full-page",
+                "--max-height",
+                "5000",
+            ])
+            .is_ok());
+    }
+
+    #[test]
+    fn rdp_timeout_validated() {
+        use super::app;
+
+        let matches = app()
+            .try_get_matches_from(vec!["scrying", "--rdp-timeout", "5"])
+            .unwrap();
+        let timeout: usize = matches.value_of_t("RDP TIMEOUT").unwrap();
+        assert_eq!(timeout, 5);
+        for bad in &["soon", "-1", "1.5"] {
+            assert!(app()
+                .try_get_matches_from(vec!["scrying", "--rdp-timeout", bad])
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn config_file_args() {
+        use super::{app, config_args, lenient_matches};
+        use std::ffi::OsString;
+
+        let app = app();
+        let cli =
+            app.clone()
+                .get_matches_from(vec!["scrying", "--threads", "5"]);
+        let config = r#"
+            threads = 20
+            web-proxy = "socks5://192.0.2.1:1080"
+            silent = true
+            no-report = false
+            target = ["192.0.2.2", "192.0.2.3"]
+            thread = 1
+        "#;
+
+        // Options on the command line win, and unknown keys are skipped
+        assert_eq!(
+            config_args(&app, &cli, config, "test").unwrap(),
+            vec![
+                "--silent",
+                "--target=192.0.2.2",
+                "--target=192.0.2.3",
+                "--web-proxy=socks5://192.0.2.1:1080",
+            ]
+        );
+        assert!(config_args(&app, &cli, "threads = [", "test").is_err());
+        assert!(
+            config_args(&app, &cli, "[retries]\ncount = 1", "test").is_err()
+        );
+
+        // An option on the command line may need one from the config file
+        let cli_args: Vec<OsString> = vec!["scrying", "--max-height", "5000"]
+            .into_iter()
+            .map(OsString::from)
+            .collect();
+        let cli = lenient_matches(&app, &cli_args);
+        let mut merged = cli_args.clone();
+        let extra = config_args(&app, &cli, "full-page = true", "test");
+        merged.splice(1..1, extra.unwrap().into_iter().map(OsString::from));
+        assert!(app.clone().try_get_matches_from(&cli_args).is_err());
+        assert!(app.clone().try_get_matches_from(&merged).is_ok());
+
+        // Environment variables come before the config file
+        std::env::set_var("SCRYING_CHROME", "Cargo.toml");
+        let extra = config_args(&app, &cli, "chrome-path = \"x\"", "test");
+        std::env::remove_var("SCRYING_CHROME");
+        assert!(extra.unwrap().is_empty());
+    }
 }
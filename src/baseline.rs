@@ -0,0 +1,271 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Compare captures against the results of an earlier scan, given with
+//! --baseline, to pick out what has changed
+
+use crate::argparse::Opts;
+use crate::error::Error;
+use crate::util::atomic_write;
+use image::{DynamicImage, ImageOutputFormat, Rgb, RgbImage};
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How far apart any colour channel of two pixels can be before they are
+/// counted as different, to allow for compression noise
+const PIXEL_TOLERANCE: u8 = 24;
+
+/// How a capture compares with the baseline
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    /// The baseline has no capture of the target
+    New,
+    Changed,
+    Unchanged,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Comparison {
+    pub status: Change,
+    /// Fraction of pixels that differ from the baseline, from 0 to 1
+    pub score: Option<f64>,
+    /// Image highlighting the changes relative to the output directory,
+    /// if --diff-images was given
+    pub diff_file: Option<String>,
+}
+
+impl Comparison {
+    /// Short description for the report, empty if nothing changed
+    pub fn describe(&self) -> String {
+        match (self.status, self.score) {
+            (Change::New, _) => "New since baseline".to_string(),
+            (Change::Changed, Some(s)) => {
+                format!("Changed since baseline ({:.1}%)", s * 100.0)
+            }
+            (Change::Changed, None) => "Changed since baseline".to_string(),
+            (Change::Unchanged, _) => String::new(),
+        }
+    }
+}
+
+/// Captures from an earlier scan
+#[derive(Debug)]
+pub struct Baseline {
+    dir: PathBuf,
+    /// Image saved for each target that was captured, relative to `dir`
+    files: HashMap<String, String>,
+}
+
+impl Baseline {
+    /// Load the results manifest from a previous output directory
+    pub fn load(dir: &str) -> Result<Self, Error> {
+        let manifest = Path::new(dir).join("results.json");
+        let results = fs::read_to_string(&manifest).map_err(|e| {
+            Error::IoError(format!(
+                "Unable to read baseline {}: {}",
+                manifest.display(),
+                e
+            ))
+        })?;
+        Self::from_results(dir, &results)
+    }
+
+    fn from_results(dir: &str, results: &str) -> Result<Self, Error> {
+        let results: Vec<serde_json::Value> = serde_json::from_str(results)?;
+        let files = results
+            .iter()
+            .filter_map(|r| {
+                Some((
+                    r.get("target")?.as_str()?.to_string(),
+                    r.get("file")?.as_str()?.to_string(),
+                ))
+            })
+            .collect();
+        Ok(Self {
+            dir: PathBuf::from(dir),
+            files,
+        })
+    }
+
+    /// Compare a capture with the baseline's capture of the same target
+    pub fn compare(
+        &self,
+        opts: &Opts,
+        target: &str,
+        file: &str,
+        image: &DynamicImage,
+    ) -> Comparison {
+        let new = Comparison {
+            status: Change::New,
+            score: None,
+            diff_file: None,
+        };
+        let old_path = match self.files.get(target) {
+            Some(f) => self.dir.join(f),
+            None => return new,
+        };
+        let old = match image::open(&old_path) {
+            Ok(old) => old,
+            Err(e) => {
+                warn!("Unable to open {}: {}", old_path.display(), e);
+                return new;
+            }
+        };
+
+        let (score, diff) = compare_images(&old, image);
+        if score <= opts.change_threshold {
+            return Comparison {
+                status: Change::Unchanged,
+                score: Some(score),
+                diff_file: None,
+            };
+        }
+
+        info!("{} has changed since the baseline", target);
+        let diff_file = match diff {
+            Some(diff) if opts.diff_images => save_diff(opts, file, &diff),
+            _ => None,
+        };
+        Comparison {
+            status: Change::Changed,
+            score: Some(score),
+            diff_file,
+        }
+    }
+
+    /// Targets captured in the baseline but not in this scan, in order
+    pub fn missing(&self, captured: &HashSet<String>) -> Vec<String> {
+        let mut missing: Vec<String> = self
+            .files
+            .keys()
+            .filter(|t| !captured.contains(*t))
+            .cloned()
+            .collect();
+        missing.sort();
+        missing
+    }
+}
+
+/// Fraction of pixels that differ between two images, along with an
+/// image showing where they differ. Images of different sizes are
+/// treated as completely different.
+fn compare_images(
+    old: &DynamicImage,
+    new: &DynamicImage,
+) -> (f64, Option<RgbImage>) {
+    let (old, new) = (old.to_rgb8(), new.to_rgb8());
+    if old.dimensions() != new.dimensions() {
+        return (1.0, None);
+    }
+
+    // Fade out the unchanged parts so that the changes stand out
+    let mut diff = RgbImage::new(new.width(), new.height());
+    let mut changed = 0;
+    for ((a, b), d) in old.pixels().zip(new.pixels()).zip(diff.pixels_mut()) {
+        let differs =
+            a.0.iter()
+                .zip(b.0.iter())
+                .any(|(x, y)| x.max(y) - x.min(y) > PIXEL_TOLERANCE);
+        if differs {
+            changed += 1;
+            *d = Rgb([255, 0, 0]);
+        } else {
+            let luma = b.0.iter().map(|c| u32::from(*c)).sum::<u32>() / 3;
+            let faded = (128 + luma / 2) as u8;
+            *d = Rgb([faded, faded, faded]);
+        }
+    }
+    let total = u64::from(new.width()) * u64::from(new.height());
+    (changed as f64 / total.max(1) as f64, Some(diff))
+}
+
+/// Save a diff image next to the capture, returning its path relative to
+/// the output directory
+fn save_diff(opts: &Opts, file: &str, diff: &RgbImage) -> Option<String> {
+    let relative = Path::new(file).with_extension("diff.png");
+    let path = Path::new(&opts.output_dir).join(&relative);
+    debug!("Saving diff image as {}", path.display());
+    let diff = DynamicImage::ImageRgb8(diff.clone());
+    match atomic_write(&path, |w| {
+        diff.write_to(w, ImageOutputFormat::Png)?;
+        Ok(())
+    }) {
+        Ok(()) => Some(relative.display().to_string()),
+        Err(e) => {
+            warn!("Unable to save {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn image_comparison() {
+        let old = RgbImage::from_pixel(10, 10, Rgb([200, 200, 200]));
+        let mut new = old.clone();
+        let (old, same) = (
+            DynamicImage::ImageRgb8(old),
+            DynamicImage::ImageRgb8(new.clone()),
+        );
+        assert_eq!(compare_images(&old, &same).0, 0.0);
+
+        // Small differences are put down to noise
+        new.put_pixel(0, 0, Rgb([210, 200, 190]));
+        new.put_pixel(1, 0, Rgb([0, 0, 0]));
+        new.put_pixel(2, 0, Rgb([200, 255, 200]));
+        let (score, diff) = compare_images(&old, &DynamicImage::ImageRgb8(new));
+        assert!((score - 0.02).abs() < f64::EPSILON);
+        let diff = diff.unwrap();
+        assert_eq!(diff.get_pixel(0, 0), &Rgb([228, 228, 228]));
+        assert_eq!(diff.get_pixel(1, 0), &Rgb([255, 0, 0]));
+        assert_eq!(diff.get_pixel(2, 0), &Rgb([255, 0, 0]));
+
+        let bigger = DynamicImage::ImageRgb8(RgbImage::new(10, 11));
+        assert_eq!(compare_images(&old, &bigger), (1.0, None));
+    }
+
+    #[test]
+    fn baseline_targets() {
+        let results = r#"[
+            {"target": "http://192.0.2.1/", "file": "web/a.png"},
+            {"target": "192.0.2.2:3389", "file": "rdp/b.png"},
+            {"target": "192.0.2.3:5900", "file": null}
+        ]"#;
+        let baseline = Baseline::from_results("old", results).unwrap();
+        assert_eq!(baseline.files.len(), 2);
+
+        let mut captured = HashSet::new();
+        captured.insert("192.0.2.2:3389".to_string());
+        assert_eq!(baseline.missing(&captured), vec!["http://192.0.2.1/"]);
+
+        let opts = Opts::default();
+        let image = DynamicImage::ImageRgb8(RgbImage::new(1, 1));
+        let comparison = baseline.compare(&opts, "new", "web/c.png", &image);
+        assert_eq!(comparison.status, Change::New);
+        assert_eq!(comparison.describe(), "New since baseline");
+    }
+}
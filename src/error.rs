@@ -17,7 +17,7 @@
  *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-#[derive(thiserror::Error, Debug)]
+#[derive(thiserror::Error, Clone, Debug)]
 pub enum Error {
     #[error("Chrome error: {0}")]
     ChromeError(String),
@@ -39,23 +39,132 @@ pub enum Error {
 
     #[error("Conversion error: {0}")]
     ConversionError(String),
+
+    #[error("JSON error: {0}")]
+    JsonError(String),
+
+    #[error("Timeout: {0}")]
+    TimeoutError(String),
+
+    #[error("Unreachable: {0}")]
+    UnreachableError(String),
+
+    #[error("Proxy error: {0}")]
+    ProxyError(String),
+
+    #[error("Authentication failed: {0}")]
+    AuthError(String),
+
+    #[error("Blank capture: {0}")]
+    BlankError(String),
+
+    #[error("Connection refused: {0}")]
+    ConnectionRefused(String),
+
+    #[error("DNS error: {0}")]
+    DnsError(String),
+
+    #[error("TLS error: {0}")]
+    TlsError(String),
+
+    #[error("Chrome crashed: {0}")]
+    TabCrashed(String),
+
+    #[error("OCR error: {0}")]
+    OcrError(String),
+
+    #[error("Target list error: {0}")]
+    TargetListError(String),
+}
+
+impl Error {
+    /// Whether the error might go away if the capture is tried again
+    pub fn is_retryable(&self) -> bool {
+        use Error::*;
+        matches!(
+            self,
+            TimeoutError(_)
+                | ConnectionRefused(_)
+                | UnreachableError(_)
+                | ProxyError(_)
+        )
+    }
+
+    /// Short name for the kind of error, used to group failures in the
+    /// results manifest
+    pub fn category(&self) -> &'static str {
+        use Error::*;
+        match self {
+            ChromeError(_) => "chrome",
+            IoError(_) => "io",
+            RdpError(_) => "rdp",
+            MpscError(_) => "mpsc",
+            TemplateError(_) => "template",
+            VncError(_) => "vnc",
+            ConversionError(_) => "conversion",
+            JsonError(_) => "json",
+            TimeoutError(_) => "timeout",
+            UnreachableError(_) => "unreachable",
+            ProxyError(_) => "proxy",
+            AuthError(_) => "auth",
+            BlankError(_) => "blank",
+            ConnectionRefused(_) => "refused",
+            DnsError(_) => "dns",
+            TlsError(_) => "tls",
+            TabCrashed(_) => "crash",
+            OcrError(_) => "ocr",
+            TargetListError(_) => "targets",
+        }
+    }
 }
 
 impl From<failure::Error> for Error {
     fn from(e: failure::Error) -> Self {
-        Self::ChromeError(e.to_string())
+        // headless_chrome signals that a wait ran past the tab's default
+        // timeout with a dedicated error type
+        let message = e.to_string();
+        if e.downcast_ref::<headless_chrome::util::Timeout>().is_some() {
+            Self::TimeoutError(message)
+        } else if message.contains("ERR_NAME_NOT_RESOLVED") {
+            Self::DnsError(message)
+        } else if message.contains("ERR_CONNECTION_REFUSED") {
+            Self::ConnectionRefused(message)
+        } else if message.contains("ERR_CERT_") || message.contains("ERR_SSL_")
+        {
+            // Certificate errors are only reported when
+            // --ignore-tls-errors is not given
+            Self::TlsError(message)
+        } else if ["ERR_PROXY", "ERR_TUNNEL", "ERR_SOCKS"]
+            .iter()
+            .any(|code| message.contains(code))
+        {
+            // Chrome's network errors for failing to reach or negotiate
+            // with the proxy, as opposed to the target itself
+            Self::ProxyError(message)
+        } else {
+            Self::ChromeError(message)
+        }
     }
 }
 
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Self {
-        Self::IoError(e.to_string())
+        use std::io::ErrorKind::*;
+        match e.kind() {
+            // Socket read timeouts show up as WouldBlock on unix
+            TimedOut | WouldBlock => Self::TimeoutError(e.to_string()),
+            ConnectionRefused => Self::ConnectionRefused(e.to_string()),
+            _ => Self::IoError(e.to_string()),
+        }
     }
 }
 
 impl From<rdp::model::error::Error> for Error {
     fn from(e: rdp::model::error::Error) -> Self {
-        Self::RdpError(format!("{:?}", e))
+        match e {
+            rdp::model::error::Error::Io(e) => e.into(),
+            e => Self::RdpError(format!("{:?}", e)),
+        }
     }
 }
 
@@ -89,8 +198,53 @@ impl From<std::num::TryFromIntError> for Error {
     }
 }
 
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::JsonError(e.to_string())
+    }
+}
+
 impl From<std::array::TryFromSliceError> for Error {
     fn from(e: std::array::TryFromSliceError) -> Self {
         Self::ConversionError(e.to_string())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn boxes_as_std_error() {
+        fn capture() -> Result<(), Error> {
+            Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "slow"))?
+        }
+        fn worker() -> Result<(), Box<dyn std::error::Error>> {
+            capture()?;
+            Ok(())
+        }
+
+        let e = worker().unwrap_err();
+        assert_eq!(e.to_string(), "Timeout: slow");
+        assert!(matches!(
+            e.downcast_ref::<Error>(),
+            Some(Error::TimeoutError(_))
+        ));
+    }
+
+    #[test]
+    fn io_error_categories() {
+        use std::io::{Error as IoError, ErrorKind};
+
+        let cases = vec![
+            (ErrorKind::TimedOut, "timeout"),
+            (ErrorKind::WouldBlock, "timeout"),
+            (ErrorKind::ConnectionRefused, "refused"),
+            (ErrorKind::PermissionDenied, "io"),
+        ];
+        for (kind, category) in cases {
+            let e: Error = IoError::new(kind, "test").into();
+            assert_eq!(e.category(), category);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Log file that is rotated once it grows past a size limit, for
+//! --log-max-size and --log-keep
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Writes to a log file at a fixed path. Once the file is over the size
+/// limit it is moved to `<path>.1`, older files move up one number and
+/// the oldest beyond `keep` are deleted, so the current log can always be
+/// followed at the same path.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    written: u64,
+    /// Whether the last write finished a line. Rotation waits for the end
+    /// of a line so that messages aren't split between files.
+    line_start: bool,
+}
+
+impl RotatingFile {
+    pub fn create(path: &Path, max_size: u64, keep: usize) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_size,
+            keep,
+            file: File::create(path)?,
+            written: 0,
+            line_start: true,
+        })
+    }
+
+    fn old_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.old_path(self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = fs::rename(self.old_path(n), self.old_path(n + 1));
+            }
+            fs::rename(&self.path, self.old_path(1))?;
+        }
+        self.file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.line_start && self.written >= self.max_size {
+            self.rotate()?;
+        }
+        let len = self.file.write(buf)?;
+        self.written += len as u64;
+        self.line_start = buf[..len].ends_with(b"\n");
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn log_rotation() {
+        let dir = std::env::temp_dir()
+            .join(format!("scrying-logrotate-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("scrying.log");
+        let mut log = RotatingFile::create(&path, 10, 2).unwrap();
+
+        // Lines aren't split even when they take the file over the limit
+        for part in &["first ", "line\n", "second\n", "third\n", "fourth\n"] {
+            log.write_all(part.as_bytes()).unwrap();
+        }
+        log.flush().unwrap();
+
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("scrying.log"), "fourth\n");
+        assert_eq!(read("scrying.log.1"), "second\nthird\n");
+        assert_eq!(read("scrying.log.2"), "first line\n");
+        assert!(!dir.join("scrying.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -17,42 +17,81 @@
  *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use crate::argparse::Opts;
-use crate::reporting::ReportMessage;
+use crate::argparse::{Mode, Opts};
+use crate::baseline::Baseline;
+use crate::reporting::{FileError, ReportMessage, ReportMessageContent};
 use error::Error;
-use headless_chrome::{Browser, LaunchOptionsBuilder};
+use headless_chrome::{Browser, LaunchOptionsBuilder, Tab};
 #[allow(unused)]
 use log::{debug, error, info, trace, warn};
-use parsing::{generate_target_lists, InputLists};
+use logfile::RotatingFile;
+use parsing::{generate_target_lists, InputLists, Target};
+use pool::{Limits, Next, Queue, Stream};
+use progress::Progress;
+use ratelimit::{HostQueue, RateLimiter};
 use simplelog::{
     CombinedLogger, Config, LevelFilter, SharedLogger, TermLogger,
     TerminalMode, WriteLogger,
 };
-use std::collections::HashMap;
-use std::ffi::OsStr;
+use std::ffi::OsString;
 use std::fs::create_dir_all;
 use std::fs::File;
-use std::path::Path;
+use std::io::Write;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{mpsc, Arc};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time::{Instant, SystemTime};
+use syslog::SyslogLogger;
+use tokio::sync::Semaphore;
+use util::capture_exists;
+use web::chrome::{ChromeGuard, ChromeProcesses};
 
 mod argparse;
+mod baseline;
 mod error;
+mod logfile;
+mod ocr;
 mod parsing;
+mod pool;
+mod probe;
+mod progress;
+mod ratelimit;
 mod rdp;
 mod reporting;
+mod syslog;
+mod tags;
+mod tls;
 mod util;
 mod vnc;
 mod web;
+mod webhook;
 
-pub enum ThreadStatus {
-    Complete,
-}
+/// Exit code when every capture succeeded, or some failed without
+/// --fail-on-error
+pub const EXIT_SUCCESS: i32 = 0;
+/// Exit code for errors that stop the scan from running, such as bad
+/// arguments, an unusable output directory, or no targets
+pub const EXIT_ERROR: i32 = 1;
+/// Exit code when there were targets but none of them were captured
+pub const EXIT_ALL_FAILED: i32 = 2;
+/// Exit code when some captures failed and --fail-on-error was given
+pub const EXIT_SOME_FAILED: i32 = 3;
+/// Exit code after a second Ctrl-C
+pub const EXIT_INTERRUPTED: i32 = 130;
 
 fn main() {
-    println!("Starting NCC Group Scrying...");
-    let opts = Arc::new(argparse::parse().unwrap());
+    let started = Instant::now();
+    let opts = Arc::new(argparse::parse().unwrap_or_else(|e| {
+        eprintln!("Error parsing arguments: {}", e);
+        process::exit(EXIT_ERROR);
+    }));
+    // With --ndjson or --json-summary stdout is kept for the results alone
+    if !opts.ndjson && !opts.json_summary {
+        println!("Starting NCC Group Scrying...");
+    }
 
     // Configure logging
     let mut log_dests: Vec<Box<dyn SharedLogger>> = Vec::new();
@@ -62,20 +101,35 @@ fn main() {
         // Increasing global log verbosity increases log file verbosity
         // accordingly. Combinations such as --silent -vv make sense
         // when using a log file as the file will get TRACE messages
-        // while the terminal only gets WARN and higher.
-        let level_filter = match opts.verbose {
+        // while the terminal only gets WARN and higher. --log-file-level
+        // sets the level outright instead.
+        let level_filter = opts.log_file_level.unwrap_or(match opts.verbose {
             0 => LevelFilter::Info,
             1 => LevelFilter::Debug,
             _ => LevelFilter::Trace,
-        };
-        log_dests.push(WriteLogger::new(
-            level_filter,
-            Config::default(),
-            File::create(log_file).unwrap(),
-        ));
+        });
+        // The loggers are not set up yet so this has to go to stderr
+        let file = match opts.log_max_size {
+            Some(max_size) => RotatingFile::create(
+                Path::new(log_file),
+                max_size,
+                opts.log_keep,
+            )
+            .map(|f| Box::new(f) as Box<dyn Write + Send>),
+            None => File::create(log_file)
+                .map(|f| Box::new(f) as Box<dyn Write + Send>),
+        }
+        .unwrap_or_else(|e| {
+            eprintln!("Error creating log file {}: {}", log_file, e);
+            process::exit(EXIT_ERROR);
+        });
+        log_dests.push(WriteLogger::new(level_filter, Config::default(), file));
     }
 
-    let level_filter = if !opts.silent {
+    // --log-level takes priority over both --verbose and --silent
+    let level_filter = if let Some(level) = opts.log_level {
+        level
+    } else if !opts.silent {
         match opts.verbose {
             0 => LevelFilter::Info,
             1 => LevelFilter::Debug,
@@ -85,19 +139,85 @@ fn main() {
         LevelFilter::Warn
     };
 
-    log_dests.push(TermLogger::new(
-        level_filter,
-        Config::default(),
-        TerminalMode::Mixed,
-    ));
+    // Syslog gets the same messages as the terminal
+    if opts.syslog || opts.syslog_server.is_some() {
+        let server = opts.syslog_server.as_deref();
+        match SyslogLogger::new(level_filter, server) {
+            Ok(logger) => log_dests.push(logger),
+            Err(e) => {
+                eprintln!("Error connecting to syslog: {}", e);
+                process::exit(EXIT_ERROR);
+            }
+        }
+    }
+
+    // The progress bar is only drawn on a terminal and is left out by
+    // --silent and --ndjson. When it is drawn the log goes through it, so
+    // that log lines appear above the bar rather than on top of it.
+    let mut progress =
+        if !opts.silent && !opts.ndjson && atty::is(atty::Stream::Stderr) {
+            Some(Progress::new())
+        } else {
+            None
+        };
+    let terminal_mode = if opts.ndjson || opts.json_summary {
+        TerminalMode::Stderr
+    } else {
+        TerminalMode::Mixed
+    };
+    match &progress {
+        Some(p) => log_dests.push(WriteLogger::new(
+            level_filter,
+            Config::default(),
+            p.log_writer(),
+        )),
+        // TermLogger always colours its output, so the log is written to
+        // stderr as plain text instead
+        None if opts.no_color => log_dests.push(WriteLogger::new(
+            level_filter,
+            Config::default(),
+            std::io::stderr(),
+        )),
+        None => log_dests.push(TermLogger::new(
+            level_filter,
+            Config::default(),
+            terminal_mode,
+        )),
+    }
 
     CombinedLogger::init(log_dests).unwrap();
 
     debug!("Got opts:\n{:?}", opts);
 
-    // Load in the target lists, parsed from arguments, files, and nmap
-    let targets = Arc::new(generate_target_lists(&opts));
-    println!("{}", targets);
+    if !opts.from_manifest.is_empty() {
+        if let Err(e) = reporting::report_from_manifests(&opts) {
+            error!("Unable to regenerate the report: {}", e);
+            process::exit(EXIT_ERROR);
+        }
+        return;
+    }
+
+    // Load in the target lists, parsed from arguments, files, and nmap.
+    // With --stream they are read once the workers are running instead.
+    let targets = if opts.stream {
+        Arc::new(InputLists::default())
+    } else {
+        match generate_target_lists(&opts) {
+            Ok(targets) => Arc::new(targets),
+            Err(e) => {
+                error!("{}", e);
+                process::exit(EXIT_ERROR);
+            }
+        }
+    };
+    if opts.dry_run {
+        print_dry_run(&targets, &opts);
+        info!("--dry-run was supplied, exiting");
+        return;
+    }
+    if !opts.stream && !opts.ndjson && !opts.json_summary {
+        println!("{}", targets);
+    }
 
     if opts.test_import {
         info!("--test-import was supplied, exiting");
@@ -105,44 +225,110 @@ fn main() {
     }
 
     // Verify that targets have been processed
-    if targets.rdp_targets.is_empty()
+    if !opts.stream
+        && targets.rdp_targets.is_empty()
         && targets.web_targets.is_empty()
         && targets.vnc_targets.is_empty()
     {
         error!("No targets imported, exiting");
-        return;
+        process::exit(EXIT_ERROR);
     }
 
-    // Create output directories if they do not exist
-    let output_base = Path::new(&opts.output_dir);
-    let rdp_output_dir = output_base.join("rdp");
-    if !targets.rdp_targets.is_empty() && !rdp_output_dir.is_dir() {
-        create_dir_all(&rdp_output_dir).unwrap_or_else(|_| {
-            panic!("Error creating directory {}", rdp_output_dir.display())
-        });
+    if opts.ocr {
+        if let Err(e) = ocr::check() {
+            error!("{}", e);
+            process::exit(EXIT_ERROR);
+        }
     }
-    let web_output_dir = output_base.join("web");
-    if !targets.web_targets.is_empty() && !web_output_dir.is_dir() {
-        create_dir_all(&web_output_dir).unwrap_or_else(|_| {
-            panic!("Error creating directory {}", web_output_dir.display())
-        });
+
+    // Create output directories if they do not exist. Which types of
+    // target there are is not known yet with --stream, so there is one
+    // for each type that was asked for. Probing saves nothing but the
+    // reports, which go in the base directory.
+    let output_base = Path::new(&opts.output_dir);
+    let wanted = |mode, list: &[Target]| {
+        if opts.probe_only {
+            false
+        } else if opts.stream {
+            opts.mode.selected(mode)
+        } else {
+            !list.is_empty()
+        }
+    };
+    for (subdir, needed) in &[
+        ("", opts.probe_only),
+        ("rdp", wanted(Mode::Rdp, &targets.rdp_targets)),
+        ("web", wanted(Mode::Web, &targets.web_targets)),
+        ("vnc", wanted(Mode::Vnc, &targets.vnc_targets)),
+    ] {
+        let output_dir = output_base.join(subdir);
+        if *needed && !output_dir.is_dir() {
+            if let Err(e) = create_dir_all(&output_dir) {
+                error!(
+                    "Error creating directory {}: {}",
+                    output_dir.display(),
+                    e
+                );
+                process::exit(EXIT_ERROR);
+            }
+        }
     }
-    let vnc_output_dir = output_base.join("vnc");
-    if !targets.vnc_targets.is_empty() && !vnc_output_dir.is_dir() {
-        create_dir_all(&vnc_output_dir).unwrap_or_else(|_| {
-            panic!("Error creating directory {}", vnc_output_dir.display())
-        });
+
+    // A source address that isn't on this machine would fail every
+    // capture, so check it up front, and warn about the connections that
+    // can't be made from it
+    if let Some(source) = opts.source_addr {
+        if let Err(e) = TcpListener::bind((source, 0)) {
+            error!("Unable to make connections from {}: {}", source, e);
+            process::exit(EXIT_ERROR);
+        }
+        if wanted(Mode::Web, &targets.web_targets) {
+            warn!(concat!(
+                "Chrome can't be told which address to use, so web pages ",
+                "are loaded from the default one rather than --source-addr"
+            ));
+        }
+        if opts.rdp_proxy.is_some() {
+            warn!("Connections to the RDP proxy don't use --source-addr");
+        }
     }
 
     // Attach interrupt handler to catch ctrl-c
     let caught_ctrl_c = Arc::new(AtomicBool::new(false));
     let caught_ctrl_c_clone_for_handler = caught_ctrl_c.clone();
+    let chrome_processes = ChromeProcesses::default();
+    let chrome_processes_for_handler = chrome_processes.clone();
+    // The first interrupt lets in-flight captures finish so that the
+    // results so far are written out, a second one exits immediately
     ctrlc::set_handler(move || {
-        warn!("Caught interrupt signal, cleaning up...");
-        caught_ctrl_c_clone_for_handler.store(true, Ordering::SeqCst);
+        if caught_ctrl_c_clone_for_handler.swap(true, Ordering::SeqCst) {
+            error!("Caught second interrupt signal, exiting now");
+            // Exiting skips destructors, so Chrome has to be killed here
+            chrome_processes_for_handler.kill_all();
+            process::exit(EXIT_INTERRUPTED);
+        }
+        warn!(concat!(
+            "Caught interrupt signal, cleaning up... ",
+            "Press Ctrl-C again to exit immediately"
+        ));
     })
     .expect("Unable to attach interrupt signal handler");
 
+    if let Some(p) = &mut progress {
+        p.start(&targets);
+    }
+
+    // Load the baseline up front so that a bad one is reported before
+    // scanning rather than at the end
+    let baseline =
+        match opts.baseline.as_deref().map(Baseline::load).transpose() {
+            Ok(b) => b,
+            Err(e) => {
+                error!("{}", e);
+                process::exit(EXIT_ERROR);
+            }
+        };
+
     // Start report collating thread
     let (report_tx, report_rx): (
         mpsc::Sender<ReportMessage>,
@@ -152,70 +338,194 @@ fn main() {
     let targets_clone = targets.clone();
     let reporting_handle = thread::spawn(move || {
         debug!("Starting report thread");
-        reporting::reporting_thread(report_rx, opts_clone, targets_clone)
+        reporting::reporting_thread(
+            report_rx,
+            opts_clone,
+            targets_clone,
+            baseline,
+            progress,
+        )
     });
 
+    // Captures of every type share the same threads and pacing
+    let limits = Arc::new(Limits {
+        threads: Semaphore::new(opts.threads),
+        rate_limiter: RateLimiter::new(opts.rate_limit, opts.jitter),
+        caught_ctrl_c,
+        deadline: opts.max_duration.map(|d| started + d),
+    });
+
+    // With --stream the targets are handed to the workers over channels
+    // as they are read, which are bounded so that reading doesn't get far
+    // ahead of capturing
+    let channel = || {
+        if opts.stream {
+            let (tx, rx) = mpsc::sync_channel(opts.threads);
+            (Some(tx), Some(rx))
+        } else {
+            (None, None)
+        }
+    };
+    let (rdp_tx, rdp_rx) = channel();
+    let (web_tx, web_rx) = channel();
+    let (vnc_tx, vnc_rx) = channel();
+
     // Spawn threads to iterate over the targets
-    let rdp_handle = if !targets.rdp_targets.is_empty() {
+    let rdp_handle = if opts.stream || !targets.rdp_targets.is_empty() {
         let targets_clone = targets.clone();
         let opts_clone = opts.clone();
         let report_tx_clone = report_tx.clone();
-        let caught_ctrl_c_clone = caught_ctrl_c.clone();
+        let limits_clone = limits.clone();
         Some(thread::spawn(move || {
-            debug!("Starting RDP worker threads");
-            rdp_worker(
-                targets_clone,
-                opts_clone,
-                report_tx_clone,
-                caught_ctrl_c_clone,
-            )
+            if let Some((queue, threads)) = make_queue(
+                &targets_clone.rdp_targets,
+                rdp_rx,
+                &opts_clone,
+                &opts_clone.rdp_proxy,
+            ) {
+                debug!("Starting RDP worker threads");
+                if opts_clone.probe_only {
+                    probe_worker(
+                        queue,
+                        threads,
+                        Mode::Rdp,
+                        opts_clone,
+                        report_tx_clone,
+                        limits_clone,
+                    )
+                } else {
+                    rdp_worker(
+                        queue,
+                        threads,
+                        opts_clone,
+                        report_tx_clone,
+                        limits_clone,
+                    )
+                }
+            }
         }))
     } else {
         None
     };
 
-    let web_handle = if !targets.web_targets.is_empty() {
+    let web_handle = if opts.stream || !targets.web_targets.is_empty() {
         let targets_clone = targets.clone();
         let opts_clone = opts.clone();
         let report_tx_clone = report_tx.clone();
-        let caught_ctrl_c_clone = caught_ctrl_c.clone();
+        let limits_clone = limits.clone();
         Some(thread::spawn(move || {
-            debug!("Starting Web worker threads");
-            web_worker(
-                targets_clone,
-                opts_clone,
-                report_tx_clone,
-                caught_ctrl_c_clone,
-            )
-            .unwrap()
+            if let Some((queue, threads)) = make_queue(
+                &targets_clone.web_targets,
+                web_rx,
+                &opts_clone,
+                &opts_clone.web_proxy,
+            ) {
+                debug!("Starting Web worker threads");
+                if opts_clone.probe_only {
+                    // Probing doesn't need Chrome, so it isn't launched
+                    probe_worker(
+                        queue,
+                        threads,
+                        Mode::Web,
+                        opts_clone,
+                        report_tx_clone,
+                        limits_clone,
+                    )
+                } else if let Err(e) = web_worker(
+                    queue,
+                    threads,
+                    opts_clone,
+                    report_tx_clone,
+                    limits_clone,
+                    chrome_processes,
+                ) {
+                    error!("Web worker failed: {}", e);
+                }
+            }
         }))
     } else {
         None
     };
 
-    let vnc_handle = if !targets.vnc_targets.is_empty() {
+    let vnc_handle = if opts.stream || !targets.vnc_targets.is_empty() {
         // clone here will be more useful when there are more target types
         let targets_clone = targets; //.clone();
-        let opts_clone = opts; //.clone();
+        let opts_clone = opts.clone();
         let report_tx_clone = report_tx.clone();
-        let caught_ctrl_c_clone = caught_ctrl_c; //.clone();
+        let limits_clone = limits.clone();
         Some(thread::spawn(move || {
-            debug!("Starting VNC worker threads");
-            vnc_worker(
-                targets_clone,
-                opts_clone,
-                report_tx_clone,
-                caught_ctrl_c_clone,
-            )
-            .unwrap()
+            if let Some((queue, threads)) = make_queue(
+                &targets_clone.vnc_targets,
+                vnc_rx,
+                &opts_clone,
+                &None,
+            ) {
+                debug!("Starting VNC worker threads");
+                if opts_clone.probe_only {
+                    probe_worker(
+                        queue,
+                        threads,
+                        Mode::Vnc,
+                        opts_clone,
+                        report_tx_clone,
+                        limits_clone,
+                    )
+                } else {
+                    vnc_worker(
+                        queue,
+                        threads,
+                        opts_clone,
+                        report_tx_clone,
+                        limits_clone,
+                    )
+                }
+            }
         }))
     } else {
         None
     };
 
+    if opts.stream {
+        let count = parsing::stream_targets(&opts, |mode, target| {
+            let tx = match mode {
+                Mode::Rdp => &rdp_tx,
+                Mode::Web => &web_tx,
+                _ => &vnc_tx,
+            };
+            // Count the target before a worker can finish it
+            report_tx.send(ReportMessage::Queued(mode)).unwrap();
+            // The workers for a type drop their end if they stop early,
+            // e.g. when Chrome cannot be started
+            if let Some(Err(e)) = tx.as_ref().map(|tx| tx.send(target)) {
+                debug!("Dropping {} as its workers have stopped", e.0);
+            }
+            !limits.caught_ctrl_c.load(Ordering::SeqCst)
+                && !limits.out_of_time()
+        });
+        // Let the workers know that there are no more targets to come
+        drop((rdp_tx, web_tx, vnc_tx));
+        let count = count.map(|(count, invalid)| {
+            report_tx.send(ReportMessage::Invalid(invalid)).unwrap();
+            count
+        });
+        match count {
+            // Remote lists are fetched before any targets are sent, so
+            // nothing has been captured yet
+            Err(e) => {
+                error!("{}", e);
+                process::exit(EXIT_ERROR);
+            }
+            Ok(0) => {
+                error!("No targets imported, exiting");
+                process::exit(EXIT_ERROR);
+            }
+            Ok(count) => info!("Read {} targets", count),
+        }
+    }
+
     // wait for the workers to complete
     if let Some(h) = rdp_handle {
-        h.join().unwrap().unwrap();
+        h.join().unwrap();
     }
     if let Some(h) = web_handle {
         h.join().unwrap();
@@ -224,159 +534,342 @@ fn main() {
         h.join().unwrap();
     }
     report_tx.send(ReportMessage::GenerateReport).unwrap();
-    reporting_handle.join().unwrap().unwrap();
+    match reporting_handle.join().unwrap() {
+        Ok(summary) => {
+            webhook::notify_complete(&opts, &summary);
+            process::exit(summary.exit_code(opts.fail_on_error))
+        }
+        Err(e) => {
+            error!("Error writing the results: {}", e);
+            process::exit(EXIT_ERROR);
+        }
+    }
+}
+
+/// Print each target along with its type and where its capture would be
+/// saved, for --dry-run
+fn print_dry_run(targets: &InputLists, opts: &Opts) {
+    let output_base = Path::new(&opts.output_dir);
+    for (subdir, list) in &[
+        ("rdp", &targets.rdp_targets),
+        ("web", &targets.web_targets),
+        ("vnc", &targets.vnc_targets),
+    ] {
+        for target in list.iter() {
+            let path =
+                output_base.join(util::capture_path(target, subdir, opts));
+            println!("{}\t{}\t{}", subdir, target, path.display());
+        }
+    }
+    let total = targets.rdp_targets.len()
+        + targets.web_targets.len()
+        + targets.vnc_targets.len();
+    println!("{} targets", total);
+    if targets.truncated > 0 {
+        println!(
+            "{} more targets were left out by --limit",
+            targets.truncated
+        );
+    }
+    if !targets.invalid.is_empty() {
+        println!("{} targets skipped as invalid", targets.invalid.len());
+    }
+}
+
+/// The queue for one type of target along with how many threads are
+/// worth starting for it. With --stream the targets are taken from `rx`
+/// as they are read, otherwise from the list that was loaded up front.
+/// Returns None if there turn out to be no targets of the type.
+fn make_queue(
+    list: &[Target],
+    rx: Option<mpsc::Receiver<Target>>,
+    opts: &Opts,
+    proxy: &Option<String>,
+) -> Option<(Box<dyn Queue>, usize)> {
+    match rx {
+        Some(rx) => Some((Box::new(Stream::new(rx)?), opts.threads)),
+        None if list.is_empty() => None,
+        None => Some((
+            Box::new(HostQueue::new(list, opts.per_host_rate, proxy)),
+            opts.threads.min(list.len()),
+        )),
+    }
 }
 
 fn rdp_worker(
-    targets: Arc<InputLists>,
+    queue: Box<dyn Queue>,
+    threads: usize,
     opts: Arc<Opts>,
     report_tx: mpsc::Sender<ReportMessage>,
-    caught_ctrl_c: Arc<AtomicBool>,
-) -> Result<(), ()> {
-    use mpsc::{Receiver, Sender};
-    let max_workers = opts.threads;
-    let mut num_workers: usize = 0;
-    let mut targets_iter = targets.rdp_targets.iter();
-    let mut workers: Vec<_> = Vec::new();
-    let (thread_status_tx, thread_status_rx): (
-        Sender<ThreadStatus>,
-        Receiver<ThreadStatus>,
-    ) = mpsc::channel();
-    while !caught_ctrl_c.load(Ordering::SeqCst) {
-        // check for status messages
-        // Turn off clippy's single_match warning here because match
-        // matches the intuition for how try_recv is processed better
-        // than an if let.
-        #[allow(clippy::single_match)]
-        match thread_status_rx.try_recv() {
-            Ok(ThreadStatus::Complete) => {
-                debug!("Thread complete, yay");
-                num_workers -= 1;
-            }
-            Err(_) => {}
-        }
-        if num_workers < max_workers {
-            if let Some(target) = targets_iter.next() {
-                let target = target.clone();
-                info!("Adding worker for {:?}", target);
-                let opts_clone = opts.clone();
-                let tx = thread_status_tx.clone();
-                let report_tx_clone = report_tx.clone();
-                let handle = thread::spawn(move || {
-                    rdp::capture(&target, &opts_clone, tx, &report_tx_clone)
-                });
-
-                workers.push(handle);
-                num_workers += 1;
-            } else {
-                break;
-            }
-        }
+    limits: Arc<Limits>,
+) {
+    // Each worker keeps its own sender as they cannot be shared
+    let workers = (0..threads).map(|_| report_tx.clone()).collect();
+    let pacing = limits.clone();
+    let cached = opts.clone();
+    let cached = move |t: &Target| capture_exists(t, "rdp", &cached);
+    let left = pool::run(queue, workers, limits, cached, move |tx, target| {
+        info!("Capturing {}", target);
+        rdp::capture(target, &opts, tx, &pacing);
+        Next::Continue
+    });
+    report_not_attempted(Mode::Rdp, left, &report_tx);
+}
+
+/// Record the targets that --max-duration left no time for
+fn report_not_attempted(
+    mode: Mode,
+    targets: Vec<Target>,
+    report_tx: &mpsc::Sender<ReportMessage>,
+) {
+    if !targets.is_empty() {
+        warn!(
+            "Ran out of time with {} {:?} targets not attempted",
+            targets.len(),
+            mode
+        );
     }
-    debug!("At the join part");
-    for w in workers {
-        debug!("Joining {:?}", w);
-        if w.join().is_err() {
-            debug!("Thread finished with errors");
-        }
+    for target in targets {
+        let content = ReportMessageContent::new(
+            mode,
+            &target,
+            SystemTime::now(),
+            FileError::NotAttempted,
+            0,
+            None,
+        );
+        report_tx.send(ReportMessage::Output(content)).unwrap();
     }
+}
 
-    Ok(())
+fn probe_worker(
+    queue: Box<dyn Queue>,
+    threads: usize,
+    mode: Mode,
+    opts: Arc<Opts>,
+    report_tx: mpsc::Sender<ReportMessage>,
+    limits: Arc<Limits>,
+) {
+    let workers = (0..threads).map(|_| report_tx.clone()).collect();
+    // Probes only ever report, so there is no earlier capture to skip
+    let cached = |_: &Target| false;
+    let left = pool::run(queue, workers, limits, cached, move |tx, target| {
+        info!("Probing {}", target);
+        probe::probe(target, mode, &opts, tx);
+        Next::Continue
+    });
+    report_not_attempted(mode, left, &report_tx);
 }
 
 fn web_worker(
-    targets: Arc<InputLists>,
+    queue: Box<dyn Queue>,
+    threads: usize,
     opts: Arc<Opts>,
     report_tx: mpsc::Sender<ReportMessage>,
-    caught_ctrl_c: Arc<AtomicBool>,
+    limits: Arc<Limits>,
+    chrome_processes: ChromeProcesses,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut chrome_env = HashMap::new();
+    let (guard, browser) = launch_browser(&opts, &chrome_processes)?;
+    let browser = Arc::new(Mutex::new(BrowserState {
+        guard,
+        browser,
+        restarts: 0,
+        chrome_processes,
+    }));
+
+    // Each worker thread drives its own tab in the shared browser
+    let workers = (0..threads)
+        .map(|_| Ok((open_tab(&browser, &opts, None)?, report_tx.clone())))
+        .collect::<Result<Vec<_>, Error>>()?;
+    let browser_clone = browser.clone();
+    let cached = opts.clone();
+    let cached = move |t: &Target| capture_exists(t, "web", &cached);
+    let left =
+        pool::run(queue, workers, limits, cached, move |(tab, tx), target| {
+            // A target that was being captured when Chrome crashed is
+            // given one more go with a fresh tab
+            let mut may_retry = true;
+            loop {
+                let e = match web::capture(
+                    target,
+                    &opts,
+                    &tab.tab,
+                    &tab.events,
+                    tx,
+                    may_retry,
+                ) {
+                    Ok(()) => return Next::Continue,
+                    Err(e) => e,
+                };
+                let crashed = match e {
+                    Error::IoError(e) => {
+                        // Should probably abort on an IO error
+                        error!("IO error: {}", e);
+                        return Next::Abort;
+                    }
+                    Error::TabCrashed(e) => {
+                        warn!("Chrome crashed capturing {}: {}", target, e);
+                        true
+                    }
+                    e => {
+                        log_web_error(target, e);
+                        !web::tab_alive(&tab.tab)
+                    }
+                };
+                if !crashed {
+                    return Next::Continue;
+                }
+
+                match open_tab(&browser_clone, &opts, Some(tab.restarts)) {
+                    Ok(new_tab) => *tab = new_tab,
+                    Err(e) => {
+                        error!("Unable to open a new tab: {}", e);
+                        return Next::Stop;
+                    }
+                }
+                if !may_retry {
+                    return Next::Continue;
+                }
+                may_retry = false;
+            }
+        });
+    report_not_attempted(Mode::Web, left, &report_tx);
+
+    let restarts = browser.lock().unwrap().restarts;
+    if restarts > 0 {
+        warn!("Chrome crashed and was restarted {} times", restarts);
+    }
+    Ok(())
+}
+
+/// Start a headless Chrome with the options needed for web captures. It
+/// is killed when the returned guard is dropped.
+fn launch_browser(
+    opts: &Opts,
+    chrome_processes: &ChromeProcesses,
+) -> Result<(ChromeGuard, Browser), Error> {
+    let mut chrome_args = Vec::new();
+    if opts.ignore_tls_errors {
+        chrome_args.push(OsString::from("--ignore-certificate-errors"));
+    }
     if let Some(p) = &opts.web_proxy {
-        chrome_env.insert("http_proxy".to_string(), p.clone());
-        chrome_env.insert("https_proxy".to_string(), p.clone());
+        // Chrome accepts http://, https://, socks4:// and socks5:// URIs
+        chrome_args.push(OsString::from(format!("--proxy-server={}", p)));
     }
+    let window_size = web::window_size(opts);
     let launch_options = LaunchOptionsBuilder::default()
+        .path(opts.chrome_path.as_ref().map(PathBuf::from))
         .headless(true)
-        .window_size(Some((1280, 720)))
-        .process_envs(Some(chrome_env))
-        .args(vec![OsStr::new("--ignore-certificate-errors")])
-        .build()?;
-    let browser = Browser::new(launch_options).expect("failed to init chrome");
-    let tab = browser.wait_for_initial_tab().expect("Failed to init tab");
-
-    for target in &targets.web_targets {
-        if caught_ctrl_c.load(Ordering::SeqCst) {
-            break;
+        .window_size(Some(window_size))
+        .args(chrome_args.iter().map(|a| a.as_os_str()).collect())
+        .build()
+        .map_err(Error::ChromeError)?;
+    // Chrome is the only web capture backend, so explain what is missing
+    // rather than passing on a bare launch error
+    let browser = Browser::new(launch_options).map_err(|e| {
+        Error::ChromeError(format!(
+            concat!(
+                "Unable to launch Chrome ({}). Web captures need Chrome or ",
+                "Chromium to be installed or given with --chrome-path, or ",
+                "use --mode to skip web targets"
+            ),
+            e
+        ))
+    })?;
+    Ok((chrome_processes.guard(browser.get_process_id()), browser))
+}
+
+/// The browser shared between the web worker threads
+struct BrowserState {
+    /// Declared before the browser so that Chrome's helper processes are
+    /// killed before the browser kills the main process
+    guard: ChromeGuard,
+    browser: Browser,
+    /// Number of times the browser has been relaunched. Tabs remember the
+    /// value they were opened at so that several threads noticing the same
+    /// crash only restart the browser once.
+    restarts: usize,
+    chrome_processes: ChromeProcesses,
+}
+
+/// A tab set up for captures
+struct WebTab {
+    tab: Arc<Tab>,
+    events: web::PageEvents,
+    restarts: usize,
+}
+
+/// Open a new tab in the browser and set it up for captures. If this is
+/// replacing a tab that died then the browser is relaunched when it can
+/// no longer open tabs.
+fn open_tab(
+    browser: &Mutex<BrowserState>,
+    opts: &Opts,
+    replacing: Option<usize>,
+) -> Result<WebTab, Error> {
+    let mut state = browser.lock().unwrap();
+    let tab = match state.browser.new_tab() {
+        Ok(tab) => tab,
+        Err(e) if replacing == Some(state.restarts) => {
+            warn!("Chrome stopped responding ({}), restarting it", e);
+            let (guard, new_browser) =
+                launch_browser(opts, &state.chrome_processes)?;
+            // Kill the old Chrome before replacing its browser
+            state.guard = guard;
+            state.browser = new_browser;
+            state.restarts += 1;
+            state.browser.new_tab()?
         }
-        if let Err(e) = web::capture(target, &opts.output_dir, &tab, &report_tx)
-        {
-            match e {
-                Error::IoError(e) => {
-                    // Should probably abort on an IO error
-                    error!("IO error: {}", e);
-                    break;
-                }
-                Error::ChromeError(e) => {
-                    warn!("Failed to capture image: {}", e);
-                }
-                _ => unreachable!(),
-            }
+        Err(e) => return Err(e.into()),
+    };
+    let events = web::configure_tab(&tab, opts)?;
+    Ok(WebTab {
+        tab,
+        events,
+        restarts: state.restarts,
+    })
+}
+
+fn log_web_error(target: &Target, e: Error) {
+    match e {
+        Error::ChromeError(e) => {
+            warn!("Failed to capture image: {}", e);
+        }
+        Error::TimeoutError(e) => {
+            warn!("Timed out capturing {}: {}", target, e);
+        }
+        Error::UnreachableError(e) => {
+            info!("Skipping {}", e);
+        }
+        Error::ProxyError(e) => {
+            warn!("Proxy error capturing {}: {}", target, e);
+        }
+        Error::BlankError(_) => {
+            info!("Skipping blank capture of {}", target);
+        }
+        Error::ConnectionRefused(e) | Error::DnsError(e) => {
+            info!("Skipping {}: {}", target, e);
+        }
+        e => {
+            warn!("Failed to capture {}: {}", target, e);
         }
     }
-    Ok(())
 }
 
 fn vnc_worker(
-    targets: Arc<InputLists>,
+    queue: Box<dyn Queue>,
+    threads: usize,
     opts: Arc<Opts>,
     report_tx: mpsc::Sender<ReportMessage>,
-    caught_ctrl_c: Arc<AtomicBool>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    use mpsc::{Receiver, Sender};
-    let max_workers = opts.threads;
-    let mut num_workers: usize = 0;
-    let mut targets_iter = targets.vnc_targets.iter();
-    let mut workers: Vec<_> = Vec::new();
-    let (thread_status_tx, thread_status_rx): (
-        Sender<ThreadStatus>,
-        Receiver<ThreadStatus>,
-    ) = mpsc::channel();
-    while !caught_ctrl_c.load(Ordering::SeqCst) {
-        // check for status messages
-        // Turn off clippy's single_match warning here because match
-        // matches the intuition for how try_recv is processed better
-        // than an if let.
-        #[allow(clippy::single_match)]
-        match thread_status_rx.try_recv() {
-            Ok(ThreadStatus::Complete) => {
-                info!("Thread complete, yay");
-                num_workers -= 1;
-            }
-            Err(_) => {}
-        }
-        if num_workers < max_workers {
-            if let Some(target) = targets_iter.next() {
-                let target = target.clone();
-                info!("Adding VNC worker for {:?}", target);
-                let opts_clone = opts.clone();
-                let tx = thread_status_tx.clone();
-                let report_tx_clone = report_tx.clone();
-                let handle = thread::spawn(move || {
-                    vnc::capture(&target, &opts_clone, tx, &report_tx_clone)
-                });
-
-                workers.push(handle);
-                num_workers += 1;
-            } else {
-                break;
-            }
-        }
-    }
-    debug!("At the join part");
-    for w in workers {
-        debug!("Joining {:?}", w);
-        w.join().unwrap();
-    }
-
-    Ok(())
+    limits: Arc<Limits>,
+) {
+    let workers = (0..threads).map(|_| report_tx.clone()).collect();
+    let cached = opts.clone();
+    let cached = move |t: &Target| capture_exists(t, "vnc", &cached);
+    let left = pool::run(queue, workers, limits, cached, move |tx, target| {
+        info!("Capturing {}", target);
+        vnc::capture(target, &opts, tx);
+        Next::Continue
+    });
+    report_not_attempted(Mode::Vnc, left, &report_tx);
 }
@@ -0,0 +1,148 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Text recognition on captures with --ocr, by running `tesseract`, so
+//! that a scan can be searched for words such as "login" or "admin"
+
+use crate::argparse::Opts;
+use crate::error::Error;
+use crate::util::atomic_write;
+use log::{debug, warn};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Program that is run to recognise the text
+const TESSERACT: &str = "tesseract";
+
+/// Path of the text that accompanies an image, i.e. the image's name
+/// with the extension replaced by `.txt`
+pub fn text_path(path: &Path) -> PathBuf {
+    path.with_extension("txt")
+}
+
+/// Make sure that tesseract can be run before starting a scan, rather
+/// than failing on every capture
+pub fn check() -> Result<(), Error> {
+    let status = Command::new(TESSERACT)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map_err(|e| {
+            Error::OcrError(format!("Unable to run {}: {}", TESSERACT, e))
+        })?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::OcrError(format!(
+            "{} exited with {}",
+            TESSERACT, status
+        )))
+    }
+}
+
+/// Recognise the text in the image at `path`
+pub fn recognise(path: &Path) -> Result<String, Error> {
+    let output = Command::new(TESSERACT)
+        .arg(path)
+        .arg("stdout")
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| {
+            Error::OcrError(format!("Unable to run {}: {}", TESSERACT, e))
+        })?;
+    if !output.status.success() {
+        return Err(Error::OcrError(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(tidy(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Drop the blank lines and trailing whitespace that tesseract leaves
+/// between blocks of text
+fn tidy(text: &str) -> String {
+    text.lines()
+        .map(str::trim_end)
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Write the text recognised in a saved capture next to it if --ocr was
+/// given. Failures are only logged as the capture itself was successful.
+pub fn save_text(path: &Path, opts: &Opts) {
+    if !opts.ocr {
+        return;
+    }
+    let text_file = text_path(path);
+    let saved = recognise(path).and_then(|text| {
+        atomic_write(&text_file, |w| {
+            writeln!(w, "{}", text)?;
+            Ok(())
+        })
+    });
+    match saved {
+        Ok(()) => debug!("Saved text as {}", text_file.display()),
+        Err(e) => {
+            warn!("Failed to recognise text in {}: {}", path.display(), e)
+        }
+    }
+}
+
+/// Text that was recognised in a capture, if there is any
+pub fn load_text(path: &Path) -> Option<String> {
+    let text = fs::read_to_string(text_path(path)).ok()?;
+    let text = text.trim();
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn saved_text() {
+        let dir = std::env::temp_dir()
+            .join(format!("scrying-ocr-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let image_file = dir.join("capture.png");
+        assert_eq!(text_path(&image_file), dir.join("capture.txt"));
+        assert_eq!(load_text(&image_file), None);
+
+        fs::write(text_path(&image_file), " \n").unwrap();
+        assert_eq!(load_text(&image_file), None);
+
+        let text = tidy("Please log in  \n\nUsername\n\n\nPassword\n\x0c");
+        // Pages end with a form feed, which is whitespace too
+        assert_eq!(text, "Please log in\nUsername\nPassword");
+        fs::write(text_path(&image_file), format!("{}\n", text)).unwrap();
+        assert_eq!(
+            load_text(&image_file).as_deref(),
+            Some("Please log in\nUsername\nPassword")
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,134 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Addresses and CIDR ranges that must never be captured, for --exclude
+
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An address or range of addresses to leave out of the scan
+#[derive(Clone, Debug, PartialEq)]
+pub struct Exclusion {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+/// Treat an IPv4-mapped IPv6 address as the legacy IP address it maps to,
+/// so that it is compared against legacy IP ranges
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+impl Exclusion {
+    /// Whether the address falls within the excluded range
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (bits, network, ip) = match (self.addr, canonical_ip(ip)) {
+            (IpAddr::V4(n), IpAddr::V4(a)) => {
+                (32, u32::from(n) as u128, u32::from(a) as u128)
+            }
+            (IpAddr::V6(n), IpAddr::V6(a)) => {
+                (128, u128::from(n), u128::from(a))
+            }
+            _ => return false,
+        };
+        let host_bits = bits - self.prefix;
+        // Shifting a u128 by 128 would overflow, and a /0 excludes
+        // everything anyway
+        host_bits == 128 || network >> host_bits == ip >> host_bits
+    }
+}
+
+impl FromStr for Exclusion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid address or CIDR range: {}", s);
+        let mut parts = s.trim().splitn(2, '/');
+        let addr: IpAddr = parts
+            .next()
+            .and_then(|a| a.parse().ok())
+            .ok_or_else(invalid)?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(p) => match p.parse() {
+                Ok(p) if p <= bits => p,
+                _ => return Err(invalid()),
+            },
+            None => bits,
+        };
+        // A range of IPv4-mapped addresses is kept as the legacy IP range
+        // that it covers, to match how addresses are compared
+        match canonical_ip(addr) {
+            IpAddr::V4(v4) if addr.is_ipv6() && prefix >= 96 => Ok(Self {
+                addr: IpAddr::V4(v4),
+                prefix: prefix - 96,
+            }),
+            _ => Ok(Self { addr, prefix }),
+        }
+    }
+}
+
+impl Display for Exclusion {
+    fn fmt(
+        &self,
+        fmt: &mut std::fmt::Formatter<'_>,
+    ) -> Result<(), std::fmt::Error> {
+        write!(fmt, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exclusion_ranges() {
+        let ip = |a: &str| a.parse::<IpAddr>().unwrap();
+        let range: Exclusion = "192.0.2.0/25".parse().unwrap();
+        assert!(range.contains(ip("192.0.2.0")));
+        assert!(range.contains(ip("192.0.2.127")));
+        assert!(!range.contains(ip("192.0.2.128")));
+        assert!(range.contains(ip("::ffff:192.0.2.1")));
+        assert!(!range.contains(ip("::ffff:192.0.2.200")));
+        assert!(!range.contains(ip("::192.0.2.1")));
+
+        let mapped: Exclusion = "::ffff:192.0.2.0/120".parse().unwrap();
+        assert_eq!(mapped.to_string(), "192.0.2.0/24");
+        assert!(mapped.contains(ip("192.0.2.9")));
+        assert!(mapped.contains(ip("::ffff:192.0.2.9")));
+
+        let single: Exclusion = "2001:db8::1".parse().unwrap();
+        assert_eq!(single.to_string(), "2001:db8::1/128");
+        assert!(single.contains(ip("2001:db8::1")));
+        assert!(!single.contains(ip("2001:db8::2")));
+
+        let everything: Exclusion = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(ip("203.0.113.9")));
+        let all_v6: Exclusion = "::/0".parse().unwrap();
+        assert!(all_v6.contains(ip("2001:db8::2")));
+
+        assert!("192.0.2.0/33".parse::<Exclusion>().is_err());
+        assert!("example.com".parse::<Exclusion>().is_err());
+        assert!("192.0.2.0/".parse::<Exclusion>().is_err());
+    }
+}
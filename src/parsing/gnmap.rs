@@ -0,0 +1,106 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Read the open ports from nmap's greppable output (-oG), which has a
+//! line for each host with tab-separated fields such as
+//! `Host: 192.0.2.1 (name)` and `Ports: 80/open/tcp//http///, ...`
+
+use super::OpenPort;
+use log::debug;
+
+pub fn parse(content: &str) -> Vec<OpenPort> {
+    let mut ports = Vec::new();
+
+    for line in content.lines() {
+        // The fields of a line are separated by tabs
+        let mut fields = line.split('\t');
+        let ip = match fields
+            .next()
+            .and_then(|f| f.strip_prefix("Host: "))
+            .and_then(|f| f.split_whitespace().next())
+            .and_then(|ip| ip.parse().ok())
+        {
+            Some(ip) => ip,
+            None => continue,
+        };
+        let port_list = match fields.find_map(|f| f.strip_prefix("Ports: ")) {
+            Some(p) => p,
+            None => continue,
+        };
+
+        // Each port is port/state/protocol/owner/service/rpc/version/
+        for entry in port_list.split(", ") {
+            let parts: Vec<&str> = entry.trim().split('/').collect();
+            let (port, service) = match parts.as_slice() {
+                [port, "open", "tcp", _, service, ..] => (port, service),
+                _ => continue,
+            };
+            let port = match port.parse() {
+                Ok(port) => port,
+                Err(_) => {
+                    debug!("Skipping gnmap port {}", entry);
+                    continue;
+                }
+            };
+            // Services tunnelled over TLS are given as e.g. ssl|http
+            let service = service.rsplit('|').next().unwrap_or_default();
+            ports.push(OpenPort {
+                ip,
+                port,
+                service: match service {
+                    "" | "unknown" => None,
+                    s => Some(s.to_string()),
+                },
+            });
+        }
+    }
+    ports
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn greppable_nmap() {
+        let content = concat!(
+            "# Nmap 7.80 scan initiated as: nmap -oG - 192.0.2.0/30\n",
+            "Host: 192.0.2.1 (example.com)\tStatus: Up\n",
+            "Host: 192.0.2.1 (example.com)\tPorts: 80/closed/tcp//http///,",
+            " 443/open/tcp//ssl|http///, 8000/open/tcp//ms-wbt-server///",
+            "\tIgnored State: filtered (997)\n",
+            "Host: 2001:db8::1 ()\tPorts: 5901/open/tcp//////,",
+            " 161/open/udp//snmp///\n",
+            "# Nmap done -- 4 IP addresses (2 hosts up)\n",
+        );
+        let port = |ip: &str, port, service: Option<&str>| OpenPort {
+            ip: ip.parse().unwrap(),
+            port,
+            service: service.map(String::from),
+        };
+        assert_eq!(
+            parse(content),
+            vec![
+                port("192.0.2.1", 443, Some("http")),
+                port("192.0.2.1", 8000, Some("ms-wbt-server")),
+                port("2001:db8::1", 5901, None),
+            ]
+        );
+    }
+}
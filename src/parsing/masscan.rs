@@ -0,0 +1,102 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Read the open ports from masscan's list output (-oL), which has lines
+//! like `open tcp 80 192.0.2.1 1600000000`. With --banners there are also
+//! `banner` lines that name the service found on a port.
+
+use super::OpenPort;
+use log::debug;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+pub fn parse(content: &str) -> Vec<OpenPort> {
+    let mut ports: Vec<OpenPort> = Vec::new();
+    let mut services: HashMap<(IpAddr, u16), String> = HashMap::new();
+
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (kind, port, ip) = match fields.as_slice() {
+            [kind, "tcp", port, ip, ..] => (*kind, port, ip),
+            _ => continue,
+        };
+        let (port, ip) = match (port.parse(), ip.parse()) {
+            (Ok(port), Ok(ip)) => (port, ip),
+            _ => {
+                debug!("Skipping masscan line {}", line);
+                continue;
+            }
+        };
+        match kind {
+            "open" => {
+                if !ports.iter().any(|p| p.ip == ip && p.port == port) {
+                    ports.push(OpenPort {
+                        ip,
+                        port,
+                        service: None,
+                    });
+                }
+            }
+            "banner" => {
+                if let Some(service) = fields.get(5) {
+                    services.insert((ip, port), service.to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for port in &mut ports {
+        port.service = services.remove(&(port.ip, port.port));
+    }
+    ports
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn masscan_list() {
+        let content = "#masscan
+open tcp 3389 192.0.2.1 1600000000
+open tcp 8080 2001:db8::1 1600000001
+banner tcp 8080 2001:db8::1 1600000002 http HTTP/1.1 200 OK
+open udp 161 192.0.2.1 1600000003
+closed tcp 80 192.0.2.1 1600000004
+open tcp 3389 192.0.2.1 1600000005
+# end
+";
+        assert_eq!(
+            parse(content),
+            vec![
+                OpenPort {
+                    ip: "192.0.2.1".parse().unwrap(),
+                    port: 3389,
+                    service: None,
+                },
+                OpenPort {
+                    ip: "2001:db8::1".parse().unwrap(),
+                    port: 8080,
+                    service: Some("http".to_string()),
+                },
+            ]
+        );
+    }
+}
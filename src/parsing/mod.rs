@@ -18,23 +18,52 @@
 */
 
 use crate::argparse::{Mode, Opts};
+use crate::error::Error;
+use crate::util::resolve;
+use flate2::bufread::MultiGzDecoder;
 #[allow(unused)]
 use log::{debug, error, info, trace, warn};
 use nessus_xml_parser::NessusScan;
 use nmap_xml_parser::{port::PortState, NmapResults};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::convert::TryFrom;
 use std::fmt::Display;
-use std::fs::{self, File};
+use std::fs::File;
+use std::hash::{Hash, Hasher};
 use std::io::{self, prelude::*, BufReader};
+use std::iter;
 use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use url::Url;
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+mod exclude;
+mod gnmap;
+mod input;
+mod masscan;
+mod remote;
+
+pub use exclude::Exclusion;
+pub use input::parse_target;
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum Target {
     Address(SocketAddr),
     //  Hostname(String),
     Url(Url),
 }
 
+/// An open port found by a scanner, along with the name of the service on
+/// it if the scanner identified one
+#[derive(Debug, PartialEq)]
+pub struct OpenPort {
+    pub ip: IpAddr,
+    pub port: u16,
+    pub service: Option<String>,
+}
+
 // InputLists moved above the impl on Target because the impl is
 // pretty long
 #[derive(Default, Debug, Eq, PartialEq, PartialOrd)]
@@ -42,6 +71,35 @@ pub struct InputLists {
     pub rdp_targets: Vec<Target>,
     pub web_targets: Vec<Target>,
     pub vnc_targets: Vec<Target>,
+    /// Number of targets left out because of --limit
+    pub truncated: usize,
+    /// Inputs that couldn't be parsed as any type of target
+    pub invalid: Vec<InvalidTarget>,
+}
+
+/// An input that couldn't be used as a target, which is kept so that it
+/// can be reported rather than quietly left out
+#[derive(Clone, Debug, Eq, PartialEq, PartialOrd)]
+pub struct InvalidTarget {
+    pub input: String,
+    /// File and line number that the input was on, if it came from a file
+    pub location: Option<String>,
+    pub reason: String,
+}
+
+impl Display for InvalidTarget {
+    fn fmt(
+        &self,
+        fmt: &mut std::fmt::Formatter,
+    ) -> Result<(), std::fmt::Error> {
+        if let Some(location) = &self.location {
+            write!(fmt, "{}: ", location)?;
+        }
+        if !self.input.is_empty() {
+            write!(fmt, "{}: ", self.input)?;
+        }
+        write!(fmt, "{}", self.reason)
+    }
 }
 
 impl InputLists {
@@ -49,16 +107,115 @@ impl InputLists {
         self.rdp_targets.append(&mut list.rdp_targets);
         self.web_targets.append(&mut list.web_targets);
         self.vnc_targets.append(&mut list.vnc_targets);
+        self.invalid.append(&mut list.invalid);
     }
 
+    /// Remove repeated targets, keeping the first of each in its original
+    /// position. URLs with different schemes or ports are kept separate.
     fn dedup(&mut self) {
-        self.rdp_targets.sort();
-        self.rdp_targets.dedup();
-        self.web_targets.sort();
-        self.web_targets.dedup();
-        self.vnc_targets.sort();
-        self.vnc_targets.dedup();
+        fn dedup_list(list: &mut Vec<Target>) -> usize {
+            let before = list.len();
+            let mut seen = HashSet::new();
+            list.retain(|t| seen.insert(t.clone()));
+            before - list.len()
+        }
+
+        let removed = dedup_list(&mut self.rdp_targets)
+            + dedup_list(&mut self.web_targets)
+            + dedup_list(&mut self.vnc_targets);
+        debug!("Removed {} duplicate targets", removed);
+    }
+
+    /// Put each list into a random order, which is repeatable when a seed
+    /// is given. If only `limit` targets are kept then they are picked at
+    /// random from all of the lists together, rather than taking the RDP
+    /// targets first.
+    fn shuffle(&mut self, seed: Option<u64>, limit: Option<usize>) {
+        use Mode::*;
+        let mut rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        self.rdp_targets.shuffle(&mut rng);
+        self.web_targets.shuffle(&mut rng);
+        self.vnc_targets.shuffle(&mut rng);
+
+        if let Some(limit) = limit {
+            // The lists are already in a random order, so it is only how
+            // many are kept from each one that needs deciding
+            let mut modes: Vec<Mode> = iter::repeat(Rdp)
+                .take(self.rdp_targets.len())
+                .chain(iter::repeat(Web).take(self.web_targets.len()))
+                .chain(iter::repeat(Vnc).take(self.vnc_targets.len()))
+                .collect();
+            modes.shuffle(&mut rng);
+            modes.truncate(limit);
+            let kept = |mode| modes.iter().filter(|m| **m == mode).count();
+            self.truncate([kept(Rdp), kept(Web), kept(Vnc)]);
+        }
+    }
+
+    /// Drop any targets whose address is excluded. Hostnames are resolved
+    /// so that they can't be used to get around an excluded range.
+    fn exclude(&mut self, exclusions: &[Exclusion]) {
+        self.rdp_targets.retain(|t| !excluded(t, exclusions));
+        self.web_targets.retain(|t| !excluded(t, exclusions));
+        self.vnc_targets.retain(|t| !excluded(t, exclusions));
+    }
+
+    /// Keep only the first `limit` targets, taking RDP targets first, then
+    /// web, then VNC
+    fn limit(&mut self, limit: usize) {
+        let rdp = self.rdp_targets.len().min(limit);
+        let web = self.web_targets.len().min(limit - rdp);
+        let vnc = self.vnc_targets.len().min(limit - rdp - web);
+        self.truncate([rdp, web, vnc]);
+    }
+
+    /// Cut the RDP, web and VNC lists down to the given lengths, counting
+    /// the targets that are left out
+    fn truncate(&mut self, lengths: [usize; 3]) {
+        for (list, &kept) in [
+            &mut self.rdp_targets,
+            &mut self.web_targets,
+            &mut self.vnc_targets,
+        ]
+        .iter_mut()
+        .zip(lengths.iter())
+        {
+            self.truncated += list.len() - kept;
+            list.truncate(kept);
+        }
+    }
+}
+
+/// Whether the target's address falls within any of the exclusions. A web
+/// target whose hostname can't be resolved is excluded too, as a proxy
+/// might still be able to resolve it to an excluded address.
+fn excluded(target: &Target, exclusions: &[Exclusion]) -> bool {
+    if exclusions.is_empty() {
+        return false;
     }
+    let ips: Vec<IpAddr> = match target {
+        Target::Address(addr) => vec![addr.ip()],
+        Target::Url(url) => match resolve(url) {
+            Ok(addrs) => addrs.iter().map(SocketAddr::ip).collect(),
+            Err(e) => {
+                warn!(
+                    "Excluding {} as it can't be checked against --exclude: {}",
+                    target, e
+                );
+                return true;
+            }
+        },
+    };
+    for ip in ips {
+        if let Some(e) = exclusions.iter().find(|e| e.contains(ip)) {
+            info!("Excluding {} because {} is in {}", target, ip, e);
+            return true;
+        }
+    }
+    false
 }
 
 impl PartialOrd for Target {
@@ -73,11 +230,42 @@ impl Ord for Target {
     }
 }
 
+/// Put a URL into a canonical form, so that the same page written in
+/// different ways is only captured once and always gets the same
+/// filename. Parsing already lowercases the host and drops the default
+/// port, which leaves the trailing dot of a fully qualified host and any
+/// trailing slash on the path. IPv6 addresses and the rest of the path are
+/// left as they are.
+fn normalise_url(mut url: Url) -> Url {
+    let host = match url.host() {
+        Some(url::Host::Domain(d)) if d.len() > 1 && d.ends_with('.') => {
+            Some(d.trim_end_matches('.').to_string())
+        }
+        _ => None,
+    };
+    if let Some(host) = host {
+        if let Err(e) = url.set_host(Some(&host)) {
+            debug!("Unable to remove the trailing dot from {}: {}", url, e);
+        }
+    }
+    if url.path().len() > 1 && url.path().ends_with('/') {
+        let path = url.path().trim_end_matches('/').to_string();
+        url.set_path(&path);
+    }
+    url
+}
+
 impl Target {
-    fn parse(input: &str, mode: Mode) -> Result<Vec<Self>, &str> {
-        use url::Host;
-        // Parse a &str into a Target using the mode hint to guide output.
-        // It doesn't make much sense to use a URL for RDP, etc.
+    /// Parse an input as targets of the given type. Inputs without a
+    /// scheme become both an https and an http URL in web mode, as there
+    /// is no knowing ahead of time which one the server uses. RDP and VNC
+    /// host names become a target for each of their addresses if
+    /// `all_addresses` is set, or just the first one otherwise.
+    fn parse(
+        input: &str,
+        mode: Mode,
+        all_addresses: bool,
+    ) -> Result<Vec<Self>, String> {
         use Mode::*;
 
         // "Auto" is not supported here because this function returns a
@@ -86,155 +274,42 @@ impl Target {
 
         //TODO basic auth
 
-        // Try to match a URL format. Examples could be:
-        // * http://example.com
-        // * https://192.0.2.3
-        // * https://[2001:db8::5]:8080
-        // * rdp://192.0.2.4:3390
-        // * rdp://[2001:db8:6]
-        // * rdp://localhost
-        if let Ok(u) = Url::parse(&input) {
-            match u.scheme() {
-                "http" | "https" => {
-                    trace!("Parsed as HTTP/HTTPS web url");
-                    if mode != Web {
-                        return Err("Non-web mode requested for web-type URL");
-                    }
-                    return Ok(vec![Target::Url(u)]);
-                }
-                "rdp" => {
-                    trace!("Parsed as RDP url");
-                    if mode != Rdp {
-                        return Err("Non-rdp mode requested for rdp-type URL");
-                    }
-                    let port = u.port().unwrap_or(3389);
-                    let address: SocketAddr = match &u
-                        .host()
-                        .expect("URL expected to have host")
-                    {
-                        Host::Ipv4(a) => {
-                            SocketAddr::from((IpAddr::V4(*a), port))
-                        }
-                        Host::Ipv6(a) => {
-                            SocketAddr::from((IpAddr::V6(*a), port))
-                        }
-                        //TODO work out how to get ? to work here rather
-                        // than unwrap
-                        Host::Domain(d) => domain_to_sockaddr(d, port).unwrap(),
-                    };
-                    return Ok(vec![Target::Address(address)]);
-                }
-                "vnc" => {
-                    //TODO code reuse
-                    trace!("Parsed as VNC url");
-                    if mode != Vnc {
-                        return Err("Non-VNC mode requested for VNC-type URL");
-                    }
-                    let port = u.port().unwrap_or(5900);
-                    let address: SocketAddr = match &u
-                        .host()
-                        .expect("URL expected to have host")
-                    {
-                        Host::Ipv4(a) => {
-                            SocketAddr::from((IpAddr::V4(*a), port))
-                        }
-                        Host::Ipv6(a) => {
-                            SocketAddr::from((IpAddr::V6(*a), port))
-                        }
-                        //TODO work out how to get ? to work here rather
-                        // than unwrap
-                        Host::Domain(d) => domain_to_sockaddr(d, port).unwrap(),
-                    };
-                    return Ok(vec![Target::Address(address)]);
-                }
-
-                _ => return Err("Invalid scheme"),
+        let parsed = parse_target(input)?;
+        match (parsed.scheme.as_deref(), mode) {
+            (Some("http"), Web) | (Some("https"), Web) => {
+                trace!("Parsed as HTTP/HTTPS web url");
+                // The url crate takes care of everything after the host,
+                // such as the query string
+                let u = Url::parse(input).map_err(|e| e.to_string())?;
+                Ok(vec![Target::Url(normalise_url(u))])
             }
-        } else {
-            // Handle the case where rdp://2001:db8::100 drops through
-            // to the forced-prefix stage when it should fail as an
-            // invalid URL
-            if input.starts_with("rdp://")
-                || input.starts_with("https://")
-                || input.starts_with("http://")
-                || input.starts_with("vnc://")
-            {
-                return Err("Parsing error");
-            }
-        }
-
-        match mode {
-            Auto => unimplemented!(), // do both
-            Rdp => {
-                // if no port specified then assume 3389, otherwise take
-                // the provided port
-
-                // Try forcing a parse that includes the port
-                if let Ok(addr) = ip_port_to_sockaddr(&input) {
-                    return Ok(vec![Target::Address(addr)]);
-                }
-
-                // If that didn't work then try parsing it as just an address
-                if let Ok(addr) = domain_to_sockaddr(&input, 3389) {
-                    return Ok(vec![Target::Address(addr)]);
+            (Some("rdp"), Rdp)
+            | (Some("vnc"), Vnc)
+            | (None, Rdp)
+            | (None, Vnc) => {
+                if parsed.scheme.is_none() && !parsed.rest.is_empty() {
+                    return Err(format!("Unexpected {}", parsed.rest));
                 }
-
-                // If none of these worked then it's probably not salvageable
-                Err("Unable to parse target")
+                let default_port = if mode == Rdp { 3389 } else { 5900 };
+                let addresses =
+                    parsed.socket_addrs(default_port, all_addresses)?;
+                Ok(addresses.into_iter().map(Target::Address).collect())
             }
-            Web => {
-                // add URLs for HTTP and HTTPS because we don't know
-                // ahead of time which protocol it uses
-                let mut targets = Vec::new();
-
-                // Try parsing as https://$INPUT
-                // if that fails then try https://[$INPUT] in case it is
-                // a v6 address without square brackets
-
-                // Try slapping an HTTP:// on the front and see whether
-                // it parses
-                if let Ok(u) = Url::parse(&format!("https://{}", input)) {
-                    targets.push(Target::Url(u));
-                } else if let Ok(u) =
-                    Url::parse(&format!("https://[{}]", input))
-                {
-                    targets.push(Target::Url(u));
-                } else {
-                    //TODO include error string
-                    return Err("Unable to parse HTTPS URL");
-                }
-
-                if let Ok(u) = Url::parse(&format!("http://{}", input)) {
-                    targets.push(Target::Url(u));
-                } else if let Ok(u) = Url::parse(&format!("http://[{}]", input))
-                {
-                    targets.push(Target::Url(u));
-                } else {
-                    //TODO include error string
-                    return Err("Unable to parse HTTP URL");
-                }
-
-                Ok(targets)
+            (None, Web) => {
+                let location = format!("{}{}", parsed.authority(), parsed.rest);
+                ["https", "http"]
+                    .iter()
+                    .map(|scheme| {
+                        Url::parse(&format!("{}://{}", scheme, location))
+                            .map(|u| Target::Url(normalise_url(u)))
+                            .map_err(|e| e.to_string())
+                    })
+                    .collect()
             }
-            Vnc => {
-                // add VNC targets
-                // if no port specified then assume 5900, otherwise take
-                // the provided port
-                //TODO code reuse
-
-                // Try forcing a parse that includes the port
-                if let Ok(addr) = ip_port_to_sockaddr(&input) {
-                    return Ok(vec![Target::Address(addr)]);
-                }
-
-                // If that didn't work then try parsing it as just an address
-                if let Ok(addr) = domain_to_sockaddr(&input, 5900) {
-                    return Ok(vec![Target::Address(addr)]);
-                }
-
-                // If none of these worked then it's probably not salvageable
-                Err("Unable to parse target")
+            (Some(scheme), _) => {
+                Err(format!("{}:// is not a {} target", scheme, mode.as_str()))
             }
+            (None, Auto) => unreachable!(),
         }
     }
 }
@@ -286,42 +361,6 @@ impl Display for InputLists {
     }
 }
 
-fn domain_to_sockaddr(
-    domain: &str,
-    port: u16,
-) -> Result<SocketAddr, io::Error> {
-    // It's currently the case that "rdp://192.0.2.1"
-    // gets parsed as a domain rather than an IPv4
-    // address. This is due to oddities in the URL
-    // standard that servo/rust-url is following and
-    // does not look like it will be resolved in a
-    // way that is favourable to applications like
-    // this because they are coming from a web-
-    // focused mindset and we are trying to parse
-    // thoroughly non-web URLs.
-    //
-    // To bypass this, if the host looks like a
-    // domain then try to parse it as an IPv4
-    // address. We specifically exclude IPv6 here as
-    // that is currently parsed correctly from the
-    // URL and the tests failing will act as an
-    // interesting canary.
-
-    // Try to resolve the domain to an IP-port combination. The domain
-    // in theory should not have a port alongside it, so this should
-    // "just work", provided the domain resolves to a valid address.
-    let mut addrs = (domain, port).to_socket_addrs()?;
-
-    if let Some(sockaddr) = addrs.next() {
-        return Ok(sockaddr);
-    }
-
-    Err(io::Error::new(
-        io::ErrorKind::Other,
-        "Unknown error resolving domain",
-    ))
-}
-
 fn host_to_socketaddr(host: &str, port: u16) -> Result<SocketAddr, io::Error> {
     // The nessus file just gives us the "host name" as a string, which
     // could be an IP address, a legacy IP address, a DNS name, or maybe
@@ -340,195 +379,490 @@ fn host_to_socketaddr(host: &str, port: u16) -> Result<SocketAddr, io::Error> {
     }
 }
 
-fn ip_port_to_sockaddr(input: &str) -> Result<SocketAddr, io::Error> {
-    let mut addrs = input.to_socket_addrs()?;
+/// Iterator over the addresses in a CIDR range, produced lazily so that
+/// large ranges do not need to be held in memory at once
+struct CidrIter {
+    next: u128,
+    remaining: u128,
+    v6: bool,
+}
 
-    if let Some(sockaddr) = addrs.next() {
-        return Ok(sockaddr);
+impl Iterator for CidrIter {
+    type Item = IpAddr;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let addr = if self.v6 {
+            IpAddr::V6(self.next.into())
+        } else {
+            // Values are bounded by the v4 address space so the
+            // truncation is lossless
+            IpAddr::V4((self.next as u32).into())
+        };
+        self.remaining -= 1;
+        // Avoid overflowing past the very last address in the space
+        self.next = self.next.wrapping_add(1);
+        Some(addr)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match usize::try_from(self.remaining) {
+            Ok(n) => (n, Some(n)),
+            Err(_) => (usize::MAX, None),
+        }
+    }
+}
+
+/// Largest number of host bits that a CIDR range may have before it is
+/// rejected for being too large to scan sensibly, unless --max-range-bits
+/// says otherwise
+const MAX_CIDR_HOST_BITS: u32 = 16;
+
+/// Most addresses that a single range may expand to when every target is
+/// loaded before the scan starts, rather than read as it goes with
+/// --stream
+const MAX_LOADED_RANGE: usize = 1 << 24;
+
+/// Try to interpret the input as a CIDR range. Returns None if the input
+/// does not look like a CIDR, and an error if it does but is invalid or
+/// has more than `max_host_bits` host bits.
+fn parse_cidr(
+    input: &str,
+    skip_network_broadcast: bool,
+    max_host_bits: u32,
+) -> Option<Result<CidrIter, String>> {
+    let mut parts = input.splitn(2, '/');
+    let addr: IpAddr = parts.next()?.parse().ok()?;
+    let prefix: u32 = parts.next()?.parse().ok()?;
+
+    let (bits, base, v6) = match addr {
+        IpAddr::V4(a) => (32, u32::from(a) as u128, false),
+        IpAddr::V6(a) => (128, u128::from(a), true),
+    };
+    if prefix > bits {
+        return Some(Err(format!("Invalid prefix length /{}", prefix)));
+    }
+    let host_bits = bits - prefix;
+    if host_bits > max_host_bits {
+        return Some(Err(format!(
+            "Range is too large, the largest allowed is /{} unless \
+             --max-range-bits is raised",
+            bits.saturating_sub(max_host_bits)
+        )));
+    }
+
+    // --max-range-bits is at most 32, but a whole IPv6 space would need
+    // every bit of the mask
+    let host_mask = 1u128.checked_shl(host_bits).map_or(u128::MAX, |m| m - 1);
+    let mut first = base & !host_mask;
+    let mut last = first | host_mask;
+
+    // Only legacy IP has network and broadcast addresses, and /31 and
+    // /32 ranges do not use them
+    if skip_network_broadcast && !v6 && host_bits >= 2 {
+        first += 1;
+        last -= 1;
     }
 
-    Err(io::Error::new(
-        io::ErrorKind::Other,
-        "Invalid sockaddr string",
-    ))
+    // This can only overflow for the whole IPv6 space, which is well past
+    // the 32 bits that --max-range-bits allows
+    Some(Ok(CidrIter {
+        next: first,
+        remaining: last - first + 1,
+        v6,
+    }))
 }
 
-pub fn generate_target_lists(opts: &Opts) -> InputLists {
+/// Expand an input into the individual targets it represents. CIDR
+/// ranges yield each address in the range, anything else is passed
+/// through untouched. Ranges that are invalid or too large are an error.
+fn expand_cidr(
+    input: &str,
+    skip_network_broadcast: bool,
+    max_host_bits: u32,
+) -> Result<Box<dyn Iterator<Item = String>>, String> {
+    match parse_cidr(input, skip_network_broadcast, max_host_bits) {
+        None => Ok(Box::new(std::iter::once(input.to_string()))),
+        Some(Ok(range)) => {
+            debug!("Expanding CIDR range {}", input);
+            Ok(Box::new(range.map(|addr| addr.to_string())))
+        }
+        Some(Err(e)) => Err(format!("Unable to expand range: {}", e)),
+    }
+}
+
+/// Parse an input along with any targets that it expands to, passing the
+/// lists for each of them on to `found` until it returns false. Inputs
+/// that can't be parsed are logged and passed on in the invalid list.
+fn expand_input<F>(
+    input: &str,
+    location: Option<String>,
+    opts: &Opts,
+    found: &mut F,
+) -> bool
+where
+    F: FnMut(InputLists) -> bool,
+{
+    let invalid = |input: &str, reason: String| {
+        let invalid = InvalidTarget {
+            input: input.to_string(),
+            location: location.clone(),
+            reason,
+        };
+        warn!("Unable to parse {}", invalid);
+        InputLists {
+            invalid: vec![invalid],
+            ..Default::default()
+        }
+    };
+    let max_host_bits = opts.max_range_bits.unwrap_or(MAX_CIDR_HOST_BITS);
+    let expanded =
+        match expand_cidr(input, opts.skip_network_broadcast, max_host_bits) {
+            Ok(expanded) => expanded,
+            Err(e) => return found(invalid(input, e)),
+        };
+    // e.g. an IPv6 /96 with --max-range-bits 32 would be billions of
+    // targets held in memory at once
+    let size = expanded.size_hint().0;
+    if !opts.stream && size > MAX_LOADED_RANGE {
+        let reason = format!(
+            "Range has {} addresses, which is too many to load without \
+             --stream",
+            size
+        );
+        return found(invalid(input, reason));
+    }
+    for t in expanded {
+        let mut list = InputLists::default();
+        match add_target(&t, opts, &mut list) {
+            Ok(()) => info!("{} loaded as target", t),
+            Err(e) => list = invalid(&t, e),
+        }
+        if !found(list) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Remove comments and surrounding whitespace from a line of a targets
+/// file. Whole-line comments start with a #, and trailing comments must
+/// be separated from the target by whitespace so that URL fragments such
+/// as http://example.com/#/login are left intact.
+pub fn strip_comment(line: &str) -> &str {
+    let line = line.trim();
+    if line.starts_with('#') {
+        return "";
+    }
+    match line.find(|c: char| c.is_whitespace()) {
+        Some(idx) if line[idx..].trim_start().starts_with('#') => {
+            line[..idx].trim_end()
+        }
+        _ => line,
+    }
+}
+
+/// Add a host:port input to the target list that matches its port, for
+/// use in auto mode where the input does not say what protocol it is
+fn add_target_by_port(
+    input: &str,
+    port: u16,
+    opts: &Opts,
+    input_lists: &mut InputLists,
+) -> Result<(), String> {
     use Mode::*;
-    let mut input_lists: InputLists = Default::default();
+    let mode =
+        detect_service("", port, &opts.scheme_ports).ok_or_else(|| {
+            format!("Unrecognised port {}, specify a scheme or --mode", port)
+        })?;
+
+    let mut targets = Target::parse(input, mode, opts.all_addresses)?;
+    debug!("{} classified as {:?} target by port", input, mode);
+    match mode {
+        Rdp => input_lists.rdp_targets.append(&mut targets),
+        Vnc => input_lists.vnc_targets.append(&mut targets),
+        Web => {
+            let scheme = guess_scheme(Some(port), &opts.scheme_ports);
+            add_web_targets(targets, scheme, opts, input_lists);
+        }
+        Auto => unreachable!(),
+    }
+    Ok(())
+}
 
-    // Process the optional command-line target argument
-    for t in &opts.targets {
-        let mut parse_successful = false;
-        match &opts.mode {
-            Auto => {
-                // Try parsing as both web and RDP, saving any that stick
-                if let Ok(mut targets) = Target::parse(&t, Rdp) {
-                    input_lists.rdp_targets.append(&mut targets);
-                    parse_successful = true;
-                    debug!("{} parsed as RDP target", t);
-                }
-                if let Ok(mut targets) = Target::parse(&t, Web) {
-                    input_lists.web_targets.append(&mut targets);
-                    parse_successful = true;
-                    debug!("{} parsed as Web target", t);
-                }
-                if let Ok(mut targets) = Target::parse(&t, Vnc) {
-                    input_lists.vnc_targets.append(&mut targets);
-                    parse_successful = true;
-                    debug!("{} parsed as VNC target", t);
-                }
-            }
-            Web => {
-                if let Ok(mut targets) = Target::parse(&t, Web) {
-                    input_lists.web_targets.append(&mut targets);
-                    parse_successful = true;
-                    debug!("{} parsed as Web target", t);
-                }
+/// Ports that are taken to be web servers when nothing else says what is
+/// running on them, along with the scheme that they usually use
+const WEB_PORTS: &[(u16, &str)] = &[
+    (80, "http"),
+    (443, "https"),
+    (631, "http"),
+    (3000, "http"),
+    (7443, "https"),
+    (8000, "http"),
+    (8080, "http"),
+    (8443, "https"),
+    (9443, "https"),
+];
+
+/// Scheme given for a port with --scheme-port, the last one winning if a
+/// port is given more than once
+fn scheme_override(port: u16, scheme_ports: &[(u16, String)]) -> Option<&str> {
+    scheme_ports
+        .iter()
+        .rev()
+        .find(|(p, _)| *p == port)
+        .map(|(_, scheme)| scheme.as_str())
+}
+
+/// Add web targets that were parsed from an input without a scheme, which
+/// come in both http and https. Only the one with `scheme` is kept unless
+/// --all-schemes was given.
+fn add_web_targets(
+    targets: Vec<Target>,
+    scheme: &str,
+    opts: &Opts,
+    input_lists: &mut InputLists,
+) {
+    input_lists
+        .web_targets
+        .extend(targets.into_iter().filter(|t| match t {
+            Target::Url(u) => opts.all_schemes || u.scheme() == scheme,
+            _ => true,
+        }));
+}
+
+/// Guess which scheme a web target given without one is using from its
+/// port, if it has one. Schemes given with --scheme-port take priority.
+fn guess_scheme(port: Option<u16>, scheme_ports: &[(u16, String)]) -> &str {
+    let port = match port {
+        Some(port) => port,
+        None => return "http",
+    };
+    scheme_override(port, scheme_ports)
+        .or_else(|| {
+            WEB_PORTS
+                .iter()
+                .find(|(p, _)| *p == port)
+                .map(|(_, scheme)| *scheme)
+        })
+        .unwrap_or("http")
+}
+
+/// Parse a single target according to the selected mode and add the
+/// results to the relevant target lists. Returns why the input couldn't
+/// be used as any type of target if it wasn't accepted.
+fn add_target(
+    input: &str,
+    opts: &Opts,
+    input_lists: &mut InputLists,
+) -> Result<(), String> {
+    use Mode::*;
+    let mode = opts.mode;
+    let parsed = parse_target(input)?;
+
+    // An explicit scheme decides the protocol, so there is only one type
+    // of target to try
+    if let Some(scheme) = &parsed.scheme {
+        let scheme_mode = match scheme.as_str() {
+            "http" | "https" => Web,
+            "rdp" => Rdp,
+            "vnc" => Vnc,
+            _ => return Err(format!("Unsupported scheme {}", scheme)),
+        };
+        if !mode.selected(scheme_mode) {
+            return Err(format!(
+                "{}:// targets are excluded by --mode",
+                scheme
+            ));
+        }
+        let mut targets =
+            Target::parse(input, scheme_mode, opts.all_addresses)?;
+        debug!("{} parsed as {:?} target", input, scheme_mode);
+        match scheme_mode {
+            Rdp => input_lists.rdp_targets.append(&mut targets),
+            Web => input_lists.web_targets.append(&mut targets),
+            _ => input_lists.vnc_targets.append(&mut targets),
+        }
+        return Ok(());
+    }
+
+    // A bare host:port does not say which protocol to use, so in auto
+    // mode the port decides rather than trying every protocol
+    if mode == Auto {
+        if let Some(port) = parsed.port {
+            return add_target_by_port(input, port, opts, input_lists);
+        }
+    }
+
+    let mut errors = Vec::new();
+    if mode.selected(Rdp) {
+        match Target::parse(input, Rdp, opts.all_addresses) {
+            Ok(mut targets) => {
+                input_lists.rdp_targets.append(&mut targets);
+                debug!("{} parsed as RDP target", input);
             }
-            Rdp => {
-                if let Ok(mut targets) = Target::parse(&t, Rdp) {
-                    input_lists.rdp_targets.append(&mut targets);
-                    parse_successful = true;
-                    debug!("{} parsed as RDP target", t);
-                }
+            Err(e) => errors.push(e),
+        }
+    }
+    if mode.selected(Web) {
+        match Target::parse(input, Web, opts.all_addresses) {
+            Ok(targets) => {
+                let scheme = guess_scheme(parsed.port, &opts.scheme_ports);
+                add_web_targets(targets, scheme, opts, input_lists);
+                debug!("{} parsed as Web target", input);
             }
-            Vnc => {
-                if let Ok(mut targets) = Target::parse(&t, Vnc) {
-                    input_lists.vnc_targets.append(&mut targets);
-                    parse_successful = true;
-                    debug!("{} parsed as VNC target", t);
-                }
+            Err(e) => errors.push(e),
+        }
+    }
+    if mode.selected(Vnc) {
+        match Target::parse(input, Vnc, opts.all_addresses) {
+            Ok(mut targets) => {
+                input_lists.vnc_targets.append(&mut targets);
+                debug!("{} parsed as VNC target", input);
             }
+            Err(e) => errors.push(e),
         }
-        if !parse_successful {
-            warn!("Unable to parse {}", t);
+    }
+
+    // An input is only rejected if it isn't any type of target
+    let selected = [Rdp, Web, Vnc]
+        .iter()
+        .filter(|m| mode.selected(**m))
+        .count();
+    if errors.len() < selected {
+        Ok(())
+    } else {
+        errors.dedup();
+        Err(errors.join(", "))
+    }
+}
+
+/// Wrap a reader of an input file so that gzipped files are read
+/// decompressed. They are recognised by their contents rather than their
+/// name, as a URL doesn't always have one.
+fn decompress<R: BufRead + 'static>(
+    mut reader: R,
+) -> io::Result<Box<dyn BufRead>> {
+    if reader.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Read the whole of a file of scan results, decompressing it if it is
+/// gzipped
+fn read_file(path: &str) -> io::Result<String> {
+    let mut content = String::new();
+    decompress(BufReader::new(File::open(path)?))?
+        .read_to_string(&mut content)?;
+    Ok(content)
+}
+
+/// Read every input in turn, handing over the targets from each target or
+/// scan result as soon as they are parsed. Reading stops early if `found`
+/// returns false. Missing or unreadable local files are skipped, as are
+/// targets that can't be parsed, but a list given with --url-list that
+/// can't be fetched is an error.
+fn read_targets<F>(opts: &Opts, mut found: F) -> Result<(), Error>
+where
+    F: FnMut(InputLists) -> bool,
+{
+    let mut skipped = 0;
+    let result = read_inputs(opts, |list| {
+        skipped += list.invalid.len();
+        found(list)
+    });
+    if skipped > 0 {
+        warn!("Skipped {} targets that could not be parsed", skipped);
+    }
+    result
+}
+
+/// Read the inputs for `read_targets`
+fn read_inputs<F>(opts: &Opts, mut found: F) -> Result<(), Error>
+where
+    F: FnMut(InputLists) -> bool,
+{
+    // Remote lists are requested before anything is read, so that one that
+    // can't be fetched stops the scan before any captures are made
+    let mut remote_lists = Vec::new();
+    for url in &opts.url_lists {
+        remote_lists.push((url.as_str(), remote::open_list(url, opts)?));
+    }
+
+    // Process the optional command-line target argument
+    for input in &opts.targets {
+        if !expand_input(input, None, opts, &mut found) {
+            return Ok(());
         }
     }
 
-    // Process the optional input file
-    for file_name in &opts.files {
+    // Process the optional input files, followed by the remote lists. A
+    // file name of "-" reads the targets from stdin instead.
+    let files = opts.files.iter().filter_map(|file_name| {
+        let reader = if file_name == "-" {
+            info!("Reading targets from stdin");
+            decompress(BufReader::new(io::stdin()))
+        } else {
+            File::open(file_name).and_then(|f| decompress(BufReader::new(f)))
+        };
+        match reader {
+            Ok(reader) => Some((file_name.as_str(), reader)),
+            Err(e) => {
+                warn!("Error opening file: {:?}", e);
+                None
+            }
+        }
+    });
+    for (file_name, reader) in files.chain(remote_lists) {
         let mut parse_successful_count: usize = 0;
         let mut parse_total_count: usize = 0;
         let mut parse_unsuccessful_count: usize = 0;
-        // This is horribly deep nesting, but it has to try opening the
-        // provided file, iterate over a reader, parse each line into a
-        // string type and then behave slightly differently depending on
-        // the selected mode, failing gracefully at each stage if any
-        // errors occur.
-        //TODO make nesting less deep somehow
-        match File::open(file_name) {
-            Ok(file) => {
-                let reader = BufReader::new(file);
-                for line in reader.lines() {
-                    debug!("Reading target {:?}", line);
-                    match line {
-                        Ok(t) => {
-                            // Try to parse the line into a Target
-                            parse_total_count += 1;
-
-                            match &opts.mode {
-                                Auto => {
-                                    // Try parsing as web, RDP, and VNC,
-                                    // saving any that stick
-                                    let mut success = false;
-                                    if let Ok(mut targets) =
-                                        Target::parse(&t, Rdp)
-                                    {
-                                        input_lists
-                                            .rdp_targets
-                                            .append(&mut targets);
-                                        parse_successful_count += 1;
-                                        success = true;
-                                        info!("{} loaded as RDP target", t);
-                                    }
-                                    if let Ok(mut targets) =
-                                        Target::parse(&t, Web)
-                                    {
-                                        input_lists
-                                            .web_targets
-                                            .append(&mut targets);
-                                        parse_successful_count += 1;
-                                        success = true;
-                                        info!("{} loaded as Web target", t);
-                                    }
-                                    if let Ok(mut targets) =
-                                        Target::parse(&t, Vnc)
-                                    {
-                                        input_lists
-                                            .vnc_targets
-                                            .append(&mut targets);
-                                        parse_successful_count += 1;
-                                        success = true;
-                                        info!("{} loaded as VNC target", t);
-                                    }
-                                    if !success {
-                                        warn!("Unable to parse {}", t);
-                                        parse_unsuccessful_count += 1;
-                                    }
-                                }
-                                Web => {
-                                    if let Ok(mut targets) =
-                                        Target::parse(&t, Web)
-                                    {
-                                        input_lists
-                                            .web_targets
-                                            .append(&mut targets);
-                                        parse_successful_count += 1;
-                                        info!("{} loaded as Web target", t);
-                                    } else {
-                                        warn!(
-                                            "{} is not a valid Web target",
-                                            t
-                                        );
-                                        parse_unsuccessful_count += 1;
-                                    }
-                                }
-                                Rdp => {
-                                    if let Ok(mut targets) =
-                                        Target::parse(&t, Rdp)
-                                    {
-                                        input_lists
-                                            .rdp_targets
-                                            .append(&mut targets);
-                                        parse_successful_count += 1;
-                                        info!("{} loaded as RDP target", t);
-                                    } else {
-                                        warn!(
-                                            "{} is not a valid RDP target",
-                                            t
-                                        );
-                                        parse_unsuccessful_count += 1;
-                                    }
-                                }
-                                Vnc => {
-                                    if let Ok(mut targets) =
-                                        Target::parse(&t, Vnc)
-                                    {
-                                        input_lists
-                                            .vnc_targets
-                                            .append(&mut targets);
-                                        parse_successful_count += 1;
-                                        info!("{} loaded as VNC target", t);
-                                    } else {
-                                        warn!(
-                                            "{} is not a valid RDP target",
-                                            t
-                                        );
-                                        parse_unsuccessful_count += 1;
-                                    }
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            warn!("Error reading line {}", e);
+        for (number, line) in (1..).zip(reader.lines()) {
+            debug!("Reading target {:?}", line);
+            match line {
+                Ok(line) => {
+                    let line = strip_comment(&line);
+                    if line.is_empty() {
+                        continue;
+                    }
+                    parse_total_count += 1;
+                    let location = format!("{}:{}", file_name, number);
+                    let mut counted = |list: InputLists| {
+                        if list.invalid.is_empty() {
+                            parse_successful_count += 1;
+                        } else {
                             parse_unsuccessful_count += 1;
                         }
+                        found(list)
+                    };
+                    if !expand_input(line, Some(location), opts, &mut counted) {
+                        return Ok(());
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        "Error reading line {} of {}: {}",
+                        number, file_name, e
+                    );
+                    parse_total_count += 1;
+                    parse_unsuccessful_count += 1;
+                    let invalid = InvalidTarget {
+                        input: String::new(),
+                        location: Some(format!("{}:{}", file_name, number)),
+                        reason: format!("Unable to read line: {}", e),
+                    };
+                    let list = InputLists {
+                        invalid: vec![invalid],
+                        ..Default::default()
+                    };
+                    if !found(list) {
+                        return Ok(());
                     }
                 }
-            }
-            Err(e) => {
-                warn!("Error opening file: {:?}", e);
             }
         }
         info!(
@@ -544,7 +878,7 @@ pub fn generate_target_lists(opts: &Opts) -> InputLists {
     for file in &opts.nmaps {
         info!("Loading nmap file {}", file);
 
-        match fs::read_to_string(file) {
+        match read_file(file) {
             Err(e) => {
                 warn!("Error opening file: {}", e);
             }
@@ -555,16 +889,15 @@ pub fn generate_target_lists(opts: &Opts) -> InputLists {
                     }
                     Ok(results) => {
                         debug!("Successfully parsed file");
-                        //TODO filter for host being UP
                         for (host, port) in results.iter_ports() {
                             // for each host check for some common open ports
                             // and add relevant ones to the list
 
                             // this has been broken out into a separate function
                             // for readability
-                            input_lists.append(&mut lists_from_nmap(
-                                host, port, &opts.mode,
-                            ));
+                            if !found(lists_from_nmap(host, port, opts)) {
+                                return Ok(());
+                            }
                         }
                     }
                 }
@@ -572,11 +905,23 @@ pub fn generate_target_lists(opts: &Opts) -> InputLists {
         }
     }
 
+    // Parse masscan -oL and nmap -oG files
+    for file in &opts.masscans {
+        if !found(lists_from_scan(file, "masscan", masscan::parse, opts)) {
+            return Ok(());
+        }
+    }
+    for file in &opts.gnmaps {
+        if !found(lists_from_scan(file, "gnmap", gnmap::parse, opts)) {
+            return Ok(());
+        }
+    }
+
     // Parse nessus file
     for file in &opts.nessus {
         info!("Loading nessus file {}", file);
 
-        match fs::read_to_string(file) {
+        match read_file(file) {
             Err(e) => {
                 warn!("Error opening file: {}", e);
             }
@@ -594,183 +939,254 @@ pub fn generate_target_lists(opts: &Opts) -> InputLists {
 
                             // this has been broken out into a separate function
                             // for readability
-                            input_lists.append(&mut lists_from_nessus(
-                                host, port, &opts.mode,
-                            ));
+                            if !found(lists_from_nessus(host, port, opts)) {
+                                return Ok(());
+                            }
                         }
                     }
                 }
             }
         }
     }
+    Ok(())
+}
+
+pub fn generate_target_lists(opts: &Opts) -> Result<InputLists, Error> {
+    let mut input_lists: InputLists = Default::default();
+    read_targets(opts, |mut list| {
+        input_lists.append(&mut list);
+        true
+    })?;
 
     input_lists.dedup();
-    input_lists
+    if !opts.exclude.is_empty() {
+        input_lists.exclude(&opts.exclude);
+    }
+    if opts.shuffle {
+        input_lists.shuffle(opts.seed, opts.limit);
+    } else if let Some(limit) = opts.limit {
+        input_lists.limit(limit);
+    }
+    if let Some(limit) = opts.limit {
+        if input_lists.truncated > 0 {
+            warn!(
+                "--limit {} left out {} targets",
+                limit, input_lists.truncated
+            );
+        }
+    }
+    Ok(input_lists)
+}
+
+/// Pass each target on as soon as it is read rather than loading them all
+/// first, for --stream. Duplicate and excluded targets are dropped on the
+/// way, and reading stops after --limit targets or once `send` returns
+/// false. Returns the number of targets that were sent along with the
+/// inputs that were invalid.
+pub fn stream_targets<F>(
+    opts: &Opts,
+    mut send: F,
+) -> Result<(usize, Vec<InvalidTarget>), Error>
+where
+    F: FnMut(Mode, Target) -> bool,
+{
+    // Only a hash of each target is kept, which is far smaller than the
+    // target and still enough to tell millions of them apart
+    let mut seen = HashSet::new();
+    let mut sent = 0;
+    let mut invalid = Vec::new();
+    read_targets(opts, |mut list| {
+        invalid.append(&mut list.invalid);
+        let targets = list
+            .rdp_targets
+            .into_iter()
+            .map(|t| (Mode::Rdp, t))
+            .chain(list.web_targets.into_iter().map(|t| (Mode::Web, t)))
+            .chain(list.vnc_targets.into_iter().map(|t| (Mode::Vnc, t)));
+        for (mode, target) in targets {
+            let mut hasher = DefaultHasher::new();
+            target.hash(&mut hasher);
+            if !seen.insert(hasher.finish()) || excluded(&target, &opts.exclude)
+            {
+                continue;
+            }
+            if !send(mode, target) {
+                return false;
+            }
+            sent += 1;
+            if opts.limit == Some(sent) {
+                info!("Stopped reading targets at --limit {}", sent);
+                return false;
+            }
+        }
+        true
+    })?;
+    Ok((sent, invalid))
 }
 
 fn lists_from_nmap(
     host: &nmap_xml_parser::host::Host,
     port: &nmap_xml_parser::port::Port,
-    mode: &Mode,
+    opts: &Opts,
 ) -> InputLists {
-    use nmap_xml_parser::host::Address;
+    use nmap_xml_parser::host::{Address, HostState};
 
     let mut list: InputLists = Default::default();
 
-    //TODO service discovery for ports identified as
-    // "web", etc.
-    //TODO break this out into a function
-    //TODO code reuse
     debug!("Parsing host {:?}", (host, port));
-    if port.status.state == PortState::Open {
-        debug!("open port");
-        // Found an open port, now add it to the
-        // input lists if it is appropriate
-        //TODO identify Web
-        let service_name = if let Some(info) = &port.service_info {
-            info.name.as_str()
-        } else {
-            ""
-        };
-        match (port.port_number, service_name) {
-            // RDP signatures
-            (3389, _) | (_, "ms-wbt-server") if mode.selected(Mode::Rdp) => {
-                debug!("Identified RDP");
-                let port = port.port_number;
-                // Iterate over the host's addresses. It may have multiple
-                // IPv6, IPv4, and MAC addresses and we want to add them
-                // all (well, maybe not the MAC addresses)
-                for address in host.addresses() {
-                    let target_string = match address {
-                        Address::IpAddr(IpAddr::V6(a)) => {
-                            trace!("address: {:?}", a);
-                            format!("[{}]:{}", a, port)
-                        }
-                        Address::IpAddr(IpAddr::V4(a)) => {
-                            trace!("legacy address: {:?}", a);
-                            format!("{}:{}", a, port)
-                        }
-                        Address::MacAddr(a) => {
-                            trace!("Ignoring MAC address {}", a);
-                            // Ignore the MAC address and move on
-                            continue;
-                        }
-                    };
+    if host.status.state != HostState::Up {
+        debug!("host is not up");
+        return list;
+    }
+    if port.status.state != PortState::Open {
+        return list;
+    }
+    debug!("open port");
 
-                    // target_string now contains a string sockaddr
-                    // representation, so we parse it as RDP and see what
-                    // happens
-                    match Target::parse(&target_string, Mode::Rdp) {
-                        Ok(mut target) => {
-                            debug!("Successfully parsed as RDP");
-                            list.rdp_targets.append(&mut target);
-                        }
-                        Err(e) => {
-                            warn!("Error parsing target as RDP: {}", e);
-                        }
-                    }
-                }
+    // Found an open port, now work out what type of service it is
+    let service_name = if let Some(info) = &port.service_info {
+        info.name.as_str()
+    } else {
+        ""
+    };
+
+    // Iterate over the host's addresses. It may have multiple IPv6,
+    // IPv4, and MAC addresses and we want to add them all (well, maybe
+    // not the MAC addresses)
+    for address in host.addresses() {
+        match address {
+            Address::IpAddr(ip) => list.append(&mut lists_from_service(
+                *ip,
+                port.port_number,
+                service_name,
+                opts,
+            )),
+            Address::MacAddr(a) => {
+                trace!("Ignoring MAC address {}", a);
             }
-            // HTTP(S) signatures
-            (80, _)
-            | (443, _)
-            | (631, _)
-            | (7443, _)
-            | (8080, _)
-            | (8443, _)
-            | (8000, _)
-            | (3000, _)
-            | (_, "http")
-            | (_, "http-mgt")
-            | (_, "https")
-            | (_, "http-alt")
-            | (_, "https-alt")
-                if mode.selected(Mode::Web) =>
-            {
-                debug!("Idenfified web");
-                let port = port.port_number;
-                // Iterate over the host's addresses. It may have multiple
-                // IPv6, IPv4, and MAC addresses and we want to add them
-                // all (well, maybe not the MAC addresses)
-                for address in host.addresses() {
-                    let target_string = match address {
-                        Address::IpAddr(IpAddr::V6(a)) => {
-                            trace!("address: {:?}", a);
-                            format!("[{}]:{}", a, port)
-                        }
-                        Address::IpAddr(IpAddr::V4(a)) => {
-                            trace!("legacy address: {:?}", a);
-                            format!("{}:{}", a, port)
-                        }
-                        Address::MacAddr(a) => {
-                            trace!("Ignoring MAC address {}", a);
-                            // Ignore the MAC address and move on
-                            continue;
-                        }
-                    };
+        }
+    }
+    list
+}
 
-                    // target_string now contains a string sockaddr
-                    // representation, so we parse it as Web and see what
-                    // happens
-                    match Target::parse(&target_string, Mode::Web) {
-                        Ok(mut target) => {
-                            debug!("Successfully parsed as Web");
-                            list.web_targets.append(&mut target);
-                        }
-                        Err(e) => {
-                            warn!("Error parsing target as Web: {}", e);
-                        }
-                    }
-                }
+/// Load the open ports from a line-based scanner output file and build the
+/// target lists from them
+fn lists_from_scan(
+    file: &str,
+    format: &str,
+    parse: fn(&str) -> Vec<OpenPort>,
+    opts: &Opts,
+) -> InputLists {
+    let mut list: InputLists = Default::default();
+    info!("Loading {} file {}", format, file);
+    match read_file(file) {
+        Err(e) => {
+            warn!("Error opening file: {}", e);
+        }
+        Ok(content) => {
+            let ports = parse(&content);
+            debug!("Found {} open ports", ports.len());
+            for port in ports {
+                list.append(&mut lists_from_service(
+                    port.ip,
+                    port.port,
+                    port.service.as_deref().unwrap_or(""),
+                    opts,
+                ));
             }
-            // VNC signatures
-            (5900, _)
-            | (5901, _)
-            | (5902, _)
-            | (5903, _)
-            | (_, "vnc")
-            | (_, "vnc-1")
-            | (_, "vnc-2")
-            | (_, "vnc-3")
-                if mode.selected(Mode::Vnc) =>
-            {
-                debug!("Identified VNC");
-                let port = port.port_number;
-                // Iterate over the host's addresses. It may have multiple
-                // IPv6, IPv4, and MAC addresses and we want to add them
-                // all (well, maybe not the MAC addresses)
-                for address in host.addresses() {
-                    let target_string = match address {
-                        Address::IpAddr(IpAddr::V6(a)) => {
-                            trace!("address: {:?}", a);
-                            format!("[{}]:{}", a, port)
-                        }
-                        Address::IpAddr(IpAddr::V4(a)) => {
-                            trace!("legacy address: {:?}", a);
-                            format!("{}:{}", a, port)
-                        }
-                        Address::MacAddr(a) => {
-                            trace!("Ignoring MAC address {}", a);
-                            // Ignore the MAC address and move on
-                            continue;
-                        }
-                    };
+        }
+    }
+    list
+}
 
-                    // target_string now contains a string sockaddr
-                    // representation, so we parse it as RDP and see what
-                    // happens
-                    match Target::parse(&target_string, Mode::Vnc) {
-                        Ok(mut target) => {
-                            debug!("Successfully parsed as VNC");
-                            list.vnc_targets.append(&mut target);
-                        }
-                        Err(e) => {
-                            warn!("Error parsing target as VNC: {}", e);
-                        }
-                    }
+/// Work out what type of service is listening on a port. If a scanner has
+/// identified the service then that takes priority over guessing based on
+/// the port number, so e.g. an ms-wbt-server on a nonstandard port still
+/// gets treated as RDP. Ports given to --scheme-port are web servers
+/// whatever the scanner or the port number suggest.
+fn detect_service(
+    service_name: &str,
+    port: u16,
+    scheme_ports: &[(u16, String)],
+) -> Option<Mode> {
+    if scheme_override(port, scheme_ports).is_some()
+        || service_scheme(service_name).is_some()
+    {
+        return Some(Mode::Web);
+    }
+    match service_name {
+        "ms-wbt-server" | "rdp" | "msrdp" => Some(Mode::Rdp),
+        "www" => Some(Mode::Web),
+        "vnc" | "vnc-1" | "vnc-2" | "vnc-3" => Some(Mode::Vnc),
+        _ => match port {
+            3389 => Some(Mode::Rdp),
+            5900..=5903 => Some(Mode::Vnc),
+            _ if WEB_PORTS.iter().any(|(p, _)| *p == port) => Some(Mode::Web),
+            _ => None,
+        },
+    }
+}
+
+/// Scheme of a web service named by a scanner, for the names that say
+/// whether it uses TLS. Nessus names https services "https?".
+fn service_scheme(service_name: &str) -> Option<&'static str> {
+    match service_name {
+        "https" | "https-alt" | "ssl/http" | "ssl/https" | "https?" => {
+            Some("https")
+        }
+        "http" | "http-mgt" | "http-alt" | "http-proxy" => Some("http"),
+        _ => None,
+    }
+}
+
+/// Build the target lists for an open port found by a scanner, which may
+/// have named the service that is running on it. Web services get the
+/// scheme from --scheme-port, or that the scanner found, or else the one
+/// guessed from the port, unless --all-schemes was given.
+fn lists_from_service(
+    ip: IpAddr,
+    port: u16,
+    service_name: &str,
+    opts: &Opts,
+) -> InputLists {
+    let mut list: InputLists = Default::default();
+    let target_mode =
+        match detect_service(service_name, port, &opts.scheme_ports) {
+            Some(m) if opts.mode.selected(m) => m,
+            _ => return list,
+        };
+    debug!("Identified {:?}", target_mode);
+
+    let target_string = match ip {
+        IpAddr::V6(a) => {
+            trace!("address: {:?}", a);
+            format!("[{}]:{}", a, port)
+        }
+        IpAddr::V4(a) => {
+            trace!("legacy address: {:?}", a);
+            format!("{}:{}", a, port)
+        }
+    };
+
+    // target_string now contains a string sockaddr representation,
+    // so we parse it as the detected type and see what happens
+    match Target::parse(&target_string, target_mode, false) {
+        Ok(mut target) => {
+            debug!("Successfully parsed as {:?}", target_mode);
+            match target_mode {
+                Mode::Rdp => list.rdp_targets.append(&mut target),
+                Mode::Web => {
+                    let scheme = scheme_override(port, &opts.scheme_ports)
+                        .or_else(|| service_scheme(service_name))
+                        .unwrap_or_else(|| guess_scheme(Some(port), &[]));
+                    add_web_targets(target, scheme, opts, &mut list);
                 }
+                Mode::Vnc => list.vnc_targets.append(&mut target),
+                Mode::Auto => unreachable!(),
             }
-            _ => {}
+        }
+        Err(e) => {
+            warn!("Error parsing target as {:?}: {}", target_mode, e);
         }
     }
     list
@@ -779,46 +1195,20 @@ fn lists_from_nmap(
 fn lists_from_nessus(
     host: &nessus_xml_parser::ReportHost,
     port: nessus_xml_parser::Port,
-    mode: &Mode,
+    opts: &Opts,
 ) -> InputLists {
-    let mut list: InputLists = Default::default();
-
     debug!("Parsing host: {}, port: {}", host, port.id);
 
     // Interpret the host.name as an address or hostname
-    if let Ok(target) = host_to_socketaddr(&host.name, port.id) {
-        //let target_string = format!("{}", target);
-        match (port.id, port.service.as_str()) {
-            (3389, _) | (_, "msrdp") if mode.selected(Mode::Rdp) => {
-                debug!("Identified RDP");
-                list.rdp_targets.push(Target::Address(target));
-            }
-            (80, _)
-            | (443, _)
-            | (631, _)
-            | (7443, _)
-            | (8080, _)
-            | (8443, _)
-            | (8000, _)
-            | (3000, _)
-            | (_, "www")
-            | (_, "https?")
-                if mode.selected(Mode::Web) =>
-            {
-                debug!("Identified Web");
-                list.web_targets.push(Target::Address(target));
-            }
-            (5900, _) | (5901, _) | (5902, _) | (5903, _) | (_, "vnc")
-                if mode.selected(Mode::Vnc) =>
-            {
-                debug!("Identified VNC");
-                list.vnc_targets.push(Target::Address(target));
-            }
-            _ => {}
+    match host_to_socketaddr(&host.name, port.id) {
+        Ok(target) => {
+            lists_from_service(target.ip(), port.id, &port.service, opts)
+        }
+        Err(e) => {
+            warn!("Unable to resolve {}: {}", host.name, e);
+            Default::default()
         }
     }
-
-    list
 }
 
 #[cfg(test)]
@@ -881,12 +1271,43 @@ mod test {
 
         for case in test_cases {
             eprintln!("Test case: {:?}", case);
-            let parsed = Target::parse(&case.0, case.2).unwrap();
+            let parsed = Target::parse(&case.0, case.2, false).unwrap();
             assert_eq!(parsed.len(), 1, "Parsed wrong number of addresses");
             assert_eq!(parsed[0], case.1,);
         }
     }
 
+    #[test]
+    fn normalised_urls() {
+        let cases = vec![
+            ("HTTP://Example.COM.:80/", "http://example.com/"),
+            ("https://example.com:443", "https://example.com/"),
+            (
+                "http://example.com:8080/App/",
+                "http://example.com:8080/App",
+            ),
+            ("http://[2001:DB8::1]/a//?q=/", "http://[2001:db8::1]/a?q=/"),
+            ("https://[2001:db8::1]:443/", "https://[2001:db8::1]/"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(
+                Target::parse(input, Mode::Web, false).unwrap(),
+                vec![Target::Url(Url::parse(expected).unwrap())],
+                "{}",
+                input
+            );
+        }
+
+        // Inputs without a scheme get both, and both are normalised
+        assert_eq!(
+            Target::parse("Example.com./Login/", Mode::Web, false).unwrap(),
+            vec![
+                Target::Url(Url::parse("https://example.com/Login").unwrap()),
+                Target::Url(Url::parse("http://example.com/Login").unwrap()),
+            ]
+        );
+    }
+
     #[test]
     fn parse_target_as_url_with_domain() {
         use Mode::Rdp;
@@ -902,12 +1323,17 @@ mod test {
             ),
         ];
 
-        let parsed = Target::parse(u, Rdp).unwrap();
+        let parsed = Target::parse(u, Rdp, false).unwrap();
         assert_eq!(parsed.len(), 1, "Parsed wrong number of addresses");
         assert!(
             possible_addresses.contains(&parsed[0]),
             "Unable to resolve URL to address"
         );
+
+        // --all-addresses gives a target for every address of the host
+        let all = Target::parse(u, Rdp, true).unwrap();
+        assert_eq!(all[0], parsed[0]);
+        assert!(all.iter().all(|t| possible_addresses.contains(t)));
     }
 
     #[test]
@@ -1009,14 +1435,14 @@ mod test {
 
         for case in test_cases {
             eprintln!("Test case: {:?}", case);
-            let parsed = Target::parse(&case.0, case.2).unwrap();
+            let parsed = Target::parse(&case.0, case.2, false).unwrap();
             assert_eq!(parsed.len(), 1, "Parsed wrong number of addresses");
             assert_eq!(parsed[0], case.1,);
         }
 
         for case in vec_test_cases {
             eprintln!("Test case: {:?}", case);
-            let parsed = Target::parse(&case.0, case.2).unwrap();
+            let parsed = Target::parse(&case.0, case.2, false).unwrap();
 
             // Each address should result in an HTTPS and HTTP URL
             assert_eq!(parsed.len(), 2, "Parsed wrong number of addresses");
@@ -1042,7 +1468,7 @@ mod test {
         for case in test_cases {
             eprintln!("Test case: {:?}", case);
 
-            let result = Target::parse(case.0, case.1);
+            let result = Target::parse(case.0, case.1, false);
             eprintln!("Result: {:?}", result);
             assert!(result.is_err());
         }
@@ -1072,6 +1498,7 @@ mod test {
                     )],
                     web_targets: Vec::new(),
                     vnc_targets: Vec::new(),
+                    ..Default::default()
                 },
                 Rdp,
             ),
@@ -1087,6 +1514,7 @@ mod test {
                     )],
                     web_targets: Vec::new(),
                     vnc_targets: Vec::new(),
+                    ..Default::default()
                 },
                 Auto,
             ),
@@ -1098,6 +1526,7 @@ mod test {
                         Url::parse("https://[2001:db8::6]:8080").unwrap(),
                     )],
                     vnc_targets: Vec::new(),
+                    ..Default::default()
                 },
                 Web,
             ),
@@ -1109,6 +1538,7 @@ mod test {
                         Url::parse("https://[2001:db8::6]").unwrap(),
                     )],
                     vnc_targets: Vec::new(),
+                    ..Default::default()
                 },
                 Auto,
             ),
@@ -1116,15 +1546,23 @@ mod test {
                 "2001:db8::6",
                 InputLists {
                     rdp_targets: Vec::new(),
-                    web_targets: vec![
-                        Target::Url(
-                            Url::parse("http://[2001:db8::6]").unwrap(),
-                        ),
-                        Target::Url(
-                            Url::parse("https://[2001:db8::6]").unwrap(),
-                        ),
-                    ],
+                    web_targets: vec![Target::Url(
+                        Url::parse("http://[2001:db8::6]").unwrap(),
+                    )],
                     vnc_targets: Vec::new(),
+                    ..Default::default()
+                },
+                Web,
+            ),
+            (
+                "192.0.2.1:8443",
+                InputLists {
+                    rdp_targets: Vec::new(),
+                    web_targets: vec![Target::Url(
+                        Url::parse("https://192.0.2.1:8443").unwrap(),
+                    )],
+                    vnc_targets: Vec::new(),
+                    ..Default::default()
                 },
                 Web,
             ),
@@ -1140,34 +1578,64 @@ mod test {
                     )],
                     web_targets: Vec::new(),
                     vnc_targets: Vec::new(),
+                    ..Default::default()
                 },
                 Rdp,
             ),
+            ("[2001:db8::6]:3300", Default::default(), Auto),
             (
-                "[2001:db8::6]:3300",
+                "[2001:db8::6]:3389",
                 InputLists {
                     rdp_targets: vec![Target::Address(
-                        "[2001:db8::6]:3300"
+                        "[2001:db8::6]:3389"
                             .to_socket_addrs()
                             .unwrap()
                             .next()
                             .unwrap(),
                     )],
-                    web_targets: vec![
-                        Target::Url(
-                            Url::parse("http://[2001:db8::6]:3300").unwrap(),
-                        ),
-                        Target::Url(
-                            Url::parse("https://[2001:db8::6]:3300").unwrap(),
-                        ),
-                    ],
+                    web_targets: Vec::new(),
+                    vnc_targets: Vec::new(),
+                    ..Default::default()
+                },
+                Auto,
+            ),
+            (
+                "192.0.2.1:5901",
+                InputLists {
+                    rdp_targets: Vec::new(),
+                    web_targets: Vec::new(),
                     vnc_targets: vec![Target::Address(
-                        "[2001:db8::6]:3300"
+                        "192.0.2.1:5901"
                             .to_socket_addrs()
                             .unwrap()
                             .next()
                             .unwrap(),
                     )],
+                    ..Default::default()
+                },
+                Auto,
+            ),
+            (
+                "192.0.2.1:8080",
+                InputLists {
+                    rdp_targets: Vec::new(),
+                    web_targets: vec![Target::Url(
+                        Url::parse("http://192.0.2.1:8080").unwrap(),
+                    )],
+                    vnc_targets: Vec::new(),
+                    ..Default::default()
+                },
+                Auto,
+            ),
+            (
+                "[2001:db8::6]:8443",
+                InputLists {
+                    rdp_targets: Vec::new(),
+                    web_targets: vec![Target::Url(
+                        Url::parse("https://[2001:db8::6]:8443").unwrap(),
+                    )],
+                    vnc_targets: Vec::new(),
+                    ..Default::default()
                 },
                 Auto,
             ),
@@ -1178,60 +1646,634 @@ mod test {
             opts.targets = vec![input.into()];
             opts.mode = mode;
 
-            let parsed = generate_target_lists(&opts);
+            let mut parsed = generate_target_lists(&opts).unwrap();
 
+            // Inputs that don't give any targets are kept as invalid
+            let invalid: Vec<String> =
+                parsed.invalid.drain(..).map(|i| i.input).collect();
+            if input_lists == InputLists::default() {
+                assert_eq!(invalid, vec![input]);
+            } else {
+                assert!(invalid.is_empty());
+            }
             assert_eq!(parsed, input_lists);
         }
     }
 
+    #[test]
+    fn target_lists_from_mixed_schemes() {
+        let opts = Opts {
+            targets: vec![
+                "rdp://192.0.2.5".into(),
+                "vnc://192.0.2.6:5901".into(),
+                "https://192.0.2.7".into(),
+                "ftp://192.0.2.8".into(),
+                "rdp://nonexistent.invalid".into(),
+            ],
+            ..Default::default()
+        };
+
+        let mut parsed = generate_target_lists(&opts).unwrap();
+
+        let invalid: Vec<String> =
+            parsed.invalid.drain(..).map(|i| i.input).collect();
+        assert_eq!(
+            invalid,
+            vec!["ftp://192.0.2.8", "rdp://nonexistent.invalid"]
+        );
+        assert_eq!(
+            parsed,
+            InputLists {
+                rdp_targets: vec![Target::Address(
+                    "192.0.2.5:3389".to_socket_addrs().unwrap().next().unwrap(),
+                )],
+                web_targets: vec![Target::Url(
+                    Url::parse("https://192.0.2.7").unwrap()
+                )],
+                vnc_targets: vec![Target::Address(
+                    "192.0.2.6:5901".to_socket_addrs().unwrap().next().unwrap(),
+                )],
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn shuffled_target_lists() {
+        let mut opts = Opts {
+            targets: vec!["192.0.2.0/28".into()],
+            mode: Mode::Rdp,
+            shuffle: true,
+            seed: Some(1),
+            ..Default::default()
+        };
+        let first = generate_target_lists(&opts).unwrap();
+        assert_eq!(first, generate_target_lists(&opts).unwrap());
+
+        // Shuffling only changes the order, after the CIDR is expanded
+        opts.shuffle = false;
+        let ordered = generate_target_lists(&opts).unwrap().rdp_targets;
+        assert_ne!(first.rdp_targets, ordered);
+        let mut shuffled = first.rdp_targets;
+        shuffled.sort();
+        let mut sorted = ordered;
+        sorted.sort();
+        assert_eq!(shuffled, sorted);
+    }
+
+    #[test]
+    fn limited_target_lists() {
+        let opts = Opts {
+            targets: vec!["192.0.2.0/30".into(), "https://example.com".into()],
+            mode: Mode::Auto,
+            limit: Some(5),
+            ..Default::default()
+        };
+        let lists = generate_target_lists(&opts).unwrap();
+        assert_eq!(lists.rdp_targets.len(), 4);
+        assert_eq!(lists.web_targets.len(), 1);
+        assert!(lists.vnc_targets.is_empty());
+        assert_eq!(lists.truncated, 8);
+
+        // Shuffled targets are limited across every type of target
+        let opts = Opts {
+            targets: vec!["192.0.2.0/26".into()],
+            mode: Mode::Auto,
+            limit: Some(20),
+            shuffle: true,
+            seed: Some(1),
+            ..Default::default()
+        };
+        let lists = generate_target_lists(&opts).unwrap();
+        let kept = [
+            lists.rdp_targets.len(),
+            lists.web_targets.len(),
+            lists.vnc_targets.len(),
+        ];
+        assert_eq!(kept.iter().sum::<usize>(), 20);
+        assert!(kept[0] < 20);
+        assert_eq!(lists.truncated, 192 - 20);
+    }
+
+    #[test]
+    fn streamed_targets() {
+        let mut opts = Opts {
+            targets: vec![
+                "192.0.2.1".into(),
+                "192.0.2.0/30".into(),
+                "192.0.2.9".into(),
+            ],
+            mode: Mode::Rdp,
+            skip_network_broadcast: true,
+            exclude: vec!["192.0.2.9".parse().unwrap()],
+            ..Default::default()
+        };
+        let stream = |opts: &Opts| {
+            let mut sent = Vec::new();
+            let (count, invalid) = stream_targets(opts, |mode, target| {
+                assert_eq!(mode, Mode::Rdp);
+                sent.push(target.to_string());
+                true
+            })
+            .unwrap();
+            assert!(invalid.is_empty());
+            assert_eq!(count, sent.len());
+            sent
+        };
+        // The repeat of the first address and the excluded one are dropped
+        assert_eq!(stream(&opts), vec!["192.0.2.1:3389", "192.0.2.2:3389"]);
+
+        opts.limit = Some(1);
+        assert_eq!(stream(&opts), vec!["192.0.2.1:3389"]);
+    }
+
+    #[test]
+    fn target_lists_with_all_schemes() {
+        let mut opts = Opts {
+            targets: vec!["example.com".into(), "192.0.2.1:8080".into()],
+            mode: Mode::Web,
+            ..Default::default()
+        };
+        let url = |u: &str| Target::Url(Url::parse(u).unwrap());
+
+        let single = InputLists {
+            web_targets: vec![
+                url("http://example.com"),
+                url("http://192.0.2.1:8080"),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(generate_target_lists(&opts).unwrap(), single);
+
+        opts.all_schemes = true;
+        let all = InputLists {
+            web_targets: vec![
+                url("https://example.com"),
+                url("http://example.com"),
+                url("https://192.0.2.1:8080"),
+                url("http://192.0.2.1:8080"),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(generate_target_lists(&opts).unwrap(), all);
+
+        // Explicit schemes are left alone either way
+        opts.all_schemes = false;
+        opts.targets = vec!["https://example.com:8080".into()];
+        assert_eq!(
+            generate_target_lists(&opts).unwrap().web_targets,
+            vec![url("https://example.com:8080")]
+        );
+    }
+
+    #[test]
+    fn schemes_guessed_by_port() {
+        let mut opts = Opts {
+            targets: vec![
+                "example.com:9443".into(),
+                "example.com:9000".into(),
+                "http://example.com:8443".into(),
+            ],
+            mode: Mode::Web,
+            ..Default::default()
+        };
+        let url = |u: &str| Target::Url(Url::parse(u).unwrap());
+        assert_eq!(
+            generate_target_lists(&opts).unwrap().web_targets,
+            vec![
+                url("https://example.com:9443"),
+                url("http://example.com:9000"),
+                url("http://example.com:8443"),
+            ]
+        );
+
+        // --scheme-port overrides the guess but not an explicit scheme,
+        // and makes the port a web one in auto mode
+        opts.scheme_ports = vec![(9000, "https".into()), (9443, "http".into())];
+        assert_eq!(
+            generate_target_lists(&opts).unwrap().web_targets,
+            vec![
+                url("http://example.com:9443"),
+                url("https://example.com:9000"),
+                url("http://example.com:8443"),
+            ]
+        );
+        opts.mode = Mode::Auto;
+        opts.targets = vec!["192.0.2.1:9000".into(), "192.0.2.1:3389".into()];
+        opts.scheme_ports = vec![(9000, "https".into())];
+        let lists = generate_target_lists(&opts).unwrap();
+        assert_eq!(lists.web_targets, vec![url("https://192.0.2.1:9000")]);
+        assert_eq!(lists.rdp_targets.len(), 1);
+
+        assert_eq!(guess_scheme(None, &opts.scheme_ports), "http");
+        assert_eq!(guess_scheme(Some(443), &[]), "https");
+        assert_eq!(guess_scheme(Some(8080), &[]), "http");
+    }
+
+    #[test]
+    fn ipv6_targets() {
+        let opts = Opts {
+            targets: vec![
+                "[::1]:443".into(),
+                "fe80::1".into(),
+                "2001:db8::/127".into(),
+            ],
+            ..Default::default()
+        };
+        let addr = |a: &str| {
+            Target::Address(a.to_socket_addrs().unwrap().next().unwrap())
+        };
+        let url = |u: &str| Target::Url(Url::parse(u).unwrap());
+
+        let expected = InputLists {
+            rdp_targets: vec![
+                addr("[fe80::1]:3389"),
+                addr("[2001:db8::]:3389"),
+                addr("[2001:db8::1]:3389"),
+            ],
+            web_targets: vec![
+                url("https://[::1]"),
+                url("http://[fe80::1]"),
+                url("http://[2001:db8::]"),
+                url("http://[2001:db8::1]"),
+            ],
+            vnc_targets: vec![
+                addr("[fe80::1]:5900"),
+                addr("[2001:db8::]:5900"),
+                addr("[2001:db8::1]:5900"),
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(generate_target_lists(&opts).unwrap(), expected);
+    }
+
     #[test]
     fn load_from_nmap_xml() {
         // Load xml from a file and parse it
-        let test_cases = vec![(
-            "test/nmap.xml",
-            InputLists {
-                rdp_targets: vec![
-                    Target::Address(
-                        "172.24.5.57:3389"
-                            .to_socket_addrs()
-                            .unwrap()
-                            .next()
-                            .unwrap(),
-                    ),
-                    Target::Address(
-                        "192.168.59.146:3389"
+        let test_cases = vec![
+            (
+                "test/nmap.xml",
+                InputLists {
+                    rdp_targets: vec![
+                        Target::Address(
+                            "172.24.5.57:3389"
+                                .to_socket_addrs()
+                                .unwrap()
+                                .next()
+                                .unwrap(),
+                        ),
+                        Target::Address(
+                            "192.168.59.146:3389"
+                                .to_socket_addrs()
+                                .unwrap()
+                                .next()
+                                .unwrap(),
+                        ),
+                    ],
+                    web_targets: vec![
+                        Target::Url(
+                            Url::parse("http://192.168.59.128:8000/").unwrap(),
+                        ),
+                        Target::Url(
+                            Url::parse("http://192.168.59.146/").unwrap(),
+                        ),
+                    ],
+                    vnc_targets: Vec::new(),
+                    ..Default::default()
+                },
+            ),
+            (
+                "test/nmap_services.xml",
+                InputLists {
+                    rdp_targets: vec![Target::Address(
+                        "192.0.2.10:8000"
                             .to_socket_addrs()
                             .unwrap()
                             .next()
                             .unwrap(),
-                    ),
-                ],
-                web_targets: vec![
-                    Target::Url(
-                        Url::parse("http://192.168.59.128:8000/").unwrap(),
-                    ),
-                    Target::Url(Url::parse("http://192.168.59.146/").unwrap()),
-                    Target::Url(
-                        Url::parse("https://192.168.59.128:8000/").unwrap(),
-                    ),
-                    Target::Url(
-                        Url::parse("https://192.168.59.146:80/").unwrap(),
-                    ),
-                ],
-                vnc_targets: Vec::new(),
-            },
-        )];
+                    )],
+                    web_targets: vec![Target::Url(
+                        Url::parse("http://192.0.2.10:3389/").unwrap(),
+                    )],
+                    vnc_targets: Vec::new(),
+                    ..Default::default()
+                },
+            ),
+        ];
         let mut opts: Opts = Default::default();
         for case in test_cases {
             eprintln!("Test case: {:?}", case);
             opts.nmaps = vec![case.0.into()];
-            let parsed = generate_target_lists(&opts);
+            let parsed = generate_target_lists(&opts).unwrap();
             eprintln!("Parsed: {:?}", parsed);
 
             assert_eq!(parsed, case.1);
         }
     }
 
+    #[test]
+    fn excluded_targets() {
+        let opts = Opts {
+            targets: vec!["192.0.2.0/29".into(), "rdp://[2001:db8::1]".into()],
+            mode: Mode::Rdp,
+            exclude: vec![
+                "192.0.2.4/30".parse().unwrap(),
+                "192.0.2.1".parse().unwrap(),
+                "2001:db8::/64".parse().unwrap(),
+            ],
+            ..Default::default()
+        };
+        let addr = |a: &str| Target::Address(a.parse().unwrap());
+        assert_eq!(
+            generate_target_lists(&opts).unwrap().rdp_targets,
+            vec![
+                addr("192.0.2.0:3389"),
+                addr("192.0.2.2:3389"),
+                addr("192.0.2.3:3389"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unresolvable_targets_excluded() {
+        let url =
+            Target::Url(Url::parse("http://nonexistent.invalid").unwrap());
+        let exclusions = vec!["192.0.2.0/24".parse().unwrap()];
+        assert!(excluded(&url, &exclusions));
+        assert!(!excluded(&url, &[]));
+
+        let mapped =
+            Target::Address("[::ffff:192.0.2.1]:3389".parse().unwrap());
+        assert!(excluded(&mapped, &exclusions));
+    }
+
+    #[test]
+    fn load_from_masscan() {
+        let mut opts = Opts {
+            masscans: vec!["test/masscan.txt".into()],
+            ..Default::default()
+        };
+        let addr = |a: &str| Target::Address(a.parse().unwrap());
+        let url = |u: &str| Target::Url(Url::parse(u).unwrap());
+
+        // Open ports are classified by the banner's service if there is
+        // one, otherwise by their port number, and so is the scheme
+        assert_eq!(
+            generate_target_lists(&opts).unwrap(),
+            InputLists {
+                rdp_targets: vec![addr("192.0.2.20:3389")],
+                web_targets: vec![
+                    url("https://192.0.2.20:8443"),
+                    url("http://192.0.2.22:9000"),
+                ],
+                vnc_targets: vec![addr("192.0.2.21:5901")],
+                ..Default::default()
+            }
+        );
+
+        opts.all_schemes = true;
+        assert_eq!(
+            generate_target_lists(&opts).unwrap().web_targets,
+            vec![
+                url("https://192.0.2.20:8443"),
+                url("http://192.0.2.20:8443"),
+                url("https://192.0.2.22:9000"),
+                url("http://192.0.2.22:9000"),
+            ]
+        );
+    }
+
+    #[test]
+    fn every_web_port_on_a_host() {
+        let ip: IpAddr = "192.0.2.30".parse().unwrap();
+        let opts = Opts {
+            mode: Mode::Auto,
+            ..Default::default()
+        };
+        let mut list: InputLists = Default::default();
+        for (port, service) in &[
+            (80, "http"),
+            (443, "ssl/https"),
+            (8080, "http-proxy"),
+            (8443, ""),
+        ] {
+            list.append(&mut lists_from_service(ip, *port, service, &opts));
+        }
+
+        // Each open port gets its own targets and its own output file
+        // rather than the host only being captured once
+        assert_eq!(list.web_targets.len(), 4);
+        let mut names: Vec<String> = list
+            .web_targets
+            .iter()
+            .map(crate::util::output_filename)
+            .collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), 4);
+    }
+
+    #[test]
+    fn scan_results_honour_scheme_ports() {
+        let ip: IpAddr = "192.0.2.31".parse().unwrap();
+        let opts = Opts {
+            mode: Mode::Auto,
+            scheme_ports: vec![(9000, "https".into()), (8080, "https".into())],
+            ..Default::default()
+        };
+        let mut list: InputLists = Default::default();
+        for (port, service) in
+            &[(9000, ""), (8080, "http-proxy"), (9443, ""), (631, "")]
+        {
+            list.append(&mut lists_from_service(ip, *port, service, &opts));
+        }
+
+        let urls: Vec<String> =
+            list.web_targets.iter().map(|t| t.to_string()).collect();
+        assert_eq!(
+            urls,
+            vec![
+                "https://192.0.2.31:9000/",
+                "https://192.0.2.31:8080/",
+                "https://192.0.2.31:9443/",
+                "http://192.0.2.31:631/",
+            ]
+        );
+    }
+
+    #[test]
+    fn gzipped_inputs() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+
+        let dir = std::env::temp_dir()
+            .join(format!("scrying-gzip-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let gzip = |name: &str, content: &[u8]| {
+            let path = dir.join(name);
+            let mut encoder = GzEncoder::new(
+                File::create(&path).unwrap(),
+                Compression::default(),
+            );
+            encoder.write_all(content).unwrap();
+            encoder.finish().unwrap();
+            path.display().to_string()
+        };
+        let plain = dir.join("plain.txt");
+        std::fs::write(&plain, "192.0.2.2\n").unwrap();
+
+        let opts = Opts {
+            files: vec![
+                gzip("targets.txt.gz", b"192.0.2.1\n# comment\n"),
+                plain.display().to_string(),
+            ],
+            masscans: vec![gzip(
+                "scan.gz",
+                &std::fs::read("test/masscan.txt").unwrap(),
+            )],
+            mode: Mode::Rdp,
+            ..Default::default()
+        };
+        let addr = |a: &str| Target::Address(a.parse().unwrap());
+        assert_eq!(
+            generate_target_lists(&opts).unwrap().rdp_targets,
+            vec![
+                addr("192.0.2.1:3389"),
+                addr("192.0.2.2:3389"),
+                addr("192.0.2.20:3389"),
+            ]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_targets_are_collected() {
+        let path = std::env::temp_dir()
+            .join(format!("scrying-invalid-{}.txt", std::process::id()));
+        std::fs::write(&path, "192.0.2.1\n# comment\nhttp://192.0.2.2:0\n")
+            .unwrap();
+        let file = path.display().to_string();
+        let opts = Opts {
+            targets: vec!["192.0.2.0/8".into()],
+            files: vec![file.clone()],
+            mode: Mode::Rdp,
+            ..Default::default()
+        };
+
+        let lists = generate_target_lists(&opts).unwrap();
+        assert_eq!(lists.rdp_targets.len(), 1);
+        assert_eq!(lists.invalid.len(), 2);
+        assert_eq!(lists.invalid[0].input, "192.0.2.0/8");
+        assert_eq!(lists.invalid[0].location, None);
+        assert_eq!(lists.invalid[1].input, "http://192.0.2.2:0");
+        assert_eq!(lists.invalid[1].location, Some(format!("{}:3", file)));
+        assert!(lists.invalid[1]
+            .to_string()
+            .starts_with(&format!("{}:3: http://192.0.2.2:0: ", file)));
+
+        let (sent, invalid) = stream_targets(&opts, |_, _| true).unwrap();
+        assert_eq!(sent, 1);
+        assert_eq!(invalid, lists.invalid);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn expand_cidr_ranges() {
+        let test_cases: Vec<(&str, bool, Option<Vec<&str>>)> = vec![
+            ("192.0.2.1", false, Some(vec!["192.0.2.1"])),
+            ("example.com/path", false, Some(vec!["example.com/path"])),
+            (
+                "192.0.2.0/30",
+                false,
+                Some(vec!["192.0.2.0", "192.0.2.1", "192.0.2.2", "192.0.2.3"]),
+            ),
+            ("192.0.2.0/30", true, Some(vec!["192.0.2.1", "192.0.2.2"])),
+            ("192.0.2.7/31", true, Some(vec!["192.0.2.6", "192.0.2.7"])),
+            ("192.0.2.9/32", true, Some(vec!["192.0.2.9"])),
+            (
+                "2001:db8::/126",
+                true,
+                Some(vec![
+                    "2001:db8::",
+                    "2001:db8::1",
+                    "2001:db8::2",
+                    "2001:db8::3",
+                ]),
+            ),
+            ("192.0.2.0/33", false, None),
+            ("10.0.0.0/8", false, None),
+            ("2001:db8::/64", false, None),
+        ];
+
+        for (input, skip, expected) in test_cases {
+            eprintln!("Test case: {:?}", (input, skip, &expected));
+            let expanded: Option<Vec<String>> =
+                expand_cidr(input, skip, MAX_CIDR_HOST_BITS)
+                    .ok()
+                    .map(Iterator::collect);
+            assert_eq!(
+                expanded,
+                expected.map(|e| e.into_iter().map(String::from).collect())
+            );
+        }
+
+        assert_eq!(
+            expand_cidr("192.0.2.0/16", false, MAX_CIDR_HOST_BITS)
+                .unwrap()
+                .count(),
+            65536
+        );
+        // The limit can be raised or lowered
+        assert_eq!(
+            expand_cidr("2001:db8::/108", false, 20).unwrap().count(),
+            1 << 20
+        );
+        assert!(expand_cidr("192.0.2.0/24", false, 4).is_err());
+        assert!(expand_cidr("192.0.2.0/28", false, 4).is_ok());
+        assert_eq!(
+            expand_cidr("2001:db8::/96", false, 32).unwrap().size_hint(),
+            (1 << 32, Some(1 << 32))
+        );
+
+        // Ranges that big need --stream rather than being loaded up front
+        let opts = Opts {
+            targets: vec!["2001:db8::/96".into()],
+            mode: Mode::Rdp,
+            max_range_bits: Some(32),
+            ..Default::default()
+        };
+        let lists = generate_target_lists(&opts).unwrap();
+        assert!(lists.rdp_targets.is_empty());
+        assert_eq!(lists.invalid.len(), 1);
+    }
+
+    #[test]
+    fn strip_target_file_comments() {
+        let test_cases = vec![
+            ("192.0.2.1", "192.0.2.1"),
+            ("  192.0.2.1\t", "192.0.2.1"),
+            ("", ""),
+            ("   ", ""),
+            ("# web servers", ""),
+            ("   #192.0.2.1", ""),
+            ("192.0.2.1  # web server", "192.0.2.1"),
+            ("192.0.2.1\t#web server", "192.0.2.1"),
+            ("http://example.com/#/login", "http://example.com/#/login"),
+            (
+                "http://example.com/#/login # app",
+                "http://example.com/#/login",
+            ),
+        ];
+
+        for case in test_cases {
+            eprintln!("Test case: {:?}", case);
+            assert_eq!(strip_comment(case.0), case.1);
+        }
+    }
+
     #[test]
     fn display_impl_for_target() {
         let test_cases = vec![
@@ -0,0 +1,108 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Fetch target lists over HTTP for --url-list, so that centrally kept
+//! lists can be used without copying them about first
+
+use super::decompress;
+use crate::argparse::Opts;
+use crate::error::Error;
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use std::io::{BufRead, BufReader};
+use std::time::Duration;
+
+/// Request the list at the URL, returning a reader of its lines. The body
+/// is read as it arrives, so a large list doesn't have to fit in memory,
+/// and gzipped lists are decompressed on the way.
+pub fn open_list(url: &str, opts: &Opts) -> Result<Box<dyn BufRead>, Error> {
+    info!("Fetching targets from {}", url);
+    let timeout = Duration::from_secs(opts.web_timeout);
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(timeout)
+        .timeout_read(timeout)
+        .build();
+    let response = agent.get(url).call().map_err(|e| {
+        Error::TargetListError(format!("Unable to fetch {}: {}", url, e))
+    })?;
+    decompress(BufReader::new(response.into_reader())).map_err(|e| {
+        Error::TargetListError(format!("Unable to read {}: {}", url, e))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Answer one request with the status line and body
+    fn serve(status: &'static str, body: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url =
+            format!("http://{}/targets.txt", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).unwrap();
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n",
+                status,
+                body.len()
+            )
+            .unwrap();
+            stream.write_all(&body).unwrap();
+        });
+        url
+    }
+
+    #[test]
+    fn remote_lists() {
+        let opts = Opts {
+            web_timeout: 5,
+            ..Default::default()
+        };
+        let list = b"192.0.2.1\nhttp://example.com\n";
+        let lines = |url: &str| -> Vec<String> {
+            open_list(url, &opts)
+                .unwrap()
+                .lines()
+                .map(|l| l.unwrap())
+                .collect()
+        };
+
+        let url = serve("200 OK", list.to_vec());
+        assert_eq!(lines(&url), vec!["192.0.2.1", "http://example.com"]);
+
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(list).unwrap();
+        let url = serve("200 OK", gzip.finish().unwrap());
+        assert_eq!(lines(&url), vec!["192.0.2.1", "http://example.com"]);
+
+        let url = serve("404 Not Found", Vec::new());
+        assert!(matches!(
+            open_list(&url, &opts),
+            Err(Error::TargetListError(_))
+        ));
+    }
+}
@@ -0,0 +1,476 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Captures taken from a shared queue by a fixed number of workers, used
+//! for every kind of capture so that they all limit their concurrency,
+//! pace themselves and stop on Ctrl-C or at --max-duration in the same
+//! way
+//!
+//! Each pool runs on a tokio runtime. Waiting for a target's turn happens
+//! in async tasks held in a bounded `JoinSet`, while the captures
+//! themselves go through `spawn_blocking` as headless_chrome, rdp-rs and
+//! vnc only have blocking APIs.
+
+use crate::parsing::Target;
+use crate::ratelimit::{pause_async, HostQueue, RateLimiter};
+use log::{debug, error};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::runtime::Builder;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::task::{self, JoinSet};
+use tokio::time;
+
+/// Longest a task waits for a free thread before checking whether the
+/// scan has been interrupted
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Wait until fewer than --threads captures are running, or give up if
+/// the scan is interrupted meanwhile
+async fn acquire<'a>(
+    threads: &'a Semaphore,
+    stop: &AtomicBool,
+) -> Option<SemaphorePermit<'a>> {
+    loop {
+        if stop.load(Ordering::SeqCst) {
+            return None;
+        }
+        if let Ok(permit) =
+            time::timeout(POLL_INTERVAL, threads.acquire()).await
+        {
+            return permit.ok();
+        }
+    }
+}
+
+/// Everything that the pools share, so that between them they keep to
+/// --threads and --rate-limit and all stop on Ctrl-C
+#[derive(Debug)]
+pub struct Limits {
+    /// One permit for each capture that may run at once, for --threads
+    pub threads: Semaphore,
+    pub rate_limiter: RateLimiter,
+    pub caught_ctrl_c: Arc<AtomicBool>,
+    /// Time after which no more captures are started, for --max-duration
+    pub deadline: Option<Instant>,
+}
+
+impl Limits {
+    /// Whether the scan has run for as long as --max-duration allows
+    pub fn out_of_time(&self) -> bool {
+        self.deadline.map_or(false, |d| Instant::now() >= d)
+    }
+}
+
+/// What a worker does after capturing a target
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Next {
+    /// Carry on with the next target
+    Continue,
+    /// Stop this worker, leaving the others to finish the queue
+    Stop,
+    /// Stop every worker, leaving the rest of the queue
+    Abort,
+}
+
+/// Where a pool takes its targets from, along with how long to wait
+/// before capturing each one
+pub trait Queue: Send + 'static {
+    fn pop(&mut self) -> Option<(Target, Duration)>;
+}
+
+impl<Q: Queue + ?Sized> Queue for Box<Q> {
+    fn pop(&mut self) -> Option<(Target, Duration)> {
+        (**self).pop()
+    }
+}
+
+impl Queue for HostQueue {
+    fn pop(&mut self) -> Option<(Target, Duration)> {
+        HostQueue::pop(self)
+    }
+}
+
+/// Targets that arrive while the inputs are still being read, for
+/// --stream. The queue runs dry once the sender is dropped.
+pub struct Stream {
+    first: Option<Target>,
+    rx: Receiver<Target>,
+}
+
+impl Stream {
+    /// Wait for the first target, returning None if there are none so
+    /// that no pool needs to be started
+    pub fn new(rx: Receiver<Target>) -> Option<Self> {
+        let first = rx.recv().ok()?;
+        Some(Self {
+            first: Some(first),
+            rx,
+        })
+    }
+}
+
+impl Queue for Stream {
+    fn pop(&mut self) -> Option<(Target, Duration)> {
+        let target = match self.first.take() {
+            Some(t) => t,
+            None => self.rx.recv().ok()?,
+        };
+        Some((target, Duration::from_secs(0)))
+    }
+}
+
+/// What the tasks of a pool share between them
+struct Shared<C, F> {
+    limits: Arc<Limits>,
+    cached: C,
+    capture: F,
+    abort: AtomicBool,
+    not_attempted: Mutex<Vec<Target>>,
+}
+
+impl<C, F> Shared<C, F> {
+    fn stopped(&self) -> bool {
+        self.limits.caught_ctrl_c.load(Ordering::SeqCst)
+            || self.abort.load(Ordering::SeqCst)
+            || self.limits.out_of_time()
+    }
+}
+
+/// Capture every target in the queue with one worker per entry of
+/// `workers`, which is the state that the worker keeps between captures
+/// (such as a browser tab). Returns once the queue is empty or the scan
+/// is interrupted and every capture has finished.
+///
+/// Targets that `cached` returns true for, such as ones that
+/// --skip-existing will skip, are handed over without waiting for their
+/// turn under the rate limits, as nothing is sent to them.
+///
+/// Once the deadline has passed the captures that are running are left to
+/// finish, and the targets that were never started are returned.
+pub fn run<Q, S, C, F>(
+    queue: Q,
+    workers: Vec<S>,
+    limits: Arc<Limits>,
+    cached: C,
+    capture: F,
+) -> Vec<Target>
+where
+    Q: Queue,
+    S: Send + 'static,
+    C: Fn(&Target) -> bool + Send + Sync + 'static,
+    F: Fn(&mut S, &Target) -> Next + Send + Sync + 'static,
+{
+    let runtime = Builder::new_multi_thread()
+        .worker_threads(1)
+        .max_blocking_threads(workers.len() + 1)
+        .enable_time()
+        .build()
+        .expect("Unable to start the capture runtime");
+    let queue = Arc::new(Mutex::new(queue));
+    let shared = Arc::new(Shared {
+        limits,
+        cached,
+        capture,
+        abort: AtomicBool::new(false),
+        not_attempted: Mutex::new(Vec::new()),
+    });
+    runtime.block_on(dispatch(queue.clone(), workers, shared.clone()));
+
+    // Whatever is still queued was cut off by the deadline, unless the
+    // scan was interrupted or aborted instead
+    let limits = &shared.limits;
+    let mut not_attempted =
+        std::mem::take(&mut *shared.not_attempted.lock().unwrap());
+    if limits.out_of_time()
+        && !limits.caught_ctrl_c.load(Ordering::SeqCst)
+        && !shared.abort.load(Ordering::SeqCst)
+    {
+        let mut queue = queue.lock().unwrap();
+        while let Some((target, _)) = queue.pop() {
+            not_attempted.push(target);
+        }
+    }
+    not_attempted
+}
+
+/// Hand the targets out to the workers as they become free, so that there
+/// is never more than one task for each of them
+async fn dispatch<Q, S, C, F>(
+    queue: Arc<Mutex<Q>>,
+    mut idle: Vec<S>,
+    shared: Arc<Shared<C, F>>,
+) where
+    Q: Queue,
+    S: Send + 'static,
+    C: Fn(&Target) -> bool + Send + Sync + 'static,
+    F: Fn(&mut S, &Target) -> Next + Send + Sync + 'static,
+{
+    let mut tasks = JoinSet::new();
+    while !shared.stopped() {
+        if idle.is_empty() {
+            match tasks.join_next().await {
+                Some(Ok(Some(state))) => idle.push(state),
+                // The worker stopped, leaving the rest to the others
+                Some(Ok(None)) => {}
+                Some(Err(e)) => debug!("Capture task failed: {}", e),
+                // Every worker has stopped
+                None => break,
+            }
+            continue;
+        }
+
+        // The queue can block while --stream waits for more targets
+        let next = {
+            let queue = queue.clone();
+            task::spawn_blocking(move || queue.lock().unwrap().pop()).await
+        };
+        let (target, delay) = match next {
+            Ok(Some(t)) => t,
+            _ => break,
+        };
+        let state = idle.pop().unwrap();
+        tasks.spawn(capture_one(state, target, delay, shared.clone()));
+    }
+    while let Some(result) = tasks.join_next().await {
+        if let Err(e) = result {
+            debug!("Capture task failed: {}", e);
+        }
+    }
+}
+
+/// Wait for the target's turn and capture it, handing back the worker's
+/// state unless the worker should stop
+async fn capture_one<S, C, F>(
+    mut state: S,
+    target: Target,
+    delay: Duration,
+    shared: Arc<Shared<C, F>>,
+) -> Option<S>
+where
+    S: Send + 'static,
+    C: Fn(&Target) -> bool + Send + Sync + 'static,
+    F: Fn(&mut S, &Target) -> Next + Send + Sync + 'static,
+{
+    let limits = &shared.limits;
+    let caught_ctrl_c = &limits.caught_ctrl_c;
+    let paced = !(shared.cached)(&target);
+    if paced {
+        pause_async(delay, caught_ctrl_c).await;
+    }
+    let _permit = acquire(&limits.threads, caught_ctrl_c).await?;
+    if paced {
+        limits.rate_limiter.wait_async(caught_ctrl_c).await;
+    }
+    if caught_ctrl_c.load(Ordering::SeqCst) {
+        return None;
+    }
+    if limits.out_of_time() {
+        shared.not_attempted.lock().unwrap().push(target);
+        return Some(state);
+    }
+
+    let capture = shared.clone();
+    let (state, next) = task::spawn_blocking(move || {
+        // A panic only loses the one target rather than the worker and
+        // everything it would have gone on to do
+        let next = panic::catch_unwind(AssertUnwindSafe(|| {
+            (capture.capture)(&mut state, &target)
+        }))
+        .unwrap_or_else(|_| {
+            error!("Capture of {} panicked", target);
+            Next::Continue
+        });
+        (state, next)
+    })
+    .await
+    .ok()?;
+    match next {
+        Next::Continue => Some(state),
+        Next::Stop => None,
+        Next::Abort => {
+            shared.abort.store(true, Ordering::SeqCst);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    #[test]
+    fn pooled_captures() {
+        let targets: Vec<Target> = (1..=20)
+            .map(|i| {
+                Target::Address(format!("192.0.2.{}:3389", i).parse().unwrap())
+            })
+            .collect();
+        let queue = || HostQueue::new(&targets, None, &None);
+        let limits = |threads, interrupted| {
+            Arc::new(Limits {
+                threads: Semaphore::new(threads),
+                rate_limiter: RateLimiter::new(None, 0.0),
+                caught_ctrl_c: Arc::new(AtomicBool::new(interrupted)),
+                deadline: None,
+            })
+        };
+        // Nothing has been captured before
+        let fresh = |_: &Target| false;
+
+        // Every target is captured once, with no more running at once
+        // than --threads allows however many workers there are
+        let captured = Arc::new(Mutex::new(Vec::new()));
+        let running = Arc::new(AtomicUsize::new(0));
+        let most_running = Arc::new(AtomicUsize::new(0));
+        let (captured_clone, running_clone, most_running_clone) =
+            (captured.clone(), running.clone(), most_running.clone());
+        run(
+            queue(),
+            vec![(); 4],
+            limits(2, false),
+            fresh,
+            move |_, target| {
+                let now = running_clone.fetch_add(1, Ordering::SeqCst) + 1;
+                most_running_clone.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(5));
+                captured_clone.lock().unwrap().push(target.clone());
+                running_clone.fetch_sub(1, Ordering::SeqCst);
+                Next::Continue
+            },
+        );
+        let mut captured = captured.lock().unwrap().clone();
+        captured.sort_by_key(|t| t.to_string());
+        let mut expected = targets.clone();
+        expected.sort_by_key(|t| t.to_string());
+        assert_eq!(captured, expected);
+        assert!(most_running.load(Ordering::SeqCst) <= 2);
+
+        // A panic only loses its target, and aborting empties the pool
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_clone = calls.clone();
+        run(
+            queue(),
+            vec![(); 1],
+            limits(1, false),
+            fresh,
+            move |_, _| match calls_clone.fetch_add(1, Ordering::SeqCst) {
+                0 => panic!("capture failed"),
+                1 => Next::Continue,
+                _ => Next::Abort,
+            },
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // Streamed targets are captured as they arrive
+        let (tx, rx) = std::sync::mpsc::sync_channel(1);
+        let sender = thread::spawn(move || {
+            for t in 1..=3 {
+                let addr = format!("192.0.2.{}:5900", t).parse().unwrap();
+                tx.send(Target::Address(addr)).unwrap();
+            }
+        });
+        let streamed = Arc::new(AtomicUsize::new(0));
+        let streamed_clone = streamed.clone();
+        let stream = Stream::new(rx).unwrap();
+        run(stream, vec![(); 2], limits(2, false), fresh, move |_, _| {
+            streamed_clone.fetch_add(1, Ordering::SeqCst);
+            Next::Continue
+        });
+        sender.join().unwrap();
+        assert_eq!(streamed.load(Ordering::SeqCst), 3);
+        let (tx, rx) = std::sync::mpsc::sync_channel::<Target>(1);
+        drop(tx);
+        assert!(Stream::new(rx).is_none());
+
+        // Nothing is captured once the scan has been interrupted
+        run(
+            queue(),
+            vec![(); 4],
+            limits(4, true),
+            fresh,
+            |_, _| unreachable!(),
+        );
+
+        // Nothing is started once the deadline has passed, and every
+        // target is handed back
+        let limits = |deadline| {
+            Arc::new(Limits {
+                threads: Semaphore::new(2),
+                rate_limiter: RateLimiter::new(None, 0.0),
+                caught_ctrl_c: Arc::new(AtomicBool::new(false)),
+                deadline: Some(deadline),
+            })
+        };
+        let left = run(
+            queue(),
+            vec![(); 2],
+            limits(Instant::now()),
+            fresh,
+            |_, _| unreachable!(),
+        );
+        assert_eq!(left, targets);
+
+        // Captures that are running when the deadline passes finish, and
+        // the rest are handed back. Each capture holds on until then, so
+        // exactly one per worker is started.
+        let deadline = limits(Instant::now() + Duration::from_millis(200));
+        let gate = deadline.clone();
+        let finished = Arc::new(AtomicUsize::new(0));
+        let finished_clone = finished.clone();
+        let left = run(queue(), vec![(); 2], deadline, fresh, move |_, _| {
+            while !gate.out_of_time() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            finished_clone.fetch_add(1, Ordering::SeqCst);
+            Next::Continue
+        });
+        assert_eq!(finished.load(Ordering::SeqCst), 2);
+        assert_eq!(left.len(), targets.len() - 2);
+
+        // Targets that are already captured aren't held up by the rate
+        // limit, which would otherwise take most of a minute here
+        let slow = Arc::new(Limits {
+            threads: Semaphore::new(2),
+            rate_limiter: RateLimiter::new(Some(0.5), 0.0),
+            caught_ctrl_c: Arc::new(AtomicBool::new(false)),
+            deadline: None,
+        });
+        let started = Instant::now();
+        let skipped = Arc::new(AtomicUsize::new(0));
+        let skipped_clone = skipped.clone();
+        run(
+            queue(),
+            vec![(); 2],
+            slow,
+            |_| true,
+            move |_, _| {
+                skipped_clone.fetch_add(1, Ordering::SeqCst);
+                Next::Continue
+            },
+        );
+        assert_eq!(skipped.load(Ordering::SeqCst), targets.len());
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,130 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Checking which targets are listening for --probe-only, which is much
+//! quicker than capturing them
+
+use crate::argparse::{Mode, Opts};
+use crate::error::Error;
+use crate::parsing::Target;
+use crate::reporting::{FileError, ReportMessage, ReportMessageContent};
+use crate::tls::fetch_certificate;
+use crate::util::{connect_any, resolve, with_retries};
+use crate::web::PageInfo;
+use log::info;
+use std::net::SocketAddr;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, SystemTime};
+
+/// Connect to the target, and complete a TLS handshake with https targets
+/// if --probe-tls was given, then send the outcome to the reporting
+/// thread. The certificate is recorded as it would be for a capture.
+pub fn probe(
+    target: &Target,
+    mode: Mode,
+    opts: &Opts,
+    report_tx: &Sender<ReportMessage>,
+) {
+    let start = SystemTime::now();
+    let mut page = None;
+    let (result, attempts) = with_retries(opts, || {
+        page = None;
+        let address = reachable(target, opts)?;
+        match target {
+            Target::Url(url) if opts.probe_tls && url.scheme() == "https" => {
+                let timeout = Duration::from_millis(opts.port_check_timeout);
+                page = Some(PageInfo {
+                    tls: Some(fetch_certificate(
+                        url,
+                        Some(address),
+                        timeout,
+                        opts.source_addr,
+                    )?),
+                    ..Default::default()
+                });
+            }
+            _ => {}
+        }
+        Ok(address)
+    });
+    let (output, address) = match result {
+        Ok(address) => {
+            info!("{} is reachable", target);
+            (FileError::Reachable, Some(address.ip()))
+        }
+        Err(e) => {
+            info!("{} is not reachable: {}", target, e);
+            (FileError::Error(e), None)
+        }
+    };
+    let mut content = ReportMessageContent::new(
+        mode, target, start, output, attempts, address,
+    );
+    if let Some(p) = page {
+        content = content.with_page(p);
+    }
+    report_tx
+        .send(ReportMessage::Output(content))
+        .expect("Reporting thread seems to have disconnected");
+}
+
+/// Connect to the target directly, as probing is the whole point, rather
+/// than going by --no-port-check
+fn reachable(target: &Target, opts: &Opts) -> Result<SocketAddr, Error> {
+    let addrs = match target {
+        Target::Address(addr) => vec![*addr],
+        Target::Url(url) => resolve(url)?,
+    };
+    let timeout = Duration::from_millis(opts.port_check_timeout);
+    connect_any(target, &addrs, timeout, opts.source_addr)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    #[test]
+    fn probed_targets() {
+        let opts = Opts {
+            port_check_timeout: 1000,
+            ..Default::default()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = Target::Address(listener.local_addr().unwrap());
+        let closed = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            Target::Address(listener.local_addr().unwrap())
+        };
+
+        let (tx, rx) = mpsc::channel();
+        probe(&open, Mode::Rdp, &opts, &tx);
+        probe(&closed, Mode::Vnc, &opts, &tx);
+        let mut outcome = || match rx.recv().unwrap() {
+            ReportMessage::Output(content) => (content.mode, content.output),
+            m => panic!("Unexpected message {:?}", m),
+        };
+        assert!(matches!(outcome(), (Mode::Rdp, FileError::Reachable)));
+        assert!(matches!(
+            outcome(),
+            (Mode::Vnc, FileError::Error(Error::ConnectionRefused(_)))
+        ));
+    }
+}
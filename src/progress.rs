@@ -0,0 +1,175 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Progress bar showing how far through the scan is, with log lines
+//! printed above it so that the two don't get mixed up
+
+use crate::argparse::Mode;
+use crate::parsing::InputLists;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::io::{self, Write};
+
+const TEMPLATE: &str =
+    "{elapsed_precise} [{bar:40}] {pos}/{len} ETA {eta} {msg}";
+
+/// Tracks how many targets of each type have been captured
+pub struct Progress {
+    bar: ProgressBar,
+    /// Completed and total RDP, web and VNC targets
+    counts: [(usize, usize); 3],
+}
+
+impl Progress {
+    /// Set up a progress bar. It stays hidden until the targets are
+    /// known, and log lines are passed straight through until then.
+    pub fn new() -> Self {
+        let bar = ProgressBar::hidden();
+        bar.set_style(ProgressStyle::default_bar().template(TEMPLATE));
+        Self {
+            bar,
+            counts: [(0, 0); 3],
+        }
+    }
+
+    /// Start showing the bar now that the number of targets is known
+    pub fn start(&mut self, targets: &InputLists) {
+        self.counts = [
+            (0, targets.rdp_targets.len()),
+            (0, targets.web_targets.len()),
+            (0, targets.vnc_targets.len()),
+        ];
+        let total = self.counts.iter().map(|(_, t)| t).sum::<usize>();
+        self.bar.set_length(total as u64);
+        self.update_message();
+        self.bar.set_draw_target(ProgressDrawTarget::stderr());
+    }
+
+    /// Record that a target has been dealt with, successfully or not
+    pub fn complete(&mut self, mode: Mode) {
+        let index = match index(mode) {
+            Some(i) => i,
+            None => return,
+        };
+        self.counts[index].0 += 1;
+        self.bar.inc(1);
+        self.update_message();
+    }
+
+    /// Count another target that has been read since the bar started,
+    /// for --stream
+    pub fn add(&mut self, mode: Mode) {
+        let index = match index(mode) {
+            Some(i) => i,
+            None => return,
+        };
+        self.counts[index].1 += 1;
+        self.bar.inc_length(1);
+        self.update_message();
+    }
+
+    pub fn finish(&self) {
+        self.bar.finish_and_clear();
+    }
+
+    /// Writer for the terminal logger that prints above the bar
+    pub fn log_writer(&self) -> ProgressWriter {
+        ProgressWriter {
+            bar: self.bar.clone(),
+            line: Vec::new(),
+        }
+    }
+
+    /// Show the progress for each type of target that is being scanned
+    fn update_message(&self) {
+        let message = ["RDP", "web", "VNC"]
+            .iter()
+            .zip(self.counts.iter())
+            .filter(|(_, (_, total))| *total > 0)
+            .map(|(name, (done, total))| format!("{} {}/{}", name, done, total))
+            .collect::<Vec<_>>()
+            .join(", ");
+        self.bar.set_message(&message);
+    }
+}
+
+/// Position of a type of target in the counts
+fn index(mode: Mode) -> Option<usize> {
+    match mode {
+        Mode::Rdp => Some(0),
+        Mode::Web => Some(1),
+        Mode::Vnc => Some(2),
+        Mode::Auto => None,
+    }
+}
+
+/// Collects log output a line at a time and prints each one above the
+/// progress bar, or straight to stderr when the bar isn't showing
+pub struct ProgressWriter {
+    bar: ProgressBar,
+    line: Vec<u8>,
+}
+
+impl Write for ProgressWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        while let Some(end) = self.line.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.line.drain(..=end).collect();
+            let text = String::from_utf8_lossy(&line[..end]);
+            if self.bar.is_hidden() || self.bar.is_finished() {
+                eprintln!("{}", text);
+            } else {
+                self.bar.println(text);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parsing::Target;
+
+    #[test]
+    fn progress_counts() {
+        let target = Target::Address("192.0.2.1:3389".parse().unwrap());
+        let targets = InputLists {
+            rdp_targets: vec![target.clone(), target.clone()],
+            web_targets: Vec::new(),
+            vnc_targets: vec![target],
+            ..Default::default()
+        };
+        let mut progress = Progress::new();
+        progress.start(&targets);
+        progress.complete(Mode::Rdp);
+        progress.complete(Mode::Vnc);
+        assert_eq!(progress.counts, [(1, 2), (0, 0), (1, 1)]);
+        assert_eq!(progress.bar.position(), 2);
+        assert_eq!(progress.bar.length(), 3);
+
+        progress.add(Mode::Web);
+        assert_eq!(progress.counts, [(1, 2), (0, 1), (1, 1)]);
+        assert_eq!(progress.bar.length(), 4);
+        progress.finish();
+    }
+}
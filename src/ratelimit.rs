@@ -0,0 +1,258 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Pace captures across every worker thread for --rate-limit, and
+//! against each host for --per-host-rate
+
+use crate::parsing::Target;
+use crate::util::resolve;
+use rand::Rng;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Longest a thread sleeps before checking whether the scan has been
+/// interrupted
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Hands out evenly spaced start times to the threads that ask for them,
+/// no matter how many threads there are
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Time between captures, or None if there is no limit
+    interval: Option<Duration>,
+    /// How much each interval may vary by, as a fraction of it
+    jitter: f64,
+    next: Mutex<Instant>,
+}
+
+/// Time between captures for a rate given in captures a second
+pub fn interval(per_second: Option<f64>) -> Option<Duration> {
+    per_second.map(|r| Duration::from_secs_f64(1.0 / r))
+}
+
+impl RateLimiter {
+    pub fn new(per_second: Option<f64>, jitter: f64) -> Self {
+        Self {
+            interval: interval(per_second),
+            jitter,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Reserve the next start time, returning how long to wait for it
+    fn reserve(&self) -> Duration {
+        let interval = match self.interval {
+            Some(i) => i,
+            None => return Duration::from_secs(0),
+        };
+        let mut next = self.next.lock().unwrap();
+        let now = Instant::now();
+        let start = (*next).max(now);
+        let factor = if self.jitter > 0.0 {
+            1.0 + rand::thread_rng().gen_range(-self.jitter..=self.jitter)
+        } else {
+            1.0
+        };
+        *next = start + interval.mul_f64(factor);
+        start - now
+    }
+
+    /// Block until this thread may start its next capture. Returns early
+    /// if `stop` gets set.
+    pub fn wait(&self, stop: &AtomicBool) {
+        pause(self.reserve(), stop);
+    }
+
+    /// Wait for the next start time like `wait`, without holding up the
+    /// thread meanwhile
+    pub async fn wait_async(&self, stop: &AtomicBool) {
+        pause_async(self.reserve(), stop).await;
+    }
+}
+
+/// Sleep for the given time, returning early if `stop` gets set
+pub fn pause(mut remaining: Duration, stop: &AtomicBool) {
+    while remaining > Duration::from_secs(0) && !stop.load(Ordering::SeqCst) {
+        let sleep = remaining.min(POLL_INTERVAL);
+        thread::sleep(sleep);
+        remaining -= sleep;
+    }
+}
+
+/// Wait for the given time like `pause`, without holding up the thread
+pub async fn pause_async(mut remaining: Duration, stop: &AtomicBool) {
+    while remaining > Duration::from_secs(0) && !stop.load(Ordering::SeqCst) {
+        let sleep = remaining.min(POLL_INTERVAL);
+        tokio::time::sleep(sleep).await;
+        remaining -= sleep;
+    }
+}
+
+/// Targets waiting to be captured, handed out so that no single host
+/// gets more than --per-host-rate captures a second. Rather than holding
+/// up the queue, targets on other hosts are handed out in the meantime.
+#[derive(Debug)]
+pub struct HostQueue {
+    /// The targets on each host along with their place in the queue, in
+    /// the order that they were given
+    hosts: HashMap<String, VecDeque<(usize, Target)>>,
+    /// Hosts that have targets left, by the earliest time that each may
+    /// be captured again and then by the place of its next target
+    ready: BinaryHeap<Reverse<(Instant, usize, String)>>,
+    interval: Option<Duration>,
+}
+
+impl HostQueue {
+    /// Queue up the targets. Hostnames are resolved so that names for the
+    /// same server share a limit, unless a proxy will be resolving them.
+    pub fn new(
+        targets: &[Target],
+        per_second: Option<f64>,
+        proxy: &Option<String>,
+    ) -> Self {
+        let mut hosts: HashMap<String, VecDeque<_>> = HashMap::new();
+        for (place, target) in targets.iter().enumerate() {
+            let host = match target {
+                // Without a limit every target shares the one queue so
+                // that they are handed out in order
+                _ if per_second.is_none() => String::new(),
+                Target::Address(addr) => addr.ip().to_string(),
+                Target::Url(url) => {
+                    let resolved = match proxy {
+                        None => resolve(url).ok(),
+                        Some(_) => None,
+                    };
+                    match resolved.as_ref().and_then(|a| a.first()) {
+                        Some(addr) => addr.ip().to_string(),
+                        None => url.host_str().unwrap_or_default().to_string(),
+                    }
+                }
+            };
+            hosts
+                .entry(host)
+                .or_default()
+                .push_back((place, target.clone()));
+        }
+
+        let now = Instant::now();
+        let ready = hosts
+            .iter()
+            .filter_map(|(host, queue)| {
+                let (place, _) = queue.front()?;
+                Some(Reverse((now, *place, host.clone())))
+            })
+            .collect();
+        Self {
+            hosts,
+            ready,
+            interval: interval(per_second),
+        }
+    }
+
+    /// Take the target that can be captured soonest, along with how long
+    /// to wait before capturing it. Of the hosts that are ready now, the
+    /// one that has been ready the longest goes first.
+    pub fn pop(&mut self) -> Option<(Target, Duration)> {
+        let Reverse((ready, _, host)) = self.ready.pop()?;
+        let queue = self.hosts.get_mut(&host)?;
+        let (_, target) = queue.pop_front()?;
+        let now = Instant::now();
+        let start = ready.max(now);
+        match queue.front() {
+            Some((place, _)) => {
+                let next = start + self.interval.unwrap_or_default();
+                self.ready.push(Reverse((next, *place, host)));
+            }
+            None => {
+                self.hosts.remove(&host);
+            }
+        }
+        Some((target, start - now))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn evenly_spaced() {
+        let limiter = RateLimiter::new(Some(10.0), 0.0);
+        assert_eq!(limiter.reserve(), Duration::from_secs(0));
+        let second = limiter.reserve();
+        let third = limiter.reserve();
+        assert!(second > Duration::from_millis(90), "{:?}", second);
+        assert!(second <= Duration::from_millis(100), "{:?}", second);
+        assert!(third > Duration::from_millis(190), "{:?}", third);
+        assert!(third <= Duration::from_millis(200), "{:?}", third);
+
+        let unlimited = RateLimiter::new(None, 0.5);
+        for _ in 0..10 {
+            assert_eq!(unlimited.reserve(), Duration::from_secs(0));
+        }
+    }
+
+    #[test]
+    fn per_host_spacing() {
+        let target = |t: &str| Target::Address(t.parse().unwrap());
+        let targets = vec![
+            target("192.0.2.1:3389"),
+            target("192.0.2.1:3390"),
+            target("192.0.2.2:3389"),
+            target("192.0.2.1:3391"),
+        ];
+
+        let mut queue = HostQueue::new(&targets, Some(1.0), &None);
+        let mut pop = || {
+            let (target, wait) = queue.pop().unwrap();
+            (target.to_string(), wait.as_millis() > 900)
+        };
+        // The second host goes ahead rather than waiting behind the first
+        assert_eq!(pop(), ("192.0.2.1:3389".to_string(), false));
+        assert_eq!(pop(), ("192.0.2.2:3389".to_string(), false));
+        assert_eq!(pop(), ("192.0.2.1:3390".to_string(), true));
+        assert!(queue.pop().unwrap().1 > Duration::from_millis(1900));
+        assert!(queue.pop().is_none());
+
+        // Without a limit the targets come out in order straight away
+        let mut queue = HostQueue::new(&targets, None, &None);
+        for t in &targets {
+            assert_eq!(queue.pop(), Some((t.clone(), Duration::from_secs(0))));
+        }
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn jitter_stays_in_bounds() {
+        let limiter = RateLimiter::new(Some(1.0), 0.5);
+        limiter.reserve();
+        let mut previous = limiter.reserve();
+        for _ in 0..20 {
+            let wait = limiter.reserve();
+            let gap = wait - previous;
+            assert!(gap >= Duration::from_millis(499), "{:?}", gap);
+            assert!(gap <= Duration::from_millis(1501), "{:?}", gap);
+            previous = wait;
+        }
+    }
+}
@@ -20,11 +20,15 @@
 use crate::argparse::Mode::Rdp;
 use crate::argparse::Opts;
 use crate::error::Error;
+use crate::ocr::save_text;
 use crate::parsing::Target;
+use crate::pool::Limits;
 use crate::reporting::ReportMessageContent;
-use crate::reporting::{FileError, ReportMessage};
-use crate::util::target_to_filename;
-use crate::ThreadStatus;
+use crate::reporting::{write_sidecar, FileError, ReportMessage};
+use crate::util::{
+    capture_path, check_blank, connect_tcp, existing_capture, precheck,
+    save_capture, save_thumbnail, with_retries,
+};
 use image::{DynamicImage, ImageBuffer, Rgba};
 #[allow(unused)]
 use log::{debug, error, info, trace, warn};
@@ -34,16 +38,17 @@ use rdp::core::event::RdpEvent;
 use socks::Socks5Stream;
 use std::io::Read;
 use std::io::Write;
-use std::net::TcpStream;
+use std::net::{SocketAddr, TcpStream};
 use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{mpsc, mpsc::Receiver, mpsc::Sender};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
-//TODO maybe make this configurable
-const IMAGE_WIDTH: u16 = 1280;
-const IMAGE_HEIGHT: u16 = 1024;
+pub mod security;
+
+/// Desktop size to fall back to if the server rejects the one requested
+const DEFAULT_RESOLUTION: (u16, u16) = (1280, 1024);
 
 struct BitmapChunk {
     width: u32,
@@ -149,10 +154,9 @@ impl Image {
     fn initialise_buffer(&mut self, chunk: &BitmapChunk) -> Result<(), ()> {
         use ImageMode::*;
         debug!("BITS PER PIXEL: {}", chunk.bpp);
-        //TODO get these values properly
-        // IMAGE_WIDTH and IMAGE_HEIGHT are u16
-        let width = IMAGE_WIDTH as u32;
-        let height = IMAGE_HEIGHT as u32;
+        // The dimensions are those negotiated when connecting
+        let width = self.width.unwrap_or(DEFAULT_RESOLUTION.0 as u32);
+        let height = self.height.unwrap_or(DEFAULT_RESOLUTION.1 as u32);
 
         let pixel_size = 4; //chunk.data.len() as u32
                             // / ((chunk.right - chunk.left) * (chunk.bottom - chunk.top));
@@ -231,11 +235,74 @@ impl Write for SocketType {
     }
 }
 
-fn capture_worker(
-    target: &Target,
+/// Open a connection to the address, through the proxy if there is one
+fn open_stream(addr: &SocketAddr, opts: &Opts) -> Result<SocketType, Error> {
+    // If the proxy configuration is selected then create a Socks5
+    // connection, otherwise create a regular TCP stream. The wrapper
+    // enum is used to get around type errors and the limitation that
+    // trait objects can only have one main trait (i.e. "dyn Read +
+    // Write") is not possible.
+    //
+    // Read and write timeouts are set on the underlying socket so that
+    // an unresponsive server cannot hold up the worker indefinitely. The
+    // socket is closed once the bitmap thread drops the client.
+    let connect_timeout = Duration::from_secs(opts.rdp_connect_timeout);
+    let stream = if let Some(proxy) = &opts.rdp_proxy {
+        debug!("Connecting to Socks proxy");
+        let stream = Socks5Stream::connect(proxy, *addr)?;
+        stream.get_ref().set_read_timeout(Some(connect_timeout))?;
+        stream.get_ref().set_write_timeout(Some(connect_timeout))?;
+        SocketType::Socks5(stream)
+    } else {
+        let stream =
+            connect_tcp(addr, Some(connect_timeout), opts.source_addr)?;
+        stream.set_read_timeout(Some(connect_timeout))?;
+        stream.set_write_timeout(Some(connect_timeout))?;
+        SocketType::Tcp(stream)
+    };
+    Ok(stream)
+}
+
+/// Open an RDP session to the address, requesting a desktop of the given
+/// size
+fn connect(
+    addr: &SocketAddr,
     opts: &Opts,
-    report_tx: &mpsc::Sender<ReportMessage>,
-) -> Result<(), Error> {
+    (width, height): (u16, u16),
+) -> Result<RdpClient<SocketType>, Error> {
+    let stream = open_stream(addr, opts)?;
+
+    // With no credentials the connection stops at the login screen,
+    // otherwise authenticate with NLA to capture the desktop
+    let client = if let Some(user) = &opts.rdp_user {
+        debug!("Authenticating as {}", user);
+        let mut connector = Connector::new()
+            .screen(width, height)
+            .use_nla(true)
+            .check_certificate(false)
+            .blank_creds(false)
+            .credentials(
+                opts.rdp_domain.clone().unwrap_or_default(),
+                user.clone(),
+                opts.rdp_pass.clone().unwrap_or_default(),
+            );
+        connector.connect(stream)?
+    } else {
+        let mut connector = Connector::new()
+            .screen(width, height)
+            .use_nla(false)
+            .check_certificate(false)
+            .blank_creds(true)
+            .credentials("".to_string(), "".to_string(), "".to_string());
+        connector.connect(stream)?
+    };
+
+    Ok(client)
+}
+
+/// Capture the target, returning the path of the saved image relative to
+/// the output directory
+fn capture_worker(target: &Target, opts: &Opts) -> Result<String, Error> {
     info!("Connecting to {:?}", target);
     let addr = match target {
         Target::Address(sock_addr) => sock_addr,
@@ -247,27 +314,38 @@ fn capture_worker(
         }
     };
 
-    // If the proxy configuration is selected then create a Socks5
-    // connection, otherwise create a regular TCP stream. The wrapper
-    // enum is used to get around type errors and the limitation that
-    // trait objects can only have one main trait (i.e. "dyn Read +
-    // Write") is not possible.
-    let stream = if let Some(proxy) = &opts.rdp_proxy {
-        debug!("Connecting to Socks proxy");
-        SocketType::Socks5(Socks5Stream::connect(proxy, *addr)?)
-    } else {
-        SocketType::Tcp(TcpStream::connect(&addr)?)
+    // Some servers refuse desktop sizes they don't like, so try again
+    // with the default before giving up, whether or not we log in
+    let mut resolution = opts.rdp_resolution;
+    let client = match connect(addr, opts, resolution) {
+        Err(Error::RdpError(e)) if resolution != DEFAULT_RESOLUTION => {
+            warn!(
+                "{} rejected {}x{} ({}), retrying at {}x{}",
+                addr,
+                resolution.0,
+                resolution.1,
+                e,
+                DEFAULT_RESOLUTION.0,
+                DEFAULT_RESOLUTION.1
+            );
+            resolution = DEFAULT_RESOLUTION;
+            connect(addr, opts, resolution)
+        }
+        result => result,
+    };
+    // With credentials, IO errors such as timeouts are kept as they are,
+    // anything else going wrong during the handshake is treated as a
+    // login failure
+    let client = client.map_err(|e| match e {
+        Error::RdpError(e) if opts.rdp_user.is_some() => Error::AuthError(e),
+        e => e,
+    })?;
+
+    let mut rdp_image = Image {
+        width: Some(resolution.0 as u32),
+        height: Some(resolution.1 as u32),
+        ..Default::default()
     };
-
-    let mut connector = Connector::new()
-        .screen(IMAGE_WIDTH, IMAGE_HEIGHT)
-        .use_nla(false)
-        .check_certificate(false)
-        .blank_creds(true)
-        .credentials("".to_string(), "".to_string(), "".to_string());
-    let client = connector.connect(stream)?;
-
-    let mut rdp_image: Image = Default::default();
     {
         // Spawn a thread to listen for bitmap events
         let (bmp_sender, bmp_receiver): (Sender<BitmapChunk>, Receiver<_>) =
@@ -276,9 +354,27 @@ fn capture_worker(
             bmp_thread(client, bmp_sender);
         });
 
-        let timeout = Duration::from_secs(2);
+        // The wait for the screen to settle restarts with every bitmap, so
+        // a server that never stops sending them is given up on once it
+        // has had --rdp-connect-timeout on top of that
+        let timeout = Duration::from_secs(opts.rdp_timeout as u64);
+        let deadline = Instant::now()
+            + timeout
+            + Duration::from_secs(opts.rdp_connect_timeout);
         loop {
-            match bmp_receiver.recv_timeout(timeout) {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::TimeoutError(format!(
+                    "{} did not stop sending bitmaps",
+                    addr
+                )));
+            }
+            match bmp_receiver.recv_timeout(timeout.min(deadline - now)) {
+                Err(RecvTimeoutError::Timeout)
+                    if Instant::now() >= deadline =>
+                {
+                    continue
+                }
                 Err(_) => {
                     warn!("Timeout reached");
                     break;
@@ -295,33 +391,27 @@ fn capture_worker(
     match rdp_image.image {
         Some(di) => {
             info!("Successfully received image");
-            let filename = format!("{}.png", target_to_filename(&target));
-            let relative_filepath = Path::new("rdp").join(&filename);
+            let relative_filepath = capture_path(&target, "rdp", opts);
             let filepath = Path::new(&opts.output_dir).join(&relative_filepath);
             info!("Saving image as {}", filepath.display());
-            di.extract().save(&filepath)?;
-            let report_message = ReportMessage::Output(ReportMessageContent {
-                mode: Rdp,
-                target: target.to_string(),
-                output: FileError::File(
-                    relative_filepath.display().to_string(),
-                ),
-            });
-            report_tx.send(report_message)?;
+            let image = di.extract();
+            check_blank(&image, opts)?;
+            save_capture(&image, &filepath, opts)?;
+            save_thumbnail(&filepath, opts);
+            save_text(&filepath, opts);
+            Ok(relative_filepath.display().to_string())
         }
         None => {
             warn!(
             "Error receiving image from {}. Perhaps the server disconnected",
             addr
             );
-            return Err(Error::RdpError(
+            Err(Error::RdpError(
                 "Error receiving image, perhaps the server disconnected"
                     .to_string(),
-            ));
+            ))
         }
     }
-
-    Ok(())
 }
 
 fn bmp_thread<T: Read + Write>(
@@ -387,35 +477,79 @@ fn bmp_thread<T: Read + Write>(
 pub fn capture(
     target: &Target,
     opts: &Opts,
-    tx: mpsc::Sender<ThreadStatus>,
     report_tx: &mpsc::Sender<ReportMessage>,
+    limits: &Limits,
 ) {
-    if let Err(e) = capture_worker(target, opts, report_tx) {
-        warn!("error: {}", e);
-        let report_message = match &e {
-            Error::RdpError(r) if r.contains("failed to fill whole buffer") => {
-                ReportMessage::Output(ReportMessageContent {
-                    mode: Rdp,
-                    target: target.to_string(),
-                    output: FileError::Error(
+    let start = SystemTime::now();
+    let mut address = None;
+    let existing = existing_capture(target, "rdp", opts);
+    let (result, attempts) = match &existing {
+        Some(file) => (Ok(file.clone()), 0),
+        None => with_retries(opts, || {
+            address = precheck(target, opts, &opts.rdp_proxy)?.map(|a| a.ip());
+            capture_worker(target, opts)
+        }),
+    };
+    let output = match result {
+        Ok(file) => FileError::File(file),
+        Err(e) => {
+            match &e {
+                // Nothing is listening, which is not worth a warning
+                Error::UnreachableError(_)
+                | Error::ConnectionRefused(_)
+                | Error::DnsError(_) => info!("Skipping {}: {}", target, e),
+                _ => warn!("error: {}", e),
+            }
+            match e {
+                Error::RdpError(r)
+                    if r.contains("failed to fill whole buffer") =>
+                {
+                    FileError::Error(Error::RdpError(
                         concat!(
                             "Unexpected disconnection, target may be XP-era ",
                             "which is currently unsupported"
                         )
                         .to_string(),
-                    ),
-                })
+                    ))
+                }
+                e => FileError::Error(e),
             }
-            _ => ReportMessage::Output(ReportMessageContent {
-                mode: Rdp,
-                target: target.to_string(),
-                output: FileError::Error(e.to_string()),
-            }),
-        };
-        report_tx
-            .send(report_message)
-            .expect("Reporting thread seems to have disconnected");
-    }
-
-    tx.send(ThreadStatus::Complete).unwrap();
+        }
+    };
+    // The security checks need connections of their own, which are made
+    // whenever something is listening, so that servers that refused the
+    // capture are covered too. Servers that timed out are left alone as
+    // they would only hold up the worker again. Not getting anything
+    // doesn't fail the capture, it is just recorded as null.
+    let listening = !matches!(
+        &output,
+        FileError::Error(Error::UnreachableError(_))
+            | FileError::Error(Error::ConnectionRefused(_))
+            | FileError::Error(Error::DnsError(_))
+            | FileError::Error(Error::TimeoutError(_))
+    );
+    let (security, certificate) = match target {
+        Target::Address(addr)
+            if listening && existing.is_none() && !opts.no_rdp_security =>
+        {
+            match security::examine(addr, opts, limits) {
+                Ok((security, certificate)) => (Some(security), certificate),
+                Err(e) => {
+                    debug!("Unable to examine security of {}: {}", target, e);
+                    (None, None)
+                }
+            }
+        }
+        _ => (None, None),
+    };
+    let content = ReportMessageContent::new(
+        Rdp, target, start, output, attempts, address,
+    )
+    .with_security(security, certificate)
+    .with_skipped(existing.is_some());
+    write_sidecar(&content, opts);
+    let report_message = ReportMessage::Output(content);
+    report_tx
+        .send(report_message)
+        .expect("Reporting thread seems to have disconnected");
 }
@@ -0,0 +1,438 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Find out how an RDP server is secured, by negotiating with it the way
+//! a client would at the start of a connection. Offering it different
+//! sets of security protocols shows which one it prefers, whether it
+//! insists on NLA, and whether it still allows standard RDP security,
+//! which shows the login screen to anyone and has weak encryption.
+//!
+//! The server's certificate is picked up along the way. It is usually
+//! self-signed and issued to the machine's own name, which is often the
+//! only way to find out what an RDP-only host is called. The RDP client
+//! doesn't expose any of this, so separate connections are made that go
+//! no further than the TLS handshake. Each of them is paced like a capture
+//! of its own, and --no-rdp-security leaves them out altogether.
+
+use super::{open_stream, SocketType};
+use crate::argparse::Opts;
+use crate::error::Error;
+use crate::pool::Limits;
+use crate::ratelimit::{interval, pause};
+use crate::tls::{describe_certificate, CertInfo};
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use native_tls::{HandshakeError, TlsConnector};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+
+/// Security protocols, as the flags used in negotiation requests
+const PROTOCOL_RDP: u32 = 0x00;
+const PROTOCOL_SSL: u32 = 0x01;
+const PROTOCOL_HYBRID: u32 = 0x02;
+const PROTOCOL_HYBRID_EX: u32 = 0x08;
+
+const TYPE_NEGOTIATION_RESPONSE: u8 = 0x02;
+const TYPE_NEGOTIATION_FAILURE: u8 = 0x03;
+
+/// Failure code of a server that only accepts NLA
+const HYBRID_REQUIRED_BY_SERVER: u32 = 0x05;
+
+/// Security protocol that a server chose
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    /// Standard RDP security
+    Rdp,
+    Tls,
+    /// CredSSP, which is what NLA is built on
+    Hybrid,
+    /// CredSSP with early user authorisation
+    HybridEx,
+}
+
+impl Protocol {
+    fn from_flag(flag: u32) -> Option<Self> {
+        match flag {
+            PROTOCOL_RDP => Some(Self::Rdp),
+            PROTOCOL_SSL => Some(Self::Tls),
+            PROTOCOL_HYBRID => Some(Self::Hybrid),
+            PROTOCOL_HYBRID_EX => Some(Self::HybridEx),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::Rdp => "Standard RDP security",
+            Self::Tls => "TLS",
+            Self::Hybrid => "CredSSP",
+            Self::HybridEx => "CredSSP with early user authorisation",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Security posture of an RDP server
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct RdpSecurity {
+    /// Protocol that the server chose when offered all of them
+    pub protocol: Option<Protocol>,
+    /// Whether the server insists on NLA, so that users have to log in
+    /// before they see anything
+    pub nla_required: bool,
+    /// Whether the server still allows standard RDP security
+    pub standard_security: bool,
+    /// Why the server refused to negotiate when offered every protocol
+    pub refusal: Option<String>,
+}
+
+impl RdpSecurity {
+    /// Summarise the posture for the report
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(protocol) = self.protocol {
+            parts.push(protocol.to_string());
+        }
+        if let Some(refusal) = &self.refusal {
+            parts.push(format!("refused: {}", refusal));
+        }
+        parts.push(
+            if self.nla_required {
+                "NLA required"
+            } else {
+                "NLA not required"
+            }
+            .to_string(),
+        );
+        if self.standard_security && self.protocol != Some(Protocol::Rdp) {
+            parts.push("standard RDP security allowed".to_string());
+        }
+        parts.join(", ")
+    }
+}
+
+/// Outcome of a negotiation request
+#[derive(Debug, PartialEq)]
+enum Negotiation {
+    Selected(u32),
+    Failed(u32),
+}
+
+/// X.224 connection request offering the given security protocols
+fn connection_request(protocols: u32) -> Vec<u8> {
+    let mut request = vec![
+        // TPKT header
+        0x03, 0x00, 0x00, 0x13, //
+        // X.224 connection request
+        0x0e, 0xe0, 0x00, 0x00, 0x00, 0x00, 0x00, //
+        // RDP negotiation request
+        0x01, 0x00, 0x08, 0x00,
+    ];
+    request.extend_from_slice(&protocols.to_le_bytes());
+    request
+}
+
+/// Read the outcome of negotiation from the server's X.224 connection
+/// confirm, without the TPKT header. Servers that only support standard
+/// RDP security may not send a negotiation response at all.
+fn negotiation(confirm: &[u8]) -> Negotiation {
+    let (kind, value) = match confirm.get(7..15) {
+        Some(n) => {
+            let mut value = [0; 4];
+            value.copy_from_slice(&n[4..8]);
+            (n[0], u32::from_le_bytes(value))
+        }
+        None => return Negotiation::Selected(PROTOCOL_RDP),
+    };
+    match kind {
+        TYPE_NEGOTIATION_FAILURE => Negotiation::Failed(value),
+        TYPE_NEGOTIATION_RESPONSE => Negotiation::Selected(value),
+        _ => Negotiation::Selected(PROTOCOL_RDP),
+    }
+}
+
+fn failure_reason(code: u32) -> String {
+    match code {
+        0x01 => "TLS is required",
+        0x02 => "TLS is not allowed",
+        0x03 => "the server has no certificate",
+        0x04 => "inconsistent request",
+        HYBRID_REQUIRED_BY_SERVER => "NLA is required",
+        0x06 => "TLS with user authentication is required",
+        _ => return format!("unknown negotiation failure {}", code),
+    }
+    .to_string()
+}
+
+/// Read the X.224 connection confirm that follows the request
+fn read_confirm<S: Read>(stream: &mut S) -> Result<Vec<u8>, Error> {
+    let mut header = [0; 4];
+    stream.read_exact(&mut header)?;
+    let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+    if header[0] != 0x03 || length < header.len() {
+        return Err(Error::RdpError(
+            "Invalid response to connection request".to_string(),
+        ));
+    }
+    let mut confirm = vec![0; length - header.len()];
+    stream.read_exact(&mut confirm)?;
+    Ok(confirm)
+}
+
+/// Wait until another connection may be made to the server without going
+/// over --rate-limit, or over --per-host-rate given that the server has
+/// only just been connected to
+fn pace(opts: &Opts, limits: &Limits) -> Result<(), Error> {
+    let stop = &limits.caught_ctrl_c;
+    if let Some(interval) = interval(opts.per_host_rate) {
+        pause(interval, stop);
+    }
+    limits.rate_limiter.wait(stop);
+    if stop.load(Ordering::SeqCst) {
+        return Err(Error::TimeoutError("Scan interrupted".to_string()));
+    }
+    Ok(())
+}
+
+/// Open a connection offering the given protocols, returning it along
+/// with what the server made of them
+fn negotiate(
+    addr: &SocketAddr,
+    opts: &Opts,
+    limits: &Limits,
+    protocols: u32,
+) -> Result<(SocketType, Negotiation), Error> {
+    pace(opts, limits)?;
+    let mut stream = open_stream(addr, opts)?;
+    stream.write_all(&connection_request(protocols))?;
+    let confirm = read_confirm(&mut stream)?;
+    Ok((stream, negotiation(&confirm)))
+}
+
+/// Complete a TLS handshake over a connection that has negotiated TLS,
+/// and return the details of the certificate that the server presents
+fn handshake(
+    addr: &SocketAddr,
+    stream: SocketType,
+) -> Result<Option<CertInfo>, Error> {
+    // The certificate is recorded rather than checked, so anything goes
+    let connector = TlsConnector::builder()
+        .danger_accept_invalid_certs(true)
+        .danger_accept_invalid_hostnames(true)
+        .use_sni(false)
+        .build()
+        .map_err(|e| Error::TlsError(e.to_string()))?;
+    let tls = match connector.connect(&addr.ip().to_string(), stream) {
+        Ok(tls) => tls,
+        Err(HandshakeError::Failure(e)) => {
+            return Err(Error::TlsError(e.to_string()))
+        }
+        Err(HandshakeError::WouldBlock(_)) => {
+            return Err(Error::TlsError("TLS handshake timed out".to_string()))
+        }
+    };
+    let der = tls
+        .peer_certificate()
+        .and_then(|c| c.map(|c| c.to_der()).transpose())
+        .map_err(|e| Error::TlsError(e.to_string()))?;
+    der.map(|der| describe_certificate(&der)).transpose()
+}
+
+/// Work out the security posture of the server, and fetch its
+/// certificate if it has one
+pub fn examine(
+    addr: &SocketAddr,
+    opts: &Opts,
+    limits: &Limits,
+) -> Result<(RdpSecurity, Option<CertInfo>), Error> {
+    let mut security = RdpSecurity::default();
+    let (stream, offered) = negotiate(
+        addr,
+        opts,
+        limits,
+        PROTOCOL_SSL | PROTOCOL_HYBRID | PROTOCOL_HYBRID_EX,
+    )?;
+    let certificate = match offered {
+        Negotiation::Selected(flag) => {
+            security.protocol = Protocol::from_flag(flag);
+            if flag == PROTOCOL_RDP {
+                None
+            } else {
+                handshake(addr, stream).unwrap_or_else(|e| {
+                    debug!("Unable to fetch certificate of {}: {}", addr, e);
+                    None
+                })
+            }
+        }
+        Negotiation::Failed(code) => {
+            security.refusal = Some(failure_reason(code));
+            None
+        }
+    };
+
+    // A server that accepts nothing but standard RDP security can't be
+    // requiring NLA, otherwise see whether it will settle for plain TLS
+    security.standard_security = negotiate(addr, opts, limits, PROTOCOL_RDP)?.1
+        == Negotiation::Selected(PROTOCOL_RDP);
+    security.nla_required = !security.standard_security
+        && negotiate(addr, opts, limits, PROTOCOL_SSL)?.1
+            == Negotiation::Failed(HYBRID_REQUIRED_BY_SERVER);
+    Ok((security, certificate))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ratelimit::RateLimiter;
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tokio::sync::Semaphore;
+
+    fn limits(per_second: Option<f64>) -> Limits {
+        Limits {
+            threads: Semaphore::new(1),
+            rate_limiter: RateLimiter::new(per_second, 0.0),
+            caught_ctrl_c: Arc::new(AtomicBool::new(false)),
+            deadline: None,
+        }
+    }
+
+    /// Connection confirm carrying a negotiation response or failure
+    fn confirm(kind: u8, value: u32) -> Vec<u8> {
+        let mut confirm = vec![
+            0x03, 0x00, 0x00, 0x13, 0x0e, 0xd0, 0x00, 0x00, 0x12, 0x34, 0x00,
+            kind, 0x00, 0x08, 0x00,
+        ];
+        confirm.extend_from_slice(&value.to_le_bytes());
+        confirm
+    }
+
+    /// Answer each connection with the next response, returning the
+    /// protocols that each request offered
+    fn serve(
+        responses: Vec<Vec<u8>>,
+    ) -> (SocketAddr, thread::JoinHandle<Vec<u32>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            responses
+                .iter()
+                .map(|response| {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let mut request = vec![0; 19];
+                    stream.read_exact(&mut request).unwrap();
+                    stream.write_all(response).unwrap();
+                    let mut protocols = [0; 4];
+                    protocols.copy_from_slice(&request[15..]);
+                    u32::from_le_bytes(protocols)
+                })
+                .collect()
+        });
+        (addr, server)
+    }
+
+    #[test]
+    fn negotiated_protocol() {
+        let old = [0x06, 0xd0, 0x00, 0x00, 0x12, 0x34, 0x00];
+        assert_eq!(
+            negotiation(&confirm(TYPE_NEGOTIATION_RESPONSE, 2)[4..]),
+            Negotiation::Selected(PROTOCOL_HYBRID)
+        );
+        assert_eq!(
+            negotiation(&confirm(TYPE_NEGOTIATION_FAILURE, 5)[4..]),
+            Negotiation::Failed(HYBRID_REQUIRED_BY_SERVER)
+        );
+        assert_eq!(negotiation(&old), Negotiation::Selected(PROTOCOL_RDP));
+        assert_eq!(connection_request(PROTOCOL_SSL)[15..], [0x01, 0, 0, 0]);
+    }
+
+    #[test]
+    fn security_posture() {
+        let opts = Opts {
+            rdp_connect_timeout: 5,
+            ..Default::default()
+        };
+        let unlimited = limits(None);
+
+        // A modern server that insists on NLA, whose TLS handshake fails
+        // as the test server hangs up
+        let (addr, server) = serve(vec![
+            confirm(TYPE_NEGOTIATION_RESPONSE, PROTOCOL_HYBRID),
+            confirm(TYPE_NEGOTIATION_FAILURE, HYBRID_REQUIRED_BY_SERVER),
+            confirm(TYPE_NEGOTIATION_FAILURE, HYBRID_REQUIRED_BY_SERVER),
+        ]);
+        let (security, certificate) =
+            examine(&addr, &opts, &unlimited).unwrap();
+        assert_eq!(
+            security,
+            RdpSecurity {
+                protocol: Some(Protocol::Hybrid),
+                nla_required: true,
+                standard_security: false,
+                refusal: None,
+            }
+        );
+        assert_eq!(security.describe(), "CredSSP, NLA required");
+        assert_eq!(certificate, None);
+        assert_eq!(
+            server.join().unwrap(),
+            vec![0x0b, PROTOCOL_RDP, PROTOCOL_SSL]
+        );
+
+        // A legacy server that doesn't negotiate at all
+        let old = vec![0x03, 0x00, 0x00, 0x0b, 0x06, 0xd0, 0, 0, 0x12, 0x34, 0];
+        let (addr, server) = serve(vec![old.clone(), old.clone()]);
+        let (security, _) = examine(&addr, &opts, &unlimited).unwrap();
+        assert_eq!(
+            security,
+            RdpSecurity {
+                protocol: Some(Protocol::Rdp),
+                nla_required: false,
+                standard_security: true,
+                refusal: None,
+            }
+        );
+        assert_eq!(
+            security.describe(),
+            "Standard RDP security, NLA not required"
+        );
+        assert_eq!(server.join().unwrap(), vec![0x0b, PROTOCOL_RDP]);
+
+        // Each connection waits for the rate limits, with the first one
+        // coming straight after the capture
+        let opts = Opts {
+            per_host_rate: Some(20.0),
+            ..opts
+        };
+        let (addr, server) = serve(vec![old.clone(), old]);
+        let started = Instant::now();
+        examine(&addr, &opts, &limits(Some(1000.0))).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        server.join().unwrap();
+    }
+}
@@ -1,13 +1,32 @@
 use crate::argparse::Mode;
 use crate::argparse::Opts;
+use crate::baseline::{Baseline, Comparison};
 use crate::error::Error;
-use crate::parsing::InputLists;
+use crate::ocr::{load_text, text_path};
+use crate::parsing::{InputLists, InvalidTarget, Target};
+use crate::progress::Progress;
+use crate::rdp::security::RdpSecurity;
+use crate::tags::add_matching;
+use crate::tls::CertInfo;
+use crate::util::{
+    atomic_write, dhash, is_blank, original_path, similar_hashes,
+    thumbnail_data_uri, thumbnail_path,
+};
+use crate::web::PageInfo;
+use crate::webhook;
+use crate::{EXIT_ALL_FAILED, EXIT_SOME_FAILED, EXIT_SUCCESS};
 
 use askama::Template;
-use std::fs;
-use std::path::Path;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use url::Url;
 
 #[allow(unused)]
 use log::{debug, error, info, trace, warn};
@@ -22,12 +41,98 @@ struct ReportTemplate {
     web_errors: Vec<ReportError>,
     vnc_outputs: Vec<ReportItem>,
     vnc_errors: Vec<ReportError>,
+    /// Targets captured in the baseline but not in this scan
+    missing: Vec<String>,
+    /// Targets that answered with --probe-only
+    reachable: Vec<String>,
+    /// Targets that --max-duration left no time for
+    not_attempted: Vec<String>,
+}
+
+impl ReportTemplate {
+    /// Every tag given to a capture, for filtering the report by
+    fn tags(&self) -> Vec<&str> {
+        let tags: BTreeSet<&str> = self
+            .rdp_outputs
+            .iter()
+            .chain(&self.web_outputs)
+            .chain(&self.vnc_outputs)
+            .flat_map(|o| o.tags.iter().map(String::as_str))
+            .collect();
+        tags.into_iter().collect()
+    }
 }
 
 #[derive(Debug)]
 struct ReportItem {
     pub target: String,
     pub file: String,
+    /// Image to show in the report grid, which is the thumbnail if one
+    /// was generated or the full capture otherwise
+    pub preview: String,
+    /// Other targets whose captures were identical to this one
+    pub duplicates: Vec<String>,
+    /// Page title and server header for web captures, empty otherwise
+    pub title: String,
+    pub server: String,
+    /// Whether the web page was served with a certificate that failed
+    /// verification
+    pub untrusted_cert: bool,
+    /// How the capture compares with the baseline, empty if it is
+    /// unchanged or there is no baseline
+    pub change: String,
+    /// Text recognised in the capture with --ocr
+    pub text: String,
+    /// Names of the --match patterns that the capture matched
+    pub tags: Vec<String>,
+    /// Name from the certificate of an RDP server
+    pub hostname: String,
+    /// Summary of an RDP server's security posture
+    pub security: String,
+}
+
+impl ReportItem {
+    fn new(
+        target: String,
+        file: String,
+        page: Option<PageInfo>,
+        opts: &Opts,
+    ) -> Self {
+        let thumbnail = thumbnail_path(Path::new(&file));
+        let preview = if opts.thumbnails
+            && Path::new(&opts.output_dir).join(&thumbnail).exists()
+        {
+            thumbnail.display().to_string()
+        } else {
+            file.clone()
+        };
+        // Embedded previews are still linked to the capture on disk
+        let preview = if opts.report_embed {
+            let path = Path::new(&opts.output_dir).join(&file);
+            thumbnail_data_uri(&path, opts.thumbnail_size).unwrap_or_else(|e| {
+                warn!("Unable to embed {}: {}", path.display(), e);
+                preview
+            })
+        } else {
+            preview
+        };
+        let page = page.unwrap_or_default();
+        let untrusted_cert = page.tls.as_ref().map_or(false, |t| !t.trusted);
+        Self {
+            target,
+            file,
+            preview,
+            duplicates: Vec::new(),
+            title: page.title.unwrap_or_default(),
+            server: page.server.unwrap_or_default(),
+            untrusted_cert,
+            change: String::new(),
+            text: String::new(),
+            tags: Vec::new(),
+            hostname: String::new(),
+            security: String::new(),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -39,6 +144,12 @@ struct ReportError {
 #[derive(Debug)]
 pub enum ReportMessage {
     Output(ReportMessageContent),
+    /// A target of the given type has been read with --stream, so that
+    /// the progress bar can count it
+    Queued(Mode),
+    /// Inputs that couldn't be parsed, sent once they have all been read
+    /// with --stream
+    Invalid(Vec<InvalidTarget>),
     GenerateReport,
 }
 
@@ -47,20 +158,443 @@ pub struct ReportMessageContent {
     pub mode: Mode,
     pub target: String,
     pub output: FileError,
+    /// Time at which the capture was started
+    pub timestamp: SystemTime,
+    /// How long the capture took
+    pub duration: Duration,
+    /// Number of times the capture was tried
+    pub attempts: u32,
+    /// IP address that the target resolved to, if known
+    pub address: Option<IpAddr>,
+    /// Details of the page for web captures
+    pub page: Option<PageInfo>,
+    /// Security posture of the server for RDP captures
+    pub security: Option<RdpSecurity>,
+    /// Certificate presented by the server for RDP captures
+    pub certificate: Option<CertInfo>,
+    /// Whether the capture was left over from an earlier scan
+    pub skipped: bool,
+}
+
+impl ReportMessageContent {
+    /// Construct the report content for a capture that was started at
+    /// `start`, measuring the duration up to now
+    pub fn new(
+        mode: Mode,
+        target: &Target,
+        start: SystemTime,
+        output: FileError,
+        attempts: u32,
+        address: Option<IpAddr>,
+    ) -> Self {
+        Self {
+            mode,
+            target: target.to_string(),
+            output,
+            timestamp: start,
+            duration: start.elapsed().unwrap_or_default(),
+            attempts,
+            address,
+            page: None,
+            security: None,
+            certificate: None,
+            skipped: false,
+        }
+    }
+
+    /// Attach the details gathered while capturing a web page
+    pub fn with_page(mut self, page: PageInfo) -> Self {
+        self.page = Some(page);
+        self
+    }
+
+    /// Attach the security posture of an RDP server and the certificate
+    /// that it presented, if it had one
+    pub fn with_security(
+        mut self,
+        security: Option<RdpSecurity>,
+        certificate: Option<CertInfo>,
+    ) -> Self {
+        self.security = security;
+        self.certificate = certificate;
+        self
+    }
+
+    /// Mark the capture as one kept from an earlier scan by
+    /// --skip-existing
+    pub fn with_skipped(mut self, skipped: bool) -> Self {
+        self.skipped = skipped;
+        self
+    }
 }
 
 /// Capture the output status as either a file or an error
 #[derive(Debug)]
 pub enum FileError {
     File(String),
-    Error(String),
+    Error(Error),
+    /// The target answered a --probe-only check, so there is no image
+    Reachable,
+    /// The scan reached --max-duration before the target was started
+    NotAttempted,
+}
+
+/// Entry in the machine-readable results manifest
+#[derive(Debug, Deserialize, Serialize)]
+struct CaptureResult {
+    target: String,
+    protocol: Mode,
+    /// IP address that was captured, which for hostnames is the one that
+    /// they resolved to
+    address: Option<IpAddr>,
+    file: Option<String>,
+    success: bool,
+    error: Option<String>,
+    error_category: Option<String>,
+    /// Perceptual hash of the image, in hex
+    hash: Option<String>,
+    /// Whether the image is close to a solid colour
+    blank: bool,
+    /// Target with an identical capture that this one was merged into
+    duplicate_of: Option<String>,
+    /// Comparison with the capture in the baseline, if --baseline was
+    /// given
+    baseline: Option<Comparison>,
+    /// Seconds since the unix epoch
+    timestamp: u64,
+    duration_ms: u64,
+    attempts: u32,
+    /// Whether the capture was kept from an earlier scan rather than
+    /// taken again
+    skipped: bool,
+    page: Option<PageInfo>,
+    /// How an RDP server is secured, which is recorded for failed
+    /// captures too as long as the server answered
+    #[serde(default)]
+    security: Option<RdpSecurity>,
+    /// Certificate presented by an RDP server, which usually names the
+    /// machine. Web certificates are recorded with the page.
+    #[serde(default)]
+    certificate: Option<CertInfo>,
+    /// Name that the RDP server's certificate was issued to
+    #[serde(default)]
+    hostname: Option<String>,
+    /// Text recognised in the capture, if --ocr was given
+    text: Option<String>,
+    /// Names of the --match patterns that the page source or text matched
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Whether the scan ran out of time before capturing the target
+    #[serde(default)]
+    not_attempted: bool,
+}
+
+impl From<&ReportMessageContent> for CaptureResult {
+    fn from(content: &ReportMessageContent) -> Self {
+        let (file, error, error_category) = match &content.output {
+            FileError::File(f) => (Some(f.clone()), None, None),
+            FileError::Error(e) => {
+                (None, Some(e.to_string()), Some(e.category().to_string()))
+            }
+            FileError::Reachable | FileError::NotAttempted => {
+                (None, None, None)
+            }
+        };
+        let not_attempted = matches!(content.output, FileError::NotAttempted);
+        Self {
+            target: content.target.clone(),
+            protocol: content.mode,
+            address: content.address,
+            success: error.is_none() && !not_attempted,
+            file,
+            error,
+            error_category,
+            hash: None,
+            blank: matches!(
+                content.output,
+                FileError::Error(Error::BlankError(_))
+            ),
+            duplicate_of: None,
+            baseline: None,
+            timestamp: content
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            duration_ms: content.duration.as_millis() as u64,
+            attempts: content.attempts,
+            skipped: content.skipped,
+            page: content.page.clone(),
+            security: content.security.clone(),
+            certificate: content.certificate.clone(),
+            hostname: content
+                .certificate
+                .as_ref()
+                .and_then(|c| c.hostname())
+                .map(|h| h.to_string()),
+            text: None,
+            tags: content
+                .page
+                .as_ref()
+                .map(|p| p.tags.clone())
+                .unwrap_or_default(),
+            not_attempted,
+        }
+    }
+}
+
+/// Size in bytes above which an embedded report is warned about, as it
+/// may be too big to email or to open comfortably in a browser
+const EMBEDDED_REPORT_WARNING: usize = 50 << 20;
+
+/// Columns of the --csv output
+const CSV_HEADER: &str =
+    "target,protocol,status,title,server,image,duration_ms";
+
+impl CaptureResult {
+    /// Describe the security posture of an RDP server for the report
+    fn security_summary(&self) -> String {
+        self.security
+            .as_ref()
+            .map(|s| s.describe())
+            .unwrap_or_default()
+    }
+
+    /// Load the text recognised in the capture if --ocr was given, and
+    /// tag the capture with the --match patterns that the text matches
+    fn add_text(&mut self, opts: &Opts) {
+        if let (true, Some(file)) = (opts.ocr, &self.file) {
+            self.text = load_text(&Path::new(&opts.output_dir).join(file));
+            if let Some(text) = &self.text {
+                add_matching(&mut self.tags, &opts.matches, text);
+            }
+        }
+    }
+
+    /// Row of the --csv output, with the columns in CSV_HEADER
+    fn csv_row(&self, opts: &Opts) -> String {
+        let status = match (self.success, &self.error_category) {
+            (true, _) if self.skipped => "skipped",
+            (true, _) if self.file.is_none() => "reachable",
+            (true, _) => "captured",
+            (false, Some(category)) => category.as_str(),
+            (false, None) if self.not_attempted => "not_attempted",
+            (false, None) => "error",
+        };
+        let page = self.page.as_ref();
+        let image = self
+            .file
+            .as_ref()
+            .map(|f| Path::new(&opts.output_dir).join(f).display().to_string());
+        [
+            self.target.clone(),
+            self.protocol.as_str().to_string(),
+            status.to_string(),
+            page.and_then(|p| p.title.clone()).unwrap_or_default(),
+            page.and_then(|p| p.server.clone()).unwrap_or_default(),
+            image.unwrap_or_default(),
+            self.duration_ms.to_string(),
+        ]
+        .iter()
+        .map(|f| csv_field(f))
+        .collect::<Vec<_>>()
+        .join(",")
+    }
+}
+
+/// Quote a CSV field if it has any characters that would break up the row.
+/// Page titles come from the targets, so fields that a spreadsheet would
+/// treat as a formula are also prefixed with a ' to keep them as text.
+fn csv_field(field: &str) -> String {
+    let field = if field.starts_with(&['=', '+', '-', '@'][..]) {
+        format!("'{}", field)
+    } else {
+        field.to_string()
+    };
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+/// Overall outcome of the scan, printed once it has finished
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Summary {
+    pub targets: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    /// Targets that were left out by --limit
+    pub truncated: usize,
+    /// Targets that were not started before --max-duration
+    pub not_attempted: usize,
+    /// Inputs that were skipped as they couldn't be parsed
+    pub invalid: usize,
+    /// Number of failures of each category
+    pub errors: BTreeMap<String, usize>,
+    pub elapsed_seconds: u64,
+}
+
+impl Summary {
+    fn new(
+        results: &[CaptureResult],
+        truncated: usize,
+        elapsed: Duration,
+    ) -> Self {
+        let mut summary = Self {
+            targets: results.len(),
+            truncated,
+            elapsed_seconds: elapsed.as_secs(),
+            ..Default::default()
+        };
+        for result in results {
+            if result.not_attempted {
+                summary.not_attempted += 1;
+                continue;
+            }
+            match &result.error_category {
+                Some(category) => {
+                    summary.failed += 1;
+                    *summary.errors.entry(category.clone()).or_insert(0) += 1;
+                }
+                None => summary.succeeded += 1,
+            }
+        }
+        summary
+    }
+
+    /// Process exit code for the outcome of the scan. Individual failures
+    /// are tolerated unless `fail_on_error` is set, and targets that there
+    /// wasn't time for don't count as failures.
+    pub fn exit_code(&self, fail_on_error: bool) -> i32 {
+        if self.targets > self.not_attempted && self.succeeded == 0 {
+            EXIT_ALL_FAILED
+        } else if fail_on_error && self.failed > 0 {
+            EXIT_SOME_FAILED
+        } else {
+            EXIT_SUCCESS
+        }
+    }
+
+    /// Print the summary as a table on stderr
+    fn print(&self) {
+        let errors = |category: &str| self.errors.get(category).copied();
+        let other = self.failed
+            - ["timeout", "refused", "dns"]
+                .iter()
+                .filter_map(|c| errors(*c))
+                .sum::<usize>();
+        let elapsed = self.elapsed_seconds;
+        let rows = [
+            ("Targets", self.targets.to_string()),
+            ("Succeeded", self.succeeded.to_string()),
+            ("Timed out", errors("timeout").unwrap_or(0).to_string()),
+            ("Refused", errors("refused").unwrap_or(0).to_string()),
+            ("DNS failures", errors("dns").unwrap_or(0).to_string()),
+            ("Other errors", other.to_string()),
+            ("Not attempted", self.not_attempted.to_string()),
+            (
+                "Elapsed",
+                format!(
+                    "{}:{:02}:{:02}",
+                    elapsed / 3600,
+                    elapsed / 60 % 60,
+                    elapsed % 60
+                ),
+            ),
+        ];
+        eprintln!("Scan summary");
+        for (label, value) in &rows {
+            eprintln!("  {:<14}{:>10}", label, value);
+        }
+        if self.truncated > 0 {
+            eprintln!(
+                "  The target list was truncated by --limit, {} targets \
+                 were not scanned",
+                self.truncated
+            );
+        }
+        if self.invalid > 0 {
+            eprintln!(
+                "  {} targets skipped as invalid, see invalid_targets.txt",
+                self.invalid
+            );
+        }
+    }
+}
+
+/// Load a saved capture to compute its perceptual hash, whether it is
+/// blank, and how it compares with the baseline
+fn analyse_capture(
+    opts: &Opts,
+    baseline: Option<&Baseline>,
+    target: &str,
+    file: &str,
+) -> Option<(u64, bool, Option<Comparison>)> {
+    let path = Path::new(&opts.output_dir).join(file);
+    match image::open(&path) {
+        Ok(image) => Some((
+            dhash(&image),
+            is_blank(&image, opts.blank_threshold),
+            baseline.map(|b| b.compare(opts, target, file, &image)),
+        )),
+        Err(e) => {
+            warn!("Unable to hash {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Delete a capture, and its thumbnail if present, that duplicates an
+/// earlier one
+fn remove_duplicate(opts: &Opts, file: &str) {
+    let path = Path::new(&opts.output_dir).join(file);
+    debug!("Removing duplicate capture {}", path.display());
+    if let Err(e) = fs::remove_file(&path) {
+        warn!("Unable to remove {}: {}", path.display(), e);
+    }
+    let _ = fs::remove_file(thumbnail_path(&path));
+    let _ = fs::remove_file(original_path(&path));
+    let _ = fs::remove_file(text_path(&path));
+    let _ = fs::remove_file(sidecar_path(&path));
+}
+
+/// Path of the metadata that accompanies an image, i.e. the image's name
+/// with the extension replaced by `.json`
+fn sidecar_path(path: &Path) -> PathBuf {
+    path.with_extension("json")
+}
+
+/// Write the details of a new capture next to its image, so that each
+/// capture describes itself even without results.json. Failures are only
+/// logged as the capture itself was successful.
+pub fn write_sidecar(content: &ReportMessageContent, opts: &Opts) {
+    let file = match &content.output {
+        FileError::File(f) if !opts.no_sidecar && !content.skipped => f,
+        _ => return,
+    };
+    let mut result = CaptureResult::from(content);
+    result.add_text(opts);
+    let path = sidecar_path(&Path::new(&opts.output_dir).join(file));
+    let written = atomic_write(&path, |w| {
+        serde_json::to_writer_pretty(&mut *w, &result)?;
+        writeln!(w)?;
+        Ok(())
+    });
+    match written {
+        Ok(()) => debug!("Saved metadata as {}", path.display()),
+        Err(e) => warn!("Failed to save {}: {}", path.display(), e),
+    }
 }
 
 pub fn reporting_thread(
     rx: mpsc::Receiver<ReportMessage>,
     opts: Arc<Opts>,
     targets: Arc<InputLists>,
-) -> Result<(), Error> {
+    baseline: Option<Baseline>,
+    mut progress: Option<Progress>,
+) -> Result<Summary, Error> {
+    let started = Instant::now();
     use Mode::*;
     // Vecs to collect the output messages in
     let mut rdp_outputs: Vec<ReportItem> = Vec::new();
@@ -70,6 +604,49 @@ pub fn reporting_thread(
     let mut rdp_errors: Vec<ReportError> = Vec::new();
     let mut web_errors: Vec<ReportError> = Vec::new();
     let mut vnc_errors: Vec<ReportError> = Vec::new();
+    let mut reachable: Vec<String> = Vec::new();
+    let mut not_attempted: Vec<String> = Vec::new();
+    let mut invalid: Vec<InvalidTarget> = targets.invalid.clone();
+
+    // Every result, successful or otherwise, for the JSON manifest. Each
+    // one is also appended to results.ndjson as soon as it arrives so
+    // that an interrupted scan still leaves its results behind, and is
+    // printed to stdout with --ndjson.
+    let mut manifest: Vec<CaptureResult> = Vec::new();
+    let stream_file = Path::new(&opts.output_dir).join("results.ndjson");
+    let mut stream = File::create(&stream_file)?;
+    let failures = webhook::FailureNotifier::start(&opts);
+    // Whoever is reading the results from stdout may stop early, e.g. when
+    // piped into head, in which case the results are only kept on disk
+    let mut to_stdout = opts.ndjson;
+    let mut record = |result: CaptureResult| -> Result<(), Error> {
+        let line = serde_json::to_string(&result)?;
+        writeln!(stream, "{}", line)?;
+        if to_stdout {
+            if let Err(e) = writeln!(io::stdout().lock(), "{}", line) {
+                warn!("Stopped writing results to stdout: {}", e);
+                to_stdout = false;
+            }
+        }
+        if let (Some(n), Some(category)) = (&failures, &result.error_category) {
+            n.notify(
+                &result.target,
+                result.protocol,
+                result.error.as_deref().unwrap_or_default(),
+                category,
+            );
+        }
+        manifest.push(result);
+        Ok(())
+    };
+
+    // Hash of each capture that made it into the outputs, along with
+    // which list it is in and where
+    let mut seen: Vec<(u64, Mode, usize)> = Vec::new();
+
+    // Every target that was captured, to find the ones that have gone
+    // missing since the baseline
+    let mut captured: HashSet<String> = HashSet::new();
 
     // Main loop listening on the channel
     while let Ok(msg) = rx.recv() {
@@ -78,44 +655,129 @@ pub fn reporting_thread(
         match msg {
             GenerateReport => break,
 
+            Queued(mode) => {
+                if let Some(p) = &mut progress {
+                    p.add(mode);
+                }
+            }
+
+            Invalid(mut list) => invalid.append(&mut list),
+
             Output(content) => {
-                match (content.output, content.mode) {
-                    (FileError::File(file), Rdp) => {
-                        rdp_outputs.push(ReportItem {
-                            target: content.target,
-                            file,
-                        });
+                if let Some(p) = &mut progress {
+                    p.complete(content.mode);
+                }
+                let mut result = CaptureResult::from(&content);
+                let hostname = result.hostname.clone().unwrap_or_default();
+                let security = result.security_summary();
+                let mut change = String::new();
+                let mut text = String::new();
+                let mut tags = Vec::new();
+                if let FileError::File(file) = &content.output {
+                    captured.insert(content.target.clone());
+                    let analysis = analyse_capture(
+                        &opts,
+                        baseline.as_ref(),
+                        &content.target,
+                        file,
+                    );
+                    let hash = analysis.as_ref().map(|(h, _, _)| *h);
+                    result.hash = hash.map(|h| format!("{:016x}", h));
+                    result.blank =
+                        analysis.as_ref().map_or(false, |(_, b, _)| *b);
+                    result.baseline = analysis.and_then(|(_, _, c)| c);
+                    if let Some(c) = &result.baseline {
+                        change = c.describe();
                     }
-                    (FileError::File(file), Web) => {
-                        web_outputs.push(ReportItem {
-                            target: content.target,
-                            file,
-                        });
+                    result.add_text(&opts);
+                    text = result.text.clone().unwrap_or_default();
+                    tags = result.tags.clone();
+
+                    // Near matches count too, as e.g. a clock on the page
+                    // is enough to change a few bits of the hash
+                    let existing = hash.and_then(|h| {
+                        seen.iter()
+                            .find(|(s, _, _)| similar_hashes(*s, h))
+                            .map(|(_, mode, idx)| (*mode, *idx))
+                    });
+                    match (hash, existing) {
+                        (Some(_), Some((mode, idx))) if opts.dedup => {
+                            // Point at the earlier capture rather than
+                            // keeping another copy
+                            let original = match mode {
+                                Rdp => &mut rdp_outputs[idx],
+                                Web => &mut web_outputs[idx],
+                                Vnc => &mut vnc_outputs[idx],
+                                Auto => unreachable!(),
+                            };
+                            info!(
+                                "{} is a duplicate of {}",
+                                content.target, original.target
+                            );
+                            remove_duplicate(&opts, file);
+                            result.file = Some(original.file.clone());
+                            result.duplicate_of = Some(original.target.clone());
+                            original.duplicates.push(content.target);
+                            record(result)?;
+                            continue;
+                        }
+                        (Some(h), None) => {
+                            let idx = match content.mode {
+                                Rdp => rdp_outputs.len(),
+                                Web => web_outputs.len(),
+                                Vnc => vnc_outputs.len(),
+                                Auto => unreachable!(),
+                            };
+                            seen.push((h, content.mode, idx));
+                        }
+                        _ => {}
                     }
-                    (FileError::File(file), Vnc) => {
-                        vnc_outputs.push(ReportItem {
-                            target: content.target,
+                }
+                record(result)?;
+                match (content.output, content.mode) {
+                    (FileError::File(file), mode) if mode != Auto => {
+                        let outputs = match mode {
+                            Rdp => &mut rdp_outputs,
+                            Web => &mut web_outputs,
+                            _ => &mut vnc_outputs,
+                        };
+                        let mut item = ReportItem::new(
+                            content.target,
                             file,
-                        });
+                            content.page,
+                            &opts,
+                        );
+                        item.change = change;
+                        item.text = text;
+                        item.tags = tags;
+                        item.hostname = hostname;
+                        item.security = security;
+                        outputs.push(item);
                     }
                     (FileError::Error(error), Rdp) => {
                         rdp_errors.push(ReportError {
                             target: content.target,
-                            error,
+                            error: error.to_string(),
                         });
                     }
                     (FileError::Error(error), Web) => {
                         web_errors.push(ReportError {
                             target: content.target,
-                            error,
+                            error: error.to_string(),
                         });
                     }
                     (FileError::Error(error), Vnc) => {
                         vnc_errors.push(ReportError {
                             target: content.target,
-                            error,
+                            error: error.to_string(),
                         });
                     }
+                    (FileError::Reachable, _) => {
+                        reachable.push(content.target);
+                    }
+                    (FileError::NotAttempted, _) => {
+                        not_attempted.push(content.target);
+                    }
                     (_, Auto) => {
                         // In theory there should never be an Auto making
                         // it to this stage
@@ -126,25 +788,492 @@ pub fn reporting_thread(
         }
     }
 
-    info!("Generating report");
+    if let Some(p) = &progress {
+        p.finish();
+    }
+    if let Some(n) = failures {
+        n.finish();
+    }
 
-    println!("RDP outputs: {:?}", rdp_outputs);
-    println!("Web outputs: {:?}", web_outputs);
+    if !opts.ndjson && !opts.json_summary {
+        println!("RDP outputs: {:?}", rdp_outputs);
+        println!("Web outputs: {:?}", web_outputs);
+    }
 
-    let report_file = Path::new(&opts.output_dir).join("report.html");
+    let missing = baseline.map_or_else(Vec::new, |b| b.missing(&captured));
+    for target in &missing {
+        info!("{} was captured in the baseline but not this time", target);
+    }
 
-    let report_template = ReportTemplate {
-        targets,
-        rdp_outputs,
-        rdp_errors,
-        web_outputs,
-        web_errors,
-        vnc_outputs,
-        vnc_errors,
-    };
+    let manifest_file = Path::new(&opts.output_dir).join("results.json");
+    fs::write(&manifest_file, serde_json::to_string_pretty(&manifest)?)?;
+    info!("Results manifest saved to {:?}", manifest_file);
+
+    if let Some(csv_file) = &opts.csv {
+        write_csv(&opts, csv_file, &manifest)?;
+    }
+
+    if !invalid.is_empty() {
+        write_invalid(&opts, &invalid)?;
+    }
+
+    let truncated = targets.truncated;
+    if opts.no_report {
+        info!("--no-report was supplied, not generating report");
+    } else {
+        let report_template = ReportTemplate {
+            targets,
+            rdp_outputs,
+            rdp_errors,
+            web_outputs,
+            web_errors,
+            vnc_outputs,
+            vnc_errors,
+            missing,
+            reachable,
+            not_attempted,
+        };
+        generate_report(&opts, report_template)?;
+    }
+
+    let mut summary = Summary::new(&manifest, truncated, started.elapsed());
+    summary.invalid = invalid.len();
+    if opts.json_summary {
+        println!("{}", serde_json::to_string(&summary)?);
+    } else if !opts.silent {
+        summary.print();
+    }
+    Ok(summary)
+}
+
+/// Save the inputs that couldn't be parsed, one per line along with where
+/// they came from and why, so that they can be fixed up and retried
+fn write_invalid(opts: &Opts, invalid: &[InvalidTarget]) -> Result<(), Error> {
+    let invalid_file = Path::new(&opts.output_dir).join("invalid_targets.txt");
+    let mut content = String::new();
+    for target in invalid {
+        content.push_str(&target.to_string());
+        content.push('\n');
+    }
+    fs::write(&invalid_file, content)?;
+    info!(
+        "{} invalid targets saved to {}",
+        invalid.len(),
+        invalid_file.display()
+    );
+    Ok(())
+}
+
+/// Save the results as CSV for --csv
+fn write_csv(
+    opts: &Opts,
+    csv_file: &str,
+    results: &[CaptureResult],
+) -> Result<(), Error> {
+    let mut csv = format!("{}\n", CSV_HEADER);
+    for result in results {
+        csv.push_str(&result.csv_row(opts));
+        csv.push('\n');
+    }
+    fs::write(csv_file, csv)?;
+    info!("CSV results saved to {}", csv_file);
+    Ok(())
+}
+
+/// Load the results manifests of earlier scans. Captures are saved
+/// relative to the directory of their manifest, so when that is not the
+/// output directory their paths are made absolute. Targets that appear in
+/// more than one manifest keep the result from the last one.
+fn load_manifests(opts: &Opts) -> Result<Vec<CaptureResult>, Error> {
+    let output_dir = Path::new(&opts.output_dir).canonicalize()?;
+    let mut results: Vec<CaptureResult> = Vec::new();
+    for manifest in &opts.from_manifest {
+        let content = fs::read_to_string(manifest).map_err(|e| {
+            Error::IoError(format!(
+                "Unable to read manifest {}: {}",
+                manifest, e
+            ))
+        })?;
+        let mut loaded: Vec<CaptureResult> = serde_json::from_str(&content)?;
+        info!("Loaded {} results from {}", loaded.len(), manifest);
+
+        let dir = match Path::new(manifest).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        }
+        .canonicalize()?;
+        if dir != output_dir {
+            for result in &mut loaded {
+                result.file = result
+                    .file
+                    .take()
+                    .map(|f| dir.join(f).display().to_string());
+            }
+        }
+        results.append(&mut loaded);
+    }
+
+    let mut seen = HashSet::new();
+    let mut latest: Vec<CaptureResult> = results
+        .into_iter()
+        .rev()
+        .filter(|r| seen.insert((r.protocol.as_str(), r.target.clone())))
+        .collect();
+    latest.reverse();
+    Ok(latest)
+}
+
+/// Rebuild the report, and the CSV if --csv was given, from the results
+/// manifests of earlier scans without capturing anything
+pub fn report_from_manifests(opts: &Opts) -> Result<(), Error> {
+    use Mode::*;
+    fs::create_dir_all(&opts.output_dir)?;
+    let results = load_manifests(opts)?;
+
+    let mut targets: InputLists = Default::default();
+    let mut rdp_outputs: Vec<ReportItem> = Vec::new();
+    let mut web_outputs: Vec<ReportItem> = Vec::new();
+    let mut vnc_outputs: Vec<ReportItem> = Vec::new();
+    let mut rdp_errors: Vec<ReportError> = Vec::new();
+    let mut web_errors: Vec<ReportError> = Vec::new();
+    let mut vnc_errors: Vec<ReportError> = Vec::new();
+    let mut reachable: Vec<String> = Vec::new();
+    let mut not_attempted: Vec<String> = Vec::new();
+
+    for result in &results {
+        let target = match result.protocol {
+            Web => Url::parse(&result.target).ok().map(Target::Url),
+            _ => result.target.parse().ok().map(Target::Address),
+        };
+        let (target_list, outputs, errors) = match result.protocol {
+            Rdp => {
+                (&mut targets.rdp_targets, &mut rdp_outputs, &mut rdp_errors)
+            }
+            Web => {
+                (&mut targets.web_targets, &mut web_outputs, &mut web_errors)
+            }
+            Vnc => {
+                (&mut targets.vnc_targets, &mut vnc_outputs, &mut vnc_errors)
+            }
+            Auto => continue,
+        };
+        target_list.extend(target);
+
+        match (&result.file, &result.error, &result.duplicate_of) {
+            (Some(_), _, Some(original)) => {
+                if let Some(item) =
+                    outputs.iter_mut().find(|i| &i.target == original)
+                {
+                    item.duplicates.push(result.target.clone());
+                }
+            }
+            (Some(file), _, None) => {
+                let mut item = ReportItem::new(
+                    result.target.clone(),
+                    file.clone(),
+                    result.page.clone(),
+                    opts,
+                );
+                if let Some(c) = &result.baseline {
+                    item.change = c.describe();
+                }
+                item.text = result.text.clone().unwrap_or_default();
+                item.tags = result.tags.clone();
+                item.hostname = result.hostname.clone().unwrap_or_default();
+                item.security = result.security_summary();
+                outputs.push(item);
+            }
+            (None, None, _) if result.not_attempted => {
+                not_attempted.push(result.target.clone())
+            }
+            (None, None, _) if result.success => {
+                reachable.push(result.target.clone())
+            }
+            (None, error, _) => errors.push(ReportError {
+                target: result.target.clone(),
+                error: error.clone().unwrap_or_default(),
+            }),
+        }
+    }
+
+    generate_report(
+        opts,
+        ReportTemplate {
+            targets: Arc::new(targets),
+            rdp_outputs,
+            rdp_errors,
+            web_outputs,
+            web_errors,
+            vnc_outputs,
+            vnc_errors,
+            missing: Vec::new(),
+            reachable,
+            not_attempted,
+        },
+    )?;
+    if let Some(csv_file) = &opts.csv {
+        write_csv(opts, csv_file, &results)?;
+    }
+    Ok(())
+}
+
+fn generate_report(
+    opts: &Opts,
+    report_template: ReportTemplate,
+) -> Result<(), Error> {
+    info!("Generating report");
+
+    let report_file = Path::new(&opts.output_dir).join("report.html");
     let report = report_template.render()?;
     debug!("Report: {:?}", report);
+    if opts.report_embed && report.len() > EMBEDDED_REPORT_WARNING {
+        warn!(
+            "The report is {} MB with its images embedded",
+            report.len() >> 20
+        );
+    }
     fs::write(&report_file, report)?;
     info!("Report saved to {:?}", report_file);
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn csv_rows() {
+        let opts = Opts {
+            output_dir: "output".to_string(),
+            ..Default::default()
+        };
+        let target = Target::Url(Url::parse("http://192.0.2.1").unwrap());
+        let mut result = CaptureResult::from(
+            &ReportMessageContent::new(
+                Mode::Web,
+                &target,
+                SystemTime::now(),
+                FileError::File("web/http_192.0.2.1_80.png".to_string()),
+                1,
+                None,
+            )
+            .with_page(PageInfo {
+                title: Some("Log in, \"admin\"".to_string()),
+                server: Some("=cmd|' /C calc'!A0".to_string()),
+                ..Default::default()
+            }),
+        );
+        result.duration_ms = 1500;
+        assert_eq!(
+            result.csv_row(&opts),
+            concat!(
+                "http://192.0.2.1/,web,captured,\"Log in, \"\"admin\"\"\",",
+                "'=cmd|' /C calc'!A0,",
+                "output/web/http_192.0.2.1_80.png,1500"
+            )
+        );
+
+        let failed = CaptureResult::from(&ReportMessageContent::new(
+            Mode::Rdp,
+            &Target::Address("192.0.2.1:3389".parse().unwrap()),
+            SystemTime::now(),
+            FileError::Error(Error::TimeoutError(String::new())),
+            3,
+            None,
+        ));
+        assert!(failed
+            .csv_row(&opts)
+            .starts_with("192.0.2.1:3389,rdp,timeout,,,,"));
+
+        let reachable = CaptureResult::from(&ReportMessageContent::new(
+            Mode::Vnc,
+            &Target::Address("192.0.2.1:5900".parse().unwrap()),
+            SystemTime::now(),
+            FileError::Reachable,
+            1,
+            None,
+        ));
+        assert!(reachable.success);
+        assert!(reachable
+            .csv_row(&opts)
+            .starts_with("192.0.2.1:5900,vnc,reachable,,,,"));
+
+        let not_attempted = CaptureResult::from(&ReportMessageContent::new(
+            Mode::Vnc,
+            &Target::Address("192.0.2.2:5900".parse().unwrap()),
+            SystemTime::now(),
+            FileError::NotAttempted,
+            0,
+            None,
+        ));
+        assert!(!not_attempted.success);
+        assert!(not_attempted
+            .csv_row(&opts)
+            .starts_with("192.0.2.2:5900,vnc,not_attempted,,,,"));
+    }
+
+    #[test]
+    fn merged_manifests() {
+        let dir = std::env::temp_dir()
+            .join(format!("scrying-manifest-{}", std::process::id()));
+        let (old, new) = (dir.join("old"), dir.join("new"));
+        fs::create_dir_all(&old).unwrap();
+        fs::create_dir_all(&new).unwrap();
+        let target = Target::Address("192.0.2.1:3389".parse().unwrap());
+        let result = |output| {
+            CaptureResult::from(&ReportMessageContent::new(
+                Mode::Rdp,
+                &target,
+                SystemTime::now(),
+                output,
+                1,
+                None,
+            ))
+        };
+        let save = |dir: &Path, result: CaptureResult| {
+            let manifest = dir.join("results.json");
+            fs::write(&manifest, serde_json::to_string(&[result]).unwrap())
+                .unwrap();
+            manifest.display().to_string()
+        };
+        let file = "rdp/192.0.2.1-3389.png".to_string();
+        let opts = Opts {
+            output_dir: new.display().to_string(),
+            from_manifest: vec![
+                save(&old, result(FileError::File(file.clone()))),
+                save(
+                    &new,
+                    result(FileError::Error(
+                        Error::TimeoutError(String::new()),
+                    )),
+                ),
+            ],
+            ..Default::default()
+        };
+
+        // The later manifest wins
+        let results = load_manifests(&opts).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].error_category.as_deref(), Some("timeout"));
+
+        // Captures from another directory are found from their manifest
+        let opts = Opts {
+            from_manifest: opts.from_manifest[..1].to_vec(),
+            ..opts
+        };
+        let results = load_manifests(&opts).unwrap();
+        assert_eq!(
+            results[0].file,
+            Some(old.canonicalize().unwrap().join(file).display().to_string())
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sidecar_metadata() {
+        let dir = std::env::temp_dir()
+            .join(format!("scrying-sidecar-{}", std::process::id()));
+        fs::create_dir_all(dir.join("web")).unwrap();
+        let target = Target::Url("http://192.0.2.1/".parse().unwrap());
+        let content = |output| {
+            ReportMessageContent::new(
+                Mode::Web,
+                &target,
+                SystemTime::now(),
+                output,
+                1,
+                None,
+            )
+            .with_page(PageInfo {
+                title: Some("phpMyAdmin".to_string()),
+                tags: vec!["phpmyadmin".to_string()],
+                ..Default::default()
+            })
+        };
+        let file = "web/http_192.0.2.1_80.png".to_string();
+        let sidecar = dir.join("web/http_192.0.2.1_80.json");
+        let mut opts = Opts {
+            output_dir: dir.display().to_string(),
+            no_sidecar: true,
+            ..Default::default()
+        };
+
+        write_sidecar(&content(FileError::File(file.clone())), &opts);
+        assert!(!sidecar.exists());
+
+        // Failed captures have no image to go alongside
+        opts.no_sidecar = false;
+        write_sidecar(
+            &content(FileError::Error(Error::TimeoutError(String::new()))),
+            &opts,
+        );
+        assert!(!sidecar.exists());
+
+        write_sidecar(&content(FileError::File(file)), &opts);
+        let result: CaptureResult =
+            serde_json::from_str(&fs::read_to_string(&sidecar).unwrap())
+                .unwrap();
+        assert_eq!(result.target, "http://192.0.2.1/");
+        assert_eq!(result.protocol, Mode::Web);
+        assert!(result.success);
+        assert_eq!(result.tags, vec!["phpmyadmin"]);
+        assert_eq!(
+            result.page.and_then(|p| p.title).as_deref(),
+            Some("phpMyAdmin")
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn summary_counts() {
+        let target = Target::Address("192.0.2.1:3389".parse().unwrap());
+        let start = SystemTime::now();
+        let result = |output| {
+            CaptureResult::from(&ReportMessageContent::new(
+                Mode::Rdp,
+                &target,
+                start,
+                output,
+                1,
+                None,
+            ))
+        };
+        let results = vec![
+            result(FileError::File("rdp/192.0.2.1-3389.png".to_string())),
+            result(FileError::Error(Error::TimeoutError(String::new()))),
+            result(FileError::Error(Error::TimeoutError(String::new()))),
+            result(FileError::Error(Error::DnsError(String::new()))),
+            result(FileError::NotAttempted),
+        ];
+
+        let summary = Summary::new(&results, 2, Duration::from_secs(61));
+        let mut errors = BTreeMap::new();
+        errors.insert("timeout".to_string(), 2);
+        errors.insert("dns".to_string(), 1);
+        assert_eq!(
+            summary,
+            Summary {
+                targets: 5,
+                succeeded: 1,
+                failed: 3,
+                truncated: 2,
+                not_attempted: 1,
+                errors,
+                elapsed_seconds: 61,
+                ..Default::default()
+            }
+        );
+        assert_eq!(summary.exit_code(false), EXIT_SUCCESS);
+        assert_eq!(summary.exit_code(true), EXIT_SOME_FAILED);
+        assert_eq!(
+            Summary::new(&results[1..], 0, Duration::from_secs(1))
+                .exit_code(false),
+            EXIT_ALL_FAILED
+        );
+        assert_eq!(
+            Summary::new(&results[..1], 0, Duration::from_secs(1))
+                .exit_code(true),
+            EXIT_SUCCESS
+        );
+    }
+}
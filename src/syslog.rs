@@ -0,0 +1,196 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Send log messages to syslog, either the local daemon or a remote
+//! server over UDP, for --syslog and --syslog-server
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use simplelog::{Config, SharedLogger};
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+
+/// Socket that the local syslog daemon listens on
+#[cfg(unix)]
+const LOCAL_SOCKET: &str = "/dev/log";
+
+/// The "user-level messages" facility
+const FACILITY_USER: u8 = 1;
+
+enum Transport {
+    #[cfg(unix)]
+    Local(UnixDatagram),
+    Remote(UdpSocket),
+}
+
+pub struct SyslogLogger {
+    level: LevelFilter,
+    config: Config,
+    transport: Transport,
+}
+
+impl SyslogLogger {
+    /// Log to the given host:port over UDP, or to the local syslog daemon
+    /// if there is no server
+    pub fn new(
+        level: LevelFilter,
+        server: Option<&str>,
+    ) -> io::Result<Box<Self>> {
+        let transport = match server {
+            Some(server) => {
+                let addr =
+                    server.to_socket_addrs()?.next().ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!("No addresses for {}", server),
+                        )
+                    })?;
+                let bind = if addr.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                };
+                let socket = UdpSocket::bind(bind)?;
+                socket.connect(addr)?;
+                Transport::Remote(socket)
+            }
+            #[cfg(unix)]
+            None => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(LOCAL_SOCKET)?;
+                Transport::Local(socket)
+            }
+            #[cfg(not(unix))]
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Local syslog is not supported, use --syslog-server",
+                ))
+            }
+        };
+        Ok(Box::new(Self {
+            level,
+            config: Config::default(),
+            transport,
+        }))
+    }
+}
+
+/// Format a message in the BSD syslog format that both local daemons and
+/// remote servers accept
+fn format_message(level: Level, pid: u32, message: &str) -> String {
+    let severity = match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    };
+    format!(
+        "<{}>scrying[{}]: {}",
+        FACILITY_USER * 8 + severity,
+        pid,
+        message
+    )
+}
+
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = format_message(
+            record.level(),
+            std::process::id(),
+            &record.args().to_string(),
+        );
+        // There is nowhere to report a failure to log, so a message that
+        // can't be sent is dropped
+        let _ = match &self.transport {
+            #[cfg(unix)]
+            Transport::Local(socket) => socket.send(message.as_bytes()),
+            Transport::Remote(socket) => socket.send(message.as_bytes()),
+        };
+    }
+
+    fn flush(&self) {}
+}
+
+impl SharedLogger for SyslogLogger {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&Config> {
+        Some(&self.config)
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        Box::new(*self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn syslog_format() {
+        assert_eq!(
+            format_message(Level::Error, 42, "Chrome crashed"),
+            "<11>scrying[42]: Chrome crashed"
+        );
+        assert_eq!(
+            format_message(Level::Info, 42, "Starting"),
+            "<14>scrying[42]: Starting"
+        );
+    }
+
+    #[test]
+    fn remote_syslog() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        let logger = SyslogLogger::new(LevelFilter::Info, Some(&addr)).unwrap();
+
+        logger.log(
+            &Record::builder()
+                .args(format_args!("hello"))
+                .level(Level::Warn)
+                .build(),
+        );
+        // Below the level, so not sent
+        logger.log(
+            &Record::builder()
+                .args(format_args!("hidden"))
+                .level(Level::Debug)
+                .build(),
+        );
+
+        let mut buf = [0; 128];
+        let len = server.recv(&mut buf).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf[..len]).unwrap(),
+            format!("<12>scrying[{}]: hello", std::process::id())
+        );
+    }
+}
@@ -0,0 +1,112 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Tagging captures whose page source or text matches a pattern given
+//! with --match, to pick out the ones worth reviewing first
+
+use regex::Regex;
+use std::str::FromStr;
+
+/// A named regular expression, given as `name=regex`
+#[derive(Clone, Debug)]
+pub struct TagPattern {
+    pub name: String,
+    regex: Regex,
+}
+
+impl FromStr for TagPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '=');
+        let name = parts.next().unwrap_or_default().trim();
+        let pattern = parts
+            .next()
+            .ok_or_else(|| format!("Expected name=regex, got {}", s))?;
+        // Tags are separated by spaces in the report
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!(
+                "Invalid tag name {:?}, use letters, digits, - and _",
+                name
+            ));
+        }
+        let regex = Regex::new(pattern)
+            .map_err(|e| format!("Invalid regex for {}: {}", name, e))?;
+        Ok(Self {
+            name: name.to_string(),
+            regex,
+        })
+    }
+}
+
+/// Names of the patterns that match the text, in the order that they
+/// were given
+pub fn matching(patterns: &[TagPattern], text: &str) -> Vec<String> {
+    patterns
+        .iter()
+        .filter(|p| p.regex.is_match(text))
+        .map(|p| p.name.clone())
+        .collect()
+}
+
+/// Add the names of the patterns that match the text to `tags`, leaving
+/// out any that are already there
+pub fn add_matching(
+    tags: &mut Vec<String>,
+    patterns: &[TagPattern],
+    text: &str,
+) {
+    for tag in matching(patterns, text) {
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tag_patterns() {
+        let patterns: Vec<TagPattern> = vec![
+            "default-creds=(?i)default password".parse().unwrap(),
+            "phpmyadmin=phpMyAdmin".parse().unwrap(),
+            "query=\\?id=\\d+".parse().unwrap(),
+        ];
+        assert_eq!(patterns[2].name, "query");
+
+        let html = "<p>The DEFAULT PASSWORD is admin</p><a href=\"?id=1\">";
+        assert_eq!(matching(&patterns, html), vec!["default-creds", "query"]);
+        assert!(matching(&patterns, "<title>Welcome</title>").is_empty());
+
+        let mut tags = vec!["query".to_string()];
+        add_matching(&mut tags, &patterns, "phpMyAdmin ?id=2");
+        assert_eq!(tags, vec!["query", "phpmyadmin"]);
+
+        assert!("no-equals".parse::<TagPattern>().is_err());
+        assert!("=regex".parse::<TagPattern>().is_err());
+        assert!("two words=regex".parse::<TagPattern>().is_err());
+        assert!("bad=(unclosed".parse::<TagPattern>().is_err());
+    }
+}
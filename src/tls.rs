@@ -0,0 +1,395 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Collect details of the certificates presented by HTTPS targets. Only
+//! the handful of fields that are useful during recon are read, so the
+//! certificate is picked apart with a minimal DER reader.
+
+use crate::error::Error;
+use crate::util::{connect_tcp, resolve};
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use native_tls::TlsConnector;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::Url;
+
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_VERSION: u8 = 0xa0;
+const TAG_EXTENSIONS: u8 = 0xa3;
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_SAN_DNS: u8 = 0x82;
+const TAG_SAN_IP: u8 = 0x87;
+
+/// Object identifier of the subject alternative name extension
+const OID_SAN: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// Details of a server's certificate
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct CertInfo {
+    pub subject: String,
+    pub common_name: Option<String>,
+    pub subject_alt_names: Vec<String>,
+    pub issuer: String,
+    pub not_before: String,
+    pub not_after: String,
+    /// Whether the certificate has expired or is not yet valid
+    pub expired: bool,
+    /// Whether the certificate was issued by its own subject
+    pub self_signed: bool,
+    /// Whether the certificate passed verification against the system's
+    /// trusted roots and the target's hostname
+    pub trusted: bool,
+}
+
+impl CertInfo {
+    /// Name of the machine that the certificate was issued to, which is
+    /// the common name or failing that the first alternative name
+    pub fn hostname(&self) -> Option<&str> {
+        self.common_name
+            .as_deref()
+            .or_else(|| self.subject_alt_names.first().map(|n| n.as_str()))
+    }
+}
+
+/// A DER element
+struct Der<'a> {
+    tag: u8,
+    body: &'a [u8],
+}
+
+/// Read one DER element from the front of the data, returning it along
+/// with whatever follows it
+fn read_der(data: &[u8]) -> Option<(Der, &[u8])> {
+    let (&tag, rest) = data.split_first()?;
+    let (&first, mut rest) = rest.split_first()?;
+    let len = if first & 0x80 == 0 {
+        first as usize
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 || rest.len() < n {
+            return None;
+        }
+        let len = rest[..n].iter().fold(0, |acc, b| (acc << 8) | *b as usize);
+        rest = &rest[n..];
+        len
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some((
+        Der {
+            tag,
+            body: &rest[..len],
+        },
+        &rest[len..],
+    ))
+}
+
+/// Iterate over the elements within a constructed element's body
+fn children(mut body: &[u8]) -> impl Iterator<Item = Der> {
+    std::iter::from_fn(move || {
+        let (der, rest) = read_der(body)?;
+        body = rest;
+        Some(der)
+    })
+}
+
+/// Read the attributes of a distinguished name, keeping the common ones
+fn name_attributes(name: &Der) -> Vec<(&'static str, String)> {
+    children(name.body)
+        .flat_map(|set| children(set.body))
+        .filter_map(|attribute| {
+            let mut parts = children(attribute.body);
+            let oid = parts.next()?;
+            let value = parts.next()?;
+            let label = match oid.body {
+                [0x55, 0x04, 0x03] => "CN",
+                [0x55, 0x04, 0x06] => "C",
+                [0x55, 0x04, 0x07] => "L",
+                [0x55, 0x04, 0x08] => "ST",
+                [0x55, 0x04, 0x0a] => "O",
+                [0x55, 0x04, 0x0b] => "OU",
+                _ => return None,
+            };
+            Some((label, String::from_utf8_lossy(value.body).into_owned()))
+        })
+        .collect()
+}
+
+fn format_name(attributes: &[(&str, String)]) -> String {
+    attributes
+        .iter()
+        .map(|(label, value)| format!("{}={}", label, value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Number of days from the unix epoch to the given date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era =
+        year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Parse a UTCTime or GeneralizedTime into seconds since the unix epoch
+/// and an ISO 8601 string
+fn parse_time(time: &Der) -> Option<(i64, String)> {
+    let s = std::str::from_utf8(time.body).ok()?;
+    let (year, rest) = match time.tag {
+        // Two digit years are 1950 to 2049
+        TAG_UTC_TIME => {
+            let year: i64 = s.get(0..2)?.parse().ok()?;
+            (if year >= 50 { 1900 + year } else { 2000 + year }, &s[2..])
+        }
+        TAG_GENERALIZED_TIME => (s.get(0..4)?.parse().ok()?, &s[4..]),
+        _ => return None,
+    };
+    let field = |i: usize| -> Option<i64> { rest.get(i..i + 2)?.parse().ok() };
+    let (month, day) = (field(0)?, field(2)?);
+    let (hour, minute, second) = (field(4)?, field(6)?, field(8)?);
+
+    let timestamp = days_from_civil(year, month, day) * 86_400
+        + hour * 3_600
+        + minute * 60
+        + second;
+    let iso = format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, hour, minute, second
+    );
+    Some((timestamp, iso))
+}
+
+/// Read the subject alternative names out of the extensions
+fn subject_alt_names(extensions: &Der) -> Vec<String> {
+    let mut names = Vec::new();
+    for extension in children(extensions.body)
+        .filter(|e| e.tag == TAG_SEQUENCE)
+        .flat_map(|e| children(e.body))
+    {
+        let mut parts = children(extension.body);
+        match parts.next() {
+            Some(oid) if oid.body == OID_SAN => {}
+            _ => continue,
+        }
+        // Skip over the critical flag if present
+        let value = match parts.next() {
+            Some(p) if p.tag == TAG_BOOLEAN => parts.next(),
+            p => p,
+        };
+        let general_names = match value.and_then(|v| read_der(v.body)) {
+            Some((g, _)) => g,
+            None => continue,
+        };
+        for name in children(general_names.body) {
+            match (name.tag, name.body.len()) {
+                (TAG_SAN_DNS, _) => {
+                    names.push(String::from_utf8_lossy(name.body).into_owned())
+                }
+                (TAG_SAN_IP, 4) => {
+                    let mut octets = [0; 4];
+                    octets.copy_from_slice(name.body);
+                    names.push(IpAddr::from(Ipv4Addr::from(octets)).to_string())
+                }
+                (TAG_SAN_IP, 16) => {
+                    let mut octets = [0; 16];
+                    octets.copy_from_slice(name.body);
+                    names.push(IpAddr::from(Ipv6Addr::from(octets)).to_string())
+                }
+                _ => {}
+            }
+        }
+    }
+    names
+}
+
+/// Extract the interesting details from a DER-encoded certificate,
+/// judging its validity against `now` in seconds since the unix epoch
+pub fn parse_certificate(der: &[u8], now: i64) -> Option<CertInfo> {
+    let (certificate, _) = read_der(der)?;
+    let tbs = children(certificate.body).next()?;
+    let mut fields = children(tbs.body).peekable();
+    if fields.peek()?.tag == TAG_VERSION {
+        fields.next();
+    }
+    let _serial = fields.next()?;
+    let _signature = fields.next()?;
+    let issuer = fields.next()?;
+    let validity = fields.next()?;
+    let subject = fields.next()?;
+    let extensions = fields.find(|f| f.tag == TAG_EXTENSIONS);
+
+    let mut times = children(validity.body);
+    let (start, not_before) = parse_time(&times.next()?)?;
+    let (end, not_after) = parse_time(&times.next()?)?;
+
+    let subject_attributes = name_attributes(&subject);
+    Some(CertInfo {
+        subject: format_name(&subject_attributes),
+        common_name: subject_attributes
+            .iter()
+            .find(|(label, _)| *label == "CN")
+            .map(|(_, value)| value.clone()),
+        subject_alt_names: extensions
+            .map(|e| subject_alt_names(&e))
+            .unwrap_or_default(),
+        issuer: format_name(&name_attributes(&issuer)),
+        not_before,
+        not_after,
+        expired: now < start || now > end,
+        self_signed: issuer.body == subject.body,
+        trusted: false,
+    })
+}
+
+/// Complete a TLS handshake with the server and return the certificate
+/// that it presents in DER form
+fn handshake(
+    url: &Url,
+    addr: Option<SocketAddr>,
+    timeout: Duration,
+    source: Option<IpAddr>,
+    verify: bool,
+) -> Result<Vec<u8>, Error> {
+    let host = url.host_str().unwrap_or_default();
+    let addr = match addr {
+        Some(addr) => addr,
+        None => resolve(url)?[0],
+    };
+    let stream = connect_tcp(&addr, Some(timeout), source)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let connector = TlsConnector::builder()
+        .danger_accept_invalid_certs(!verify)
+        .danger_accept_invalid_hostnames(!verify)
+        .build()
+        .map_err(|e| Error::TlsError(e.to_string()))?;
+    let tls = connector
+        .connect(host, stream)
+        .map_err(|e| Error::TlsError(e.to_string()))?;
+    tls.peer_certificate()
+        .and_then(|c| c.map(|c| c.to_der()).transpose())
+        .map_err(|e| Error::TlsError(e.to_string()))?
+        .ok_or_else(|| {
+            Error::TlsError("Server did not present a certificate".to_string())
+        })
+}
+
+/// Connect to the server and return the details of the certificate that
+/// it presents. Invalid certificates are accepted as the point is to
+/// record them, but are marked as untrusted. The server is reached at
+/// `addr` if it is known, e.g. from the check that it is listening, and
+/// otherwise at the first address of its host.
+pub fn fetch_certificate(
+    url: &Url,
+    addr: Option<SocketAddr>,
+    timeout: Duration,
+    source: Option<IpAddr>,
+) -> Result<CertInfo, Error> {
+    let (der, trusted) = match handshake(url, addr, timeout, source, true) {
+        Ok(der) => (der, true),
+        Err(Error::TlsError(e)) => {
+            debug!("Certificate of {} failed verification: {}", url, e);
+            (handshake(url, addr, timeout, source, false)?, false)
+        }
+        Err(e) => return Err(e),
+    };
+    Ok(CertInfo {
+        trusted,
+        ..describe_certificate(&der)?
+    })
+}
+
+/// Extract the details of a DER-encoded certificate as of now. It is not
+/// verified, so is always marked as untrusted.
+pub fn describe_certificate(der: &[u8]) -> Result<CertInfo, Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    parse_certificate(der, now).ok_or_else(|| {
+        Error::TlsError("Unable to parse server certificate".to_string())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn certificate_details() {
+        let der = std::fs::read("test/cert.der").unwrap();
+        // 2025-01-01T00:00:00Z
+        let info = parse_certificate(&der, 1_735_689_600).unwrap();
+        assert_eq!(
+            info,
+            CertInfo {
+                subject: "C=GB, O=Scrying Test, CN=example.com".to_string(),
+                common_name: Some("example.com".to_string()),
+                subject_alt_names: vec![
+                    "example.com".to_string(),
+                    "www.example.com".to_string(),
+                    "192.0.2.1".to_string(),
+                ],
+                issuer: "C=GB, O=Scrying Test, CN=example.com".to_string(),
+                not_before: "2020-01-01T00:00:00Z".to_string(),
+                not_after: "2030-01-01T00:00:00Z".to_string(),
+                expired: false,
+                self_signed: true,
+                trusted: false,
+            }
+        );
+
+        // 2031-01-01T00:00:00Z
+        assert!(parse_certificate(&der, 1_924_992_000).unwrap().expired);
+        assert!(parse_certificate(&der[..100], 0).is_none());
+        assert_eq!(info.hostname(), Some("example.com"));
+        let unnamed = CertInfo {
+            common_name: None,
+            ..info
+        };
+        assert_eq!(unnamed.hostname(), Some("example.com"));
+        assert_eq!(CertInfo::default().hostname(), None);
+    }
+
+    #[test]
+    fn certificate_times() {
+        let cases = vec![
+            (TAG_UTC_TIME, "200101000000Z", 1_577_836_800),
+            (TAG_UTC_TIME, "491231235959Z", 2_524_607_999),
+            (TAG_UTC_TIME, "500101000000Z", -631_152_000),
+            (TAG_GENERALIZED_TIME, "20500101000000Z", 2_524_608_000),
+        ];
+        for (tag, time, expected) in cases {
+            let der = Der {
+                tag,
+                body: time.as_bytes(),
+            };
+            assert_eq!(parse_time(&der).unwrap().0, expected, "{}", time);
+        }
+    }
+}
@@ -17,55 +17,909 @@
  *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use crate::argparse::{ImageFormat, Opts};
+use crate::error::Error;
 use crate::parsing::Target;
-use std::net::SocketAddr;
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, ImageOutputFormat};
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use once_cell::sync::Lazy;
+use socket2::{Domain, Socket, Type};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use url::{Host, Url};
 
-//TODO maybe move this to impl fmt::Display rather than a function
-pub fn target_to_filename(target: &Target) -> String {
-    match target {
+/// Whether --skip-existing was supplied and the target already has a
+/// capture in the given subdirectory
+pub fn capture_exists(target: &Target, subdir: &str, opts: &Opts) -> bool {
+    opts.skip_existing
+        && Path::new(&opts.output_dir)
+            .join(capture_path(target, subdir, opts))
+            .is_file()
+}
+
+/// Path relative to the output directory of an earlier capture of the
+/// target in the given subdirectory, if --skip-existing was supplied and
+/// there is one
+pub fn existing_capture(
+    target: &Target,
+    subdir: &str,
+    opts: &Opts,
+) -> Option<String> {
+    if !capture_exists(target, subdir, opts) {
+        return None;
+    }
+    info!("Skipping {}, it has already been captured", target);
+    Some(capture_path(target, subdir, opts).display().to_string())
+}
+
+/// Longest that the backoff between retries grows to
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// Double the delay before the next retry, up to MAX_RETRY_DELAY
+fn backoff(delay: Duration) -> Duration {
+    delay
+        .checked_mul(2)
+        .map_or(MAX_RETRY_DELAY, |d| d.min(MAX_RETRY_DELAY))
+}
+
+/// Run a capture, trying again with exponential backoff while it fails
+/// with a retryable error and --retries allows. Returns the final result
+/// along with the number of attempts made.
+pub fn with_retries<T, F>(
+    opts: &Opts,
+    mut capture: F,
+) -> (Result<T, Error>, u32)
+where
+    F: FnMut() -> Result<T, Error>,
+{
+    let mut attempts = 0;
+    let mut delay = Duration::from_millis(opts.retry_delay);
+    loop {
+        attempts += 1;
+        match capture() {
+            Err(e) if e.is_retryable() && attempts <= opts.retries => {
+                info!(
+                    "Attempt {} failed ({}), retrying in {:?}",
+                    attempts, e, delay
+                );
+                thread::sleep(delay);
+                delay = backoff(delay);
+            }
+            result => return (result, attempts),
+        }
+    }
+}
+
+/// Write a file via `<name>.tmp` in the same directory, which is renamed
+/// into place once everything has been flushed to disk. This means that
+/// a file with the final name is always complete, even if the write is
+/// interrupted.
+pub fn atomic_write<F>(path: &Path, write: F) -> Result<(), Error>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<(), Error>,
+{
+    let mut tmp_name = path
+        .file_name()
+        .map_or_else(OsString::new, |n| n.to_os_string());
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let result = File::create(&tmp_path)
+        .map_err(Error::from)
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            write(&mut writer)?;
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+            Ok(())
+        })
+        .and_then(|_| fs::rename(&tmp_path, path).map_err(Error::from));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Save a captured image to the given path in the format selected on the
+/// command line
+pub fn save_image(
+    image: &DynamicImage,
+    path: &Path,
+    opts: &Opts,
+) -> Result<(), Error> {
+    match opts.image_format {
+        ImageFormat::Png => atomic_write(path, |w| {
+            image.write_to(w, ImageOutputFormat::Png)?;
+            Ok(())
+        }),
+        ImageFormat::Jpg => {
+            // JPEG has no alpha channel, so drop it before encoding
+            let rgb = DynamicImage::ImageRgb8(image.to_rgb8());
+            atomic_write(path, |w| {
+                rgb.write_to(w, ImageOutputFormat::Jpeg(opts.jpeg_quality))?;
+                Ok(())
+            })
+        }
+    }
+}
+
+/// Path that the full size image is kept at when a capture is shrunk
+/// with --keep-original, i.e. `<name>.orig.<ext>`
+pub fn original_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}.orig.{}", stem, extension))
+}
+
+/// Shrink a capture to fit within --max-dimension, keeping its aspect
+/// ratio, or None if it already fits
+pub fn shrink(image: &DynamicImage, opts: &Opts) -> Option<DynamicImage> {
+    let max_dim = opts.max_dimension?;
+    let (width, height) = image.dimensions();
+    if width.max(height) <= max_dim {
+        return None;
+    }
+    debug!(
+        "Shrinking capture from {}x{} to fit within {}",
+        width, height, max_dim
+    );
+    Some(image.resize(max_dim, max_dim, FilterType::Triangle))
+}
+
+/// Save a capture, shrunk to fit within --max-dimension if it is any
+/// bigger. With --keep-original the full size image is saved as well,
+/// once the shrunk one has been.
+pub fn save_capture(
+    image: &DynamicImage,
+    path: &Path,
+    opts: &Opts,
+) -> Result<(), Error> {
+    match shrink(image, opts) {
+        Some(shrunk) => {
+            save_image(&shrunk, path, opts)?;
+            if opts.keep_original {
+                save_image(image, &original_path(path), opts)?;
+            }
+            Ok(())
+        }
+        None => save_image(image, path, opts),
+    }
+}
+
+/// 32-bit MurmurHash3 (x86 variant) with a seed of zero, as used for
+/// favicon fingerprinting
+pub fn murmur3_32(data: &[u8]) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+    let mut hash: u32 = 0;
+
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k =
+            u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        hash ^= k;
+        hash = hash
+            .rotate_left(13)
+            .wrapping_mul(5)
+            .wrapping_add(0xe654_6b64);
+    }
+
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        let mut k: u32 = 0;
+        for (i, byte) in tail.iter().enumerate() {
+            k |= (*byte as u32) << (8 * i);
+        }
+        hash ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    }
+
+    hash ^= data.len() as u32;
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85eb_ca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2_ae35);
+    hash ^ (hash >> 16)
+}
+
+/// Favicon hash in the same form as Shodan's `http.favicon.hash`, which
+/// is the signed MurmurHash3 of the icon's base64 encoding split into
+/// lines of 76 characters
+pub fn favicon_hash(icon: &[u8]) -> i32 {
+    let encoded = base64::encode(icon);
+    let mut wrapped =
+        String::with_capacity(encoded.len() + encoded.len() / 76 + 1);
+    for line in encoded.as_bytes().chunks(76) {
+        wrapped.push_str(std::str::from_utf8(line).unwrap_or_default());
+        wrapped.push('\n');
+    }
+    murmur3_32(wrapped.as_bytes()) as i32
+}
+
+/// How much brighter a pixel has to be than its neighbour to set a bit in
+/// the difference hash
+const DHASH_MARGIN: u8 = 4;
+
+/// Perceptual difference hash of an image, used to spot near-identical
+/// captures. Small differences in brightness are ignored so that blank
+/// and nearly blank images all hash to zero and cluster together.
+pub fn dhash(image: &DynamicImage) -> u64 {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let left = small.get_pixel(x, y)[0];
+            let right = small.get_pixel(x + 1, y)[0];
+            hash <<= 1;
+            if left > right.saturating_add(DHASH_MARGIN) {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+/// Most bits that two difference hashes can differ by for their images
+/// to count as the same, allowing for e.g. a clock or a counter changing
+const SIMILAR_HASH_BITS: u32 = 5;
+
+/// Whether two difference hashes are close enough for their images to be
+/// treated as duplicates
+pub fn similar_hashes(a: u64, b: u64) -> bool {
+    (a ^ b).count_ones() <= SIMILAR_HASH_BITS
+}
+
+/// Whether an image is close to a single solid colour, such as a blank
+/// page or an empty desktop. The threshold is the largest standard
+/// deviation of brightness, from 0 to 255, that still counts as blank.
+pub fn is_blank(image: &DynamicImage, threshold: f64) -> bool {
+    // Work on a small copy as the exact pixels don't matter here
+    let small = image.thumbnail(128, 128).to_luma8();
+    let count = (small.width() * small.height()) as f64;
+    if count == 0.0 {
+        return true;
+    }
+    let mean = small.pixels().map(|p| p[0] as f64).sum::<f64>() / count;
+    let variance = small
+        .pixels()
+        .map(|p| (p[0] as f64 - mean).powi(2))
+        .sum::<f64>()
+        / count;
+    variance.sqrt() <= threshold
+}
+
+/// Refuse to save blank captures if --skip-blank was given
+pub fn check_blank(image: &DynamicImage, opts: &Opts) -> Result<(), Error> {
+    if opts.skip_blank && is_blank(image, opts.blank_threshold) {
+        Err(Error::BlankError("Not saving blank image".to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Path of the thumbnail that accompanies an image, i.e. the image's
+/// name with the extension replaced by `.thumb.jpg`
+pub fn thumbnail_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}.thumb.jpg", stem))
+}
+
+/// Shrink the image at `path` to fit within `max_dim` pixels in both
+/// directions
+fn thumbnail_image(path: &Path, max_dim: u32) -> Result<DynamicImage, Error> {
+    let thumbnail = image::open(path)?.thumbnail(max_dim, max_dim);
+    Ok(DynamicImage::ImageRgb8(thumbnail.to_rgb8()))
+}
+
+/// Write a thumbnail of the image at `path` that fits within `max_dim`
+/// pixels in both directions, returning the path of the thumbnail
+pub fn make_thumbnail(path: &Path, max_dim: u32) -> Result<PathBuf, Error> {
+    let thumbnail_file = thumbnail_path(path);
+    let rgb = thumbnail_image(path, max_dim)?;
+    atomic_write(&thumbnail_file, |w| {
+        rgb.write_to(w, ImageOutputFormat::Jpeg(75))?;
+        Ok(())
+    })?;
+    Ok(thumbnail_file)
+}
+
+/// Thumbnail of the image at `path` as a data: URI, for embedding in the
+/// report. The saved thumbnail is used if there is one, otherwise one is
+/// made without saving it.
+pub fn thumbnail_data_uri(path: &Path, max_dim: u32) -> Result<String, Error> {
+    let thumbnail_file = thumbnail_path(path);
+    let jpeg = if thumbnail_file.is_file() {
+        fs::read(&thumbnail_file)?
+    } else {
+        let mut jpeg = Vec::new();
+        thumbnail_image(path, max_dim)?
+            .write_to(&mut jpeg, ImageOutputFormat::Jpeg(75))?;
+        jpeg
+    };
+    Ok(format!("data:image/jpeg;base64,{}", base64::encode(&jpeg)))
+}
+
+/// Write a thumbnail next to a saved capture if --thumbnails was given.
+/// Failures are only logged as the capture itself was successful.
+pub fn save_thumbnail(path: &Path, opts: &Opts) {
+    if !opts.thumbnails {
+        return;
+    }
+    match make_thumbnail(path, opts.thumbnail_size) {
+        Ok(t) => debug!("Saved thumbnail as {}", t.display()),
+        Err(e) => {
+            warn!("Failed to create thumbnail of {}: {}", path.display(), e)
+        }
+    }
+}
+
+/// Open a TCP connection to the address, from the --source-addr address
+/// if one was given
+pub fn connect_tcp(
+    addr: &SocketAddr,
+    timeout: Option<Duration>,
+    source: Option<IpAddr>,
+) -> io::Result<TcpStream> {
+    let source = match source {
+        Some(source) => source,
+        None => {
+            return match timeout {
+                Some(timeout) => TcpStream::connect_timeout(addr, timeout),
+                None => TcpStream::connect(addr),
+            }
+        }
+    };
+    if source.is_ipv4() != addr.is_ipv4() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} can't be reached from {}", addr, source),
+        ));
+    }
+    let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, None)?;
+    socket.bind(&SocketAddr::new(source, 0).into())?;
+    match timeout {
+        Some(timeout) => socket.connect_timeout(&(*addr).into(), timeout)?,
+        None => socket.connect(&(*addr).into())?,
+    }
+    Ok(socket.into())
+}
+
+/// Check whether a TCP connection can be opened to the address within
+/// the given timeout
+pub fn port_open(
+    addr: &SocketAddr,
+    timeout: Duration,
+    source: Option<IpAddr>,
+) -> Result<(), Error> {
+    match connect_tcp(addr, Some(timeout), source) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            debug!("Unable to connect to {}: {}", addr, e);
+            Err(e.into())
+        }
+    }
+}
+
+/// Addresses that hostnames have already resolved to, shared between
+/// every thread so that each host is only looked up once however many
+/// targets there are on it
+static RESOLVED: Lazy<Mutex<HashMap<String, Vec<IpAddr>>>> =
+    Lazy::new(Default::default);
+
+/// Resolve a hostname to every one of its A and AAAA records, caching the
+/// result for later targets on the same host
+pub fn resolve_host(host: &str) -> Result<Vec<IpAddr>, Error> {
+    if let Some(ips) = RESOLVED.lock().unwrap().get(host) {
+        return Ok(ips.clone());
+    }
+
+    let addrs = (host, 0).to_socket_addrs().map_err(|e| {
+        Error::DnsError(format!("Unable to resolve {}: {}", host, e))
+    })?;
+    let mut ips: Vec<IpAddr> = Vec::new();
+    for ip in addrs.map(|a| a.ip()) {
+        if !ips.contains(&ip) {
+            ips.push(ip);
+        }
+    }
+    if ips.is_empty() {
+        return Err(Error::DnsError(format!("No addresses for {}", host)));
+    }
+    debug!("Resolved {} to {:?}", host, ips);
+    RESOLVED
+        .lock()
+        .unwrap()
+        .insert(host.to_string(), ips.clone());
+    Ok(ips)
+}
+
+/// Resolve the host of a URL to all of its addresses, along with the port
+/// of the URL
+pub fn resolve(url: &Url) -> Result<Vec<SocketAddr>, Error> {
+    let port = url.port_or_known_default().unwrap_or_default();
+    let ips = match url.host() {
+        Some(Host::Ipv4(ip)) => vec![IpAddr::V4(ip)],
+        Some(Host::Ipv6(ip)) => vec![IpAddr::V6(ip)],
+        Some(Host::Domain(host)) => resolve_host(host)?,
+        None => {
+            return Err(Error::DnsError(format!("{} has no host", url)));
+        }
+    };
+    Ok(ips
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect())
+}
+
+/// Quickly check whether the target is listening before attempting a
+/// full capture, returning the address that the capture should connect
+/// to. Every address that a hostname resolves to is tried in turn. The
+/// check is skipped if it has been disabled or a proxy is in use, as the
+/// target may not be directly reachable, and the first address is used.
+/// Hostnames are left for the proxy to resolve.
+pub fn precheck(
+    target: &Target,
+    opts: &Opts,
+    proxy: &Option<String>,
+) -> Result<Option<SocketAddr>, Error> {
+    let addrs = match (target, proxy) {
+        (Target::Address(addr), _) => vec![*addr],
+        (Target::Url(_), Some(_)) => return Ok(None),
+        (Target::Url(u), None) => resolve(u)?,
+    };
+    if opts.no_port_check || proxy.is_some() {
+        return Ok(addrs.first().copied());
+    }
+    let timeout = Duration::from_millis(opts.port_check_timeout);
+    connect_any(target, &addrs, timeout, opts.source_addr).map(Some)
+}
+
+/// Try to connect to each of the target's addresses in turn, returning
+/// the first one that accepts the connection
+pub fn connect_any(
+    target: &Target,
+    addrs: &[SocketAddr],
+    timeout: Duration,
+    source: Option<IpAddr>,
+) -> Result<SocketAddr, Error> {
+    // Only report a refused connection if that is what every address
+    // did, anything else means the target is unreachable
+    let mut all_refused = !addrs.is_empty();
+    for addr in addrs {
+        match port_open(addr, timeout, source) {
+            Ok(()) => return Ok(*addr),
+            Err(Error::ConnectionRefused(_)) => {}
+            Err(_) => all_refused = false,
+        }
+    }
+    if all_refused {
+        Err(Error::ConnectionRefused(format!(
+            "{} refused the connection",
+            target
+        )))
+    } else {
+        Err(Error::UnreachableError(format!(
+            "Unable to connect to {}",
+            target
+        )))
+    }
+}
+
+/// Longest filename stem before it is shortened, leaving room for
+/// suffixes such as .thumb.jpg within the usual limit of 255 bytes
+const MAX_FILENAME_STEM: usize = 200;
+
+/// Write every byte other than ASCII letters, digits, dots and the
+/// characters in `keep` as %XX
+fn escape_filename(s: &str, keep: &[char]) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for b in s.bytes() {
+        let c = b as char;
+        if c.is_ascii_alphanumeric() || c == '.' || keep.contains(&c) {
+            escaped.push(c);
+        } else {
+            escaped.push_str(&format!("%{:02X}", b));
+        }
+    }
+    escaped
+}
+
+/// Stem of the filename that captures of the target are saved under.
+///
+/// * Addresses become `<ip>-<port>`, with the colons in IPv6 addresses
+///   replaced by underscores
+/// * URLs become `<scheme>_<host>_<port>`, followed by `-<segment>` for
+///   each part of the path and `%3F<query>` if there is a query string.
+///   The colons in IPv6 hosts are replaced by dots.
+///
+/// Any other character that would be ambiguous or is not allowed in
+/// filenames on Windows is written as %XX, so different targets always
+/// get different names. Credentials, fragments, and empty path segments
+/// such as trailing slashes are left out. Names that would be too long
+/// are cut short and end with `~` and a hash of the full name.
+pub fn output_filename(target: &Target) -> String {
+    let stem = match target {
         Target::Address(SocketAddr::V4(addr)) => {
-            format!("{}", addr).replace(":", "-")
+            format!("{}-{}", addr.ip(), addr.port())
         }
-        Target::Address(SocketAddr::V6(addr)) => format!("{}", addr)
-            .replace("]:", "-")
-            .replace("[", "")
-            .replace(":", "_"),
+        Target::Address(SocketAddr::V6(addr)) => format!(
+            "{}-{}",
+            addr.ip().to_string().replace(":", "_"),
+            addr.port()
+        ),
         Target::Url(u) => {
-            // The :// scheme separator is converted to a hyphen
-            // Any slashes in the URL are converted into hyphens
-            // The port-separating colon is converted into an underscore
-            // This avoids the edge case where http://example.com:8080/thing
-            // and http://example.com/8080/thing convert to the same.
-            // TODO maybe convert the colons in a v6 address to dots
-            // rather than underscores
-            let mut converted: String = String::from(u.as_str())
-                .replace("://", "_") // Replace the scheme separator with -
-                .replace("/", "-") // replace all slashes with /
-                .replace(":", "_") // replace colon (probably port, could be uname)
-                .replace("[", "") // Remove the square brackets as they are not
-                .replace("]", "") // needed for uniqueness
-                ;
-            while converted.ends_with('-') {
-                // remove the trailing - if the URL had a trailing /
-                converted.pop();
+            let host = match u.host() {
+                Some(Host::Ipv6(ip)) => ip.to_string().replace(":", "."),
+                Some(host) => escape_filename(&host.to_string(), &['-']),
+                None => String::new(),
+            };
+            let mut stem =
+                format!("{}_{}", escape_filename(u.scheme(), &['-']), host);
+            if let Some(port) = u.port_or_known_default() {
+                stem.push_str(&format!("_{}", port));
             }
-
-            converted
+            for segment in u.path_segments().into_iter().flatten() {
+                if !segment.is_empty() {
+                    stem.push('-');
+                    stem.push_str(&escape_filename(segment, &[]));
+                }
+            }
+            if let Some(query) = u.query() {
+                stem.push_str("%3F");
+                stem.push_str(&escape_filename(query, &[]));
+            }
+            stem
         }
+    };
+
+    if stem.len() > MAX_FILENAME_STEM {
+        // Everything has been escaped to ASCII, so any byte boundary is
+        // a character boundary
+        let hash = murmur3_32(stem.as_bytes());
+        format!("{}~{:08x}", &stem[..MAX_FILENAME_STEM - 9], hash)
+    } else {
+        stem
     }
 }
 
+/// Path relative to the output directory that a capture of the target is
+/// saved at, in the subdirectory for its type of target
+pub fn capture_path(target: &Target, subdir: &str, opts: &Opts) -> PathBuf {
+    Path::new(subdir).join(format!(
+        "{}.{}",
+        output_filename(target),
+        opts.image_format.extension()
+    ))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use std::net::ToSocketAddrs;
-    use url::Url;
     #[test]
-    fn test_target_to_filename() {
+    fn murmur3() {
+        assert_eq!(murmur3_32(b""), 0);
+        assert_eq!(murmur3_32(b"hello") as i32, 613153351);
+        assert_eq!(murmur3_32(b"foo") as i32, -156908512);
+        assert_eq!(murmur3_32(b"hello, world") as i32, 345750399);
+
+        // "aGVsbG8=\n" is the line-wrapped base64 of "hello"
+        assert_eq!(favicon_hash(b"hello"), murmur3_32(b"aGVsbG8=\n") as i32);
+    }
+
+    #[test]
+    fn perceptual_hash() {
+        use image::{ImageBuffer, Rgb};
+
+        let black = DynamicImage::new_rgb8(1280, 720);
+        assert_eq!(dhash(&black), 0);
+
+        // Slight noise on a blank page should still hash as blank
+        let noisy =
+            DynamicImage::ImageRgb8(ImageBuffer::from_fn(1280, 720, |x, y| {
+                let v = 250 - ((x * 7 + y * 13) % 3) as u8;
+                Rgb([v, v, v])
+            }));
+        assert_eq!(dhash(&noisy), 0);
+
+        // A gradient gets darker to the right so every bit is set, and
+        // rescaling the image does not change the hash
+        let gradient =
+            DynamicImage::ImageRgb8(ImageBuffer::from_fn(900, 800, |x, _| {
+                let v = 255 - (x * 255 / 900) as u8;
+                Rgb([v, v, v])
+            }));
+        assert_eq!(dhash(&gradient), u64::MAX);
+        assert_eq!(dhash(&gradient.thumbnail(450, 400)), u64::MAX);
+
+        // Changing a pixel still leaves the images similar, unlike a blank
+        let mut changed = gradient.to_rgb8();
+        changed.put_pixel(450, 400, Rgb([255, 0, 0]));
+        let changed = DynamicImage::ImageRgb8(changed);
+        assert!(similar_hashes(dhash(&gradient), dhash(&changed)));
+        assert!(!similar_hashes(dhash(&gradient), dhash(&black)));
+        assert!(similar_hashes(0b11111, 0));
+        assert!(!similar_hashes(0b111111, 0));
+    }
+
+    #[test]
+    fn blank_detection() {
+        use image::{ImageBuffer, Rgb};
+
+        // A black page
+        assert!(is_blank(&DynamicImage::new_rgb8(1280, 720), 8.0));
+
+        // A white page with a little noise is still blank
+        let noisy =
+            DynamicImage::ImageRgb8(ImageBuffer::from_fn(1280, 720, |x, y| {
+                let v = 250 - ((x * 7 + y * 13) % 3) as u8;
+                Rgb([v, v, v])
+            }));
+        assert!(is_blank(&noisy, 8.0));
+        assert!(!is_blank(&noisy, 0.0));
+
+        // Half black and half white is not
+        let split =
+            DynamicImage::ImageRgb8(ImageBuffer::from_fn(1280, 720, |x, _| {
+                if x < 640 {
+                    Rgb([0, 0, 0])
+                } else {
+                    Rgb([255, 255, 255])
+                }
+            }));
+        assert!(!is_blank(&split, 8.0));
+    }
+
+    #[test]
+    fn precheck_addresses() {
+        let opts = Opts {
+            no_port_check: true,
+            ..Default::default()
+        };
+        let ip: IpAddr = "192.0.2.45".parse().unwrap();
+
+        let addr = SocketAddr::new(ip, 3389);
+        let target = Target::Address(addr);
+        assert_eq!(precheck(&target, &opts, &None).unwrap(), Some(addr));
+
+        let target = Target::Url(Url::parse("https://192.0.2.45").unwrap());
+        assert_eq!(
+            precheck(&target, &opts, &None).unwrap(),
+            Some(SocketAddr::new(ip, 443))
+        );
+        let proxy = Some("socks5://[::1]:1080".to_string());
+        assert_eq!(precheck(&target, &opts, &proxy).unwrap(), None);
+
+        let target =
+            Target::Url(Url::parse("https://nonexistent.invalid").unwrap());
+        assert!(matches!(
+            precheck(&target, &opts, &None),
+            Err(Error::DnsError(_))
+        ));
+    }
+
+    #[test]
+    fn shared_resolver() {
+        // Lookups made on one thread are there for the others
+        let ips = thread::spawn(|| resolve_host("localhost").unwrap())
+            .join()
+            .unwrap();
+        assert!(RESOLVED.lock().unwrap().contains_key("localhost"));
+        assert_eq!(resolve_host("localhost").unwrap(), ips);
+
+        // URLs on the host get every address with their own port
+        let url = Url::parse("http://localhost:8080/").unwrap();
+        let addrs = resolve(&url).unwrap();
+        assert_eq!(addrs.len(), ips.len());
+        assert!(addrs.iter().all(|a| a.port() == 8080));
+    }
+
+    #[test]
+    fn source_addresses() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let timeout = Some(Duration::from_secs(5));
+
+        let source = "127.0.0.1".parse().unwrap();
+        let stream = connect_tcp(&addr, timeout, Some(source)).unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), source);
+        assert!(connect_tcp(&addr, None, None).is_ok());
+
+        let err = connect_tcp(&addr, timeout, Some("::1".parse().unwrap()))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn retries() {
+        let opts = Opts {
+            retries: 2,
+            retry_delay: 1,
+            ..Default::default()
+        };
+
+        // Retryable errors are tried again until the retries run out
+        let (result, attempts) = with_retries(&opts, || -> Result<(), _> {
+            Err(Error::TimeoutError("slow".to_string()))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+
+        // Success part way through stops retrying
+        let mut calls = 0;
+        let (result, attempts) = with_retries(&opts, || {
+            calls += 1;
+            if calls < 2 {
+                Err(Error::ConnectionRefused("busy".to_string()))
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(attempts, 2);
+
+        // Other errors fail immediately
+        let (result, attempts) = with_retries(&opts, || -> Result<(), _> {
+            Err(Error::DnsError("NXDOMAIN".to_string()))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+
+        // The backoff levels off rather than overflowing
+        assert_eq!(backoff(Duration::from_secs(2)), Duration::from_secs(4));
+        assert_eq!(backoff(Duration::from_secs(200)), MAX_RETRY_DELAY);
+        assert_eq!(backoff(Duration::from_millis(u64::MAX)), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn atomic_writes() {
+        let dir = std::env::temp_dir()
+            .join(format!("scrying-atomic-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("capture.png");
+
+        atomic_write(&file, |w| {
+            w.write_all(b"complete")?;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(fs::read(&file).unwrap(), b"complete");
+        assert!(!dir.join("capture.png.tmp").exists());
+
+        // A failed write leaves neither the file nor the temporary file
+        let failed = dir.join("failed.png");
+        let result = atomic_write(&failed, |w| {
+            w.write_all(b"partial")?;
+            Err(Error::IoError("interrupted".to_string()))
+        });
+        assert!(result.is_err());
+        assert!(!failed.exists());
+        assert!(!dir.join("failed.png.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn existing_captures() {
+        let dir = std::env::temp_dir()
+            .join(format!("scrying-existing-{}", std::process::id()));
+        fs::create_dir_all(dir.join("rdp")).unwrap();
+        fs::write(dir.join("rdp").join("192.0.2.1-3389.png"), b"").unwrap();
+
+        let mut opts = Opts {
+            output_dir: dir.display().to_string(),
+            ..Default::default()
+        };
+        let captured = Target::Address("192.0.2.1:3389".parse().unwrap());
+        let missing = Target::Address("192.0.2.2:3389".parse().unwrap());
+        assert_eq!(existing_capture(&captured, "rdp", &opts), None);
+
+        opts.skip_existing = true;
+        assert_eq!(
+            existing_capture(&captured, "rdp", &opts),
+            Some(
+                Path::new("rdp")
+                    .join("192.0.2.1-3389.png")
+                    .display()
+                    .to_string()
+            )
+        );
+        assert_eq!(existing_capture(&missing, "rdp", &opts), None);
+        assert_eq!(existing_capture(&captured, "vnc", &opts), None);
+        assert!(capture_exists(&captured, "rdp", &opts));
+        assert!(!capture_exists(&missing, "rdp", &opts));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn thumbnails() {
+        assert_eq!(
+            thumbnail_path(Path::new("web/http_example.com.png")),
+            Path::new("web/http_example.com.thumb.jpg")
+        );
+        assert_eq!(
+            thumbnail_path(Path::new("rdp/192.0.2.45-3389.jpg")),
+            Path::new("rdp/192.0.2.45-3389.thumb.jpg")
+        );
+
+        let dir = std::env::temp_dir()
+            .join(format!("scrying-thumbnail-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let image_file = dir.join("capture.png");
+        DynamicImage::new_rgba8(1280, 640)
+            .save(&image_file)
+            .unwrap();
+
+        // Embedding works before there is a thumbnail on disk
+        let uri = thumbnail_data_uri(&image_file, 320).unwrap();
+        assert!(uri.starts_with("data:image/jpeg;base64,/9j/"));
+
+        let thumbnail_file = make_thumbnail(&image_file, 320).unwrap();
+        assert_eq!(thumbnail_file, dir.join("capture.thumb.jpg"));
+        let thumbnail = image::open(&thumbnail_file).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (320, 160));
+        assert_eq!(
+            thumbnail_data_uri(&image_file, 320).unwrap(),
+            format!(
+                "data:image/jpeg;base64,{}",
+                base64::encode(fs::read(&thumbnail_file).unwrap())
+            )
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shrunk_captures() {
+        let dir = std::env::temp_dir()
+            .join(format!("scrying-shrink-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let image_file = dir.join("capture.png");
+        let original_file = original_path(&image_file);
+        assert_eq!(original_file, dir.join("capture.orig.png"));
+        let image = DynamicImage::new_rgba8(400, 1000);
+        let dimensions = |path| image::image_dimensions(path).unwrap();
+
+        // Nothing happens without --max-dimension or within it
+        assert!(shrink(&image, &Opts::default()).is_none());
+        let opts = Opts {
+            max_dimension: Some(1000),
+            keep_original: true,
+            ..Default::default()
+        };
+        save_capture(&image, &image_file, &opts).unwrap();
+        assert_eq!(dimensions(&image_file), (400, 1000));
+        assert!(!original_file.exists());
+
+        let opts = Opts {
+            max_dimension: Some(500),
+            keep_original: true,
+            ..Default::default()
+        };
+        save_capture(&image, &image_file, &opts).unwrap();
+        assert_eq!(dimensions(&image_file), (200, 500));
+        assert_eq!(dimensions(&original_file), (400, 1000));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_output_filename() {
         let test_cases: Vec<(Target, &str)> = vec![
             (
                 Target::Url(Url::parse("http://example.com///").unwrap()),
-                "http_example.com",
+                "http_example.com_80",
             ),
             (
                 Target::Url(Url::parse("http://example.com:8443").unwrap()),
@@ -80,11 +934,11 @@ mod test {
             ),
             (
                 Target::Url(Url::parse("http://192.0.2.65_8443/").unwrap()),
-                "http_192.0.2.65_8443",
+                "http_192.0.2.65%5F8443_80",
             ),
             (
                 Target::Url(Url::parse("http://[2001:db8::56]:8443/").unwrap()),
-                "http_2001_db8__56_8443",
+                "http_2001.db8..56_8443",
             ),
             (
                 Target::Address(
@@ -112,12 +966,42 @@ mod test {
                 ),
                 "192.0.2.45-3389",
             ),
+            // Hyphens in hostnames can't be confused with the path
+            (
+                Target::Url(Url::parse("https://a-b.example/").unwrap()),
+                "https_a-b.example_443",
+            ),
+            (
+                Target::Url(Url::parse("https://a/b.example").unwrap()),
+                "https_a_443-b.example",
+            ),
+            (
+                Target::Url(Url::parse("http://a/b-c/_d").unwrap()),
+                "http_a_80-b%2Dc-%5Fd",
+            ),
+            (
+                Target::Url(
+                    Url::parse("http://user:pass@a/search?q=*&x=\"|\"#top")
+                        .unwrap(),
+                ),
+                "http_a_80-search%3Fq%3D%2A%26x%3D%2522%7C%2522",
+            ),
         ];
 
         for case in test_cases {
             eprintln!("Test case: {:?}", case);
-            let parsed = target_to_filename(&case.0);
+            let parsed = output_filename(&case.0);
             assert_eq!(parsed, case.1);
         }
+
+        // Long names are shortened without losing their uniqueness
+        let long = |end| {
+            let path = format!("{}{}", "x".repeat(300), end);
+            let url = Url::parse("http://example.com/").unwrap().join(&path);
+            output_filename(&Target::Url(url.unwrap()))
+        };
+        assert_eq!(long("a").len(), MAX_FILENAME_STEM);
+        assert!(long("a").starts_with("http_example.com_80-xxx"));
+        assert_ne!(long("a"), long("b"));
     }
 }
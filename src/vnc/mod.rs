@@ -20,18 +20,21 @@
 use crate::argparse::Mode::Vnc;
 use crate::argparse::Opts;
 use crate::error::Error;
+use crate::ocr::save_text;
 use crate::parsing::Target;
 use crate::reporting::ReportMessageContent;
-use crate::reporting::{FileError, ReportMessage};
-use crate::util::target_to_filename;
-use crate::ThreadStatus;
+use crate::reporting::{write_sidecar, FileError, ReportMessage};
+use crate::util::{
+    capture_path, check_blank, connect_tcp, existing_capture, precheck,
+    save_capture, save_thumbnail, with_retries,
+};
 use image::{DynamicImage, ImageBuffer, Rgb};
 #[allow(unused)]
 use log::{debug, error, info, trace, warn};
 use std::convert::TryInto;
-use std::net::TcpStream;
 use std::path::Path;
 use std::sync::mpsc::Sender;
+use std::time::SystemTime;
 use vnc::client::{AuthChoice, AuthMethod, Client};
 use vnc::Colour;
 use vnc::{PixelFormat, Rect};
@@ -351,11 +354,9 @@ struct ColourMap {
     colours: Vec<Colour>,
 }
 
-fn vnc_capture(
-    target: &Target,
-    opts: &Opts,
-    report_tx: &Sender<ReportMessage>,
-) -> Result<(), Error> {
+/// Capture the target, returning the path of the saved image relative to
+/// the output directory
+fn vnc_capture(target: &Target, opts: &Opts) -> Result<String, Error> {
     info!("Connecting to {:?}", target);
     let addr = match target {
         Target::Address(sock_addr) => sock_addr,
@@ -367,8 +368,12 @@ fn vnc_capture(
         }
     };
 
-    let stream = TcpStream::connect(addr)?;
+    let stream = connect_tcp(addr, None, opts.source_addr)?;
 
+    // Set by the auth callback if the server does not offer a
+    // no-authentication method, so that the resulting handshake error
+    // can be reported as such rather than as a generic failure
+    let mut auth_required = false;
     let mut vnc = Client::from_tcp_stream(stream, false, |methods| {
         debug!("available auth methods: {:?}", methods);
         // Turn off Clippy's single_match check because there might be
@@ -381,7 +386,15 @@ fn vnc_capture(
             }
         }
         warn!("AuthMethod::None may not be supported");
+        auth_required = true;
         None
+    })
+    .map_err(|e| {
+        if auth_required {
+            Error::VncError("Server requires authentication".to_string())
+        } else {
+            e.into()
+        }
     })?;
 
     let (width, height) = vnc.size();
@@ -420,20 +433,15 @@ fn vnc_capture(
 
     // Save the image
     info!("Successfully received image");
-    let filename = format!("{}.png", target_to_filename(&target));
-    let relative_filepath = Path::new("vnc").join(&filename);
+    let relative_filepath = capture_path(&target, "vnc", opts);
     let filepath = Path::new(&opts.output_dir).join(&relative_filepath);
     info!("Saving image as {}", filepath.display());
-    vnc_image.image.save(&filepath)?;
-
-    let report_message = ReportMessage::Output(ReportMessageContent {
-        mode: Vnc,
-        target: target.to_string(),
-        output: FileError::File(relative_filepath.display().to_string()),
-    });
-    report_tx.send(report_message)?;
+    check_blank(&vnc_image.image, opts)?;
+    save_capture(&vnc_image.image, &filepath, opts)?;
+    save_thumbnail(&filepath, opts);
+    save_text(&filepath, opts);
 
-    Ok(())
+    Ok(relative_filepath.display().to_string())
 }
 
 fn vnc_poll(mut vnc: Client, vnc_image: &mut Image) -> Result<(), Error> {
@@ -471,12 +479,32 @@ fn vnc_poll(mut vnc: Client, vnc_image: &mut Image) -> Result<(), Error> {
 pub fn capture(
     target: &Target,
     opts: &Opts,
-    tx: Sender<ThreadStatus>,
     report_tx: &Sender<ReportMessage>,
 ) {
-    if let Err(e) = vnc_capture(&target, opts, report_tx) {
-        warn!("VNC error: {}", e);
-    }
-
-    tx.send(ThreadStatus::Complete).unwrap();
+    let start = SystemTime::now();
+    let mut address = None;
+    let existing = existing_capture(target, "vnc", opts);
+    let (result, attempts) = match &existing {
+        Some(file) => (Ok(file.clone()), 0),
+        None => with_retries(opts, || {
+            address = precheck(target, opts, &None)?.map(|a| a.ip());
+            vnc_capture(&target, opts)
+        }),
+    };
+    let output = match result {
+        Ok(file) => FileError::File(file),
+        Err(e) => {
+            warn!("VNC error: {}", e);
+            FileError::Error(e)
+        }
+    };
+    let content = ReportMessageContent::new(
+        Vnc, target, start, output, attempts, address,
+    )
+    .with_skipped(existing.is_some());
+    write_sidecar(&content, opts);
+    let report_message = ReportMessage::Output(content);
+    report_tx
+        .send(report_message)
+        .expect("Reporting thread seems to have disconnected");
 }
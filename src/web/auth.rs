@@ -0,0 +1,72 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Answering HTTP auth challenges for --web-auth. headless_chrome's
+//! request interception handlers can only continue a request or replace
+//! its response, so challenges are answered with the DevTools method
+//! directly.
+
+use super::emulation::EmptyReturnObject;
+use headless_chrome::protocol::Method;
+use serde::Serialize;
+
+/// Continue an intercepted request that is waiting on an auth challenge
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContinueWithAuth<'a> {
+    pub interception_id: &'a str,
+    pub auth_challenge_response: AuthChallengeResponse<'a>,
+}
+
+impl<'a> Method for ContinueWithAuth<'a> {
+    const NAME: &'static str = "Network.continueInterceptedRequest";
+    type ReturnObject = EmptyReturnObject;
+}
+
+/// How to answer an auth challenge. Cancelling it leaves the 401 response
+/// to be captured.
+#[derive(Debug, Serialize)]
+pub struct AuthChallengeResponse<'a> {
+    pub response: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<&'a str>,
+}
+
+impl<'a> AuthChallengeResponse<'a> {
+    /// Answer with the user:pass credentials from --web-auth
+    pub fn provide(credentials: &'a str) -> Self {
+        let mut parts = credentials.splitn(2, ':');
+        Self {
+            response: "ProvideCredentials",
+            username: parts.next(),
+            password: Some(parts.next().unwrap_or_default()),
+        }
+    }
+
+    /// Refuse to answer, so that the request fails with its 401
+    pub fn cancel() -> Self {
+        Self {
+            response: "CancelAuth",
+            username: None,
+            password: None,
+        }
+    }
+}
@@ -0,0 +1,172 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Make sure that no Chrome processes are left behind when captures
+//! finish, Chrome crashes, or scrying is interrupted
+
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use std::sync::{Arc, Mutex};
+
+/// The Chrome processes that are currently running, shared with the
+/// interrupt handler so that they can be killed before exiting
+#[derive(Clone, Debug, Default)]
+pub struct ChromeProcesses(Arc<Mutex<Vec<u32>>>);
+
+impl ChromeProcesses {
+    /// Kill every Chrome that is still running, for when scrying exits
+    /// without unwinding
+    pub fn kill_all(&self) {
+        for pid in self.0.lock().unwrap().drain(..) {
+            kill_tree(pid);
+        }
+    }
+
+    /// Start tracking a Chrome process, killing it when the returned
+    /// guard is dropped
+    pub fn guard(&self, pid: Option<u32>) -> ChromeGuard {
+        if let Some(pid) = pid {
+            self.0.lock().unwrap().push(pid);
+        }
+        ChromeGuard {
+            pid,
+            processes: self.clone(),
+        }
+    }
+}
+
+/// Kills a Chrome process along with its renderer and helper processes
+/// when dropped. This has to happen before the `Browser` is dropped,
+/// because the helpers can only be found while their parent is alive.
+#[derive(Debug)]
+pub struct ChromeGuard {
+    pid: Option<u32>,
+    processes: ChromeProcesses,
+}
+
+impl Drop for ChromeGuard {
+    fn drop(&mut self) {
+        if let Some(pid) = self.pid {
+            let mut running = self.processes.0.lock().unwrap();
+            // If the interrupt handler already killed it then the PID may
+            // have been reused
+            if let Some(i) = running.iter().position(|p| *p == pid) {
+                running.remove(i);
+                debug!("Killing Chrome process {}", pid);
+                kill_tree(pid);
+            }
+        }
+    }
+}
+
+/// Parent PID from the contents of /proc/<pid>/stat. The process name
+/// is in brackets and may itself contain brackets or spaces.
+#[cfg(unix)]
+fn parent_pid(stat: &str) -> Option<u32> {
+    let fields = &stat[stat.rfind(')')? + 1..];
+    fields.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Every process descended from the given one. This relies on /proc, so
+/// only finds anything on Linux.
+#[cfg(unix)]
+fn descendants(pid: u32) -> Vec<u32> {
+    let mut parents = Vec::new();
+    if let Ok(entries) = std::fs::read_dir("/proc") {
+        for entry in entries.flatten() {
+            let child = match entry.file_name().to_str().map(str::parse) {
+                Some(Ok(child)) => child,
+                _ => continue,
+            };
+            let stat = entry.path().join("stat");
+            if let Ok(Some(parent)) =
+                std::fs::read_to_string(stat).map(|s| parent_pid(&s))
+            {
+                parents.push((child, parent));
+            }
+        }
+    }
+
+    let mut found = vec![pid];
+    let mut i = 0;
+    while i < found.len() {
+        let current = found[i];
+        found.extend(
+            parents
+                .iter()
+                .filter(|(_, parent)| *parent == current)
+                .map(|(child, _)| *child),
+        );
+        i += 1;
+    }
+    found.remove(0);
+    found
+}
+
+#[cfg(unix)]
+fn kill_tree(pid: u32) {
+    for p in descendants(pid).into_iter().chain(Some(pid)) {
+        // Safe because kill has no memory effects, at worst the process
+        // has already gone
+        unsafe {
+            libc::kill(p as libc::pid_t, libc::SIGKILL);
+        }
+    }
+}
+
+#[cfg(windows)]
+fn kill_tree(pid: u32) {
+    let pid = pid.to_string();
+    if let Err(e) = std::process::Command::new("taskkill")
+        .args(&["/F", "/T", "/PID", &pid])
+        .output()
+    {
+        warn!("Unable to kill Chrome process {}: {}", pid, e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn stat_parsing() {
+        let stat = "1234 (chrome) S 1000 1234 1000 0 -1 4194560 1234";
+        assert_eq!(parent_pid(stat), Some(1000));
+        let stat = "1235 (Web Content (1)) S 1234 1234 1000 0 -1";
+        assert_eq!(parent_pid(stat), Some(1234));
+        assert_eq!(parent_pid("garbage"), None);
+    }
+
+    #[test]
+    fn guard_tracks_processes() {
+        let processes = ChromeProcesses::default();
+        let guard = processes.guard(None);
+        assert!(processes.0.lock().unwrap().is_empty());
+        drop(guard);
+
+        // Nothing is killed once the PID has been forgotten, otherwise
+        // this would kill the test runner
+        drop(ChromeGuard {
+            pid: Some(std::process::id()),
+            processes,
+        });
+    }
+}
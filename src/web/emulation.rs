@@ -0,0 +1,81 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! DevTools emulation methods that headless_chrome doesn't wrap, used to
+//! emulate a device with --device and set the locale and timezone with
+//! --lang and --timezone
+
+use headless_chrome::protocol::Method;
+use serde::{Deserialize, Serialize};
+
+/// Return value of the emulation methods, which is always empty
+#[derive(Debug, Deserialize)]
+pub struct EmptyReturnObject {}
+
+/// Override the size and pixel density of the screen that the page sees
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetDeviceMetricsOverride {
+    pub width: u32,
+    pub height: u32,
+    pub device_scale_factor: f64,
+    pub mobile: bool,
+}
+
+impl Method for SetDeviceMetricsOverride {
+    const NAME: &'static str = "Emulation.setDeviceMetricsOverride";
+    type ReturnObject = EmptyReturnObject;
+}
+
+/// Make the page think that it is on a touch screen
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetTouchEmulationEnabled {
+    pub enabled: bool,
+    pub max_touch_points: u32,
+}
+
+impl Method for SetTouchEmulationEnabled {
+    const NAME: &'static str = "Emulation.setTouchEmulationEnabled";
+    type ReturnObject = EmptyReturnObject;
+}
+
+/// Override the locale that pages see through navigator.language and
+/// Intl, e.g. when formatting dates
+#[derive(Debug, Serialize)]
+pub struct SetLocaleOverride<'a> {
+    pub locale: &'a str,
+}
+
+impl<'a> Method for SetLocaleOverride<'a> {
+    const NAME: &'static str = "Emulation.setLocaleOverride";
+    type ReturnObject = EmptyReturnObject;
+}
+
+/// Override the timezone that pages see
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetTimezoneOverride<'a> {
+    pub timezone_id: &'a str,
+}
+
+impl<'a> Method for SetTimezoneOverride<'a> {
+    const NAME: &'static str = "Emulation.setTimezoneOverride";
+    type ReturnObject = EmptyReturnObject;
+}
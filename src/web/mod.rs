@@ -18,46 +18,768 @@
 */
 
 use crate::argparse::Mode::Web;
+use crate::argparse::{ImageFormat, Opts, OutputFormat};
 use crate::error::Error;
+use crate::ocr::save_text;
 use crate::parsing::Target;
 use crate::reporting::ReportMessageContent;
-use crate::reporting::{FileError, ReportMessage};
-use crate::util::target_to_filename;
-use headless_chrome::{protocol::page::ScreenshotFormat, Tab};
+use crate::reporting::{write_sidecar, FileError, ReportMessage};
+use crate::tags::matching;
+use crate::tls::{fetch_certificate, CertInfo};
+use crate::util::{
+    atomic_write, capture_path, check_blank, existing_capture, favicon_hash,
+    original_path, output_filename, precheck, save_image, save_thumbnail,
+    shrink, with_retries,
+};
+use auth::{AuthChallengeResponse, ContinueWithAuth};
+use emulation::{
+    SetDeviceMetricsOverride, SetLocaleOverride, SetTimezoneOverride,
+    SetTouchEmulationEnabled,
+};
+use headless_chrome::browser::tab::RequestInterceptionDecision;
+use headless_chrome::protocol::network::events::RequestInterceptedEventParams;
+use headless_chrome::protocol::network::methods::RequestPattern;
+use headless_chrome::protocol::page::{
+    PrintToPdfOptions, ScreenshotFormat, Viewport,
+};
+use headless_chrome::Tab;
 #[allow(unused)]
 use log::{debug, error, info, trace, warn};
+use page::{CaptureScreenshot, GetLayoutMetrics};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::net::SocketAddr;
 use std::path::Path;
-use std::sync::mpsc;
-use std::{fs::File, io::Write};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use url::Url;
 
+mod auth;
+pub mod chrome;
+mod emulation;
+mod page;
+
+/// User agent sent when --mobile is supplied
+const MOBILE_USER_AGENT: &str = concat!(
+    "Mozilla/5.0 (iPhone; CPU iPhone OS 14_0 like Mac OS X) ",
+    "AppleWebKit/605.1.15 (KHTML, like Gecko) Version/14.0 ",
+    "Mobile/15E148 Safari/604.1"
+);
+
+/// Browser window size used when --mobile is supplied
+const MOBILE_WINDOW_SIZE: (u32, u32) = (375, 812);
+
+/// Browser window size used by default
+const DEFAULT_WINDOW_SIZE: (u32, u32) = (1280, 720);
+
+/// Number of touch points that an emulated --device has
+const DEVICE_TOUCH_POINTS: u32 = 5;
+
+/// Response served in place of a redirect once --max-redirects is reached,
+/// so that redirect loops are cut short
+const REDIRECT_LOOP_RESPONSE: &str =
+    "HTTP/1.1 508 Loop Detected\r\nContent-Length: 0\r\n\r\n";
+
+/// Response served in place of the resources blocked by --block-resources,
+/// so that they are never requested from the server
+const BLOCKED_RESPONSE: &str =
+    "HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n";
+
+/// Fetch the page's favicon from within the page, returning it base64
+/// encoded or null if there isn't one
+const FETCH_FAVICON_JS: &str = concat!(
+    "(async () => {",
+    "const link = document.querySelector(\"link[rel~='icon']\");",
+    "const res = await fetch(link ? link.href : '/favicon.ico');",
+    "if (!res.ok) { return null; }",
+    "const bytes = new Uint8Array(await res.arrayBuffer());",
+    "if (bytes.length === 0) { return null; }",
+    "let s = '';",
+    "for (const b of bytes) { s += String.fromCharCode(b); }",
+    "return btoa(s);",
+    "})()"
+);
+
+/// One response received while navigating to a page
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Hop {
+    pub url: String,
+    pub status: u32,
+}
+
+#[derive(Debug, Default)]
+struct NavigationLog {
+    /// Responses for the page so far, ending with the one that was not a
+    /// redirect
+    hops: Vec<Hop>,
+    /// Whether the final response has been seen
+    complete: bool,
+    /// Whether the redirects were cut short by --max-redirects
+    too_many_redirects: bool,
+    /// Server header of the final response
+    server: Option<String>,
+    /// Host of the target being captured, which is the only one that is
+    /// given the --web-auth credentials if no --web-auth-host was given
+    target_host: Option<String>,
+    /// Origins whose auth challenges have already been answered
+    auth_origins: HashSet<String>,
+}
+
+/// Record of what happened while navigating the tab, filled in by the
+/// request interception handler installed in `configure_tab`
+#[derive(Clone, Default)]
+pub struct PageEvents(Arc<Mutex<NavigationLog>>);
+
+impl PageEvents {
+    /// Clear the record ready for navigating to `url`
+    fn reset(&self, url: &Url) {
+        *self.0.lock().unwrap() = NavigationLog {
+            target_host: url.host_str().map(String::from),
+            ..Default::default()
+        };
+    }
+
+    /// Take the record of the last navigation
+    fn take(&self) -> NavigationLog {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+
+    /// Note a navigation response, deciding whether to let it through
+    fn handle(
+        &self,
+        intercepted: RequestInterceptedEventParams,
+        max_redirects: usize,
+    ) -> RequestInterceptionDecision {
+        let mut log = self.0.lock().unwrap();
+        // Frames within the page are navigations too, but they only
+        // start loading after the page itself has arrived
+        if !intercepted.is_navigation_request || log.complete {
+            return RequestInterceptionDecision::Continue;
+        }
+
+        let status = intercepted.response_status_code.unwrap_or_default();
+        log.hops.push(Hop {
+            url: intercepted.request.url,
+            status,
+        });
+        if !(300..400).contains(&status) {
+            log.complete = true;
+            log.server = intercepted.response_headers.and_then(|headers| {
+                headers
+                    .into_iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case("server"))
+                    .map(|(_, value)| value)
+            });
+        } else if log.hops.len() > max_redirects {
+            log.complete = true;
+            log.too_many_redirects = true;
+            return RequestInterceptionDecision::Response(base64::encode(
+                REDIRECT_LOOP_RESPONSE,
+            ));
+        }
+        RequestInterceptionDecision::Continue
+    }
+
+    /// Decide whether to give the --web-auth credentials in answer to an
+    /// auth challenge for a request to `url`. They only go to the hosts
+    /// they are meant for, whatever the request is for and wherever it
+    /// was redirected from, and only once for each origin so that a
+    /// failed login is captured rather than retried.
+    fn provide_auth(&self, url: &str, hosts: &[String]) -> bool {
+        let url = match Url::parse(url) {
+            Ok(url) => url,
+            Err(_) => return false,
+        };
+        let mut log = self.0.lock().unwrap();
+        let allowed = match url.host_str() {
+            Some(host) if hosts.is_empty() => log
+                .target_host
+                .as_deref()
+                .map_or(false, |target| target.eq_ignore_ascii_case(host)),
+            Some(host) => hosts.iter().any(|h| h.eq_ignore_ascii_case(host)),
+            None => false,
+        };
+        allowed && log.auth_origins.insert(url.origin().ascii_serialization())
+    }
+}
+
+/// Size of the browser window, which is given by --window-size or else
+/// depends on whether a mobile device is being emulated
+pub fn window_size(opts: &Opts) -> (u32, u32) {
+    match (opts.window_size, opts.mobile, opts.device) {
+        (Some((w, h)), _, _) => (u32::from(w), u32::from(h)),
+        (None, true, _) => MOBILE_WINDOW_SIZE,
+        (None, false, Some(device)) => device.window_size(),
+        (None, false, None) => DEFAULT_WINDOW_SIZE,
+    }
+}
+
+/// Apply the per-tab settings from the command line options. This needs
+/// to happen before the tab is used for any captures. The returned
+/// events are filled in during each capture.
+pub fn configure_tab(tab: &Tab, opts: &Opts) -> Result<PageEvents, Error> {
+    tab.set_default_timeout(Duration::from_secs(opts.web_timeout));
+
+    // An explicit user agent takes priority over the --mobile and
+    // --device defaults
+    let user_agent = match (&opts.user_agent, opts.mobile, opts.device) {
+        (Some(ua), _, _) => Some(ua.as_str()),
+        (None, true, _) => Some(MOBILE_USER_AGENT),
+        (None, false, Some(device)) => Some(device.user_agent()),
+        (None, false, None) => None,
+    };
+    if let Some(ua) = user_agent {
+        debug!("Setting user agent to {}", ua);
+        tab.set_user_agent(ua, None, None)?;
+    }
+
+    if let Some(device) = opts.device {
+        let (width, height) = window_size(opts);
+        debug!("Emulating {:?} at {}x{}", device, width, height);
+        tab.call_method(SetDeviceMetricsOverride {
+            width,
+            height,
+            device_scale_factor: device.scale_factor(),
+            mobile: true,
+        })?;
+        tab.call_method(SetTouchEmulationEnabled {
+            enabled: true,
+            max_touch_points: DEVICE_TOUCH_POINTS,
+        })?;
+    }
+
+    set_headers(tab, opts)?;
+
+    // Left alone, Chrome uses the locale and timezone of this machine.
+    // The language is sent in Accept-Language too, by set_headers.
+    if let Some(lang) = &opts.lang {
+        debug!("Setting locale to {}", lang);
+        tab.call_method(SetLocaleOverride { locale: lang })?;
+    }
+    if let Some(timezone) = &opts.timezone {
+        debug!("Setting timezone to {}", timezone);
+        tab.call_method(SetTimezoneOverride {
+            timezone_id: timezone,
+        })?;
+    }
+
+    // Watch the responses to page loads so that the redirect chain can
+    // be recorded, and stop any --block-resources before they are sent.
+    // With --web-auth every response is watched, so that auth challenges
+    // for the page's resources can be answered too.
+    let events = PageEvents::default();
+    let handler_events = events.clone();
+    let max_redirects = opts.max_redirects;
+    let blocked = opts.block_resources.clone();
+    let web_auth = opts.web_auth.clone();
+    let web_auth_hosts = opts.web_auth_hosts.clone();
+    let mut patterns = vec![RequestPattern {
+        url_pattern: None,
+        resource_type: Some("Document"),
+        interception_stage: Some("HeadersReceived"),
+    }];
+    if web_auth.is_some() {
+        patterns.push(RequestPattern {
+            url_pattern: None,
+            resource_type: None,
+            interception_stage: Some("HeadersReceived"),
+        });
+    }
+    for resource_type in &opts.block_resources {
+        patterns.push(RequestPattern {
+            url_pattern: None,
+            resource_type: Some(resource_type),
+            interception_stage: Some("Request"),
+        });
+    }
+    tab.enable_request_interception(
+        &patterns,
+        Box::new(move |transport, session_id, intercepted| {
+            if intercepted.auth_challenge.is_some() {
+                let url = &intercepted.request.url;
+                let response = match &web_auth {
+                    Some(creds)
+                        if handler_events
+                            .provide_auth(url, &web_auth_hosts) =>
+                    {
+                        debug!("Sending basic auth credentials to {}", url);
+                        AuthChallengeResponse::provide(creds)
+                    }
+                    _ => AuthChallengeResponse::cancel(),
+                };
+                let method = ContinueWithAuth {
+                    interception_id: &intercepted.interception_id,
+                    auth_challenge_response: response,
+                };
+                if let Err(e) =
+                    transport.call_method_on_target(session_id, method)
+                {
+                    warn!("Unable to answer auth challenge for {}: {}", url, e);
+                }
+                // The request has been continued above, so headless_chrome
+                // continuing it again is turned down by Chrome
+                return RequestInterceptionDecision::Continue;
+            }
+            if blocked.contains(&intercepted.resource_type) {
+                trace!("Blocking {}", intercepted.request.url);
+                return RequestInterceptionDecision::Response(base64::encode(
+                    BLOCKED_RESPONSE,
+                ));
+            }
+            handler_events.handle(intercepted, max_redirects)
+        }),
+    )?;
+
+    Ok(events)
+}
+
+/// Set the extra HTTP headers from --header and --lang, which are sent
+/// with every request. --web-auth credentials are never sent this way, as
+/// they would go to every host that the page loads anything from, and are
+/// given in answer to auth challenges instead.
+fn set_headers(tab: &Tab, opts: &Opts) -> Result<(), Error> {
+    if opts.headers.is_empty() && opts.lang.is_none() {
+        return Ok(());
+    }
+
+    let mut headers: HashMap<&str, &str> = opts
+        .headers
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect();
+    // An Accept-Language given with --header wins over --lang
+    if let Some(lang) = &opts.lang {
+        if !headers
+            .keys()
+            .any(|name| name.eq_ignore_ascii_case("accept-language"))
+        {
+            headers.insert("Accept-Language", lang);
+        }
+    }
+    debug!("Setting extra HTTP headers: {:?}", headers);
+    tab.set_extra_http_headers(headers)?;
+
+    Ok(())
+}
+
+/// Check whether the tab still responds, as it may have crashed while
+/// rendering a page
+pub fn tab_alive(tab: &Tab) -> bool {
+    tab.evaluate("1", false).is_ok()
+}
+
+/// Details about a web page gathered alongside the screenshot
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PageInfo {
+    /// URL that the page ended up at after any redirects
+    pub final_url: Option<String>,
+    /// Saved page source relative to the output directory, if --save-html
+    /// was given
+    pub html_file: Option<String>,
+    /// Every response on the way to the page, starting with the target
+    /// itself
+    pub redirects: Vec<Hop>,
+    /// Title of the page, or the final URL if it has no title
+    pub title: Option<String>,
+    /// Server header of the page
+    pub server: Option<String>,
+    /// Shodan-style hash of the favicon, if the page has one
+    pub favicon_hash: Option<i32>,
+    /// Saved PDF of the page relative to the output directory, if --pdf
+    /// was given
+    #[serde(default)]
+    pub pdf_file: Option<String>,
+    /// Certificate presented by HTTPS targets
+    pub tls: Option<CertInfo>,
+    /// Whether the element given by --wait-for-selector never appeared,
+    /// in which case the page was captured anyway
+    pub selector_timed_out: bool,
+    /// Names of the --match patterns that the page source matched
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Fetch the favicon of the current page and hash it
+fn hash_favicon(tab: &Tab) -> Result<Option<i32>, Error> {
+    let encoded = tab.evaluate(FETCH_FAVICON_JS, true)?.value;
+    match encoded.as_ref().and_then(|v| v.as_str()) {
+        Some(e) => {
+            let icon = base64::decode(e).map_err(|e| {
+                Error::ConversionError(format!("Invalid favicon data: {}", e))
+            })?;
+            Ok(Some(favicon_hash(&icon)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Rendered DOM of the current page
+fn page_html(tab: &Tab) -> Result<String, Error> {
+    Ok(tab
+        .evaluate("document.documentElement.outerHTML", false)?
+        .value
+        .and_then(|v| v.as_str().map(|s| s.to_string()))
+        .unwrap_or_default())
+}
+
+/// Save the rendered DOM of the current page with the URL that it came
+/// from at the top, returning the path relative to the output directory
+fn save_html(
+    html: &str,
+    opts: &Opts,
+    basename: &str,
+    final_url: &str,
+) -> Result<String, Error> {
+    let relative_filepath = Path::new("web").join(format!("{}.html", basename));
+    let output_file = Path::new(&opts.output_dir).join(&relative_filepath);
+    info!("Saving page source as {}", output_file.display());
+    atomic_write(&output_file, |w| {
+        // A double hyphen would end the comment early
+        writeln!(w, "<!-- {} -->", final_url.replace("--", "%2D%2D"))?;
+        w.write_all(html.as_bytes())?;
+        Ok(())
+    })?;
+
+    Ok(relative_filepath.display().to_string())
+}
+
+/// Print the current page to a PDF with the paper size and orientation
+/// that were asked for, returning the path relative to the output
+/// directory
+fn save_pdf(tab: &Tab, opts: &Opts, basename: &str) -> Result<String, Error> {
+    let (paper_width, paper_height) = opts.pdf_paper.inches();
+    let pdf = tab.print_to_pdf(Some(PrintToPdfOptions {
+        landscape: Some(opts.pdf_landscape),
+        print_background: Some(true),
+        paper_width: Some(paper_width),
+        paper_height: Some(paper_height),
+        ..Default::default()
+    }))?;
+
+    let relative_filepath = Path::new("web").join(format!("{}.pdf", basename));
+    let output_file = Path::new(&opts.output_dir).join(&relative_filepath);
+    info!("Saving PDF as {}", output_file.display());
+    atomic_write(&output_file, |w| {
+        w.write_all(&pdf)?;
+        Ok(())
+    })?;
+
+    Ok(relative_filepath.display().to_string())
+}
+
+/// Limit the tab's waits to the time left before the deadline, failing if
+/// it has already passed
+fn until(tab: &Tab, deadline: Instant, url: &Url) -> Result<(), Error> {
+    let left = deadline.saturating_duration_since(Instant::now());
+    if left == Duration::from_secs(0) {
+        return Err(Error::TimeoutError(format!(
+            "{} took longer than --web-timeout",
+            url
+        )));
+    }
+    tab.set_default_timeout(left);
+    Ok(())
+}
+
+/// Give the page a chance to finish rendering after it has loaded, as
+/// requested by --wait-for-selector and --wait, without going past the
+/// deadline
+fn wait_for_render(
+    tab: &Tab,
+    opts: &Opts,
+    url: &Url,
+    page: &mut PageInfo,
+    deadline: Instant,
+) {
+    if let Some(selector) = &opts.wait_for_selector {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if let Err(e) =
+            tab.wait_for_element_with_custom_timeout(selector, timeout)
+        {
+            warn!(
+                "{} did not appear on {}, capturing anyway: {}",
+                selector, url, e
+            );
+            page.selector_timed_out = true;
+        }
+    }
+    if opts.wait > 0 {
+        debug!("Waiting {}ms for {} to render", opts.wait, url);
+        let left = deadline.saturating_duration_since(Instant::now());
+        std::thread::sleep(Duration::from_millis(opts.wait).min(left));
+    }
+}
+
+/// Run the scripts given with --eval and --eval-file. A failing script is
+/// logged, but the page is still captured.
+fn run_scripts(tab: &Tab, opts: &Opts, url: &Url) {
+    for (i, script) in opts.scripts.iter().enumerate() {
+        if let Err(e) = tab.evaluate(script, true) {
+            warn!("Script {} failed on {}: {}", i + 1, url, e);
+        }
+    }
+}
+
+/// Capture the whole page rather than just the part that fits in the
+/// window, cut off at --max-height
+fn full_page_screenshot(tab: &Tab, opts: &Opts) -> Result<Vec<u8>, Error> {
+    let metrics = tab.call_method(GetLayoutMetrics {})?;
+    let size = metrics.css_content_size.unwrap_or(metrics.content_size);
+    let max_height = f64::from(opts.max_height);
+    if size.height > max_height {
+        debug!(
+            "Cutting off page of height {} at {}",
+            size.height, max_height
+        );
+    }
+    let (format, quality) = match opts.image_format {
+        ImageFormat::Png => ("png", None),
+        ImageFormat::Jpg => ("jpeg", Some(opts.jpeg_quality)),
+    };
+    let screenshot = tab.call_method(CaptureScreenshot {
+        format,
+        quality,
+        clip: Viewport {
+            x: 0.0,
+            y: 0.0,
+            width: size.width,
+            height: size.height.min(max_height),
+            scale: 1.0,
+        },
+        from_surface: true,
+        capture_beyond_viewport: true,
+    })?;
+    base64::decode(&screenshot.data).map_err(|e| {
+        Error::ChromeError(format!("Invalid screenshot data: {}", e))
+    })
+}
+
+/// Capture the target, returning the path of the saved image relative to
+/// the output directory. Details about the page are filled in as they
+/// are found so that they are kept even if the capture fails part way.
+fn capture_worker(
+    target: &Target,
+    opts: &Opts,
+    tab: &Tab,
+    events: &PageEvents,
+    address: Option<SocketAddr>,
+    page: &mut PageInfo,
+) -> Result<String, Error> {
+    let url = match target {
+        Target::Url(u) => u,
+        Target::Address(_) => {
+            return Err(Error::ChromeError(format!(
+                "Invalid web target: {}",
+                target
+            )));
+        }
+    };
+
+    let basename = output_filename(&target);
+    let relative_filepath = capture_path(&target, "web", opts);
+    let output_file = Path::new(&opts.output_dir).join(&relative_filepath);
+    info!("Saving image as {}", output_file.display());
+    // The certificate is read over a separate connection, to the address
+    // that was checked, which is only possible when the target is directly
+    // reachable. Chrome makes its own lookup for the page itself.
+    if url.scheme() == "https" && opts.web_proxy.is_none() {
+        let timeout = Duration::from_secs(opts.web_timeout);
+        page.tls = fetch_certificate(url, address, timeout, opts.source_addr)
+            .map_err(|e| debug!("Unable to read certificate of {}: {}", url, e))
+            .ok();
+    }
+
+    // --web-timeout covers loading, waiting for and capturing the page as
+    // a whole rather than each step, so that a page that keeps Chrome just
+    // short of the timeout at every step doesn't hold up the worker
+    let deadline = Instant::now() + Duration::from_secs(opts.web_timeout);
+    events.reset(url);
+    until(tab, deadline, url)?;
+    tab.navigate_to(url.as_str())?;
+    tab.wait_until_navigated()?;
+    wait_for_render(tab, opts, url, page, deadline);
+    until(tab, deadline, url)?;
+    run_scripts(tab, opts, url);
+    let final_url = tab.get_url();
+    page.final_url = Some(final_url.clone());
+
+    let title = tab
+        .evaluate("document.title", false)?
+        .value
+        .and_then(|v| v.as_str().map(|s| s.trim().to_string()))
+        .filter(|t| !t.is_empty());
+    page.title = Some(title.unwrap_or_else(|| final_url.clone()));
+
+    // A missing or broken favicon shouldn't fail the capture
+    page.favicon_hash = hash_favicon(tab).unwrap_or_else(|e| {
+        debug!("Unable to fetch favicon for {}: {}", url, e);
+        None
+    });
+
+    let log = events.take();
+    page.redirects = log.hops;
+    page.server = log.server;
+    if log.too_many_redirects {
+        return Err(Error::ChromeError(format!(
+            "Stopped after {} redirects, possibly a redirect loop",
+            opts.max_redirects
+        )));
+    }
+
+    until(tab, deadline, url)?;
+    let image_data = if opts.full_page {
+        full_page_screenshot(tab, opts)?
+    } else {
+        let format = match opts.image_format {
+            ImageFormat::Png => ScreenshotFormat::PNG,
+            ImageFormat::Jpg => {
+                ScreenshotFormat::JPEG(Some(opts.jpeg_quality as u32))
+            }
+        };
+        tab.capture_screenshot(format, None, true)?
+    };
+    // Chrome has already encoded the screenshot, so it is only decoded if
+    // it needs to be looked at or shrunk
+    let image = if opts.skip_blank || opts.max_dimension.is_some() {
+        Some(image::load_from_memory(&image_data).map_err(|e| {
+            Error::ChromeError(format!("Unable to decode screenshot: {}", e))
+        })?)
+    } else {
+        None
+    };
+    if let (Some(image), true) = (&image, opts.skip_blank) {
+        check_blank(image, opts)?;
+    }
+    let write_data = |path: &Path| {
+        atomic_write(path, |w| {
+            w.write_all(&image_data)?;
+            Ok(())
+        })
+    };
+    match image.as_ref().and_then(|i| shrink(i, opts)) {
+        Some(shrunk) => {
+            save_image(&shrunk, &output_file, opts)?;
+            if opts.keep_original {
+                write_data(&original_path(&output_file))?;
+            }
+        }
+        None => write_data(&output_file)?,
+    }
+    save_thumbnail(&output_file, opts);
+    save_text(&output_file, opts);
+
+    // The image has been saved by now, so the extras going wrong doesn't
+    // fail the capture
+    if opts.save_html || !opts.matches.is_empty() {
+        match page_html(tab) {
+            Ok(html) => {
+                page.tags = matching(&opts.matches, &html);
+                if opts.save_html {
+                    match save_html(&html, opts, &basename, &final_url) {
+                        Ok(file) => page.html_file = Some(file),
+                        Err(e) => {
+                            warn!("Unable to save HTML of {}: {}", url, e)
+                        }
+                    }
+                }
+            }
+            Err(e) => warn!("Unable to read HTML of {}: {}", url, e),
+        }
+    }
+    if opts.output_format == OutputFormat::Pdf {
+        page.pdf_file = Some(save_pdf(tab, opts, &basename)?);
+    }
+
+    Ok(relative_filepath.display().to_string())
+}
+
+/// Capture the target and send the outcome to the reporting thread. Any
+/// error is also returned so that the caller can decide whether to
+/// continue with the remaining targets. If `may_retry` is set and the tab
+/// crashed then nothing is reported and `TabCrashed` is returned, so that
+/// the caller can try again in a new tab.
 pub fn capture(
     target: &Target,
-    output_dir: &str,
+    opts: &Opts,
     tab: &Tab,
+    events: &PageEvents,
     report_tx: &mpsc::Sender<ReportMessage>,
+    may_retry: bool,
 ) -> Result<(), Error> {
     info!("Processing {}", target);
 
-    let filename = format!("{}.png", target_to_filename(&target));
+    let start = SystemTime::now();
+    let mut address = None;
+    let mut page = PageInfo::default();
+    let existing = existing_capture(target, "web", opts);
+    let (result, attempts) = match &existing {
+        Some(file) => (Ok(file.clone()), 0),
+        None => with_retries(opts, || {
+            page = PageInfo::default();
+            address = precheck(target, opts, &opts.web_proxy)?;
+            let result =
+                capture_worker(target, opts, tab, events, address, &mut page);
+            // The capture shortens the tab's timeout as it goes
+            tab.set_default_timeout(Duration::from_secs(opts.web_timeout));
+            result
+        }),
+    };
+    if let Err(e) = &result {
+        if may_retry && !tab_alive(tab) {
+            return Err(Error::TabCrashed(e.to_string()));
+        }
+    }
+    let output = match &result {
+        Ok(file) => FileError::File(file.clone()),
+        Err(e) => FileError::Error(e.clone()),
+    };
+    let content = ReportMessageContent::new(
+        Web,
+        target,
+        start,
+        output,
+        attempts,
+        address.map(|a| a.ip()),
+    )
+    .with_page(page)
+    .with_skipped(existing.is_some());
+    write_sidecar(&content, opts);
+    let report_message = ReportMessage::Output(content);
+    report_tx.send(report_message)?;
 
-    let relative_filepath = Path::new("web").join(&filename);
-    let output_file = Path::new(output_dir).join(&relative_filepath);
-    info!("Saving image as {}", output_file.display());
-    if let Target::Url(target) = target {
-        tab.navigate_to(target.as_str())?;
-        tab.wait_until_navigated()?;
-        let png_data = tab
-            .capture_screenshot(ScreenshotFormat::PNG, None, true)
-            .expect("error making screenshot");
-        let mut file = File::create(&output_file)?;
-        file.write_all(&png_data)?;
-
-        let report_message = ReportMessage::Output(ReportMessageContent {
-            mode: Web,
-            target: target.to_string(),
-            output: FileError::File(relative_filepath.display().to_string()),
-        });
-        report_tx.send(report_message)?;
+    result.map(|_| ())
+}
+
+#[cfg(test)]
+mod test {
+    use super::PageEvents;
+    use url::Url;
+
+    #[test]
+    fn auth_only_sent_to_its_hosts() {
+        let events = PageEvents::default();
+        events.reset(&Url::parse("http://intranet.example/app/").unwrap());
+
+        // Without --web-auth-host only the target's own host is answered,
+        // not a subresource from elsewhere or a redirect to another host
+        assert!(events.provide_auth("http://intranet.example/app/", &[]));
+        assert!(!events.provide_auth("https://cdn.example/app.js", &[]));
+        assert!(!events.provide_auth("http://login.example/sso", &[]));
+        // A second challenge means the credentials were refused
+        assert!(!events.provide_auth("http://intranet.example/app/", &[]));
+        assert!(!events.provide_auth("not a url", &[]));
+
+        // The record of answered challenges starts again for each page
+        events.reset(&Url::parse("http://intranet.example/").unwrap());
+        assert!(events.provide_auth("http://INTRANET.example/", &[]));
+
+        let hosts = vec!["login.example".to_string()];
+        events.reset(&Url::parse("http://intranet.example/").unwrap());
+        assert!(!events.provide_auth("http://intranet.example/", &hosts));
+        assert!(events.provide_auth("http://login.example/sso", &hosts));
+        assert!(!events.provide_auth("https://cdn.example/app.js", &hosts));
+        // Each origin on an allowed host gets its own answer
+        assert!(events.provide_auth("https://login.example/sso", &hosts));
     }
-    Ok(())
 }
@@ -0,0 +1,76 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! DevTools page methods that headless_chrome doesn't wrap, used to
+//! measure and capture the whole page for --full-page. headless_chrome's
+//! screenshots stop at the edge of the viewport.
+
+use headless_chrome::protocol::page::Viewport;
+use headless_chrome::protocol::Method;
+use serde::{Deserialize, Serialize};
+
+/// Measure the page, including the parts that are scrolled out of view
+#[derive(Debug, Serialize)]
+pub struct GetLayoutMetrics {}
+
+impl Method for GetLayoutMetrics {
+    const NAME: &'static str = "Page.getLayoutMetrics";
+    type ReturnObject = LayoutMetrics;
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LayoutMetrics {
+    /// Size of the whole page, which newer versions of Chrome give in
+    /// device pixels rather than CSS pixels
+    pub content_size: Rect,
+    /// Size of the whole page in CSS pixels, only given by newer versions
+    /// of Chrome
+    pub css_content_size: Option<Rect>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Rect {
+    pub width: f64,
+    pub height: f64,
+}
+
+/// Capture a screenshot of the given part of the page, even where it is
+/// outside of the viewport
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CaptureScreenshot<'a> {
+    pub format: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality: Option<u8>,
+    pub clip: Viewport,
+    pub from_surface: bool,
+    pub capture_beyond_viewport: bool,
+}
+
+impl<'a> Method for CaptureScreenshot<'a> {
+    const NAME: &'static str = "Page.captureScreenshot";
+    type ReturnObject = Screenshot;
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Screenshot {
+    /// The image, base64 encoded
+    pub data: String,
+}
@@ -0,0 +1,239 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Notify a webhook when a scan finishes, and optionally whenever a capture
+//! fails, for --webhook and --webhook-on-error
+
+use crate::argparse::{Mode, Opts};
+use crate::reporting::Summary;
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use serde::Serialize;
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Body posted once the scan has finished
+#[derive(Debug, Serialize)]
+struct Completion<'a> {
+    event: &'static str,
+    output_dir: &'a str,
+    #[serde(flatten)]
+    summary: &'a Summary,
+}
+
+/// Body posted for each failed capture with --webhook-on-error
+#[derive(Debug, Serialize)]
+struct Failure {
+    event: &'static str,
+    target: String,
+    protocol: Mode,
+    error: String,
+    error_category: String,
+}
+
+/// POST the body to the webhook as JSON. Notifications are a courtesy, so
+/// a webhook that can't be reached is logged rather than ending the scan,
+/// and the caller is told so that it can stop sending more.
+fn post<T: Serialize>(url: &str, body: &T, opts: &Opts) -> Result<(), ()> {
+    let body = match serde_json::to_string(body) {
+        Ok(b) => b,
+        Err(e) => {
+            warn!("Unable to encode the webhook body: {}", e);
+            return Ok(());
+        }
+    };
+    let timeout = Duration::from_secs(opts.web_timeout);
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(timeout)
+        .timeout_read(timeout)
+        .build();
+    debug!("Posting {} to {}", body, url);
+    if let Err(e) = agent
+        .post(url)
+        .set("Content-Type", "application/json")
+        .send_string(&body)
+    {
+        warn!("Unable to notify the webhook {}: {}", url, e);
+        return Err(());
+    }
+    Ok(())
+}
+
+/// Let the webhook know that the scan has finished and how it went
+pub fn notify_complete(opts: &Opts, summary: &Summary) {
+    if let Some(url) = &opts.webhook {
+        info!("Notifying {} that the scan has finished", url);
+        let body = Completion {
+            event: "complete",
+            output_dir: &opts.output_dir,
+            summary,
+        };
+        let _ = post(url, &body, opts);
+    }
+}
+
+/// Posts failed captures to the webhook for --webhook-on-error from a
+/// thread of its own, so that a slow webhook doesn't hold up the report.
+/// Once a post can't be delivered the rest are dropped rather than each
+/// waiting out the timeout in turn.
+pub struct FailureNotifier {
+    tx: mpsc::Sender<Failure>,
+    handle: JoinHandle<()>,
+}
+
+impl FailureNotifier {
+    /// Start posting failures, if --webhook-on-error was given
+    pub fn start(opts: &Arc<Opts>) -> Option<Self> {
+        let url = match &opts.webhook {
+            Some(url) if opts.webhook_on_error => url.clone(),
+            _ => return None,
+        };
+        let opts = Arc::clone(opts);
+        let (tx, rx) = mpsc::channel::<Failure>();
+        let handle = thread::spawn(move || {
+            for body in rx {
+                if post(&url, &body, &opts).is_err() {
+                    warn!("Not notifying {} of any more failures", url);
+                    break;
+                }
+            }
+        });
+        Some(Self { tx, handle })
+    }
+
+    /// Queue a failed capture to be posted
+    pub fn notify(
+        &self,
+        target: &str,
+        protocol: Mode,
+        error: &str,
+        error_category: &str,
+    ) {
+        // The thread has only gone if it has stopped posting
+        let _ = self.tx.send(Failure {
+            event: "failure",
+            target: target.to_string(),
+            protocol,
+            error: error.to_string(),
+            error_category: error_category.to_string(),
+        });
+    }
+
+    /// Wait for the failures that have been queued to be posted
+    pub fn finish(self) {
+        drop(self.tx);
+        if self.handle.join().is_err() {
+            warn!("Webhook thread panicked");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    /// Accept requests until the test ends, sending each body back over
+    /// the channel
+    fn serve() -> (String, mpsc::Receiver<serde_json::Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end().to_lowercase();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(l) = line.strip_prefix("content-length:") {
+                        length = l.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                tx.send(serde_json::from_slice(&body).unwrap()).unwrap();
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n"
+                )
+                .unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    #[test]
+    fn webhook_notifications() {
+        let (url, rx) = serve();
+        let mut opts = Arc::new(Opts {
+            webhook: Some(url),
+            web_timeout: 5,
+            output_dir: "output".into(),
+            ..Default::default()
+        });
+        let summary = Summary {
+            targets: 3,
+            succeeded: 2,
+            failed: 1,
+            elapsed_seconds: 12,
+            ..Default::default()
+        };
+
+        notify_complete(&opts, &summary);
+        let body = rx.recv().unwrap();
+        assert_eq!(body["event"], "complete");
+        assert_eq!(body["output_dir"], "output");
+        assert_eq!(body["succeeded"], 2);
+        assert_eq!(body["failed"], 1);
+        assert_eq!(body["elapsed_seconds"], 12);
+
+        // Failures are only sent with --webhook-on-error
+        assert!(FailureNotifier::start(&opts).is_none());
+        Arc::get_mut(&mut opts).unwrap().webhook_on_error = true;
+        let notifier = FailureNotifier::start(&opts).unwrap();
+        notifier.notify("192.0.2.1:3389", Mode::Rdp, "refused", "io");
+        notifier.finish();
+        let body = rx.recv().unwrap();
+        assert_eq!(body["event"], "failure");
+        assert_eq!(body["target"], "192.0.2.1:3389");
+        assert_eq!(body["error_category"], "io");
+        assert!(rx.try_recv().is_err());
+
+        // A webhook that isn't listening doesn't stop anything, and only
+        // the first failure is attempted
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+        Arc::get_mut(&mut opts).unwrap().webhook =
+            Some(format!("http://{}/hook", closed.local_addr().unwrap()));
+        drop(closed);
+        notify_complete(&opts, &summary);
+        let notifier = FailureNotifier::start(&opts).unwrap();
+        notifier.notify("192.0.2.1:3389", Mode::Rdp, "refused", "io");
+        notifier.notify("192.0.2.2:3389", Mode::Rdp, "refused", "io");
+        notifier.finish();
+    }
+}